@@ -0,0 +1,70 @@
+CREATE TABLE users (
+    id TEXT PRIMARY KEY,
+    username TEXT NOT NULL UNIQUE,
+    pubkey TEXT NOT NULL,
+    nonce TEXT NOT NULL,
+    address TEXT NOT NULL,
+    has_double_spent BOOLEAN NOT NULL DEFAULT FALSE
+);
+CREATE INDEX users_address_idx ON users (address);
+CREATE INDEX users_pubkey_idx ON users (pubkey);
+
+-- Note and note history ids held by a user, the `notes` array of the Mongo user document.
+CREATE TABLE user_notes (
+    seq BIGSERIAL PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    note_id TEXT NOT NULL
+);
+CREATE INDEX user_notes_user_idx ON user_notes (user_id);
+
+CREATE TABLE notes (
+    id TEXT PRIMARY KEY,
+    asset_hash TEXT NOT NULL,
+    owner TEXT NOT NULL,
+    value BIGINT NOT NULL,
+    step BIGINT NOT NULL,
+    parent_note TEXT NOT NULL,
+    out_index TEXT NOT NULL,
+    blind TEXT NOT NULL
+);
+CREATE INDEX notes_owner_idx ON notes (owner);
+
+CREATE TABLE note_history (
+    id TEXT PRIMARY KEY,
+    sender TEXT NOT NULL,
+    address TEXT NOT NULL,
+    data BYTEA NOT NULL
+);
+
+CREATE TABLE messages (
+    id TEXT PRIMARY KEY,
+    sender TEXT NOT NULL,
+    recipient TEXT NOT NULL,
+    message TEXT NOT NULL,
+    timestamp BIGINT NOT NULL,
+    attachment_id TEXT,
+    read BOOLEAN NOT NULL DEFAULT FALSE
+);
+CREATE INDEX messages_recipient_read_idx ON messages (recipient, read, timestamp);
+
+CREATE TABLE nullifiers (
+    id TEXT PRIMARY KEY,
+    nullifier TEXT NOT NULL,
+    note TEXT NOT NULL,
+    step BIGINT NOT NULL,
+    owner TEXT NOT NULL,
+    state TEXT NOT NULL UNIQUE
+);
+CREATE INDEX nullifiers_nullifier_idx ON nullifiers (nullifier);
+
+CREATE TABLE challenges (
+    challenge_id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL
+);
+
+CREATE TABLE sessions (
+    session_id TEXT PRIMARY KEY,
+    username TEXT NOT NULL
+);
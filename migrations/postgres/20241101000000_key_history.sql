@@ -0,0 +1,10 @@
+-- Keys a user rotated away from. The current key stays in users.pubkey, it has
+-- been valid since the valid_until of the latest row here.
+CREATE TABLE key_history (
+    username TEXT NOT NULL,
+    pubkey TEXT NOT NULL,
+    key_type TEXT NOT NULL,
+    valid_from BIGINT,
+    valid_until BIGINT NOT NULL
+);
+CREATE INDEX key_history_username_idx ON key_history (username, valid_until);
@@ -0,0 +1,6 @@
+-- A nullifier is spent once: uniqueness moves from state to nullifier so that
+-- /spend can insert without reading first. Fails if a nullifier is stored with
+-- several states, those have to be resolved first.
+ALTER TABLE nullifiers DROP CONSTRAINT nullifiers_state_key;
+DROP INDEX nullifiers_nullifier_idx;
+CREATE UNIQUE INDEX nullifiers_nullifier_key ON nullifiers (nullifier);
@@ -0,0 +1,18 @@
+-- Evidence of every conflicting spend, kept whatever the review of the flag.
+-- nullifier, note, step and state are the first spend, its owner is username.
+CREATE TABLE betrayals (
+    id TEXT PRIMARY KEY,
+    username TEXT NOT NULL,
+    nullifier TEXT NOT NULL,
+    note TEXT NOT NULL,
+    step BIGINT NOT NULL,
+    state TEXT NOT NULL,
+    conflicting_owner TEXT NOT NULL,
+    conflicting_note TEXT NOT NULL,
+    conflicting_step BIGINT NOT NULL,
+    conflicting_state TEXT NOT NULL,
+    detected_at BIGINT NOT NULL
+);
+CREATE INDEX betrayals_username_idx ON betrayals (username, detected_at);
+CREATE INDEX betrayals_note_idx ON betrayals (note);
+CREATE INDEX betrayals_conflicting_note_idx ON betrayals (conflicting_note);
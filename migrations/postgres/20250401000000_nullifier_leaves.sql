@@ -0,0 +1,8 @@
+-- Leaf of every nullifier in the nullifier Merkle tree. Leaves are dense and follow
+-- the order nullifiers were stored in, ids are ObjectIds and sort by creation time.
+CREATE TABLE nullifier_leaves (
+    leaf_index BIGINT PRIMARY KEY,
+    nullifier TEXT NOT NULL UNIQUE
+);
+INSERT INTO nullifier_leaves (leaf_index, nullifier)
+    SELECT ROW_NUMBER() OVER (ORDER BY id) - 1, nullifier FROM nullifiers;
@@ -0,0 +1,13 @@
+-- Sessions get a public id, a bearer token and an expiry. Existing sessions
+-- never expired, so they are dropped and users log in again.
+DROP TABLE sessions;
+
+CREATE TABLE sessions (
+    id TEXT PRIMARY KEY,
+    token TEXT NOT NULL UNIQUE,
+    username TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL
+);
+CREATE INDEX sessions_username_idx ON sessions (username);
+CREATE INDEX sessions_expires_at_idx ON sessions (expires_at);
@@ -0,0 +1,3 @@
+-- Users name the signature scheme of their pubkey. Existing users keep NULL,
+-- which means ed25519.
+ALTER TABLE users ADD COLUMN key_type TEXT;
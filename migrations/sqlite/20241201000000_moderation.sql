@@ -0,0 +1,29 @@
+-- Admins can suspend users and review double-spend flags.
+ALTER TABLE users ADD COLUMN suspended BOOLEAN NOT NULL DEFAULT FALSE;
+CREATE INDEX users_has_double_spent_idx ON users (has_double_spent);
+
+-- Evidence recorded whenever a user is flagged. The nullifier columns copy the
+-- stored nullifier, its owner is username.
+CREATE TABLE double_spend_flags (
+    id TEXT PRIMARY KEY,
+    username TEXT NOT NULL,
+    nullifier TEXT NOT NULL,
+    note TEXT NOT NULL,
+    step BIGINT NOT NULL,
+    state TEXT NOT NULL,
+    presented_state TEXT NOT NULL,
+    flagged_at BIGINT NOT NULL,
+    status TEXT NOT NULL
+);
+CREATE INDEX double_spend_flags_username_idx ON double_spend_flags (username, flagged_at);
+
+-- Append-only trail of admin actions. seq orders entries made in the same second.
+CREATE TABLE admin_audit_log (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    id TEXT NOT NULL UNIQUE,
+    admin TEXT NOT NULL,
+    action TEXT NOT NULL,
+    username TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    at BIGINT NOT NULL
+);
@@ -0,0 +1,17 @@
+-- A nullifier is spent once: uniqueness moves from state to nullifier so that
+-- /spend can insert without reading first. SQLite cannot drop a column
+-- constraint, so the table is rebuilt. Fails if a nullifier is stored with
+-- several states, those have to be resolved first.
+CREATE TABLE nullifiers_unique (
+    id TEXT PRIMARY KEY,
+    nullifier TEXT NOT NULL,
+    note TEXT NOT NULL,
+    step BIGINT NOT NULL,
+    owner TEXT NOT NULL,
+    state TEXT NOT NULL
+);
+INSERT INTO nullifiers_unique (id, nullifier, note, step, owner, state)
+    SELECT id, nullifier, note, step, owner, state FROM nullifiers;
+DROP TABLE nullifiers;
+ALTER TABLE nullifiers_unique RENAME TO nullifiers;
+CREATE UNIQUE INDEX nullifiers_nullifier_key ON nullifiers (nullifier);
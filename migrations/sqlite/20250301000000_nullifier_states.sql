@@ -0,0 +1,14 @@
+-- State vector of every nullifier, a step holds one state and a state belongs to one step.
+-- The step and state on nullifiers stay the first spend.
+CREATE TABLE nullifier_states (
+    nullifier TEXT NOT NULL,
+    step BIGINT NOT NULL,
+    state TEXT NOT NULL,
+    UNIQUE (nullifier, step),
+    UNIQUE (nullifier, state)
+);
+INSERT INTO nullifier_states (nullifier, step, state) SELECT nullifier, step, state FROM nullifiers;
+
+-- The vector of the spent nullifier as JSON, '[]' on older rows.
+ALTER TABLE double_spend_flags ADD COLUMN states TEXT NOT NULL DEFAULT '[]';
+ALTER TABLE betrayals ADD COLUMN states TEXT NOT NULL DEFAULT '[]';
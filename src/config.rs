@@ -0,0 +1,491 @@
+use std::{collections::BTreeMap, env, fmt, fs, net::SocketAddr, path::{Path, PathBuf}};
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Serialize};
+use error_stack::{Report, Result, ResultExt};
+
+/// Configuration file read when neither `--config` nor `IOU_CONFIG` is given.
+pub const DEFAULT_CONFIG_PATH: &str = "service-http.toml";
+
+const LOG_LEVELS: &[&str] = &["off", "error", "warn", "info", "debug", "trace"];
+
+#[derive(Debug)]
+pub enum ConfigError {
+    ReadError,
+    ParseError,
+    InvalidValue,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::ReadError => write!(f, "Failed to read configuration file"),
+            ConfigError::ParseError => write!(f, "Failed to parse configuration"),
+            ConfigError::InvalidValue => write!(f, "Invalid configuration"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Mongo,
+    Sql,
+    Memory,
+}
+
+/// Server configuration.
+///
+/// Values are layered: built-in defaults, then the TOML file, then `IOU_*`
+/// environment variables, then command line flags.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub cors: CorsConfig,
+    pub auth: AuthConfig,
+    pub rate_limit: RateLimitConfig,
+    pub nullifier_tree: NullifierTreeConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen_addr: SocketAddr,
+    /// Largest accepted request body, in bytes.
+    pub body_limit: usize,
+    /// One of `off`, `error`, `warn`, `info`, `debug` or `trace`.
+    pub log_level: String,
+    /// Seconds in-flight requests get to finish after SIGTERM or SIGINT.
+    pub shutdown_timeout: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub backend: StorageBackend,
+    /// Connection string, a `mongodb://` uri or a `sqlite:`/`postgres:` url. Unused by `memory`.
+    pub uri: Option<String>,
+    /// Database name, only used by the MongoDB backend.
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins allowed to call the API, `["*"]` allows any origin.
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Seconds a login challenge stays valid.
+    pub challenge_ttl: i64,
+    /// Seconds a session stays valid after login.
+    pub session_ttl: i64,
+    /// Usernames allowed to call the `/admin` routes.
+    pub admins: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /// Take the client IP from the last `X-Forwarded-For` entry, the one the proxy in
+    /// front of the server appended. Only enable behind a proxy that appends to the
+    /// header, clients could pick their IP otherwise.
+    pub trust_forwarded_for: bool,
+    /// Quota shared by every route without its own entry in `routes`, unlimited when unset.
+    pub default: Option<Quota>,
+    /// Quotas by route path, e.g. `"/auth"`. Each client IP and each user gets its own bucket.
+    pub routes: BTreeMap<String, Quota>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NullifierTreeConfig {
+    /// Hex encoded 32 byte ed25519 secret key signing non-membership proofs. Serving the
+    /// `mongo` and `sql` backends requires it, their proofs must outlive the process. The
+    /// `memory` backend generates a key at startup when unset.
+    pub signing_key: Option<String>,
+}
+
+impl NullifierTreeConfig {
+    /// The decoded `signing_key`, `None` when it is unset or malformed.
+    pub fn signing_key(&self) -> Option<[u8; 32]> {
+        let bytes = hex::decode(self.signing_key.as_deref()?).ok()?;
+        bytes.try_into().ok()
+    }
+}
+
+/// A token bucket: `burst` requests at once, refilled at `per_minute`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Quota {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+impl Quota {
+    pub const fn new(burst: u32, per_minute: u32) -> Self {
+        Self { burst, per_minute }
+    }
+
+    pub fn per_second(&self) -> f64 {
+        self.per_minute as f64 / 60.0
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            body_limit: 2 * 1024 * 1024,
+            log_level: "info".to_owned(),
+            shutdown_timeout: 30,
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::Mongo,
+            uri: None,
+            name: "iou".to_owned(),
+        }
+    }
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self { allowed_origins: vec!["*".to_owned()] }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self { challenge_ttl: 300, session_ttl: 24 * 60 * 60, admins: Vec::new() }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        let routes = [
+            ("/create_user", Quota::new(5, 10)),
+            ("/auth", Quota::new(10, 30)),
+            ("/get_session", Quota::new(10, 30)),
+            ("/send_message", Quota::new(20, 60)),
+        ];
+        Self {
+            enabled: true,
+            trust_forwarded_for: false,
+            default: Some(Quota::new(120, 600)),
+            routes: routes.into_iter().map(|(route, quota)| (route.to_owned(), quota)).collect(),
+        }
+    }
+}
+
+/// Command line flags of `service-http`, each overriding the matching setting.
+#[derive(Debug, Clone, Default, Args)]
+pub struct ConfigArgs {
+    /// TOML configuration file [env: IOU_CONFIG] [default: service-http.toml]
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on, e.g. 0.0.0.0:3000
+    #[arg(long, global = true, value_name = "ADDR")]
+    pub listen_addr: Option<SocketAddr>,
+    /// Storage backend
+    #[arg(long, global = true)]
+    pub storage: Option<StorageBackend>,
+    /// Database connection string
+    #[arg(long, global = true, value_name = "URI")]
+    pub database_uri: Option<String>,
+    /// MongoDB database name
+    #[arg(long, global = true, value_name = "NAME")]
+    pub database_name: Option<String>,
+    /// Allowed CORS origin, repeat for several
+    #[arg(long = "cors-origin", global = true, value_name = "ORIGIN")]
+    pub cors_origins: Vec<String>,
+    /// Seconds a login challenge stays valid
+    #[arg(long, global = true, value_name = "SECONDS")]
+    pub challenge_ttl: Option<i64>,
+    /// Seconds a session stays valid after login
+    #[arg(long, global = true, value_name = "SECONDS")]
+    pub session_ttl: Option<i64>,
+    /// Username allowed to call the admin routes, repeat for several
+    #[arg(long = "admin", global = true, value_name = "USERNAME")]
+    pub admins: Vec<String>,
+    /// Enforce the rate limit quotas
+    #[arg(long, global = true, value_name = "BOOL")]
+    pub rate_limit: Option<bool>,
+    /// Take client IPs from X-Forwarded-For, only behind a proxy that sets it
+    #[arg(long, global = true, value_name = "BOOL")]
+    pub trust_forwarded_for: Option<bool>,
+    /// Largest accepted request body in bytes
+    #[arg(long, global = true, value_name = "BYTES")]
+    pub body_limit: Option<usize>,
+    /// Log level: off, error, warn, info, debug or trace
+    #[arg(long, global = true, value_name = "LEVEL")]
+    pub log_level: Option<String>,
+    /// Seconds in-flight requests get to finish on shutdown
+    #[arg(long, global = true, value_name = "SECONDS")]
+    pub shutdown_timeout: Option<u64>,
+    /// Hex encoded ed25519 key signing non-membership proofs
+    #[arg(long, global = true, value_name = "HEX")]
+    pub tree_signing_key: Option<String>,
+    /// Print the effective configuration and exit
+    #[arg(long, global = true)]
+    pub print_config: bool,
+}
+
+impl Config {
+    /// Builds the effective configuration from every source and validates it.
+    pub fn load(args: &ConfigArgs) -> Result<Self, ConfigError> {
+        let mut config = match args.config.clone().or_else(|| env::var_os("IOU_CONFIG").map(PathBuf::from)) {
+            Some(path) => Self::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?,
+            None => Self::default(),
+        };
+
+        config.apply_env()?;
+        config.apply_args(args);
+        // Which variable holds the uri depends on the backend, a flag may have changed it.
+        if args.database_uri.is_none() {
+            config.apply_uri_env();
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path)
+            .change_context(ConfigError::ReadError)
+            .attach_printable_lazy(|| format!("Path: {}", path.display()))?;
+
+        Self::from_toml(&contents)
+            .attach_printable_lazy(|| format!("Path: {}", path.display()))
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Self, ConfigError> {
+        toml::from_str(contents).change_context(ConfigError::ParseError)
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        if let Some(addr) = env_var("IOU_LISTEN_ADDR") {
+            self.server.listen_addr = parse_env("IOU_LISTEN_ADDR", &addr)?;
+        }
+        if let Some(limit) = env_var("IOU_BODY_LIMIT") {
+            self.server.body_limit = parse_env("IOU_BODY_LIMIT", &limit)?;
+        }
+        if let Some(level) = env_var("IOU_LOG_LEVEL") {
+            self.server.log_level = level;
+        }
+        if let Some(timeout) = env_var("IOU_SHUTDOWN_TIMEOUT") {
+            self.server.shutdown_timeout = parse_env("IOU_SHUTDOWN_TIMEOUT", &timeout)?;
+        }
+        if let Some(backend) = env_var("IOU_STORAGE") {
+            self.database.backend = match StorageBackend::from_str(&backend, true) {
+                Ok(backend) => backend,
+                Err(e) => return Err(Report::new(ConfigError::InvalidValue)
+                    .attach_printable(format!("IOU_STORAGE: {}", e))),
+            };
+        }
+        if let Some(name) = env_var("IOU_DATABASE_NAME") {
+            self.database.name = name;
+        }
+        if let Some(origins) = env_var("IOU_CORS_ORIGINS") {
+            self.cors.allowed_origins = origins.split(',').map(|origin| origin.trim().to_owned()).collect();
+        }
+        if let Some(ttl) = env_var("IOU_CHALLENGE_TTL") {
+            self.auth.challenge_ttl = parse_env("IOU_CHALLENGE_TTL", &ttl)?;
+        }
+        if let Some(ttl) = env_var("IOU_SESSION_TTL") {
+            self.auth.session_ttl = parse_env("IOU_SESSION_TTL", &ttl)?;
+        }
+        if let Some(admins) = env_var("IOU_ADMINS") {
+            self.auth.admins = admins.split(',').map(|admin| admin.trim().to_owned()).collect();
+        }
+        if let Some(enabled) = env_var("IOU_RATE_LIMIT") {
+            self.rate_limit.enabled = parse_env("IOU_RATE_LIMIT", &enabled)?;
+        }
+        if let Some(trust) = env_var("IOU_TRUST_FORWARDED_FOR") {
+            self.rate_limit.trust_forwarded_for = parse_env("IOU_TRUST_FORWARDED_FOR", &trust)?;
+        }
+        if let Some(key) = env_var("IOU_TREE_SIGNING_KEY") {
+            self.nullifier_tree.signing_key = Some(key);
+        }
+        Ok(())
+    }
+
+    // MONGODB_URI and DATABASE_URL predate the configuration file and are still honoured.
+    fn apply_uri_env(&mut self) {
+        let uri = env_var("IOU_DATABASE_URI").or_else(|| match self.database.backend {
+            StorageBackend::Mongo => env_var("MONGODB_URI"),
+            StorageBackend::Sql => env_var("DATABASE_URL"),
+            StorageBackend::Memory => None,
+        });
+        if uri.is_some() {
+            self.database.uri = uri;
+        }
+    }
+
+    fn apply_args(&mut self, args: &ConfigArgs) {
+        if let Some(addr) = args.listen_addr {
+            self.server.listen_addr = addr;
+        }
+        if let Some(limit) = args.body_limit {
+            self.server.body_limit = limit;
+        }
+        if let Some(level) = &args.log_level {
+            self.server.log_level = level.clone();
+        }
+        if let Some(timeout) = args.shutdown_timeout {
+            self.server.shutdown_timeout = timeout;
+        }
+        if let Some(backend) = args.storage {
+            self.database.backend = backend;
+        }
+        if let Some(uri) = &args.database_uri {
+            self.database.uri = Some(uri.clone());
+        }
+        if let Some(name) = &args.database_name {
+            self.database.name = name.clone();
+        }
+        if !args.cors_origins.is_empty() {
+            self.cors.allowed_origins = args.cors_origins.clone();
+        }
+        if let Some(ttl) = args.challenge_ttl {
+            self.auth.challenge_ttl = ttl;
+        }
+        if let Some(ttl) = args.session_ttl {
+            self.auth.session_ttl = ttl;
+        }
+        if !args.admins.is_empty() {
+            self.auth.admins = args.admins.clone();
+        }
+        if let Some(enabled) = args.rate_limit {
+            self.rate_limit.enabled = enabled;
+        }
+        if let Some(trust) = args.trust_forwarded_for {
+            self.rate_limit.trust_forwarded_for = trust;
+        }
+        if let Some(key) = &args.tree_signing_key {
+            self.nullifier_tree.signing_key = Some(key.clone());
+        }
+    }
+
+    /// Checks settings that parse fine but cannot work, so the server fails at startup
+    /// instead of on the first request.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: String| Err(Report::new(ConfigError::InvalidValue).attach_printable(message));
+
+        if self.server.body_limit == 0 {
+            return invalid("server.body_limit must be greater than zero".to_owned());
+        }
+        if !LOG_LEVELS.contains(&self.server.log_level.to_lowercase().as_str()) {
+            return invalid(format!("server.log_level '{}' is not one of {}", self.server.log_level, LOG_LEVELS.join(", ")));
+        }
+
+        match (self.database.backend, self.database.uri.as_deref()) {
+            (StorageBackend::Memory, _) => {},
+            (backend, None) => return invalid(format!("database.uri is required for the {:?} backend", backend)),
+            (StorageBackend::Mongo, Some(uri)) if !uri.starts_with("mongodb://") && !uri.starts_with("mongodb+srv://") =>
+                return invalid("database.uri must be a mongodb:// or mongodb+srv:// uri for the mongo backend".to_owned()),
+            (StorageBackend::Sql, Some(uri)) if !uri.starts_with("sqlite:") && !uri.starts_with("postgres:") && !uri.starts_with("postgresql:") =>
+                return invalid("database.uri must be a sqlite: or postgres: url for the sql backend".to_owned()),
+            _ => {},
+        }
+        if self.database.name.is_empty() {
+            return invalid("database.name must not be empty".to_owned());
+        }
+
+        let origins = &self.cors.allowed_origins;
+        if origins.is_empty() {
+            return invalid("cors.allowed_origins must not be empty, use [\"*\"] to allow any origin".to_owned());
+        }
+        if origins.len() > 1 && origins.iter().any(|origin| origin == "*") {
+            return invalid("cors.allowed_origins cannot mix \"*\" with explicit origins".to_owned());
+        }
+        for origin in origins.iter().filter(|origin| *origin != "*") {
+            if !(origin.starts_with("http://") || origin.starts_with("https://")) || http::HeaderValue::from_str(origin).is_err() {
+                return invalid(format!("cors.allowed_origins entry '{}' is not an http(s) origin", origin));
+            }
+        }
+
+        if self.auth.challenge_ttl <= 0 {
+            return invalid("auth.challenge_ttl must be greater than zero".to_owned());
+        }
+        if self.auth.session_ttl <= 0 {
+            return invalid("auth.session_ttl must be greater than zero".to_owned());
+        }
+        if self.auth.admins.iter().any(|admin| admin.is_empty()) {
+            return invalid("auth.admins must not contain empty usernames".to_owned());
+        }
+
+        let quotas = self.rate_limit.default.iter().map(|quota| ("rate_limit.default".to_owned(), quota))
+            .chain(self.rate_limit.routes.iter().map(|(route, quota)| (format!("rate_limit.routes.\"{}\"", route), quota)));
+        for (name, quota) in quotas {
+            if quota.burst == 0 || quota.per_minute == 0 {
+                return invalid(format!("{} needs a burst and per_minute greater than zero", name));
+            }
+        }
+        if let Some(route) = self.rate_limit.routes.keys().find(|route| !route.starts_with('/')) {
+            return invalid(format!("rate_limit.routes key '{}' must be a path starting with /", route));
+        }
+
+        if self.nullifier_tree.signing_key.is_some() && self.nullifier_tree.signing_key().is_none() {
+            return invalid("nullifier_tree.signing_key must be 64 hex digits".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Checks settings only serving requests needs, so `migrate` and `--print-config`
+    /// work without them.
+    pub fn validate_serving(&self) -> Result<(), ConfigError> {
+        match (self.database.backend, self.nullifier_tree.signing_key.is_some()) {
+            (StorageBackend::Mongo | StorageBackend::Sql, false) => Err(Report::new(ConfigError::InvalidValue)
+                .attach_printable(format!("nullifier_tree.signing_key is required to serve the {:?} backend", self.database.backend))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Renders the configuration as TOML with any password in `database.uri` and the
+    /// signing key masked.
+    pub fn to_toml(&self) -> Result<String, ConfigError> {
+        let mut printable = self.clone();
+        printable.database.uri = printable.database.uri.as_deref().map(redact_password);
+        printable.nullifier_tree.signing_key = printable.nullifier_tree.signing_key.map(|_| "***".to_owned());
+        toml::to_string_pretty(&printable).change_context(ConfigError::ParseError)
+    }
+}
+
+fn env_var(key: &str) -> Option<String> {
+    env::var(key).ok().filter(|value| !value.is_empty())
+}
+
+fn parse_env<T>(key: &str, value: &str) -> Result<T, ConfigError>
+where
+    T: std::str::FromStr,
+    T::Err: fmt::Display,
+{
+    value.parse().map_err(|e| Report::new(ConfigError::InvalidValue)
+        .attach_printable(format!("{}='{}': {}", key, value, e)))
+}
+
+// Replaces `user:password@` with `user:***@` in a connection string.
+fn redact_password(uri: &str) -> String {
+    let Some((scheme, rest)) = uri.split_once("://") else {
+        return uri.to_owned();
+    };
+    let Some((credentials, host)) = rest.split_once('@') else {
+        return uri.to_owned();
+    };
+    match credentials.split_once(':') {
+        Some((user, _)) => format!("{}://{}:***@{}", scheme, user, host),
+        None => uri.to_owned(),
+    }
+}
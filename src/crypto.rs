@@ -0,0 +1,233 @@
+use std::sync::OnceLock;
+use ark_bn254::Fr;
+use ark_crypto_primitives::sponge::poseidon::{find_poseidon_ark_and_mds, PoseidonConfig};
+use ark_ff::{BigInteger, Field, PrimeField};
+use ark_serialize::CanonicalDeserialize;
+use arkeddsa::{ed_on_bn254_twist::EdwardsConfig, signature::Signature as BabyJubJubSignature, PublicKey as BabyJubJubPublicKey};
+use ed25519_dalek::{Signature, VerifyingKey};
+use error_stack::{Report, Result};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::routes::{error::DatabaseError, schema::NoteSchema};
+
+/// Signature scheme of a user's `pubkey`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyType {
+  /// Hex encoded 32 byte ed25519 key. Users created before key types existed have no
+  /// tag and are treated as ed25519.
+  #[default]
+  Ed25519,
+  /// Hex encoded compressed BabyJubJub point, the curve our ZK circuits sign on.
+  /// Signatures are EdDSA over BN254 with Poseidon as the hash.
+  BabyJubJub,
+}
+
+impl KeyType {
+  /// The tag used in JSON and in the SQL `key_type` column.
+  pub fn as_str(self) -> &'static str {
+    match self {
+      KeyType::Ed25519 => "ed25519",
+      KeyType::BabyJubJub => "babyjubjub",
+    }
+  }
+
+  pub fn parse(value: &str) -> Option<Self> {
+    match value {
+      "ed25519" => Some(KeyType::Ed25519),
+      "babyjubjub" => Some(KeyType::BabyJubJub),
+      _ => None,
+    }
+  }
+}
+
+// circomlib's Poseidon over the BN254 scalar field: the state is one element wider than
+// the inputs, with 8 full rounds, alpha 5, and circomlib's partial rounds for each width
+// from 2 to 17.
+const POSEIDON_FULL_ROUNDS: usize = 8;
+const POSEIDON_PARTIAL_ROUNDS: [usize; POSEIDON_MAX_INPUTS] = [56, 57, 56, 60, 60, 63, 64, 63, 60, 66, 60, 65, 70, 60, 64, 68];
+const POSEIDON_ALPHA: u64 = 5;
+
+/// Most inputs `poseidon` takes, as circomlib's `Poseidon(nInputs)`.
+pub const POSEIDON_MAX_INPUTS: usize = 16;
+
+/// Inputs BabyJubJub signatures absorb per permutation.
+pub const SIGNATURE_POSEIDON_ARITY: usize = 4;
+
+/// Parameters of circomlib's `Poseidon(arity)`, `1 <= arity <= POSEIDON_MAX_INPUTS`.
+/// The round constants and MDS matrix come from the Grain LFSR of the Poseidon
+/// reference implementation, the procedure circomlib's `poseidon_constants` were
+/// generated with. `tests/commitments.rs` pins them to circomlib's.
+pub fn poseidon_config(arity: usize) -> &'static PoseidonConfig<Fr> {
+  static CONFIGS: OnceLock<[OnceLock<PoseidonConfig<Fr>>; POSEIDON_MAX_INPUTS]> = OnceLock::new();
+
+  let partial_rounds = POSEIDON_PARTIAL_ROUNDS[arity - 1];
+  CONFIGS.get_or_init(|| std::array::from_fn(|_| OnceLock::new()))[arity - 1].get_or_init(|| {
+    let (ark, mds) = find_poseidon_ark_and_mds::<Fr>(
+      Fr::MODULUS_BIT_SIZE as u64,
+      arity,
+      POSEIDON_FULL_ROUNDS as u64,
+      partial_rounds as u64,
+      0,
+    );
+    PoseidonConfig::new(POSEIDON_FULL_ROUNDS, partial_rounds, POSEIDON_ALPHA, mds, ark, arity, 1)
+  })
+}
+
+/// Packs `message` into field elements, 31 little-endian bytes each, so every
+/// chunk fits below the BN254 modulus. This is the message BabyJubJub keys sign.
+pub fn message_to_field_elements(message: &[u8]) -> Vec<Fr> {
+  message.chunks(31).map(Fr::from_le_bytes_mod_order).collect()
+}
+
+/// Parses a BN254 scalar given as `0x` prefixed big-endian hex or as decimal. The
+/// empty string is zero, the parent of a note minted from nothing. Values at or
+/// above the modulus are rejected rather than reduced, so every element has one
+/// spelling.
+pub fn parse_field(value: &str, what: &str) -> Result<Fr, DatabaseError> {
+  let invalid = |reason: &str| Report::new(DatabaseError::ConversionError)
+    .attach_printable(format!("Invalid {} '{}': {}", what, value, reason));
+
+  if value.is_empty() {
+    return Ok(Fr::from(0u64));
+  }
+  if let Some(digits) = value.strip_prefix("0x") {
+    if digits.is_empty() {
+      return Err(invalid("no digits"));
+    }
+    let digits = if digits.len() % 2 == 1 { format!("0{}", digits) } else { digits.to_owned() };
+    let mut bytes = hex::decode(digits).map_err(|e| invalid(&e.to_string()))?;
+    if bytes.len() > 32 {
+      return Err(invalid("longer than 32 bytes"));
+    }
+    bytes.reverse();
+    bytes.resize(32, 0);
+    return Fr::deserialize_compressed(bytes.as_slice()).map_err(|_| invalid("not below the field modulus"));
+  }
+
+  // `from_str` reduces, printing the element back catches anything that was. `Fr`
+  // prints zero as "", its integer does not.
+  match value.parse::<Fr>() {
+    Ok(element) if element.into_bigint().to_string() == value => Ok(element),
+    _ => Err(invalid("not a decimal below the field modulus")),
+  }
+}
+
+/// The canonical spelling of `value`: `0x` and 64 lowercase hex digits, big-endian.
+pub fn field_to_hex(value: Fr) -> String {
+  format!("0x{}", hex::encode(value.into_bigint().to_bytes_be()))
+}
+
+/// circomlib's `Poseidon(inputs.len())`: a zero followed by `inputs` is permuted and
+/// the first element of the state is the hash.
+pub fn poseidon(inputs: &[Fr]) -> Result<Fr, DatabaseError> {
+  if inputs.is_empty() || inputs.len() > POSEIDON_MAX_INPUTS {
+    return Err(Report::new(DatabaseError::ConversionError)
+      .attach_printable(format!("Poseidon takes 1 to {} inputs, got {}", POSEIDON_MAX_INPUTS, inputs.len())));
+  }
+  let config = poseidon_config(inputs.len());
+  let mut state = vec![Fr::from(0u64)];
+  state.extend_from_slice(inputs);
+
+  let half_full_rounds = config.full_rounds / 2;
+  for (round, constants) in config.ark.iter().enumerate() {
+    for (element, constant) in state.iter_mut().zip(constants) {
+      *element += constant;
+    }
+    if round < half_full_rounds || round >= half_full_rounds + config.partial_rounds {
+      state.iter_mut().for_each(|element| *element = element.pow([config.alpha]));
+    } else {
+      state[0] = state[0].pow([config.alpha]);
+    }
+    state = config.mds.iter()
+      .map(|row| row.iter().zip(&state).map(|(entry, element)| *entry * element).sum())
+      .collect();
+  }
+  Ok(state[0])
+}
+
+/// Commitment to a note, as our circuits compute it:
+/// `Poseidon(asset_hash, owner, value, step, parent_note, out_index, blind)`.
+/// `owner` is the hex public key read as a little-endian integer reduced mod the
+/// field order, the other strings are field elements, see `parse_field`.
+pub fn note_commitment(note: &NoteSchema) -> Result<Fr, DatabaseError> {
+  let owner = decode_hex(&note.owner, "owner", DatabaseError::ConversionError)?;
+  poseidon(&[
+    parse_field(&note.asset_hash, "asset_hash")?,
+    Fr::from_le_bytes_mod_order(&owner),
+    Fr::from(note.value),
+    Fr::from(note.step),
+    parse_field(&note.parent_note, "parent_note")?,
+    parse_field(&note.out_index, "out_index")?,
+    parse_field(&note.blind, "blind")?,
+  ])
+}
+
+/// Nullifier of a note, `Poseidon(commitment, blind)`. Without the opening it
+/// cannot be linked to the commitment.
+pub fn note_nullifier(note: &NoteSchema) -> Result<Fr, DatabaseError> {
+  poseidon(&[note_commitment(note)?, parse_field(&note.blind, "blind")?])
+}
+
+/// Checks that `public_key_hex` is a well formed key of `key_type`.
+pub fn validate_public_key(key_type: KeyType, public_key_hex: &str) -> Result<(), DatabaseError> {
+  let bytes = decode_hex(public_key_hex, "pubkey", DatabaseError::ConversionError)?;
+  match key_type {
+    KeyType::Ed25519 => VerifyingKey::try_from(bytes.as_slice()).map(|_| ()).map_err(|e| Report::new(DatabaseError::ConversionError)
+      .attach_printable(format!("Invalid ed25519 pubkey: {}", e))),
+    KeyType::BabyJubJub => BabyJubJubPublicKey::<EdwardsConfig>::deserialize_compressed(bytes.as_slice()).map(|_| ())
+      .map_err(|e| Report::new(DatabaseError::ConversionError)
+        .attach_printable(format!("Invalid BabyJubJub pubkey: {}", e))),
+  }
+}
+
+/// Checks `signature_hex` over `message` against the hex encoded `public_key_hex`
+/// with the scheme of `key_type`. A malformed signature is an `AuthenticationError`,
+/// a malformed stored key a `ConversionError`.
+pub fn verify_signature(key_type: KeyType, public_key_hex: &str, signature_hex: &str, message: &[u8]) -> Result<bool, DatabaseError> {
+  let public_key_bytes = decode_hex(public_key_hex, "pubkey", DatabaseError::ConversionError)?;
+  let signature_bytes = decode_hex(signature_hex, "signature", DatabaseError::AuthenticationError)?;
+
+  match key_type {
+    KeyType::Ed25519 => verify_ed25519(&public_key_bytes, &signature_bytes, message),
+    KeyType::BabyJubJub => verify_babyjubjub(&public_key_bytes, &signature_bytes, message),
+  }
+}
+
+fn decode_hex(value: &str, what: &str, context: DatabaseError) -> Result<Vec<u8>, DatabaseError> {
+  hex::decode(value).map_err(|e| Report::new(context)
+    .attach_printable(format!("Failed to decode {}: {}", what, e)))
+}
+
+fn verify_ed25519(public_key_bytes: &[u8], signature_bytes: &[u8], message: &[u8]) -> Result<bool, DatabaseError> {
+  let public_key = match VerifyingKey::try_from(public_key_bytes) {
+    Ok(key) => key,
+    Err(e) => return Err(Report::new(DatabaseError::ConversionError)
+      .attach_printable(format!("Failed to create PublicKey: {}", e))),
+  };
+
+  let signature = match Signature::from_slice(signature_bytes) {
+    Ok(sig) => sig,
+    Err(e) => return Err(Report::new(DatabaseError::AuthenticationError)
+      .attach_printable(format!("Failed to create Signature: {}", e))),
+  };
+
+  Ok(public_key.verify_strict(message, &signature).is_ok())
+}
+
+fn verify_babyjubjub(public_key_bytes: &[u8], signature_bytes: &[u8], message: &[u8]) -> Result<bool, DatabaseError> {
+  let public_key = match BabyJubJubPublicKey::<EdwardsConfig>::deserialize_compressed(public_key_bytes) {
+    Ok(key) => key,
+    Err(e) => return Err(Report::new(DatabaseError::ConversionError)
+      .attach_printable(format!("Failed to decode BabyJubJub pubkey: {}", e))),
+  };
+
+  let signature = match BabyJubJubSignature::deserialize_compressed(signature_bytes) {
+    Ok(sig) => sig,
+    Err(e) => return Err(Report::new(DatabaseError::AuthenticationError)
+      .attach_printable(format!("Failed to decode BabyJubJub signature: {}", e))),
+  };
+
+  let message = message_to_field_elements(message);
+  Ok(public_key.verify(poseidon_config(SIGNATURE_POSEIDON_ARITY), &message, &signature).is_ok())
+}
@@ -1,58 +1,315 @@
 pub mod routes;
 pub mod mongo;
+pub mod migrations;
+pub mod memory;
+pub mod sql;
+pub mod store;
+pub mod session;
+pub mod repository;
+pub mod config;
+pub mod crypto;
+pub mod merkle;
+pub mod openapi;
+pub mod rate_limit;
+use std::{future::IntoFuture, net::SocketAddr, sync::Arc, time::Duration};
 use axum::{
-    routing::{post, get},
+    routing::{on, MethodFilter, MethodRouter},
+    handler::Handler,
+    extract::DefaultBodyLimit,
+    middleware,
     Router,
     Extension,
 };
-use tower_http::cors::{CorsLayer, Any};
+use http::Method;
+use utoipa::OpenApi;
+use utoipa_swagger_ui::SwaggerUi;
+use openapi::ApiDoc;
+use error_stack::Result;
+use tower_http::cors::{AllowOrigin, CorsLayer, Any};
+use config::{Config, StorageBackend};
 use mongo::IOUServiceDB;
+use memory::MemoryDB;
+use sql::SqlDB;
+use store::Store;
+use merkle::NullifierTree;
+use rate_limit::{limit_by_ip, limit_by_user, RateLimiter};
 use routes::notes::{create_and_transfer_note_history, get_notes, save_note, get_user_note_history};
 use routes::messages::{send_message, read_user_messages};
-use routes::nullifier::{store_nullifier, verify_nullifier};
+use routes::nullifier::{nullifier_absence, nullifier_history, nullifier_proof, nullifier_root, spend, store_nullifier, verify_nullifier};
+use routes::betrayals::{note_betrayals, user_betrayals};
+use routes::health::{healthz, readyz, metrics};
+use routes::admin::{list_flagged_users, flag_evidence, clear_flag, confirm_flag, suspend_user, restore_user, audit_log};
+use routes::signed::{verify_signed_request, NONCE_HEADER, SIGNATURE_HEADER, USER_HEADER};
 use routes::users::{
   get_user,
   create_user,
-  create_and_send_challenge, 
+  create_and_send_challenge,
+  verify_challenge,
+  validate_session,
+  logout,
+  list_sessions,
+  revoke_session,
+  rotate_key,
+  key_history,
 };
 
-pub async fn run() {
-    let mongo = IOUServiceDB::init().await;
+/// Connects to the storage backend selected by `config.database`.
+pub async fn init_store(config: &Config) -> Result<Store, routes::error::DatabaseError> {
+    let database = &config.database;
+    // `Config::validate` guarantees a uri for every backend but `memory`.
+    let uri = database.uri.as_deref().unwrap_or_default();
+    let auth = config.auth.clone();
 
-    let cors = CorsLayer::new().allow_origin(Any).allow_methods([http::Method::GET, http::Method::POST]);
+    let store: Store = match database.backend {
+        // Runs the service without a database, nothing is persisted.
+        StorageBackend::Memory => Arc::new(MemoryDB::new(auth)),
+        StorageBackend::Sql => Arc::new(SqlDB::init(uri, auth).await?),
+        StorageBackend::Mongo => Arc::new(IOUServiceDB::init(uri, &database.name, auth).await?),
+    };
+    Ok(store)
+}
+
+pub async fn run(config: Config) {
+    env_logger::Builder::new().parse_filters(&config.server.log_level).init();
+
+    if let Err(err) = config.validate_serving() {
+        log::error!("Invalid configuration: {:?}", err);
+        std::process::exit(1);
+    }
+
+    let store = match init_store(&config).await {
+        Ok(store) => store,
+        Err(err) => {
+            log::error!("Failed to connect to the database: {:?}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // `migrate` reports every migration, the ones applied before this run are left out of the count.
+    let applied_before: Vec<i64> = match store.migration_status().await {
+        Ok(migrations) => migrations.into_iter().filter(|migration| migration.applied).map(|migration| migration.version).collect(),
+        Err(err) => {
+            log::error!("Failed to read migration status: {:?}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // Refuse to serve requests against a schema that is not up to date.
+    match store.migrate().await {
+        Ok(migrations) => {
+            let applied = migrations.iter()
+                .filter(|migration| migration.applied && !applied_before.contains(&migration.version))
+                .count();
+            log::info!("Applied {} migrations", applied);
+        }
+        Err(err) => {
+            log::error!("Failed to apply migrations: {:?}", err);
+            std::process::exit(1);
+        }
+    }
+
+    // `Config::validate_serving` only lets the memory backend run without a key.
+    if config.nullifier_tree.signing_key.is_none() {
+        log::warn!("nullifier_tree.signing_key is unset, non-membership proofs are signed with a key for this process only");
+    }
+
+    let addr = config.server.listen_addr;
+    let shutdown_timeout = Duration::from_secs(config.server.shutdown_timeout);
+    let app = app(store, &config);
+
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("Failed to bind {}: {}", addr, err);
+            std::process::exit(1);
+        }
+    };
+    log::info!("Listening on http://{}", addr);
+
+    // Stop accepting connections on the first signal and give in-flight
+    // requests, like note transfers, until the deadline to finish.
+    let (signal_tx, signal_rx) = tokio::sync::oneshot::channel();
+    let server = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            let _ = signal_tx.send(());
+        })
+        .into_future();
+    let deadline = async move {
+        match signal_rx.await {
+            Ok(()) => tokio::time::sleep(shutdown_timeout).await,
+            Err(_) => std::future::pending().await,
+        }
+    };
+
+    tokio::select! {
+        result = server => {
+            if let Err(err) = result {
+                log::error!("Server error: {}", err);
+                std::process::exit(1);
+            }
+            log::info!("Shut down gracefully");
+        },
+        _ = deadline => {
+            log::warn!("Shutdown deadline of {:?} elapsed, dropping in-flight requests", shutdown_timeout);
+        },
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            log::error!("Failed to listen for SIGINT: {}", err);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => { signal.recv().await; },
+            Err(err) => {
+                log::error!("Failed to listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    let app = Router::new()  // Add the CORS middleware
+    tokio::select! {
+        _ = ctrl_c => log::info!("Received SIGINT, shutting down"),
+        _ = terminate => log::info!("Received SIGTERM, shutting down"),
+    }
+}
+
+fn cors_layer(config: &Config) -> CorsLayer {
+    let origins = &config.cors.allowed_origins;
+    let allow_origin = if origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::from(Any)
+    } else {
+        // Origins were checked to be valid header values by `Config::validate`.
+        AllowOrigin::list(origins.iter().filter_map(|origin| origin.parse().ok()))
+    };
+
+    CorsLayer::new().allow_origin(allow_origin).allow_methods([Method::GET, Method::POST])
+        .allow_headers([
+            http::header::AUTHORIZATION,
+            http::header::CONTENT_TYPE,
+            http::HeaderName::from_static(USER_HEADER),
+            http::HeaderName::from_static(NONCE_HEADER),
+            http::HeaderName::from_static(SIGNATURE_HEADER),
+        ])
+}
+
+/// A route served by `app`. Routes live in one table so tests can compare
+/// them with the OpenAPI document.
+pub struct ApiRoute {
+    pub path: &'static str,
+    pub method: Method,
+    handler: MethodRouter,
+}
+
+fn route<H, T>(path: &'static str, method: Method, handler: H) -> ApiRoute
+where
+    H: Handler<T, ()>,
+    T: 'static,
+{
+    let filter = MethodFilter::try_from(method.clone()).expect("axum supports the method");
+    ApiRoute { path, method, handler: on(filter, handler) }
+}
+
+/// Every API route, each of them must be documented in `ApiDoc`.
+pub fn api_routes() -> Vec<ApiRoute> {
+    vec![
+        // probes
+        route("/healthz", Method::GET, healthz),
+        route("/readyz", Method::GET, readyz),
+        route("/metrics", Method::GET, metrics),
         // user routes
-        .route("/get_user", post(get_user))  // Changed to POST as per your frontend
-        .route("/create_user", post(create_user))
+        route("/get_user", Method::POST, get_user),  // Changed to POST as per your frontend
+        route("/create_user", Method::POST, create_user),
         // verifier routes
-        .route("/verify_nullifier", get(verify_nullifier))
-        .route("/auth", post(create_and_send_challenge))
-        // .route("/get_session", post(verify_challenge))
+        route("/verify_nullifier", Method::GET, verify_nullifier),
+        route("/nullifier_history", Method::GET, nullifier_history),
+        route("/nullifier_root", Method::GET, nullifier_root),
+        route("/nullifier_proof", Method::GET, nullifier_proof),
+        route("/nullifier_absence", Method::GET, nullifier_absence),
+        route("/auth", Method::POST, create_and_send_challenge),
+        route("/get_session", Method::POST, verify_challenge),
+        route("/validate_session", Method::GET, validate_session),
+        route("/logout", Method::POST, logout),
+        route("/list_sessions", Method::GET, list_sessions),
+        route("/revoke_session", Method::POST, revoke_session),
+        route("/rotate_key", Method::POST, rotate_key),
+        route("/key_history", Method::GET, key_history),
         // note routes
-        .route("/store_note", post(save_note))
-        .route("/get_notes", get(get_notes))
+        route("/store_note", Method::POST, save_note),
+        route("/get_notes", Method::GET, get_notes),
         // message routes
-        .route("/send_message", post(send_message))
-        .route("/read_messages", get(read_user_messages))
+        route("/send_message", Method::POST, send_message),
+        route("/read_messages", Method::GET, read_user_messages),
         // store
-        .route("/store_nullifier", post(store_nullifier))
+        route("/store_nullifier", Method::POST, store_nullifier),
+        route("/spend", Method::POST, spend),
+        route("/betrayals_for_user", Method::GET, user_betrayals),
+        route("/betrayals_for_note", Method::GET, note_betrayals),
         // create and transfer notes history
-        .route("/create_and_transfer_note_history", post(create_and_transfer_note_history))
-        .route("/get_note_history_for_user", get(get_user_note_history))
+        route("/create_and_transfer_note_history", Method::POST, create_and_transfer_note_history),
+        route("/get_note_history_for_user", Method::GET, get_user_note_history),
+        // admin routes
+        route("/admin/flagged_users", Method::GET, list_flagged_users),
+        route("/admin/flag_evidence", Method::GET, flag_evidence),
+        route("/admin/clear_flag", Method::POST, clear_flag),
+        route("/admin/confirm_flag", Method::POST, confirm_flag),
+        route("/admin/suspend_user", Method::POST, suspend_user),
+        route("/admin/restore_user", Method::POST, restore_user),
+        route("/admin/audit_log", Method::GET, audit_log),
+    ]
+}
+
+/// Builds the router on top of the given storage backend.
+pub fn app(store: Store, config: &Config) -> Router {
+    let cors = cors_layer(config);
+    let body_limit = config.server.body_limit;
+    let limiter = RateLimiter::new(config.rate_limit.clone());
+    let user_limiter = limiter.clone();
+    let ip_limiter = limiter.clone();
+
+    let router = api_routes().into_iter()
+        .fold(Router::new(), |router, api_route| router.route(api_route.path, api_route.handler));
+
+    router
+        // API reference, interactive docs at /docs
+        .merge(SwaggerUi::new("/docs").url("/openapi.json", ApiDoc::openapi()))
         // fallback, state, and db
         .fallback(handler_404)
+        .layer(DefaultBodyLimit::max(body_limit))
+        // per user quotas, once the signature or session is known to be valid
+        .layer(middleware::from_fn(move |request: axum::extract::Request, next: middleware::Next| {
+            limit_by_user(user_limiter.clone(), request, next)
+        }))
+        // signed requests, checked before any handler sees the body
+        .layer(middleware::from_fn(move |request: axum::extract::Request, next: middleware::Next| {
+            verify_signed_request(body_limit, request, next)
+        }))
+        // per IP quotas, before any other work is done for the request
+        .layer(middleware::from_fn(move |request: axum::extract::Request, next: middleware::Next| {
+            limit_by_ip(ip_limiter.clone(), request, next)
+        }))
         .layer(cors)
-        .layer(Extension(mongo));
-
-
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:80").await.unwrap();
-    println!("Listening on http://0.0.0.0:80");
-    axum::serve(listener, app).await.unwrap()
+        .layer(Extension(store))
+        // nullifier Merkle tree, caught up from the store by its routes
+        .layer(Extension(NullifierTree::new(config.nullifier_tree.signing_key().unwrap_or_else(rand::random))))
+        // admins, read by `AdminUser`
+        .layer(Extension(config.auth.clone()))
+        // rejection counters, read by `/metrics`
+        .layer(Extension(limiter))
 }
 
 #[axum::debug_handler]
-async fn handler_404(method: axum::http::Method, uri: axum::http::Uri) -> impl axum::response::IntoResponse {
-    println!("404 for {} {}", method, uri);
-    (axum::http::StatusCode::NOT_FOUND, "Not Found")
+async fn handler_404(method: axum::http::Method, uri: axum::http::Uri) -> routes::error::ApiError {
+    log::debug!("404 for {} {}", method, uri);
+    routes::error::ApiError::new(routes::error::ErrorCode::NotFound, format!("No route for {} {}", method, uri.path()))
 }
@@ -1,10 +1,82 @@
-pub mod mongo;
-pub mod routes;
-use service_http::run;
-use tokio;
+use clap::{Parser, Subcommand};
+use service_http::{config::{Config, ConfigArgs}, init_store, run};
+
+#[derive(Parser)]
+#[command(name = "service-http", about = "IOU service with double-spending prevention")]
+struct Cli {
+   #[command(flatten)]
+   config: ConfigArgs,
+   #[command(subcommand)]
+   command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+   /// Inspect or apply schema migrations without starting the server
+   Migrate {
+      #[command(subcommand)]
+      action: MigrateAction,
+   },
+}
+
+#[derive(Subcommand)]
+enum MigrateAction {
+   /// List every migration and whether it has been applied
+   Status,
+   /// Apply all pending migrations
+   Apply,
+}
 
 #[tokio::main]
 async fn main() {
-   run().await;
-}
+   let cli = Cli::parse();
+
+   let config = match Config::load(&cli.config) {
+      Ok(config) => config,
+      Err(err) => {
+         eprintln!("Invalid configuration: {:?}", err);
+         std::process::exit(1);
+      }
+   };
+
+   if cli.config.print_config {
+      match config.to_toml() {
+         Ok(toml) => print!("{}", toml),
+         Err(err) => {
+            eprintln!("Failed to print configuration: {:?}", err);
+            std::process::exit(1);
+         }
+      }
+      return;
+   }
 
+   match cli.command {
+      None => run(config).await,
+      Some(Command::Migrate { action }) => {
+         let store = match init_store(&config).await {
+            Ok(store) => store,
+            Err(err) => {
+               eprintln!("Failed to connect to the database: {:?}", err);
+               std::process::exit(1);
+            }
+         };
+         let result = match action {
+            MigrateAction::Status => store.migration_status().await,
+            MigrateAction::Apply => store.migrate().await,
+         };
+
+         match result {
+            Ok(migrations) => {
+               for migration in migrations {
+                  let state = if migration.applied { "applied" } else { "pending" };
+                  println!("{:>4}  {:<8} {}", migration.version, state, migration.description);
+               }
+            },
+            Err(err) => {
+               eprintln!("Migration failed: {:?}", err);
+               std::process::exit(1);
+            }
+         }
+      }
+   }
+}
@@ -0,0 +1,511 @@
+use bson::{oid::ObjectId, Bson};
+use std::{sync::{Arc, RwLock}, collections::HashMap};
+use crate::routes::{
+  error::DatabaseError,
+  response::{
+    MessageSingleResponse,
+    NoteHistoryResponse,
+    NoteResponse,
+    NullifierResponseData,
+    UserSingleResponse
+  },
+  schema::{
+    AdminAction, AuditEntry, Betrayal, ChallengeSchema, CreateUserSchema, DoubleSpendFlag, FlagStatus, KeyRecord, MessageRequestSchema, MessageSchema, NoteHistorySaved, NoteNullifierSchema, NoteSchema, SaveNoteHistoryRequestSchema, SaveNoteRequestSchema, SpendOutcome, User
+  }
+};
+use crate::store::{challenge_error, first_spend, new_betrayal, new_challenge, new_flag, push_state, spend_outcome, IOUStore, MigrationStatus};
+use crate::session::{Session, SessionStore};
+use crate::config::AuthConfig;
+use crate::crypto::KeyType;
+use chrono::Utc;
+use async_trait::async_trait;
+use error_stack::{Report, Result};
+
+/// In-process storage backend. Nothing survives a restart, so this is meant
+/// for tests and local development without a running `mongod`.
+#[derive(Debug, Clone)]
+pub struct MemoryDB {
+  state: Arc<RwLock<MemoryState>>,
+  auth: AuthConfig,
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+  users: Vec<User>,
+  notes: Vec<NoteSchema>,
+  note_history: Vec<NoteHistorySaved>,
+  messages: Vec<MessageSchema>,
+  // in the order they were stored, which is their order in the Merkle tree
+  nullifiers: Vec<NoteNullifierSchema>,
+  challenges: Vec<ChallengeSchema>,
+  key_history: Vec<KeyRecord>,
+  flags: Vec<DoubleSpendFlag>,
+  betrayals: Vec<Betrayal>,
+  audit_log: Vec<AuditEntry>,
+  // keyed by token
+  sessions: HashMap<String, Session>,
+}
+
+impl MemoryDB {
+  /// `auth` sets how long challenges and sessions stay valid.
+  pub fn new(auth: AuthConfig) -> Self {
+    Self {
+      state: Arc::default(),
+      auth,
+    }
+  }
+}
+
+fn get_current_timestamp() -> i64 {
+  Utc::now().timestamp()
+}
+
+impl MemoryState {
+  fn find_user(&self, username: &str) -> Result<&User, DatabaseError> {
+    self.users.iter()
+      .find(|user| user.username.as_deref() == Some(username))
+      .ok_or_else(|| Report::new(DatabaseError::NotFoundError)
+        .attach_printable(format!("User '{}' not found", username)))
+  }
+
+  fn insert_message(&mut self, body: &MessageRequestSchema) -> MessageSchema {
+    let id = ObjectId::new();
+    let message = MessageSchema {
+      sender: body.sender.clone(),
+      recipient: body.recipient.clone(),
+      message: body.message.clone(),
+      timestamp: get_current_timestamp(),
+      attachment_id: body.attachment_id.clone(),
+      read: false,
+      _id: Some(Bson::ObjectId(id)),
+    };
+    self.messages.push(message.clone());
+
+    if let Some(user) = self.users.iter_mut().find(|user| user.username.as_deref() == Some(body.recipient.as_str())) {
+      user.messages.get_or_insert_with(Vec::new).push(id);
+    }
+
+    message
+  }
+
+  fn insert_note_history(&mut self, body: SaveNoteHistoryRequestSchema) -> NoteHistorySaved {
+    let id = ObjectId::new();
+    let note_history = NoteHistorySaved {
+      sender: body.sender,
+      data: body.data,
+      address: body.address,
+      _id: Some(Bson::ObjectId(id)),
+    };
+    self.note_history.push(note_history.clone());
+
+    if let Some(user) = self.users.iter_mut().find(|user| user.address.as_deref() == Some(note_history.address.as_str())) {
+      user.notes.get_or_insert_with(Vec::new).push(id);
+    }
+
+    note_history
+  }
+}
+
+#[async_trait]
+impl IOUStore for MemoryDB {
+  async fn ping(&self) -> Result<(), DatabaseError> {
+    Ok(())
+  }
+
+  // Migrations, there is no schema to keep in sync
+  async fn migration_status(&self) -> Result<Vec<MigrationStatus>, DatabaseError> {
+    Ok(Vec::new())
+  }
+
+  async fn migrate(&self) -> Result<Vec<MigrationStatus>, DatabaseError> {
+    Ok(Vec::new())
+  }
+
+  // User
+  async fn get_user_with_username(&self, username: &str) -> Result<UserSingleResponse, DatabaseError> {
+    let state = self.state.read().unwrap();
+    let user = state.find_user(username)?.clone();
+
+    Ok(UserSingleResponse {
+      status: "success",
+      user
+    })
+  }
+
+  async fn get_user_with_address(&self, address: &str) -> Result<UserSingleResponse, DatabaseError> {
+    let state = self.state.read().unwrap();
+    let user = match state.users.iter().find(|user| user.address.as_deref() == Some(address)) {
+      Some(user) => user.clone(),
+      None => return Err(Report::new(DatabaseError::NotFoundError)
+        .attach_printable(format!("User '{}' not found", address))),
+    };
+
+    Ok(UserSingleResponse {
+      status: "success",
+      user
+    })
+  }
+
+  async fn create_user(&self, body: &CreateUserSchema) -> Result<UserSingleResponse, DatabaseError> {
+    let mut state = self.state.write().unwrap();
+    if state.find_user(&body.username).is_ok() {
+      return Err(Report::new(DatabaseError::DuplicateKeyError)
+        .attach_printable(format!("Username '{}' already exists", body.username)));
+    }
+
+    let user = User {
+      id: Some(ObjectId::new()),
+      has_double_spent: Some(body.has_double_spent),
+      nonce: Some(body.nonce.clone()),
+      username: Some(body.username.clone()),
+      pubkey: Some(body.pubkey.clone()),
+      key_type: Some(body.key_type),
+      suspended: Some(false),
+      messages: Some(body.messages.iter().filter_map(|id| ObjectId::parse_str(id).ok()).collect()),
+      notes: Some(body.notes.iter().filter_map(|id| ObjectId::parse_str(id).ok()).collect()),
+      address: Some(body.address.clone()),
+    };
+    state.users.push(user.clone());
+
+    Ok(UserSingleResponse {
+      status: "success",
+      user,
+    })
+  }
+
+  // Messages
+  async fn send_message(&self, body: &MessageRequestSchema) -> Result<MessageSingleResponse, DatabaseError> {
+    let message = self.state.write().unwrap().insert_message(body);
+
+    Ok(MessageSingleResponse {
+      status: "success",
+      message
+    })
+  }
+
+  async fn get_unread_messages(&self, username: &str) -> Result<Vec<MessageSchema>, DatabaseError> {
+    let mut state = self.state.write().unwrap();
+    let mut messages = Vec::new();
+    for message in state.messages.iter_mut().filter(|msg| msg.recipient == username && !msg.read) {
+      message.read = true;
+      messages.push(message.clone());
+    }
+    messages.sort_by_key(|msg| msg.timestamp);
+
+    Ok(messages)
+  }
+
+  // Nullifiers
+  async fn store_nullifier(&self, body: &NoteNullifierSchema) -> Result<NullifierResponseData, DatabaseError> {
+    let mut state = self.state.write().unwrap();
+    if state.nullifiers.iter().any(|stored| stored.nullifier == body.nullifier) {
+      return Err(Report::new(DatabaseError::DuplicateKeyError)
+        .attach_printable(format!("Nullifier '{}' already stored", body.nullifier)));
+    }
+    let nullifier = first_spend(body);
+    state.nullifiers.push(nullifier.clone());
+
+    Ok(NullifierResponseData {
+      status: "success",
+      nullifier
+    })
+  }
+
+  async fn record_nullifier_state(&self, body: &NoteNullifierSchema) -> Result<Option<NoteNullifierSchema>, DatabaseError> {
+    let mut state = self.state.write().unwrap();
+    let Some(stored) = state.nullifiers.iter_mut().find(|stored| stored.nullifier == body.nullifier) else {
+      state.nullifiers.push(first_spend(body));
+      return Ok(None);
+    };
+
+    let before = stored.clone();
+    if stored.owner == body.owner && spend_outcome(&stored.states, body.step, &body.state) == SpendOutcome::Accepted {
+      push_state(&mut stored.states, body.step, &body.state);
+    }
+    Ok(Some(before))
+  }
+
+  async fn find_nullifier(&self, nullifier: &str) -> Result<Option<NoteNullifierSchema>, DatabaseError> {
+    let state = self.state.read().unwrap();
+    Ok(state.nullifiers.iter().find(|stored| stored.nullifier == nullifier).cloned())
+  }
+
+  async fn nullifier_leaves(&self, from: u64) -> Result<Vec<String>, DatabaseError> {
+    let state = self.state.read().unwrap();
+    Ok(state.nullifiers.iter().skip(from as usize).map(|stored| stored.nullifier.clone()).collect())
+  }
+
+  async fn flag_double_spend(&self, stored: &NoteNullifierSchema, conflicting: &NoteNullifierSchema) -> Result<(), DatabaseError> {
+    let mut state = self.state.write().unwrap();
+    let Some(user) = state.users.iter_mut().find(|user| user.username.as_deref() == Some(stored.owner.as_str())) else {
+      return Err(Report::new(DatabaseError::NotFoundError)
+        .attach_printable(format!("Owner '{}' of nullifier '{}' not found", stored.owner, stored.nullifier)));
+    };
+    user.has_double_spent = Some(true);
+    state.flags.push(new_flag(stored, &conflicting.state));
+    state.betrayals.push(new_betrayal(stored, conflicting));
+    Ok(())
+  }
+
+  async fn get_user_betrayals(&self, username: &str) -> Result<Vec<Betrayal>, DatabaseError> {
+    let state = self.state.read().unwrap();
+    Ok(state.betrayals.iter().filter(|betrayal| betrayal.username == username).cloned().collect())
+  }
+
+  async fn get_note_betrayals(&self, note: &str) -> Result<Vec<Betrayal>, DatabaseError> {
+    let state = self.state.read().unwrap();
+    Ok(state.betrayals.iter()
+      .filter(|betrayal| betrayal.spent.note == note || betrayal.conflicting.note == note)
+      .cloned()
+      .collect())
+  }
+
+  // Notes
+  async fn store_note(&self, body: &SaveNoteRequestSchema) -> Result<NoteResponse, DatabaseError> {
+    let mut state = self.state.write().unwrap();
+    let id = ObjectId::new();
+    let note = NoteSchema {
+      asset_hash: body.asset_hash.clone(),
+      owner: body.owner.clone(),
+      value: body.value,
+      step: body.step,
+      parent_note: body.parent_note.clone(),
+      out_index: body.out_index.clone(),
+      blind: body.blind.clone(),
+      _id: Some(Bson::ObjectId(id)),
+    };
+    state.notes.push(note.clone());
+
+    if let Some(user) = state.users.iter_mut().find(|user| user.pubkey.as_deref() == Some(note.owner.as_str())) {
+      user.notes.get_or_insert_with(Vec::new).push(id);
+    }
+
+    Ok(NoteResponse { status: "success", note })
+  }
+
+  async fn get_user_notes(&self, user_pub_key: &str) -> Result<Vec<NoteSchema>, DatabaseError> {
+    let state = self.state.read().unwrap();
+    let notes = state.notes.iter()
+      .filter(|note| note.owner == user_pub_key)
+      .cloned()
+      .collect();
+
+    Ok(notes)
+  }
+
+  // Notes History
+  async fn store_note_history(&self, body: SaveNoteHistoryRequestSchema) -> Result<NoteHistoryResponse, DatabaseError> {
+    let note_history = self.state.write().unwrap().insert_note_history(body);
+
+    Ok(NoteHistoryResponse {
+      status: "success",
+      note_history
+    })
+  }
+
+  async fn get_note_history_for_user(&self, username: String) -> Result<Vec<NoteHistorySaved>, DatabaseError> {
+    let mut state = self.state.write().unwrap();
+    let note_ids = match state.users.iter_mut().find(|user| user.username.as_deref() == Some(username.as_str())) {
+      Some(user) => user.notes.replace(Vec::new()).unwrap_or_default(),
+      None => return Err(Report::new(DatabaseError::NotFoundError)
+        .attach_printable(format!("User '{}' not found", username))),
+    };
+
+    let notes = note_ids.iter()
+      .filter_map(|note_id| state.note_history.iter()
+        .find(|history| history._id == Some(Bson::ObjectId(*note_id)))
+        .cloned())
+      .collect();
+
+    Ok(notes)
+  }
+
+  async fn create_and_transfer_note_history(
+    &self,
+    owner_username: String,
+    recipient_username: &str,
+    body: SaveNoteHistoryRequestSchema,
+    message: String,
+  ) -> Result<MessageSingleResponse, DatabaseError> {
+    // A single write lock covers every step, so other requests never see a half done transfer.
+    let mut state = self.state.write().unwrap();
+    if state.find_user(recipient_username).is_err() {
+      return Err(Report::new(DatabaseError::NotFoundError)
+        .attach_printable(format!("Recipient '{}' not found", recipient_username)));
+    }
+    let stored_note = state.insert_note_history(SaveNoteHistoryRequestSchema {
+      data: body.data,
+      address: body.address,
+      sender: owner_username.clone(),
+    });
+    let note_id = stored_note._id.clone();
+
+    if let Some(Bson::ObjectId(id)) = note_id {
+      if let Some(owner) = state.users.iter_mut().find(|user| user.username.as_deref() == Some(owner_username.as_str())) {
+        owner.notes.get_or_insert_with(Vec::new).retain(|note| *note != id);
+      }
+      if let Some(recipient) = state.users.iter_mut().find(|user| user.username.as_deref() == Some(recipient_username)) {
+        recipient.notes.get_or_insert_with(Vec::new).push(id);
+      }
+    }
+
+    let message = state.insert_message(&MessageRequestSchema {
+      recipient: recipient_username.to_owned(),
+      sender: owner_username,
+      message,
+      attachment_id: note_id,
+    });
+
+    Ok(MessageSingleResponse {
+      status: "success",
+      message
+    })
+  }
+
+  // Challenges
+  async fn create_challenge(&self, username: &str) -> Result<ChallengeSchema, DatabaseError> {
+    let mut state = self.state.write().unwrap();
+    let now = get_current_timestamp();
+    state.challenges.retain(|challenge| challenge.expires_at > now);
+
+    let challenge = new_challenge(username, self.auth.challenge_ttl);
+    state.challenges.push(challenge.clone());
+    Ok(challenge)
+  }
+
+  async fn consume_challenge(&self, challenge_id: &str, username: &str) -> Result<ChallengeSchema, DatabaseError> {
+    let mut state = self.state.write().unwrap();
+    let now = get_current_timestamp();
+
+    let position = state.challenges.iter()
+      .position(|c| c.challenge_id == challenge_id && c.user_id == username && c.expires_at > now);
+    match position {
+      Some(position) => Ok(state.challenges.remove(position)),
+      None => {
+        let issued_to_other = state.challenges.iter()
+          .any(|c| c.challenge_id == challenge_id && c.user_id != username && c.expires_at > now);
+        Err(challenge_error(challenge_id, issued_to_other))
+      }
+    }
+  }
+
+  // Nonces
+  async fn compare_and_swap_nonce(&self, username: &str, current: &str, new: &str) -> Result<bool, DatabaseError> {
+    let mut state = self.state.write().unwrap();
+    let user = state.users.iter_mut().find(|user| user.username.as_deref() == Some(username));
+    match user {
+      Some(user) if user.nonce.as_deref().unwrap_or_default() == current => {
+        user.nonce = Some(new.to_owned());
+        Ok(true)
+      },
+      _ => Ok(false),
+    }
+  }
+
+  // Keys
+  async fn get_key_history(&self, username: &str) -> Result<Vec<KeyRecord>, DatabaseError> {
+    let state = self.state.read().unwrap();
+    Ok(state.key_history.iter().filter(|key| key.username == username).cloned().collect())
+  }
+
+  async fn replace_key(&self, retired: &KeyRecord, new_key_type: KeyType, new_pubkey: &str) -> Result<bool, DatabaseError> {
+    let mut state = self.state.write().unwrap();
+    let user = state.users.iter_mut()
+      .find(|user| user.username.as_deref() == Some(retired.username.as_str()) && user.pubkey.as_deref() == Some(retired.pubkey.as_str()));
+    match user {
+      Some(user) => {
+        user.pubkey = Some(new_pubkey.to_owned());
+        user.key_type = Some(new_key_type);
+      },
+      None => return Ok(false),
+    }
+    state.key_history.push(retired.clone());
+    Ok(true)
+  }
+
+  // Moderation
+  async fn list_flagged_users(&self) -> Result<Vec<User>, DatabaseError> {
+    let state = self.state.read().unwrap();
+    let mut users: Vec<User> = state.users.iter()
+      .filter(|user| user.has_double_spent == Some(true))
+      .cloned()
+      .collect();
+    users.sort_by(|a, b| a.username.cmp(&b.username));
+    Ok(users)
+  }
+
+  async fn get_flags(&self, username: &str) -> Result<Vec<DoubleSpendFlag>, DatabaseError> {
+    let state = self.state.read().unwrap();
+    Ok(state.flags.iter().filter(|flag| flag.username == username).cloned().collect())
+  }
+
+  async fn moderate(&self, entry: &AuditEntry) -> Result<bool, DatabaseError> {
+    let mut guard = self.state.write().unwrap();
+    let state = &mut *guard;
+    let user = match state.users.iter_mut().find(|user| user.username.as_deref() == Some(entry.username.as_str())) {
+      Some(user) => user,
+      None => return Err(Report::new(DatabaseError::NotFoundError)
+        .attach_printable(format!("User '{}' not found", entry.username))),
+    };
+
+    let flagged = user.has_double_spent == Some(true);
+    match entry.action {
+      AdminAction::ClearFlag | AdminAction::ConfirmFlag if !flagged => return Ok(false),
+      AdminAction::ClearFlag => user.has_double_spent = Some(false),
+      AdminAction::ConfirmFlag => {},
+      AdminAction::Suspend => user.suspended = Some(true),
+      AdminAction::Restore => user.suspended = Some(false),
+    }
+
+    for flag in state.flags.iter_mut().filter(|flag| flag.username == entry.username) {
+      match (entry.action, flag.status) {
+        (AdminAction::ClearFlag, FlagStatus::Open | FlagStatus::Confirmed) => flag.status = FlagStatus::Cleared,
+        (AdminAction::ConfirmFlag, FlagStatus::Open) => flag.status = FlagStatus::Confirmed,
+        _ => {},
+      }
+    }
+    state.audit_log.push(entry.clone());
+    Ok(true)
+  }
+
+  async fn get_audit_log(&self) -> Result<Vec<AuditEntry>, DatabaseError> {
+    Ok(self.state.read().unwrap().audit_log.clone())
+  }
+}
+
+#[async_trait]
+impl SessionStore for MemoryDB {
+  async fn create_session(&self, username: &str) -> Result<Session, DatabaseError> {
+    let mut state = self.state.write().unwrap();
+    let now = get_current_timestamp();
+    state.sessions.retain(|_, session| !session.is_expired(now));
+
+    let session = Session::new(username, self.auth.session_ttl);
+    state.sessions.insert(session.token.clone(), session.clone());
+    Ok(session)
+  }
+
+  async fn get_session(&self, token: &str) -> Result<Option<Session>, DatabaseError> {
+    let now = get_current_timestamp();
+    Ok(self.state.read().unwrap().sessions.get(token)
+      .filter(|session| !session.is_expired(now))
+      .cloned())
+  }
+
+  async fn list_sessions(&self, username: &str) -> Result<Vec<Session>, DatabaseError> {
+    let now = get_current_timestamp();
+    let mut sessions: Vec<Session> = self.state.read().unwrap().sessions.values()
+      .filter(|session| session.username == username && !session.is_expired(now))
+      .cloned()
+      .collect();
+    sessions.sort_by_key(|session| session.created_at);
+    Ok(sessions)
+  }
+
+  async fn revoke_session(&self, username: &str, id: &str) -> Result<bool, DatabaseError> {
+    let mut state = self.state.write().unwrap();
+    let before = state.sessions.len();
+    state.sessions.retain(|_, session| !(session.id == id && session.username == username));
+    Ok(state.sessions.len() < before)
+  }
+}
@@ -0,0 +1,424 @@
+use std::{
+  borrow::Borrow,
+  collections::{BTreeMap, HashMap},
+  ops::RangeInclusive,
+  sync::{Arc, OnceLock, PoisonError, RwLock},
+};
+use ark_bn254::Fr;
+use ark_crypto_primitives::{crh::{CRHScheme, TwoToOneCRHScheme}, CryptoError};
+use ark_crypto_primitives::merkle_tree::{Config, IdentityDigestConverter, MerkleTree, Path};
+use ark_ff::{BigInteger, PrimeField};
+use ed25519_dalek::{Signer, SigningKey};
+use error_stack::{Report, Result};
+use crate::crypto::{field_to_hex, message_to_field_elements, parse_field, poseidon, verify_signature, KeyType};
+use crate::routes::{error::DatabaseError, response::{InclusionProof, NonMembershipProof, NullifierRoot}};
+use crate::store::Store;
+
+// First input of every leaf hash, the tag keeps a leaf from passing for an inner node.
+const LEAF_DOMAIN: u64 = 1;
+
+// Height of the sparse tree, one level per bit of a nullifier.
+const SPARSE_DEPTH: usize = Fr::MODULUS_BIT_SIZE as usize;
+
+// A position in the sparse tree, or a node's key prefix.
+type Key = <Fr as PrimeField>::BigInt;
+
+struct TreeConfig;
+
+impl Config for TreeConfig {
+  type Leaf = [Fr];
+  type LeafDigest = Fr;
+  type LeafInnerDigestConverter = IdentityDigestConverter<Fr>;
+  type InnerDigest = Fr;
+  type LeafHash = LeafHash;
+  type TwoToOneHash = NodeHash;
+}
+
+// circomlib's Poseidon for arkworks' Merkle tree, so the trees hash as the circuits do.
+// It has no parameters to set up.
+struct LeafHash;
+struct NodeHash;
+
+// `poseidon` only fails on the number of inputs.
+fn ark_error(inputs: usize) -> ark_crypto_primitives::Error {
+  Box::new(CryptoError::IncorrectInputLength(inputs))
+}
+
+impl CRHScheme for LeafHash {
+  type Input = [Fr];
+  type Output = Fr;
+  type Parameters = ();
+
+  fn setup<R: ark_std::rand::Rng>(_: &mut R) -> std::result::Result<(), ark_crypto_primitives::Error> {
+    Ok(())
+  }
+
+  fn evaluate<T: Borrow<[Fr]>>(_: &(), input: T) -> std::result::Result<Fr, ark_crypto_primitives::Error> {
+    poseidon(input.borrow()).map_err(|_| ark_error(input.borrow().len()))
+  }
+}
+
+impl TwoToOneCRHScheme for NodeHash {
+  type Input = Fr;
+  type Output = Fr;
+  type Parameters = ();
+
+  fn setup<R: ark_std::rand::Rng>(_: &mut R) -> std::result::Result<(), ark_crypto_primitives::Error> {
+    Ok(())
+  }
+
+  fn evaluate<T: Borrow<Fr>>(_: &(), left: T, right: T) -> std::result::Result<Fr, ark_crypto_primitives::Error> {
+    Self::compress(&(), left, right)
+  }
+
+  fn compress<T: Borrow<Fr>>(_: &(), left: T, right: T) -> std::result::Result<Fr, ark_crypto_primitives::Error> {
+    poseidon(&[*left.borrow(), *right.borrow()]).map_err(|_| ark_error(2))
+  }
+}
+
+fn tree_error(e: impl std::fmt::Display) -> Report<DatabaseError> {
+  Report::new(DatabaseError::ConversionError)
+    .attach_printable(format!("Nullifier tree failed: {}", e))
+}
+
+// The preimage of the leaf of `nullifier`. Nullifiers stored before they were checked
+// to be field elements are hashed from their bytes.
+fn leaf(nullifier: &str) -> Result<[Fr; 2], DatabaseError> {
+  let value = match parse_field(nullifier, "nullifier") {
+    Ok(value) => value,
+    Err(_) => poseidon(&message_to_field_elements(nullifier.as_bytes()))?,
+  };
+  Ok([Fr::from(LEAF_DOMAIN), value])
+}
+
+fn compress(left: Fr, right: Fr) -> Result<Fr, DatabaseError> {
+  poseidon(&[left, right])
+}
+
+// Roots of empty sparse subtrees by height, an empty leaf is zero.
+fn empty_subtrees() -> &'static [Fr] {
+  static EMPTY: OnceLock<Vec<Fr>> = OnceLock::new();
+  EMPTY.get_or_init(|| {
+    let mut empty = vec![Fr::from(0u64)];
+    for height in 0..SPARSE_DEPTH {
+      empty.push(compress(empty[height], empty[height]).expect("Poseidon compresses two elements"));
+    }
+    empty
+  })
+}
+
+// Parent of `node` at `height` on the path to `key`, whose sibling is `sibling`.
+fn parent(key: &Key, height: usize, node: Fr, sibling: Fr) -> Result<Fr, DatabaseError> {
+  if key.get_bit(height) { compress(sibling, node) } else { compress(node, sibling) }
+}
+
+// Prefix of the node at `height` above `key`.
+fn prefix(key: &Key, height: usize) -> Key {
+  let mut prefix = *key;
+  prefix.divn(height as u32);
+  prefix
+}
+
+fn sibling(prefix: &Key) -> Key {
+  let mut sibling = *prefix;
+  sibling.0[0] ^= 1;
+  sibling
+}
+
+// Keys of the leaves under the node at `height` with `prefix`.
+fn subtree(height: usize, prefix: &Key) -> RangeInclusive<Key> {
+  let one = Key::from(1u64);
+  let mut first = *prefix;
+  first.muln(height as u32);
+  let mut span = one;
+  span.muln(height as u32);
+  span.sub_with_borrow(&one);
+  let mut last = first;
+  last.add_with_carry(&span);
+  first..=last
+}
+
+/// Sparse Merkle tree with a leaf for every possible nullifier, at the position given
+/// by its bits. Stored nullifiers have the same leaf digest as in the append-only tree,
+/// every other leaf is zero.
+///
+/// Only subtrees with two or more leaves are kept, the rest are empty or hashed from
+/// their one leaf when needed.
+#[derive(Clone)]
+struct SparseTree {
+  leaves: BTreeMap<Key, Fr>,
+  nodes: HashMap<(usize, Key), Fr>,
+  root: Fr,
+}
+
+impl SparseTree {
+  fn new() -> Self {
+    Self {
+      leaves: BTreeMap::new(),
+      nodes: HashMap::new(),
+      root: empty_subtrees()[SPARSE_DEPTH],
+    }
+  }
+
+  fn node(&self, height: usize, prefix: &Key) -> Result<Fr, DatabaseError> {
+    if let Some(node) = self.nodes.get(&(height, *prefix)) {
+      return Ok(*node);
+    }
+    let Some((key, leaf)) = self.leaves.range(subtree(height, prefix)).next() else {
+      return Ok(empty_subtrees()[height]);
+    };
+    let mut node = *leaf;
+    for level in 0..height {
+      node = parent(key, level, node, empty_subtrees()[level])?;
+    }
+    Ok(node)
+  }
+
+  fn insert(&mut self, key: Key, leaf: Fr) -> Result<(), DatabaseError> {
+    self.leaves.insert(key, leaf);
+    let mut node = leaf;
+    for height in 0..SPARSE_DEPTH {
+      let prefix = prefix(&key, height);
+      if self.leaves.range(subtree(height, &prefix)).nth(1).is_some() {
+        self.nodes.insert((height, prefix), node);
+      }
+      let sibling = self.node(height, &sibling(&prefix))?;
+      node = parent(&key, height, node, sibling)?;
+    }
+    self.root = node;
+    Ok(())
+  }
+
+  // Siblings on the path to `key` from the root down, `None` for empty subtrees.
+  fn siblings(&self, key: &Key) -> Result<Vec<Option<Fr>>, DatabaseError> {
+    let mut siblings = Vec::with_capacity(SPARSE_DEPTH);
+    for height in (0..SPARSE_DEPTH).rev() {
+      let node = self.node(height, &sibling(&prefix(key, height)))?;
+      siblings.push(Some(node).filter(|node| *node != empty_subtrees()[height]));
+    }
+    Ok(siblings)
+  }
+}
+
+#[derive(Clone)]
+struct TreeState {
+  // in leaf order
+  nullifiers: Vec<String>,
+  leaf_index: HashMap<String, usize>,
+  // leaf digests of `nullifiers`, the tree pads them with zeros
+  digests: Vec<Fr>,
+  tree: MerkleTree<TreeConfig>,
+  sparse: SparseTree,
+}
+
+impl TreeState {
+  fn new() -> Result<Self, DatabaseError> {
+    Ok(Self {
+      nullifiers: Vec::new(),
+      leaf_index: HashMap::new(),
+      digests: Vec::new(),
+      tree: Self::build(vec![Fr::from(0u64); 2])?,
+      sparse: SparseTree::new(),
+    })
+  }
+
+  fn build(digests: Vec<Fr>) -> Result<MerkleTree<TreeConfig>, DatabaseError> {
+    MerkleTree::new_with_leaf_digest(&(), &(), digests).map_err(tree_error)
+  }
+
+  // The state with `nullifiers` appended. Everything is built into copies, so a failure
+  // part way leaves `self` as it was.
+  fn appended(&self, nullifiers: Vec<String>) -> Result<Self, DatabaseError> {
+    let first = self.nullifiers.len();
+    let leaves = nullifiers.iter().map(|nullifier| leaf(nullifier)).collect::<Result<Vec<_>, _>>()?;
+    let new_digests = leaves.iter().map(|leaf| poseidon(leaf)).collect::<Result<Vec<_>, _>>()?;
+    let mut digests = self.digests.clone();
+    digests.extend_from_slice(&new_digests);
+
+    let capacity = (first + nullifiers.len()).next_power_of_two().max(2);
+    let tree = if capacity > first.next_power_of_two().max(2) {
+      let mut padded = digests.clone();
+      padded.resize(capacity, Fr::from(0u64));
+      Self::build(padded)?
+    } else {
+      let mut tree = self.tree.clone();
+      for (offset, leaf) in leaves.iter().enumerate() {
+        tree.update(first + offset, leaf.as_slice()).map_err(tree_error)?;
+      }
+      tree
+    };
+
+    let mut sparse = self.sparse.clone();
+    for (leaf, digest) in leaves.iter().zip(&new_digests) {
+      sparse.insert(leaf[1].into_bigint(), *digest)?;
+    }
+
+    let mut leaf_index = self.leaf_index.clone();
+    let mut all = self.nullifiers.clone();
+    for (offset, nullifier) in nullifiers.into_iter().enumerate() {
+      leaf_index.insert(nullifier.clone(), first + offset);
+      all.push(nullifier);
+    }
+    Ok(Self { nullifiers: all, leaf_index, digests, tree, sparse })
+  }
+}
+
+/// Append-only Merkle tree over stored nullifiers, in the order the store assigned
+/// their leaves. Leaves are `Poseidon(1, nullifier)`, inner nodes `Poseidon(left, right)`,
+/// and the tree is padded with zero leaves to the next power of two.
+///
+/// A sparse tree over the same nullifiers proves that a nullifier is not stored, those
+/// proofs are signed with the ed25519 key of the server.
+///
+/// The trees live in process. `store_nullifier` and `spend` add their leaf right after the
+/// store commits it, and every read first catches up on leaves other replicas stored, so
+/// all replicas agree on the roots for the same number of leaves.
+#[derive(Clone)]
+pub struct NullifierTree {
+  // Replaced whole by `sync`, readers take the current state and never wait on hashing.
+  state: Arc<RwLock<Arc<TreeState>>>,
+  // One sync at a time, the others wait for it and catch up from its state.
+  syncing: Arc<tokio::sync::Mutex<()>>,
+  signing_key: SigningKey,
+}
+
+impl NullifierTree {
+  pub fn new(secret_key: [u8; 32]) -> Self {
+    Self {
+      state: Arc::new(RwLock::new(Arc::new(TreeState::new().expect("an empty tree can be built")))),
+      syncing: Arc::new(tokio::sync::Mutex::new(())),
+      signing_key: SigningKey::from_bytes(&secret_key),
+    }
+  }
+
+  // The lock only swaps one complete state for another, a panic can't leave it half
+  // written, so a poisoned lock is still good to use.
+  fn current(&self) -> Arc<TreeState> {
+    self.state.read().unwrap_or_else(PoisonError::into_inner).clone()
+  }
+
+  /// Appends the nullifiers stored since the last sync.
+  pub async fn sync(&self, db: &Store) -> Result<(), DatabaseError> {
+    let _syncing = self.syncing.lock().await;
+    let current = self.current();
+    let fetched = db.nullifier_leaves(current.nullifiers.len() as u64).await?;
+    if fetched.is_empty() {
+      return Ok(());
+    }
+
+    // Every leaf takes hundreds of Poseidon permutations in the sparse tree, and a
+    // restart replays the whole store, so hash on the blocking pool.
+    let next = tokio::task::spawn_blocking(move || current.appended(fetched)).await.map_err(tree_error)??;
+    *self.state.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(next);
+    Ok(())
+  }
+
+  pub fn root(&self) -> NullifierRoot {
+    let state = self.current();
+    NullifierRoot {
+      root: field_to_hex(state.tree.root()),
+      sparse_root: field_to_hex(state.sparse.root),
+      size: state.nullifiers.len() as u64,
+      public_key: hex::encode(self.signing_key.verifying_key().as_bytes()),
+    }
+  }
+
+  /// Inclusion proof of `nullifier` against the current root, `None` when it has no leaf.
+  pub fn prove(&self, nullifier: &str) -> Result<Option<InclusionProof>, DatabaseError> {
+    let state = self.current();
+    let Some(&index) = state.leaf_index.get(nullifier) else {
+      return Ok(None);
+    };
+    let path = state.tree.generate_proof(index).map_err(tree_error)?;
+
+    Ok(Some(InclusionProof {
+      nullifier: nullifier.to_owned(),
+      leaf_index: index as u64,
+      root: field_to_hex(state.tree.root()),
+      size: state.nullifiers.len() as u64,
+      leaf_sibling: field_to_hex(path.leaf_sibling_hash),
+      path: path.auth_path.into_iter().map(field_to_hex).collect(),
+    }))
+  }
+
+  /// Signed proof that `nullifier` is not stored, against the current sparse root.
+  /// `None` when it is.
+  pub fn prove_absence(&self, nullifier: &str) -> Result<Option<NonMembershipProof>, DatabaseError> {
+    let state = self.current();
+    if state.leaf_index.contains_key(nullifier) {
+      return Ok(None);
+    }
+    let key = leaf(nullifier)?[1].into_bigint();
+    let root = field_to_hex(state.sparse.root);
+    let size = state.nullifiers.len() as u64;
+    let message = non_membership_message(nullifier, &root, size);
+
+    Ok(Some(NonMembershipProof {
+      nullifier: nullifier.to_owned(),
+      path: state.sparse.siblings(&key)?.into_iter().map(|node| node.map(field_to_hex)).collect(),
+      root,
+      size,
+      public_key: hex::encode(self.signing_key.verifying_key().as_bytes()),
+      signature: hex::encode(self.signing_key.sign(message.as_bytes()).to_bytes()),
+    }))
+  }
+}
+
+/// What the server signs in a non-membership proof.
+pub fn non_membership_message(nullifier: &str, root: &str, size: u64) -> String {
+  format!("iou nullifier non-membership\nnullifier: {}\nroot: {}\nsize: {}", nullifier, root, size)
+}
+
+/// Checks `proof` the way a client would, from the proof alone.
+pub fn verify_inclusion(proof: &InclusionProof) -> bool {
+  let fields = |values: &[String]| values.iter().map(|value| parse_field(value, "proof node")).collect::<Result<Vec<_>, _>>();
+  let (Ok(root), Ok(leaf_sibling_hash), Ok(auth_path), Ok(leaf)) = (
+    parse_field(&proof.root, "root"),
+    parse_field(&proof.leaf_sibling, "leaf_sibling"),
+    fields(&proof.path),
+    leaf(&proof.nullifier),
+  ) else {
+    return false;
+  };
+
+  // Path verification only reads as many index bits as the path is long.
+  let capacity = 1u64 << (auth_path.len() + 1).min(63);
+  if proof.leaf_index >= proof.size || proof.size > capacity {
+    return false;
+  }
+
+  let path = Path::<TreeConfig> { leaf_sibling_hash, auth_path, leaf_index: proof.leaf_index as usize };
+  path.verify(&(), &(), &root, leaf.as_slice()).unwrap_or(false)
+}
+
+/// Checks `proof` the way a client would, against the server key `public_key`. Whether
+/// its root is one the server published is up to the caller.
+pub fn verify_non_membership(proof: &NonMembershipProof, public_key: &str) -> bool {
+  let message = non_membership_message(&proof.nullifier, &proof.root, proof.size);
+  if proof.public_key != public_key || !verify_signature(KeyType::Ed25519, public_key, &proof.signature, message.as_bytes()).unwrap_or(false) {
+    return false;
+  }
+  let (Ok(root), Ok(leaf)) = (parse_field(&proof.root, "root"), leaf(&proof.nullifier)) else {
+    return false;
+  };
+  if proof.path.len() != SPARSE_DEPTH {
+    return false;
+  }
+
+  let key = leaf[1].into_bigint();
+  let mut node = Fr::from(0u64);
+  for (height, sibling) in proof.path.iter().rev().enumerate() {
+    let sibling = match sibling {
+      Some(sibling) => match parse_field(sibling, "proof node") {
+        Ok(sibling) => sibling,
+        Err(_) => return false,
+      },
+      None => empty_subtrees()[height],
+    };
+    node = match parent(&key, height, node, sibling) {
+      Ok(node) => node,
+      Err(_) => return false,
+    };
+  }
+  node == root
+}
@@ -0,0 +1,269 @@
+use bson::{doc, Bson, Document};
+use futures::stream::TryStreamExt;
+use std::time::Duration;
+use mongodb::{error::ErrorKind, options::{IndexOptions, UpdateOptions}, Database, IndexModel};
+use error_stack::{Report, Result};
+use crate::routes::error::DatabaseError;
+use crate::store::MigrationStatus;
+
+/// Collection recording which versions of `MIGRATIONS` have been applied.
+pub const MIGRATIONS_COLLECTION: &str = "_migrations";
+
+// Server error codes of `dropIndexes` on a missing index or collection.
+const INDEX_NOT_FOUND: i32 = 27;
+const NAMESPACE_NOT_FOUND: i32 = 26;
+
+/// A versioned set of indexes. Migrations are applied in order and never edited
+/// once released, changes go into a new version.
+pub struct Migration {
+  pub version: i64,
+  pub description: &'static str,
+  pub indexes: fn() -> Vec<(&'static str, IndexModel)>,
+  /// Indexes of earlier migrations this one replaces, by collection and index name.
+  /// They are dropped before `indexes` are created.
+  pub dropped: &'static [(&'static str, &'static str)],
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+  Migration {
+    version: 1,
+    description: "initial indexes",
+    indexes: initial_indexes,
+    dropped: &[],
+  },
+  Migration {
+    version: 2,
+    description: "expiring sessions",
+    indexes: session_indexes,
+    dropped: &[],
+  },
+  Migration {
+    version: 3,
+    description: "expiring challenges",
+    indexes: challenge_indexes,
+    dropped: &[],
+  },
+  Migration {
+    version: 4,
+    description: "key history",
+    indexes: key_history_indexes,
+    dropped: &[],
+  },
+  Migration {
+    version: 5,
+    description: "moderation",
+    indexes: moderation_indexes,
+    dropped: &[],
+  },
+  Migration {
+    version: 6,
+    description: "unique nullifiers",
+    indexes: unique_nullifier_indexes,
+    dropped: &[("nullifiers", "state_1"), ("nullifiers", "nullifier_1")],
+  },
+  Migration {
+    version: 7,
+    description: "betrayals",
+    indexes: betrayal_indexes,
+    dropped: &[],
+  },
+  Migration {
+    version: 8,
+    description: "nullifier leaves",
+    indexes: nullifier_leaf_indexes,
+    dropped: &[],
+  },
+];
+
+fn index(keys: Document) -> IndexModel {
+  IndexModel::builder().keys(keys).build()
+}
+
+fn unique_index(keys: Document) -> IndexModel {
+  let options = IndexOptions::builder().unique(true).build();
+  IndexModel::builder().keys(keys).options(options).build()
+}
+
+// Documents are removed once the date in `keys` has passed.
+fn ttl_index(keys: Document) -> IndexModel {
+  let options = IndexOptions::builder().expire_after(Duration::ZERO).build();
+  IndexModel::builder().keys(keys).options(options).build()
+}
+
+fn initial_indexes() -> Vec<(&'static str, IndexModel)> {
+  vec![
+    ("users", unique_index(doc! { "username": 1 })),
+    ("users", index(doc! { "address": 1 })),
+    ("users", index(doc! { "pubkey": 1 })),
+    ("notes", index(doc! { "owner": 1 })),
+    ("note_history", index(doc! { "address": 1 })),
+    ("messages", index(doc! { "recipient": 1, "read": 1, "timestamp": 1 })),
+    ("nullifiers", unique_index(doc! { "state": 1 })),
+    ("nullifiers", index(doc! { "nullifier": 1 })),
+    ("challenges", unique_index(doc! { "challenge_id": 1 })),
+  ]
+}
+
+fn session_indexes() -> Vec<(&'static str, IndexModel)> {
+  vec![
+    ("sessions", unique_index(doc! { "token": 1 })),
+    ("sessions", unique_index(doc! { "session_id": 1 })),
+    ("sessions", index(doc! { "username": 1, "created_at": 1 })),
+    ("sessions", ttl_index(doc! { "expires_at": 1 })),
+  ]
+}
+
+async fn applied_versions(db: &Database) -> Result<Vec<i64>, DatabaseError> {
+  let cursor = match db.collection::<Document>(MIGRATIONS_COLLECTION).find(doc! {}, None).await {
+    Ok(cur) => cur,
+    Err(e) => return Err(Report::new(DatabaseError::FetchError)
+      .attach_printable(format!("Failed to fetch applied migrations: {}", e))),
+  };
+
+  let applied: Vec<Document> = match cursor.try_collect().await {
+    Ok(docs) => docs,
+    Err(e) => return Err(Report::new(DatabaseError::FetchError)
+      .attach_printable(format!("Failed to fetch applied migrations: {}", e))),
+  };
+
+  Ok(applied.iter().filter_map(|doc| doc.get_i64("version").ok()).collect())
+}
+
+// The name MongoDB gives an index created without one, e.g. `recipient_1_read_1`.
+fn index_name(index: &IndexModel) -> String {
+  index.keys.iter()
+    .map(|(key, direction)| match direction {
+      Bson::String(kind) => format!("{}_{}", key, kind),
+      other => format!("{}_{}", key, other),
+    })
+    .collect::<Vec<_>>()
+    .join("_")
+}
+
+// Whether a migration after `version` dropped `name` from `collection`.
+fn replaced_later(version: i64, collection: &str, name: &str) -> bool {
+  MIGRATIONS.iter()
+    .filter(|migration| migration.version > version)
+    .any(|migration| migration.dropped.contains(&(collection, name)))
+}
+
+async fn indexes_present(db: &Database, migration: &Migration) -> Result<bool, DatabaseError> {
+  for (collection, index) in (migration.indexes)() {
+    if replaced_later(migration.version, collection, &index_name(&index)) {
+      continue;
+    }
+    let names = match db.collection::<Document>(collection).list_index_names().await {
+      Ok(names) => names,
+      // Listing the indexes of a collection that does not exist yet fails.
+      Err(_) => return Ok(false),
+    };
+    if !names.contains(&index_name(&index)) {
+      return Ok(false);
+    }
+  }
+  Ok(true)
+}
+
+/// Lists every known migration and whether it has been applied to `db`. A migration
+/// only counts as applied while all of its indexes still exist.
+pub async fn status(db: &Database) -> Result<Vec<MigrationStatus>, DatabaseError> {
+  let recorded = applied_versions(db).await?;
+
+  let mut statuses = Vec::new();
+  for migration in MIGRATIONS {
+    let applied = recorded.contains(&migration.version) && indexes_present(db, migration).await?;
+    statuses.push(MigrationStatus {
+      version: migration.version,
+      description: migration.description.to_owned(),
+      applied,
+    });
+  }
+  Ok(statuses)
+}
+
+/// Applies all pending migrations in order and stops at the first failure.
+pub async fn apply(db: &Database) -> Result<Vec<MigrationStatus>, DatabaseError> {
+  let applied: Vec<i64> = status(db).await?.into_iter()
+    .filter(|migration| migration.applied)
+    .map(|migration| migration.version)
+    .collect();
+
+  for migration in MIGRATIONS.iter().filter(|migration| !applied.contains(&migration.version)) {
+    for (collection, name) in migration.dropped {
+      match db.collection::<Document>(collection).drop_index(*name, None).await {
+        Ok(()) => {},
+        // Already gone, e.g. an earlier attempt of this migration got this far.
+        Err(e) if matches!(*e.kind, ErrorKind::Command(ref err) if err.code == INDEX_NOT_FOUND || err.code == NAMESPACE_NOT_FOUND) => {},
+        Err(e) => return Err(Report::new(DatabaseError::IndexCreationError)
+          .attach_printable(format!("Migration {} ({}) failed to drop '{}' on '{}': {}", migration.version, migration.description, name, collection, e))),
+      }
+    }
+    for (collection, index) in (migration.indexes)() {
+      if let Err(e) = db.collection::<Document>(collection).create_index(index, None).await {
+        return Err(Report::new(DatabaseError::IndexCreationError)
+          .attach_printable(format!("Migration {} ({}) failed on '{}': {}", migration.version, migration.description, collection, e)));
+      }
+    }
+
+    // Upserting keeps this idempotent when several instances start at once.
+    let options = UpdateOptions::builder().upsert(true).build();
+    if let Err(e) = db.collection::<Document>(MIGRATIONS_COLLECTION).update_one(
+      doc! { "version": migration.version },
+      doc! { "$setOnInsert": {
+        "description": migration.description,
+        "applied_at": bson::DateTime::now(),
+      } },
+      options,
+    ).await {
+      return Err(Report::new(DatabaseError::MigrationError)
+        .attach_printable(format!("Failed to record migration {}: {}", migration.version, e)));
+    }
+  }
+
+  status(db).await
+}
+
+fn challenge_indexes() -> Vec<(&'static str, IndexModel)> {
+  vec![
+    ("challenges", ttl_index(doc! { "expires_at": 1 })),
+  ]
+}
+
+fn key_history_indexes() -> Vec<(&'static str, IndexModel)> {
+  vec![
+    ("key_history", index(doc! { "username": 1, "valid_until": 1 })),
+  ]
+}
+
+fn moderation_indexes() -> Vec<(&'static str, IndexModel)> {
+  vec![
+    ("users", index(doc! { "has_double_spent": 1 })),
+    ("double_spend_flags", unique_index(doc! { "id": 1 })),
+    ("double_spend_flags", index(doc! { "username": 1, "flagged_at": 1 })),
+    ("admin_audit_log", unique_index(doc! { "id": 1 })),
+    ("admin_audit_log", index(doc! { "at": 1 })),
+  ]
+}
+
+// A nullifier is spent once, `/spend` relies on this index instead of reading first.
+// Fails while a nullifier is stored with several states.
+fn unique_nullifier_indexes() -> Vec<(&'static str, IndexModel)> {
+  vec![
+    ("nullifiers", unique_index(doc! { "nullifier": 1 })),
+  ]
+}
+
+fn betrayal_indexes() -> Vec<(&'static str, IndexModel)> {
+  vec![
+    ("betrayals", unique_index(doc! { "id": 1 })),
+    ("betrayals", index(doc! { "username": 1, "detected_at": 1 })),
+    ("betrayals", index(doc! { "spent.note": 1 })),
+    ("betrayals", index(doc! { "conflicting.note": 1 })),
+  ]
+}
+
+fn nullifier_leaf_indexes() -> Vec<(&'static str, IndexModel)> {
+  vec![
+    ("nullifier_leaves", unique_index(doc! { "nullifier": 1 })),
+  ]
+}
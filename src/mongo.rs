@@ -1,277 +1,550 @@
-use ark_crypto_primitives::Error;
-use bson::{doc, Document, Binary, Bson};
-use mongodb::{Cursor, options::{ ClientOptions, FindOptions, ServerApi, ServerApiVersion, IndexOptions }, Client, Collection, IndexModel};
-use std::{sync::{Arc, RwLock}, collections::HashMap, env};
+use bson::{doc, oid::ObjectId, Document, Bson};
+use mongodb::{
+  options::{ ClientOptions, FindOneAndUpdateOptions, FindOptions, ReturnDocument, ServerApi, ServerApiVersion },
+  error::{TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT},
+  Client, ClientSession, Collection, Database
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use crate::routes::{
-  error::{ConvertToDocError, CreateUserError, DatabaseError, InsertDocumentError, MyError},
+  error::DatabaseError,
   response::{
     MessageSingleResponse,
     NoteHistoryResponse,
     NoteResponse,
-    NullifierResponse,
     NullifierResponseData,
     UserSingleResponse
   },
   schema::{
-    ChallengeSchema, CreateUserSchema, MessageRequestSchema, MessageSchema, NoteHistorySaved, NoteNullifierSchema, NoteSchema, SaveNoteHistoryRequestSchema, SaveNoteRequestSchema, User
+    AdminAction, AuditEntry, Betrayal, ChallengeSchema, CreateUserSchema, DoubleSpendFlag, FlagStatus, KeyRecord, MessageRequestSchema, MessageSchema, NoteHistorySaved, NoteNullifierSchema, NoteSchema, SaveNoteHistoryRequestSchema, SaveNoteRequestSchema, User
   }
 };
 use chrono::Utc;
-use futures::stream::TryStreamExt;
-use hex;
-use rand::{Rng, distributions::Alphanumeric};
-use ed25519_dalek::{PublicKey, Signature};
 use error_stack::{Report, Result};
+use async_trait::async_trait;
+use crate::store::{challenge_error, first_spend, leaf_backoff, leaf_contention_error, new_betrayal, new_challenge, new_flag, push_state, IOUStore, MigrationStatus, MAX_LEAF_ATTEMPTS};
+use crate::session::{Session, SessionStore};
+use crate::config::AuthConfig;
+use crate::crypto::KeyType;
+use crate::repository::{is_duplicate_key, Repository};
+use crate::migrations;
+
+// Attempts made for a transaction, or its commit, before giving up.
+const MAX_TRANSACTION_ATTEMPTS: u32 = 3;
 
 #[derive(Debug, Clone)]
 pub struct IOUServiceDB {
-  pub users_collection: Collection<User>,
+  pub client: Client,
+  pub db: Database,
+  pub users_collection: Repository<User>,
   pub users: Collection<Document>,
-  pub notes: Collection<Document>,
-  pub notes_collection: Collection<NoteSchema>,
-  pub note_history: Collection<Document>,
-  pub note_history_collection: Collection<NoteHistorySaved>,
+  pub notes_collection: Repository<NoteSchema>,
+  pub note_history_collection: Repository<NoteHistorySaved>,
   pub messages: Collection<Document>,
-  pub messages_collection: Collection<MessageSchema>,
-  pub nullifiers: Collection<Document>,
-  pub nullifiers_collection: Collection<NoteNullifierSchema>,
-  pub challenges_collection: Collection<ChallengeSchema>,
-  pub challenges: Collection<Document>,
-  pub sessions: Arc<RwLock<HashMap<String, String>>>, 
+  pub messages_collection: Repository<MessageSchema>,
+  pub nullifiers_collection: Repository<NoteNullifierSchema>,
+  pub challenges_collection: Repository<ChallengeDocument>,
+  pub sessions_collection: Repository<SessionDocument>,
+  pub key_history_collection: Repository<KeyRecord>,
+  pub flags_collection: Repository<DoubleSpendFlag>,
+  pub betrayals_collection: Repository<Betrayal>,
+  pub nullifier_leaves_collection: Repository<LeafDocument>,
+  pub counters: Collection<Document>,
+  pub audit_log_collection: Repository<AuditEntry>,
+  pub auth: AuthConfig,
+}
+
+/// A session as stored in MongoDB. `expires_at` is a BSON date so the TTL
+/// index can remove expired sessions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionDocument {
+  pub session_id: String,
+  pub token: String,
+  pub username: String,
+  pub created_at: bson::DateTime,
+  pub expires_at: bson::DateTime,
+}
+
+impl From<&Session> for SessionDocument {
+  fn from(session: &Session) -> Self {
+    Self {
+      session_id: session.id.clone(),
+      token: session.token.clone(),
+      username: session.username.clone(),
+      created_at: bson::DateTime::from_millis(session.created_at * 1000),
+      expires_at: bson::DateTime::from_millis(session.expires_at * 1000),
+    }
+  }
+}
+
+/// A nullifier's leaf in the nullifier Merkle tree, `_id` is its position.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LeafDocument {
+  #[serde(rename = "_id")]
+  pub leaf_index: i64,
+  pub nullifier: String,
+}
+
+/// A challenge as stored in MongoDB, `expires_at` is a BSON date for the TTL index.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChallengeDocument {
+  pub challenge_id: String,
+  pub user_id: String,
+  pub created_at: bson::DateTime,
+  pub expires_at: bson::DateTime,
+}
+
+impl From<&ChallengeSchema> for ChallengeDocument {
+  fn from(challenge: &ChallengeSchema) -> Self {
+    Self {
+      challenge_id: challenge.challenge_id.clone(),
+      user_id: challenge.user_id.clone(),
+      created_at: bson::DateTime::from_millis(challenge.created_at * 1000),
+      expires_at: bson::DateTime::from_millis(challenge.expires_at * 1000),
+    }
+  }
+}
+
+impl From<ChallengeDocument> for ChallengeSchema {
+  fn from(doc: ChallengeDocument) -> Self {
+    Self {
+      challenge_id: doc.challenge_id,
+      user_id: doc.user_id,
+      created_at: doc.created_at.timestamp_millis() / 1000,
+      expires_at: doc.expires_at.timestamp_millis() / 1000,
+    }
+  }
+}
+
+impl From<SessionDocument> for Session {
+  fn from(doc: SessionDocument) -> Self {
+    Self {
+      id: doc.session_id,
+      token: doc.token,
+      username: doc.username,
+      created_at: doc.created_at.timestamp_millis() / 1000,
+      expires_at: doc.expires_at.timestamp_millis() / 1000,
+    }
+  }
 }
 
 impl IOUServiceDB {
-  pub async fn init() -> Self {
-    let uri = env::var("MONGODB_URI").map_err(|_| MyError::InternalServerError("MONGODB_URI not set".to_string()));
-    let mut client_options = ClientOptions::parse(uri.expect("uri is set")).await.unwrap();
+  /// Connects to the MongoDB deployment at `uri` and uses the database `name`.
+  /// `auth` sets how long challenges and sessions stay valid.
+  pub async fn init(uri: &str, name: &str, auth: AuthConfig) -> Result<Self, DatabaseError> {
+    let mut client_options = match ClientOptions::parse(uri).await {
+      Ok(options) => options,
+      Err(e) => return Err(Report::new(DatabaseError::FetchError)
+        .attach_printable(format!("Failed to parse MongoDB uri: {}", e))),
+    };
     let server_api = ServerApi::builder().version(ServerApiVersion::V1).build();
     client_options.server_api = Some(server_api);
-    let client = Client::with_options(client_options).unwrap();
-    let db = client.database("iou");
+    let client = match Client::with_options(client_options) {
+      Ok(client) => client,
+      Err(e) => return Err(Report::new(DatabaseError::FetchError)
+        .attach_printable(format!("Failed to create MongoDB client: {}", e))),
+    };
+    let db = client.database(name);
     // users
     let users = db.collection::<Document>("users");
-    let users_collection = db.collection("users");
+    let users_collection = Repository::new(db.collection("users"));
     // notes
-    let notes = db.collection::<Document>("notes");
-    let notes_collection = db.collection("notes");
+    let notes_collection = Repository::new(db.collection("notes"));
     // note history
-    let note_history = db.collection::<Document>("note_history");
-    let note_history_collection = db.collection("note_history");
+    let note_history_collection = Repository::new(db.collection("note_history"));
     //messages
     let messages = db.collection::<Document>("messages");
-    let messages_collection = db.collection("messages");
+    let messages_collection = Repository::new(db.collection("messages"));
     // betrayal detection system
-    let nullifiers = db.collection::<Document>("nullifiers");
-    let nullifiers_collection = db.collection("nullifiers");
+    let nullifiers_collection = Repository::new(db.collection("nullifiers"));
+    let nullifier_leaves_collection = Repository::new(db.collection("nullifier_leaves"));
+    // sequences, by `_id`
+    let counters = db.collection::<Document>("counters");
     // auth challenge
-    let challenges_collection = db.collection("challenges");
-    let challenges = db.collection::<Document>("challenges");
-    let sessions = Arc::new(RwLock::new(HashMap::new()));
-
-    Self {
+    let challenges_collection = Repository::new(db.collection("challenges"));
+    // sessions, expired ones are removed by a TTL index
+    let sessions_collection = Repository::new(db.collection("sessions"));
+    // keys users rotated away from
+    let key_history_collection = Repository::new(db.collection("key_history"));
+    // moderation
+    let flags_collection = Repository::new(db.collection("double_spend_flags"));
+    let betrayals_collection = Repository::new(db.collection("betrayals"));
+    let audit_log_collection = Repository::new(db.collection("admin_audit_log"));
+
+    Ok(Self {
+      client,
+      db,
       users,
       users_collection,
-      notes,
       notes_collection,
       messages,
       messages_collection,
-      nullifiers,
       nullifiers_collection,
-      note_history,
       note_history_collection,
-      challenges,
       challenges_collection,
-      sessions
-    }
+      sessions_collection,
+      key_history_collection,
+      flags_collection,
+      betrayals_collection,
+      nullifier_leaves_collection,
+      counters,
+      audit_log_collection,
+      auth,
+    })
   }
 
-
   // Helpers
+  fn get_current_timestamp(&self) -> i64 {
+    Utc::now().timestamp()
+  }
 
-  async fn insert_and_fetch<T>(
-    &self,
-    collection: &Collection<Document>,
-    document: Document,
-    transform: impl Fn(Document) -> T,
-  ) -> Result<T, InsertDocumentError> {
-    match collection.insert_one(document, None).await {
-      Ok(insert_result) => {
-        match insert_result.inserted_id.as_object_id() {
-          Some(new_id) => {
-            match collection.find_one(doc! {"_id": new_id}, None).await {
-              Ok(Some(fetched_doc)) => Ok(transform(fetched_doc)),
-              Ok(None) => Err(Report::new(InsertDocumentError)
-                .attach_printable("Document not found after insertion")),
-              Err(e) => Err(Report::new(InsertDocumentError)
-                .attach_printable(format!("Failed to fetch inserted document: {}", e))),
-            }
-          },
-          None => Err(Report::new(InsertDocumentError)
-            .attach_printable("Failed to get inserted _id")),
-        }
-      },
-      Err(e) => Err(Report::new(InsertDocumentError)
-        .attach_printable(format!("Failed to insert document: {}", e))),
+  async fn find_user(&self, filter: Document, identifier: &str) -> Result<User, DatabaseError> {
+    match self.users_collection.find_one(filter).await {
+      Ok(Some(user)) => Ok(user),
+      Ok(None) => Err(Report::new(DatabaseError::NotFoundError)
+        .attach_printable(format!("User '{}' not found", identifier))),
+      Err(e) => Err(e.attach_printable(format!("Failed to fetch user '{}'", identifier))),
+    }
+  }
+
+  // Messages
+  fn new_message(&self, body: &MessageRequestSchema) -> MessageSchema {
+    MessageSchema {
+      sender: body.sender.clone(),
+      recipient: body.recipient.clone(),
+      message: body.message.clone(),
+      timestamp: self.get_current_timestamp(),
+      attachment_id: body.attachment_id.clone(),
+      read: false,
+      _id: None,
     }
   }
 
-  async fn create_unique_index(&self, collection: &Collection<Document>, field: &str) -> Result<String, Error> {
-    let options = IndexOptions::builder().unique(true).build();
-    let index = IndexModel::builder()
-      .keys(doc! {field: 1})
-      .options(options)
-      .build();
+  // Notes History
+  // Runs every write of a note transfer inside the transaction of `session`
+  // and returns the id of the message announcing it, or None when the recipient
+  // does not exist and the transaction has to be aborted.
+  async fn transfer_note_history(
+    &self,
+    session: &mut ClientSession,
+    owner_username: &str,
+    recipient_username: &str,
+    body: &SaveNoteHistoryRequestSchema,
+    message: &str,
+  ) -> std::result::Result<Option<Bson>, mongodb::error::Error> {
+    let note_history = NoteHistorySaved {
+      sender: owner_username.to_owned(),
+      data: body.data.clone(),
+      address: body.address.clone(),
+      _id: None,
+    };
+    let note_id = self.note_history_collection.collection()
+      .insert_one_with_session(&note_history, None, session).await?.inserted_id;
+
+    self.users.update_one_with_session(
+      doc! { "address": body.address.clone() },
+      doc! { "$push": { "notes": note_id.clone() } },
+      None,
+      session,
+    ).await?;
+    self.users.update_one_with_session(
+      doc! { "username": owner_username },
+      doc! { "$pull": { "notes": note_id.clone() } },
+      None,
+      session,
+    ).await?;
+    let received = self.users.update_one_with_session(
+      doc! { "username": recipient_username },
+      doc! { "$push": { "notes": note_id.clone() } },
+      None,
+      session,
+    ).await?;
+    if received.matched_count == 0 {
+      return Ok(None);
+    }
+
+    let message = self.new_message(&MessageRequestSchema {
+      recipient: recipient_username.to_owned(),
+      sender: owner_username.to_owned(),
+      message: message.to_owned(),
+      attachment_id: Some(note_id),
+    });
+    let message_id = self.messages_collection.collection()
+      .insert_one_with_session(&message, None, session).await?.inserted_id;
+
+    self.users.update_one_with_session(
+      doc! { "username": recipient_username },
+      doc! { "$push": { "messages": message_id.clone() } },
+      None,
+      session,
+    ).await?;
 
-    let _ = collection.create_index(index, None).await;
-    Ok("Success".to_owned())
+    Ok(Some(message_id))
   }
 
-  fn get_current_timestamp(&self) -> i64 {
-    Utc::now().timestamp()
+  // Keys
+  // Swaps the user's key and records the retired one inside the transaction of `session`.
+  // Returns false without writing when the user's key is no longer `retired.pubkey`.
+  async fn swap_key(
+    &self,
+    session: &mut ClientSession,
+    retired: &KeyRecord,
+    new_key_type: KeyType,
+    new_pubkey: &str,
+  ) -> std::result::Result<bool, mongodb::error::Error> {
+    let result = self.users.update_one_with_session(
+      doc! { "username": &retired.username, "pubkey": &retired.pubkey },
+      doc! { "$set": { "pubkey": new_pubkey, "key_type": new_key_type.as_str() } },
+      None,
+      session,
+    ).await?;
+    if result.modified_count != 1 {
+      return Ok(false);
+    }
+
+    self.key_history_collection.collection()
+      .insert_one_with_session(retired, None, session).await?;
+    Ok(true)
   }
 
-  // User
-  fn create_user_document(&self, body: &CreateUserSchema) -> Result<Document, ConvertToDocError> {
-    let user = doc! {
-      "username": body.username.clone(),
-      "pubkey": body.pubkey.clone(),
-      "nonce": body.nonce.clone(),
-      "messages": body.messages.clone(),
-      "notes": body.notes.clone(),
-      "has_double_spent": body.has_double_spent,
-      "address": body.address.clone(),
-    };
-    
-    Ok(user)
-  }
-
-  fn doc_to_user(&self, doc: Document) -> UserSingleResponse {
-    let user = User {
-      id: doc.get_str("_id").ok().map(|s| s.to_owned()),
-      has_double_spent: doc.get_bool("has_double_spent").ok(),
-      nonce: doc.get_str("nonce").ok().map(|s| s.to_owned()),
-      username: doc.get_str("username").ok().map(|s| s.to_owned()),
-      address: doc.get_str("address").ok().map(|s| s.to_owned()),
-      pubkey: doc.get_str("pubkey").ok().map(|s| s.to_owned()),
-      messages: doc.get_array("messages").ok().map(|arr| 
-        arr.iter().filter_map(|bson| bson.as_str().map(|s| s.to_owned())).collect()),
-      notes: doc.get_array("notes").ok().and_then(|arr| {
-          let object_ids: Option<Vec<bson::oid::ObjectId>> = arr.iter()
-              .map(|bson| match bson {
-                  Bson::ObjectId(oid) => Some(*oid),
-                  _ => None,
-              })
-              .collect();
-          object_ids
-      }),
-    };
+  // Moderation
+  // Applies `entry` and records it inside the transaction of `session`. Returns `None`
+  // when the user doesn't exist and `Some(false)` without writing when there is no flag to review.
+  async fn apply_moderation(
+    &self,
+    session: &mut ClientSession,
+    entry: &AuditEntry,
+  ) -> std::result::Result<Option<bool>, mongodb::error::Error> {
+    let user = match self.users.find_one_with_session(doc! { "username": &entry.username }, None, session).await? {
+      Some(user) => user,
+      None => return Ok(None),
+    };
+
+    let flagged = user.get_bool("has_double_spent").unwrap_or(false);
+    let (user_update, flag_update) = match entry.action {
+      AdminAction::ClearFlag | AdminAction::ConfirmFlag if !flagged => return Ok(Some(false)),
+      AdminAction::ClearFlag => (
+        Some(doc! { "$set": { "has_double_spent": false } }),
+        Some((vec![FlagStatus::Open.as_str(), FlagStatus::Confirmed.as_str()], FlagStatus::Cleared)),
+      ),
+      AdminAction::ConfirmFlag => (None, Some((vec![FlagStatus::Open.as_str()], FlagStatus::Confirmed))),
+      AdminAction::Suspend => (Some(doc! { "$set": { "suspended": true } }), None),
+      AdminAction::Restore => (Some(doc! { "$set": { "suspended": false } }), None),
+    };
+
+    if let Some(update) = user_update {
+      self.users.update_one_with_session(doc! { "username": &entry.username }, update, None, session).await?;
+    }
+    if let Some((from, to)) = flag_update {
+      self.flags_collection.collection().update_many_with_session(
+        doc! { "username": &entry.username, "status": { "$in": from } },
+        doc! { "$set": { "status": to.as_str() } },
+        None,
+        session,
+      ).await?;
+    }
+    self.audit_log_collection.collection()
+      .insert_one_with_session(entry, None, session).await?;
+    Ok(Some(true))
+  }
 
-    UserSingleResponse {
-      status: "success",
-      user
+  // Flags the owner of `stored` and records the flag and betrayal inside the transaction
+  // of `session`. Returns false without writing when the owner doesn't exist.
+  async fn record_flag(
+    &self,
+    session: &mut ClientSession,
+    stored: &NoteNullifierSchema,
+    conflicting: &NoteNullifierSchema,
+  ) -> std::result::Result<bool, mongodb::error::Error> {
+    let result = self.users.update_one_with_session(
+      doc! { "username": &stored.owner },
+      doc! { "$set": { "has_double_spent": true } },
+      None,
+      session,
+    ).await?;
+    if result.matched_count == 0 {
+      return Ok(false);
     }
+
+    self.flags_collection.collection()
+      .insert_one_with_session(new_flag(stored, &conflicting.state), None, session).await?;
+    self.betrayals_collection.collection()
+      .insert_one_with_session(new_betrayal(stored, conflicting), None, session).await?;
+    Ok(true)
   }
 
-  pub async fn get_user_with_username(&self, username: &str) -> Result<UserSingleResponse, DatabaseError> {
-    // Find the user document
-    let user_doc = match self.users.find_one(doc! {"username": username}, None).await {
-      Ok(Some(doc)) => doc,
-      Ok(None) => return Err(Report::new(DatabaseError::NotFoundError)
-        .attach_printable(format!("User '{}' not found", username))),
-      Err(e) => return Err(Report::new(DatabaseError::FetchError)
-        .attach_printable(format!("Failed to fetch user '{}': {}", username, e))),
+  // Gives `nullifier` the next leaf of the nullifier Merkle tree inside the transaction
+  // of `session`. Concurrent transactions conflict on the counter, so leaves are dense
+  // and committed in order.
+  async fn add_leaf(&self, session: &mut ClientSession, nullifier: &str) -> std::result::Result<(), mongodb::error::Error> {
+    let options = FindOneAndUpdateOptions::builder().upsert(true).return_document(ReturnDocument::Before).build();
+    let before = self.counters.find_one_and_update_with_session(
+      doc! { "_id": "nullifier_leaves" },
+      doc! { "$inc": { "next": 1_i64 } },
+      options,
+      session,
+    ).await?;
+
+    let leaf = LeafDocument {
+      leaf_index: before.and_then(|counter| counter.get_i64("next").ok()).unwrap_or(0),
+      nullifier: nullifier.to_owned(),
+    };
+    self.nullifier_leaves_collection.collection().insert_one_with_session(&leaf, None, session).await?;
+    Ok(())
+  }
+
+  // Stores `record`, when given, and the leaf of `nullifier` in one transaction. Concurrent
+  // first spends conflict on the leaf counter, the losers abort and retry.
+  async fn append_nullifier(&self, nullifier: &str, record: Option<&NoteNullifierSchema>) -> Result<(), DatabaseError> {
+    let mut session = match self.client.start_session(None).await {
+      Ok(session) => session,
+      Err(e) => return Err(Report::new(DatabaseError::InsertError)
+        .attach_printable(format!("Failed to start session: {}", e))),
     };
 
-    let user_res = self.doc_to_user(user_doc);
+    let mut attempt = 0;
+    loop {
+      attempt += 1;
+      if let Err(e) = session.start_transaction(None).await {
+        return Err(Report::new(DatabaseError::InsertError)
+          .attach_printable(format!("Failed to start transaction: {}", e)));
+      }
 
-    Ok(UserSingleResponse {
-      status: "success",
-      user: user_res.user
-    })
+      let written = match record {
+        Some(record) => self.nullifiers_collection.collection().insert_one_with_session(record, None, &mut session).await.map(|_| ()),
+        None => Ok(()),
+      };
+      let written = match written {
+        Ok(()) => self.add_leaf(&mut session, nullifier).await,
+        Err(e) => Err(e),
+      };
+      let result = match written {
+        Ok(()) => self.commit_transaction(&mut session).await,
+        Err(e) => {
+          if let Err(abort_err) = session.abort_transaction().await {
+            log::error!("Error aborting nullifier: {:?}", abort_err);
+          }
+          Err(e)
+        }
+      };
+
+      match result {
+        Ok(()) => return Ok(()),
+        Err(e) if e.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempt < MAX_LEAF_ATTEMPTS => {
+          log::warn!("Retrying nullifier after transient error: {}", e);
+          leaf_backoff(attempt).await;
+        },
+        Err(e) if e.contains_label(TRANSIENT_TRANSACTION_ERROR) => return Err(leaf_contention_error(nullifier)
+          .attach_printable(format!("Last attempt failed: {}", e))),
+        Err(e) => {
+          let context = if is_duplicate_key(&e) { DatabaseError::DuplicateKeyError } else { DatabaseError::InsertError };
+          return Err(Report::new(context)
+            .attach_printable(format!("Failed to store nullifier '{}': {}", nullifier, e)));
+        },
+      }
+    }
   }
 
-  pub async fn get_user_with_address(&self, address: &str) -> Result<UserSingleResponse, DatabaseError> {
-    // Find the user document
-    let user_doc = match self.users.find_one(doc! {"address": address}, None).await {
-      Ok(Some(doc)) => doc,
-      Ok(None) => return Err(Report::new(DatabaseError::NotFoundError)
-        .attach_printable(format!("User '{}' not found", address))),
-      Err(e) => return Err(Report::new(DatabaseError::FetchError)
-        .attach_printable(format!("Failed to fetch user '{}': {}", address, e))),
-    };
+  // Gives nullifiers stored before the Merkle tree a leaf, oldest first.
+  async fn backfill_nullifier_leaves(&self) -> Result<(), DatabaseError> {
+    let leaves = self.nullifier_leaves_collection.find(doc! {}, None).await?;
+    let known: HashSet<String> = leaves.into_iter().map(|leaf| leaf.nullifier).collect();
 
-    let user_res = self.doc_to_user(user_doc);
+    let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+    for stored in self.nullifiers_collection.find(doc! {}, options).await? {
+      if !known.contains(&stored.nullifier) {
+        self.append_nullifier(&stored.nullifier, None).await?;
+      }
+    }
+    Ok(())
+  }
 
-    Ok(UserSingleResponse {
-      status: "success",
-      user: user_res.user
-    })
+  async fn commit_transaction(&self, session: &mut ClientSession) -> std::result::Result<(), mongodb::error::Error> {
+    let mut attempt = 0;
+    loop {
+      attempt += 1;
+      match session.commit_transaction().await {
+        Err(e) if e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) && attempt < MAX_TRANSACTION_ATTEMPTS => continue,
+        result => return result,
+      }
+    }
   }
+}
 
-  pub async fn create_user(&self, body: &CreateUserSchema) -> Result<UserSingleResponse, CreateUserError> {
-    let document = match self.create_user_document(body) {
-      Ok(doc) => doc,
-      Err(e) => return Err(Report::new(CreateUserError)
-        .attach_printable(format!("Failed to create user document: {}", e))),
-    };
+// Records stored before the state vector have no `states`, and pushes to them never
+// added the first spend. It is the top level `step` and `state`.
+fn with_first_spend(mut stored: NoteNullifierSchema) -> NoteNullifierSchema {
+  if !stored.states.iter().any(|entry| entry.step == stored.step) {
+    push_state(&mut stored.states, stored.step, &stored.state);
+  }
+  stored
+}
 
-    match self.create_unique_index(&self.users, "username").await {
-      Ok(_) => {},
-      Err(e) => return Err(Report::new(CreateUserError)
-        .attach_printable(format!("Failed to create unique index: {}", e))),
+#[async_trait]
+impl IOUStore for IOUServiceDB {
+  async fn ping(&self) -> Result<(), DatabaseError> {
+    match self.db.run_command(doc! { "ping": 1 }, None).await {
+      Ok(_) => Ok(()),
+      Err(e) => Err(Report::new(DatabaseError::FetchError)
+        .attach_printable(format!("Failed to ping MongoDB: {}", e))),
     }
+  }
 
-    let user = match self.insert_and_fetch(&self.users, document, |doc| self.doc_to_user(doc).user).await {
-      Ok(user) => user,
-      Err(e) => return Err(Report::new(CreateUserError)
-        .attach_printable(format!("Failed to insert and fetch user: {}", e))),
-    };
+  // Migrations
+  async fn migration_status(&self) -> Result<Vec<MigrationStatus>, DatabaseError> {
+    migrations::status(&self.db).await
+  }
+
+  async fn migrate(&self) -> Result<Vec<MigrationStatus>, DatabaseError> {
+    let status = migrations::apply(&self.db).await?;
+    self.backfill_nullifier_leaves().await?;
+    Ok(status)
+  }
+
+  // User
+  async fn get_user_with_username(&self, username: &str) -> Result<UserSingleResponse, DatabaseError> {
+    let user = self.find_user(doc! {"username": username}, username).await?;
 
     Ok(UserSingleResponse {
       status: "success",
-      user,
+      user
     })
   }
 
-  // Messages
-  fn doc_to_message(&self, doc: Document) -> MessageSingleResponse {
-    let message = MessageSchema {
-      sender: doc.get_str("sender").ok().map(|s| s.to_owned()).unwrap(),
-      recipient: doc.get_str("recipient").ok().map(|s| s.to_owned()).unwrap() ,
-      message: doc.get_str("message").ok().map(|s| s.to_owned()).unwrap(),
-      timestamp: doc.get_i64("timestamp").ok().unwrap(),
-      attachment_id: doc.get("attachment_id").to_owned().cloned(),
-      read: doc.get_bool("read").ok().unwrap(),
-      _id: doc.get("_id").to_owned().cloned()
-    };
+  async fn get_user_with_address(&self, address: &str) -> Result<UserSingleResponse, DatabaseError> {
+    let user = self.find_user(doc! {"address": address}, address).await?;
 
-    MessageSingleResponse {
+    Ok(UserSingleResponse {
       status: "success",
-      message: message
-    }
+      user
+    })
   }
 
-  fn create_message_document(&self, body: &MessageRequestSchema) -> Document {
-    let message = doc! {
-      "sender": body.sender.clone(),
-      "recipient": body.recipient.clone(),
-      "message": body.message.clone(),
-      "timestamp": self.get_current_timestamp(),
-      "attachment_id": body.attachment_id.clone(),
-      "read": false,
+  async fn create_user(&self, body: &CreateUserSchema) -> Result<UserSingleResponse, DatabaseError> {
+    let new_user = User {
+      id: None,
+      username: Some(body.username.clone()),
+      pubkey: Some(body.pubkey.clone()),
+      key_type: Some(body.key_type),
+      suspended: Some(false),
+      nonce: Some(body.nonce.clone()),
+      messages: Some(body.messages.iter().filter_map(|id| ObjectId::parse_str(id).ok()).collect()),
+      notes: Some(body.notes.iter().filter_map(|id| ObjectId::parse_str(id).ok()).collect()),
+      has_double_spent: Some(body.has_double_spent),
+      address: Some(body.address.clone()),
     };
 
-    message
+    let user = self.users_collection.insert(&new_user).await?;
+
+    Ok(UserSingleResponse {
+      status: "success",
+      user,
+    })
   }
-  
-  pub async fn send_message(&self, body: &MessageRequestSchema) -> Result<MessageSingleResponse, DatabaseError> {
-    let document = self.create_message_document(body);
 
-    let message = match self.insert_and_fetch(&self.messages, document, |doc| self.doc_to_message(doc).message).await {
-      Ok(msg) => msg,
-      Err(e) => return Err(Report::new(DatabaseError::InsertError)
-        .attach_printable(format!("Failed to insert and fetch message: {}", e))),
-    };
+  // Messages
+  async fn send_message(&self, body: &MessageRequestSchema) -> Result<MessageSingleResponse, DatabaseError> {
+    let message = self.messages_collection.insert(&self.new_message(body)).await?;
 
     match self.users.update_one(
       doc! { "username": message.recipient.clone() },
@@ -289,7 +562,7 @@ impl IOUServiceDB {
     })
   }
 
-  pub async fn get_unread_messages(&self, username: &str) -> Result<Vec<MessageSchema>, DatabaseError> {
+  async fn get_unread_messages(&self, username: &str) -> Result<Vec<MessageSchema>, DatabaseError> {
     let filter = doc! {
       "recipient": username,
       "read": false
@@ -297,173 +570,142 @@ impl IOUServiceDB {
     let sort = doc! { "timestamp": 1 };
     let find_options = FindOptions::builder().sort(sort).build();
 
-    let cursor = match self.messages.find(filter, Some(find_options)).await {
-      Ok(cur) => cur,
-      Err(e) => return Err(Report::new(DatabaseError::FetchError)
-        .attach_printable(format!("Failed to fetch unread messages: {}", e))),
-    };
+    let messages = self.messages_collection.find(filter, find_options).await?;
+    let ids: Vec<Bson> = messages.iter().filter_map(|msg| msg._id.clone()).collect();
 
-    let messages: Vec<MessageSchema> = match cursor
-      .try_filter_map(|doc| async move {
-        let msg = self.doc_to_message(doc);
-        match self.messages
-          .update_one(
-            doc! { "_id": msg.message._id.clone() },
-            doc! { "$set": { "read": true } },
-            None,
-          )
-          .await
-        {
-          Ok(_) => Ok(Some(msg.message)),
-          Err(err) => {
-            eprintln!("Error marking message as read: {:?}", err);
-            Ok(None)
-          }
-        }
-      })
-      .try_collect()
-      .await
-    {
-      Ok(msgs) => msgs,
+    match self.messages.update_many(
+      doc! { "_id": { "$in": ids } },
+      doc! { "$set": { "read": true } },
+      None,
+    ).await {
+      Ok(_) => {},
       Err(e) => return Err(Report::new(DatabaseError::UpdateError)
         .attach_printable(format!("Failed to update message read status: {}", e))),
-    };
+    }
 
     Ok(messages)
   }
 
-  // Nullifiers 
-  fn doc_to_nullifier(&self, doc: Document) -> NullifierResponseData {
-    let nullifier = NoteNullifierSchema {
-      nullifier: doc.get_str("nullifier").ok().map(|s| s.to_owned()).unwrap(),
-      note: doc.get_str("note").ok().map(|s| s.to_owned()).unwrap(),
-      step: doc.get_i32("step").ok().unwrap(),
-      owner: doc.get_str("owner").ok().map(|s| s.to_owned()).unwrap(),
-      state: doc.get_str("state").ok().map(|s| s.to_owned()).unwrap(),
-    };
+  // Nullifiers
+  async fn store_nullifier(&self, body: &NoteNullifierSchema) -> Result<NullifierResponseData, DatabaseError> {
+    let nullifier = first_spend(body);
+    self.append_nullifier(&nullifier.nullifier, Some(&nullifier)).await?;
 
-    NullifierResponseData {
+    Ok(NullifierResponseData {
       status: "success",
       nullifier
-    }
+    })
   }
 
-  fn create_note_nullifier_document(&self, body: &NoteNullifierSchema) -> Document {
-    let nullifier = doc! {
-      "nullifier": body.nullifier.clone(),
-      "note": body.note.clone(),
-      "step": body.step,
-      "owner": body.owner.clone(),
-      "state": body.state.clone()
-    };
+  async fn record_nullifier_state(&self, body: &NoteNullifierSchema) -> Result<Option<NoteNullifierSchema>, DatabaseError> {
+    match self.append_nullifier(&body.nullifier, Some(&first_spend(body))).await {
+      Ok(()) => return Ok(None),
+      // Lost to the unique index on `nullifier`, the stored record is never deleted.
+      Err(err) if matches!(err.current_context(), DatabaseError::DuplicateKeyError) => {},
+      Err(err) => return Err(err),
+    }
 
-    nullifier
+    // Pushes only for the owner and while neither the step nor the state is in the
+    // vector, checked and written by one update. The top level step and state are the first spend, which
+    // records from before the state vector lack in `states`.
+    let filter = doc! {
+      "nullifier": &body.nullifier,
+      "owner": &body.owner,
+      "step": { "$ne": body.step },
+      "state": { "$ne": &body.state },
+      "states.step": { "$ne": body.step },
+      "states.state": { "$ne": &body.state },
+    };
+    let entry = doc! { "step": body.step, "state": &body.state };
+    let update = doc! { "$push": { "states": { "$each": [entry], "$sort": { "step": 1 } } } };
+    if let Some(before) = self.nullifiers_collection.find_one_and_update(filter, update, None).await? {
+      return Ok(Some(with_first_spend(before)));
+    }
+
+    match self.find_nullifier(&body.nullifier).await? {
+      Some(stored) => Ok(Some(stored)),
+      None => Err(Report::new(DatabaseError::NotFoundError)
+        .attach_printable(format!("Nullifier '{}' conflicted but is not stored", body.nullifier))),
+    }
   }
 
-  pub async fn store_nullifier(&self, body: &NoteNullifierSchema) -> Result<NullifierResponseData, DatabaseError> {
-    let document = self.create_note_nullifier_document(body);
+  async fn find_nullifier(&self, nullifier: &str) -> Result<Option<NoteNullifierSchema>, DatabaseError> {
+    Ok(self.nullifiers_collection.find_one(doc! {"nullifier": nullifier}).await?.map(with_first_spend))
+  }
 
-    match self.create_unique_index(&self.nullifiers, "state").await {
-      Ok(_) => {},
-      Err(e) => return Err(Report::new(DatabaseError::IndexCreationError)
-        .attach_printable(format!("Failed to create unique index: {}", e))),
-    }
+  async fn nullifier_leaves(&self, from: u64) -> Result<Vec<String>, DatabaseError> {
+    let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+    let leaves = self.nullifier_leaves_collection.find(doc! { "_id": { "$gte": from as i64 } }, options).await?;
+    Ok(leaves.into_iter().map(|leaf| leaf.nullifier).collect())
+  }
 
-    let nullifier = match self.insert_and_fetch(&self.nullifiers, document, |doc| self.doc_to_nullifier(doc).nullifier).await {
-      Ok(null) => null,
-      Err(e) => return Err(Report::new(DatabaseError::InsertError)
-        .attach_printable(format!("Failed to insert and fetch nullifier: {}", e))),
+  async fn flag_double_spend(&self, stored: &NoteNullifierSchema, conflicting: &NoteNullifierSchema) -> Result<(), DatabaseError> {
+    let mut session = match self.client.start_session(None).await {
+      Ok(session) => session,
+      Err(e) => return Err(Report::new(DatabaseError::UpdateError)
+        .attach_printable(format!("Failed to start session: {}", e))),
     };
 
-    Ok(NullifierResponseData {
-      status: "success",
-      nullifier
-    })
-  }
+    let mut attempt = 0;
+    let flagged = loop {
+      attempt += 1;
+      if let Err(e) = session.start_transaction(None).await {
+        return Err(Report::new(DatabaseError::UpdateError)
+          .attach_printable(format!("Failed to start transaction: {}", e)));
+      }
 
-  pub async fn get_nullifier(&self, nullifier: &str, expected_state: &str) -> NullifierResponse { 
-    let nullifier_doc = self
-      .nullifiers
-      .find_one(doc! {"nullifier": nullifier}, None)
-      .await;
-
-    match nullifier_doc {
-      Ok(Some(doc)) => {
-        if let Some(state) = doc.get_str("state").ok() { 
-          if state == expected_state {
-            if let Ok(owner) = doc.get_str("owner") {
-              let update_result = self.users
-                .update_one(
-                  doc! {"username": owner},
-                  doc! {"$set": {"has_double_spent": true}},
-                  None,
-                )
-                .await;
-
-              if let Err(err) = update_result {
-                eprintln!("Error updating user: {:?}", err);
-              }
-            } else {
-              eprintln!("Owner not found in nullifier document.");
-            }
-
-            println!("WARNING: USER IS ATTEMPTING TO DOUBLE SPEND, we have flagged their account.");
-            return NullifierResponse::Ok(self.doc_to_nullifier(doc).nullifier);
-          } else {
-            println!("Nullifier and state combination is unique");
-            return NullifierResponse::Error; 
+      let result = match self.record_flag(&mut session, stored, conflicting).await {
+        Ok(true) => self.commit_transaction(&mut session).await.map(|_| true),
+        Ok(false) => session.abort_transaction().await.map(|_| false),
+        Err(e) => {
+          if let Err(abort_err) = session.abort_transaction().await {
+            log::error!("Error aborting flag: {:?}", abort_err);
           }
-        } else {
-          eprintln!("State field not found in nullifier document.");
-          return NullifierResponse::Error; 
+          Err(e)
         }
+      };
+
+      match result {
+        Ok(flagged) => break flagged,
+        Err(e) if e.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempt < MAX_TRANSACTION_ATTEMPTS => {
+          log::warn!("Retrying flag after transient error: {}", e);
+        },
+        Err(e) => return Err(Report::new(DatabaseError::UpdateError)
+          .attach_printable(format!("Failed to flag user '{}': {}", stored.owner, e))),
       }
-      Ok(None) => NullifierResponse::NotFound, 
-      Err(err) => {
-        eprintln!("Error getting nullifier: {:?}", err);
-        NullifierResponse::Error
-      }
-    }
-  } 
-  
-  // Notes
-  fn doc_to_note(&self, doc: Document) -> NoteResponse {
-    let note = NoteSchema {
-      asset_hash: doc.get_str("asset_hash").ok().map(|s| s.to_owned()).unwrap(),
-      owner: doc.get_str("owner").ok().map(|s| s.to_owned()).unwrap(),
-      value: doc.get_i64("value").ok().unwrap() as u64,
-      step: doc.get_i32("step").ok().unwrap() as u32,
-      parent_note: doc.get_str("parent_note").ok().map(|s| s.to_owned()).unwrap(),
-      out_index: doc.get_str("out_index").ok().map(|s| s.to_owned()).unwrap(),
-      blind: doc.get_str("blind").ok().map(|s| s.to_owned()).unwrap(),
-      _id: doc.get("_id").to_owned().cloned()
     };
 
-    NoteResponse { status: "success", note }
+    if !flagged {
+      return Err(Report::new(DatabaseError::NotFoundError)
+        .attach_printable(format!("Owner '{}' of nullifier '{}' not found", stored.owner, stored.nullifier)));
+    }
+    Ok(())
   }
 
-  fn create_note_document(&self, body: &SaveNoteRequestSchema) -> Document {
-    let note = doc! {
-      "asset_hash": body.asset_hash.clone(),
-      "owner": body.owner.clone(),
-      "value": body.value as i64,
-      "step": body.step as i32,
-      "parent_note": body.parent_note.clone(),
-      "out_index": body.out_index.clone(),
-      "blind": body.blind.clone(),
-    };
-
-    note
+  async fn get_user_betrayals(&self, username: &str) -> Result<Vec<Betrayal>, DatabaseError> {
+    let options = FindOptions::builder().sort(doc! { "detected_at": 1, "_id": 1 }).build();
+    self.betrayals_collection.find(doc! { "username": username }, options).await
   }
 
-  pub async fn store_note(&self, body: &SaveNoteRequestSchema) -> Result<NoteResponse, DatabaseError> {
-    let document = self.create_note_document(body);
+  async fn get_note_betrayals(&self, note: &str) -> Result<Vec<Betrayal>, DatabaseError> {
+    let options = FindOptions::builder().sort(doc! { "detected_at": 1, "_id": 1 }).build();
+    let filter = doc! { "$or": [{ "spent.note": note }, { "conflicting.note": note }] };
+    self.betrayals_collection.find(filter, options).await
+  }
 
-    let note = match self.insert_and_fetch(&self.notes, document, |doc| self.doc_to_note(doc).note).await {
-      Ok(n) => n,
-      Err(e) => return Err(Report::new(DatabaseError::InsertError)
-        .attach_printable(format!("Failed to insert and fetch note: {}", e))),
-    };
+  // Notes
+  async fn store_note(&self, body: &SaveNoteRequestSchema) -> Result<NoteResponse, DatabaseError> {
+    let new_note = NoteSchema {
+      asset_hash: body.asset_hash.clone(),
+      owner: body.owner.clone(),
+      value: body.value,
+      step: body.step,
+      parent_note: body.parent_note.clone(),
+      out_index: body.out_index.clone(),
+      blind: body.blind.clone(),
+      _id: None,
+    };
+    let note = self.notes_collection.insert(&new_note).await?;
 
     match self.users.update_one(
       doc! { "pubkey": note.owner.clone() },
@@ -478,76 +720,19 @@ impl IOUServiceDB {
     Ok(NoteResponse { status: "success", note })
   }
 
-  pub async fn get_user_notes(&self, user_pub_key: &str) -> Result<Vec<NoteSchema>, DatabaseError> {
-    let filter = doc! { "owner": user_pub_key };
-    
-    let mut cursor: Cursor<Document> = match self.notes.find(filter, None).await {
-      Ok(cur) => cur,
-      Err(e) => return Err(Report::new(DatabaseError::FetchError)
-        .attach_printable(format!("Failed to fetch user notes: {}", e))),
-    };
-
-    let mut notes = Vec::new();
-    
-    loop {
-      match cursor.try_next().await {
-        Ok(Some(doc)) => {
-          let note = self.doc_to_note(doc);
-          notes.push(note.note);
-        },
-        Ok(None) => break,
-        Err(e) => return Err(Report::new(DatabaseError::FetchError)
-          .attach_printable(format!("Failed to fetch next note: {}", e))),
-      }
-    }
-
-    Ok(notes)
+  async fn get_user_notes(&self, user_pub_key: &str) -> Result<Vec<NoteSchema>, DatabaseError> {
+    self.notes_collection.find(doc! { "owner": user_pub_key }, None).await
   }
 
   // Notes History
-  fn doc_to_note_history(&self, doc: Document) -> NoteHistoryResponse {
-    let note_history = NoteHistorySaved {
-      data: doc.get_array("data")
-        .unwrap_or(&Vec::new())
-        .iter()
-        .flat_map(|bson| {
-            match bson {
-                bson::Bson::Binary(binary) => binary.bytes.to_vec(),
-                _ => Vec::new(),
-            }
-        })
-        .collect::<Vec<u8>>(),
-      address: doc.get_str("address").ok().map(|s| s.to_owned()).unwrap_or_else(String::new),
-      _id: doc.get("_id").to_owned().cloned(),
-      sender: doc.get_str("sender").ok().map(|s| s.to_owned()).unwrap_or_else(String::new),
-    };
-
-    NoteHistoryResponse {
-      status: "success", 
-      note_history
-    }
-  }
-
-  fn create_note_history_document(&self, body: SaveNoteHistoryRequestSchema) -> Document {
-      let note_history = doc! {
-          "data": Bson::Binary(Binary {
-              subtype: bson::spec::BinarySubtype::Generic,
-              bytes: body.data.clone(),
-          }),
-          "address": body.address.clone(),
-          "sender": body.sender.clone()
-      };
-      note_history
-  }
-
-  pub async fn store_note_history(&self, body: SaveNoteHistoryRequestSchema) -> Result<NoteHistoryResponse, DatabaseError> {
-    let document = self.create_note_history_document(body);
-
-    let note_history = match self.insert_and_fetch(&self.note_history, document, |doc| self.doc_to_note_history(doc).note_history).await {
-      Ok(history) => history,
-      Err(e) => return Err(Report::new(DatabaseError::InsertError)
-        .attach_printable(format!("Failed to insert and fetch note history: {}", e))),
+  async fn store_note_history(&self, body: SaveNoteHistoryRequestSchema) -> Result<NoteHistoryResponse, DatabaseError> {
+    let new_note_history = NoteHistorySaved {
+      sender: body.sender,
+      data: body.data,
+      address: body.address,
+      _id: None,
     };
+    let note_history = self.note_history_collection.insert(&new_note_history).await?;
 
     match self.users.update_one(
       doc! { "address": note_history.address.clone() },
@@ -561,199 +746,262 @@ impl IOUServiceDB {
 
     Ok(NoteHistoryResponse {
       status: "success",
-      note_history: note_history
+      note_history
     })
   }
 
-  pub async fn get_note_history_for_user(&self, username: String) -> Result<Vec<NoteHistorySaved>, DatabaseError> {
-    let user = self.get_user_with_username(&username).await
-    .expect("user");
+  async fn get_note_history_for_user(&self, username: String) -> Result<Vec<NoteHistorySaved>, DatabaseError> {
+    let user = self.find_user(doc! {"username": &username}, &username).await?;
+    let note_ids = user.notes.unwrap_or_default();
 
-    let note_ids = user.user.notes.unwrap_or_default();
+    let mut notes = Vec::new();
+    for note_id in note_ids {
+      match self.note_history_collection.find_one(doc! { "_id": note_id }).await? {
+        Some(note_history) => notes.push(note_history),
+        None => log::warn!("Note {} not found", note_id),
+      }
+    }
 
     // Update the user in the database to remove the notes
-    self.users.update_one(
+    match self.users.update_one(
       doc! { "username": &username },
       doc! { "$set": { "notes": [] } },
       None
-    ).await.expect("couldn't remove notes from user");
-
-    let mut notes = Vec::new();
-    for note_id in note_ids {
-      match self.note_history.find_one(doc! { "_id": note_id }, None).await {
-        Ok(Some(doc)) => {
-            let note_history = NoteHistorySaved {
-                data: doc.get_binary_generic("data")
-                    .map(|b| b.clone())
-                    .unwrap_or_default(),
-                address: doc.get_str("address")
-                    .map(|s| s.to_string())
-                    .unwrap_or_default(),
-                _id: doc.get("_id").cloned(),
-                sender: doc.get_str("sender")
-                  .map(|s| s.to_string())
-                  .unwrap_or_default(),
-            };
-            notes.push(note_history);
-        },
-        Ok(None) => eprintln!("Note {} not found", note_id),
-        Err(e) => eprintln!("Error finding note {}: {}", note_id, e),
-    }
+    ).await {
+      Ok(_) => {},
+      Err(e) => return Err(Report::new(DatabaseError::UpdateError)
+        .attach_printable(format!("Failed to remove notes from user: {}", e))),
     }
-    println!("{:#?}", notes);
+
     Ok(notes)
   }
 
-  pub async fn create_and_transfer_note_history(
+  async fn create_and_transfer_note_history(
     &self,
     owner_username: String,
     recipient_username: &str,
     body: SaveNoteHistoryRequestSchema,
     message: String,
   ) -> Result<MessageSingleResponse, DatabaseError> {
-    let to_save = SaveNoteHistoryRequestSchema {
-      data: body.data.clone(),
-      address: body.address.clone(),
-      sender: owner_username.clone(),
+    let mut session = match self.client.start_session(None).await {
+      Ok(session) => session,
+      Err(e) => return Err(Report::new(DatabaseError::UpdateError)
+        .attach_printable(format!("Failed to start session: {}", e))),
     };
-    let stored_note = self.store_note_history(to_save).await;
-    let note_id = stored_note.expect("no note id").note_history._id.clone();
-    
-    let message = MessageRequestSchema {
-      recipient: recipient_username.to_owned(),
-      sender: owner_username.clone(),
-      message: message.to_owned(),
-      attachment_id: note_id.clone(),
+
+    let mut attempt = 0;
+    let message_id = loop {
+      attempt += 1;
+      if let Err(e) = session.start_transaction(None).await {
+        return Err(Report::new(DatabaseError::UpdateError)
+          .attach_printable(format!("Failed to start transaction: {}", e)));
+      }
+
+      let result = match self.transfer_note_history(&mut session, &owner_username, recipient_username, &body, &message).await {
+        Ok(Some(message_id)) => self.commit_transaction(&mut session).await.map(|_| Some(message_id)),
+        Ok(None) => session.abort_transaction().await.map(|_| None),
+        Err(e) => {
+          if let Err(abort_err) = session.abort_transaction().await {
+            log::error!("Error aborting note transfer: {:?}", abort_err);
+          }
+          Err(e)
+        }
+      };
+
+      match result {
+        Ok(Some(message_id)) => break message_id,
+        Ok(None) => return Err(Report::new(DatabaseError::NotFoundError)
+          .attach_printable(format!("Recipient '{}' not found", recipient_username))),
+        Err(e) if e.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempt < MAX_TRANSACTION_ATTEMPTS => {
+          log::warn!("Retrying note transfer after transient error: {}", e);
+        },
+        Err(e) => return Err(Report::new(DatabaseError::UpdateError)
+          .attach_printable(format!("Failed to transfer note history: {}", e))),
+      }
     };
 
-    self.users.update_one(
-      doc! { "username": owner_username.to_owned() },
-      doc! { "$pull": { "notes": note_id.clone() } },
-      None,
-    ).await.map_err(|e| Report::new(DatabaseError::UpdateError)
-      .attach_printable(format!("Failed to remove note from current owner: {}", e)))?;
+    match self.messages_collection.find_one(doc! { "_id": message_id.clone() }).await? {
+      Some(message) => Ok(MessageSingleResponse {
+        status: "success",
+        message
+      }),
+      None => Err(Report::new(DatabaseError::NotFoundError)
+        .attach_printable(format!("Message {} not found after transfer", message_id))),
+    }
+  }
+
+  // Challenges
+  async fn create_challenge(&self, username: &str) -> Result<ChallengeSchema, DatabaseError> {
+    let challenge = new_challenge(username, self.auth.challenge_ttl);
+
+    match self.challenges_collection.collection().insert_one(ChallengeDocument::from(&challenge), None).await {
+      Ok(_) => Ok(challenge),
+      Err(e) => Err(Report::new(DatabaseError::InsertError)
+        .attach_printable(format!("Failed to insert new challenge: {}", e))),
+    }
+  }
+
+  async fn consume_challenge(&self, challenge_id: &str, username: &str) -> Result<ChallengeSchema, DatabaseError> {
+    let now = bson::DateTime::now();
+
+    // Deleting is what claims the challenge, of two concurrent logins only one gets the document.
+    let filter = doc! { "challenge_id": challenge_id, "user_id": username, "expires_at": { "$gt": now } };
+    match self.challenges_collection.collection().find_one_and_delete(filter, None).await {
+      Ok(Some(challenge)) => return Ok(challenge.into()),
+      Ok(None) => {},
+      Err(e) => return Err(Report::new(DatabaseError::UpdateError)
+        .attach_printable(format!("Failed to consume challenge: {}", e))),
+    }
+
+    let filter = doc! { "challenge_id": challenge_id, "user_id": { "$ne": username }, "expires_at": { "$gt": now } };
+    let issued_to_other = self.challenges_collection.find_one(filter).await?.is_some();
+    Err(challenge_error(challenge_id, issued_to_other))
+  }
+
+  // Nonces
+  async fn compare_and_swap_nonce(&self, username: &str, current: &str, new: &str) -> Result<bool, DatabaseError> {
+    // Users without a nonce have none or an empty one stored, `current` is then empty.
+    let expected = if current.is_empty() { Bson::Document(doc! { "$in": [Bson::Null, ""] }) } else { Bson::String(current.to_owned()) };
     match self.users.update_one(
-      doc! { "username": recipient_username },
-      doc! { "$push": { "notes": note_id.clone() } },
+      doc! { "username": username, "nonce": expected },
+      doc! { "$set": { "nonce": new } },
       None,
     ).await {
-      Ok(_) => {},
-      Err(e) => return Err(Report::new(DatabaseError::UpdateError)
-        .attach_printable(format!("Failed to update user's notes: {}", e))),
+      Ok(result) => Ok(result.modified_count == 1),
+      Err(e) => Err(Report::new(DatabaseError::UpdateError)
+        .attach_printable(format!("Failed to update nonce of user '{}': {}", username, e))),
     }
+  }
 
-    let sent = self.send_message(&message);
-
-    Ok(sent.await.expect("msg sent"))
+  // Keys
+  async fn get_key_history(&self, username: &str) -> Result<Vec<KeyRecord>, DatabaseError> {
+    let options = FindOptions::builder().sort(doc! { "valid_until": 1 }).build();
+    self.key_history_collection.find(doc! { "username": username }, options).await
   }
 
-  //auth & challenges:  NOT IN USE DURING MVP
-  pub async fn authenticate_user(
-    &self,
-    username: &str,
-    signature_hex: &str,
-    challenge_id: &str,
-  ) -> Result<bool, DatabaseError> {
-    let challenge = match self.get_challenge(Some(challenge_id), username).await {
-      Ok(c) => c,
-      Err(e) => return Err(Report::new(DatabaseError::FetchError)
-        .attach_printable(format!("Failed to get challenge: {}", e))),
+  async fn replace_key(&self, retired: &KeyRecord, new_key_type: KeyType, new_pubkey: &str) -> Result<bool, DatabaseError> {
+    let mut session = match self.client.start_session(None).await {
+      Ok(session) => session,
+      Err(e) => return Err(Report::new(DatabaseError::UpdateError)
+        .attach_printable(format!("Failed to start session: {}", e))),
     };
 
-    let user_doc = match self.users.find_one(doc! {"username": username}, None).await {
-      Ok(Some(doc)) => doc,
-      Ok(None) => return Err(Report::new(DatabaseError::AuthenticationError)
-          .attach_printable("User not found")),
-      Err(e) => return Err(Report::new(DatabaseError::FetchError)
-        .attach_printable(format!("Failed to fetch user: {}", e))),
-    };
+    let mut attempt = 0;
+    loop {
+      attempt += 1;
+      if let Err(e) = session.start_transaction(None).await {
+        return Err(Report::new(DatabaseError::UpdateError)
+          .attach_printable(format!("Failed to start transaction: {}", e)));
+      }
 
-    let public_key_str = match user_doc.get_str("pubkey") {
-      Ok(key) => key,
-      Err(e) => return Err(Report::new(DatabaseError::ConversionError)
-        .attach_printable(format!("Failed to get pubkey: {}", e))),
-    };
+      let result = match self.swap_key(&mut session, retired, new_key_type, new_pubkey).await {
+        Ok(true) => self.commit_transaction(&mut session).await.map(|_| true),
+        Ok(false) => session.abort_transaction().await.map(|_| false),
+        Err(e) => {
+          if let Err(abort_err) = session.abort_transaction().await {
+            log::error!("Error aborting key rotation: {:?}", abort_err);
+          }
+          Err(e)
+        }
+      };
 
-    let public_key_bytes = match hex::decode(public_key_str) {
-      Ok(bytes) => bytes,
-      Err(e) => return Err(Report::new(DatabaseError::ConversionError)
-        .attach_printable(format!("Failed to decode pubkey: {}", e))),
-    };
+      match result {
+        Ok(swapped) => return Ok(swapped),
+        Err(e) if e.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempt < MAX_TRANSACTION_ATTEMPTS => {
+          log::warn!("Retrying key rotation after transient error: {}", e);
+        },
+        Err(e) => return Err(Report::new(DatabaseError::UpdateError)
+          .attach_printable(format!("Failed to rotate key of user '{}': {}", retired.username, e))),
+      }
+    }
+  }
 
-    let public_key = match PublicKey::from_bytes(&public_key_bytes) {
-      Ok(key) => key,
-      Err(e) => return Err(Report::new(DatabaseError::ConversionError)
-        .attach_printable(format!("Failed to create PublicKey: {}", e))),
-    };
+  // Moderation
+  async fn list_flagged_users(&self) -> Result<Vec<User>, DatabaseError> {
+    let options = FindOptions::builder().sort(doc! { "username": 1 }).build();
+    self.users_collection.find(doc! { "has_double_spent": true }, options).await
+  }
 
-    let signature_bytes = match hex::decode(signature_hex) {
-      Ok(bytes) => bytes,
-      Err(e) => return Err(Report::new(DatabaseError::ConversionError)
-        .attach_printable(format!("Failed to decode signature: {}", e))),
-    };
+  async fn get_flags(&self, username: &str) -> Result<Vec<DoubleSpendFlag>, DatabaseError> {
+    let options = FindOptions::builder().sort(doc! { "flagged_at": 1 }).build();
+    self.flags_collection.find(doc! { "username": username }, options).await
+  }
 
-    let signature = match Signature::from_bytes(&signature_bytes) {
-      Ok(sig) => sig,
-      Err(e) => return Err(Report::new(DatabaseError::ConversionError)
-        .attach_printable(format!("Failed to create Signature: {}", e))),
+  async fn moderate(&self, entry: &AuditEntry) -> Result<bool, DatabaseError> {
+    let mut session = match self.client.start_session(None).await {
+      Ok(session) => session,
+      Err(e) => return Err(Report::new(DatabaseError::UpdateError)
+        .attach_printable(format!("Failed to start session: {}", e))),
     };
 
-    let is_valid = public_key.verify_strict(&challenge, &signature).is_ok();
-    Ok(is_valid)
-  }
+    let mut attempt = 0;
+    let applied = loop {
+      attempt += 1;
+      if let Err(e) = session.start_transaction(None).await {
+        return Err(Report::new(DatabaseError::UpdateError)
+          .attach_printable(format!("Failed to start transaction: {}", e)));
+      }
 
-  pub fn insert_session(&self, session_id: String, username: String) {
-    self.sessions.write().unwrap().insert(session_id, username);
-  }
+      let result = match self.apply_moderation(&mut session, entry).await {
+        Ok(Some(true)) => self.commit_transaction(&mut session).await.map(|_| Some(true)),
+        Ok(skipped) => session.abort_transaction().await.map(|_| skipped),
+        Err(e) => {
+          if let Err(abort_err) = session.abort_transaction().await {
+            log::error!("Error aborting moderation: {:?}", abort_err);
+          }
+          Err(e)
+        }
+      };
 
-  pub async fn get_challenge(
-    &self,
-    challenge_id: Option<&str>,
-    username: &str,
-  ) -> Result<Vec<u8>, DatabaseError> {
-    if let Some(challenge_id) = challenge_id {
-      match self.challenges.find_one(
-        doc! {"challenge_id": challenge_id, "expires_at": { "$gt": Utc::now() }},
-        None
-      ).await {
-        Ok(Some(doc)) => {
-          let challenge = self.document_to_challenge(doc);
-          return Ok(challenge.challenge_id.as_bytes().to_vec())
+      match result {
+        Ok(applied) => break applied,
+        Err(e) if e.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempt < MAX_TRANSACTION_ATTEMPTS => {
+          log::warn!("Retrying moderation after transient error: {}", e);
         },
-        Ok(None) => {},
-        Err(e) => return Err(Report::new(DatabaseError::FetchError)
-         .attach_printable(format!("Failed to fetch challenge: {}", e))),
+        Err(e) => return Err(Report::new(DatabaseError::UpdateError)
+          .attach_printable(format!("Failed to {} user '{}': {}", entry.action.as_str(), entry.username, e))),
       }
+    };
+
+    match applied {
+      Some(applied) => Ok(applied),
+      None => Err(Report::new(DatabaseError::NotFoundError)
+        .attach_printable(format!("User '{}' not found", entry.username))),
     }
+  }
 
-    let challenge_id: String = rand::thread_rng()
-      .sample_iter(&Alphanumeric)
-      .take(32)
-      .map(char::from)
-      .collect();
+  async fn get_audit_log(&self) -> Result<Vec<AuditEntry>, DatabaseError> {
+    let options = FindOptions::builder().sort(doc! { "at": 1, "_id": 1 }).build();
+    self.audit_log_collection.find(doc! {}, options).await
+  }
+}
 
-    let new_challenge = ChallengeSchema {
-      challenge_id: challenge_id.clone(),
-      user_id: username.to_owned(),
-      created_at: self.get_current_timestamp(),
-      expires_at: self.get_current_timestamp() + 300,
-    };
+#[async_trait]
+impl SessionStore for IOUServiceDB {
+  async fn create_session(&self, username: &str) -> Result<Session, DatabaseError> {
+    let session = Session::new(username, self.auth.session_ttl);
+    self.sessions_collection.insert(&SessionDocument::from(&session)).await?;
+    Ok(session)
+  }
 
-    match self.challenges_collection.insert_one(new_challenge, None).await {
-      Ok(_) => Ok(challenge_id.as_bytes().to_vec()),
-      Err(e) => Err(Report::new(DatabaseError::InsertError)
-        .attach_printable(format!("Failed to insert new challenge: {}", e))),
-    }
+  async fn get_session(&self, token: &str) -> Result<Option<Session>, DatabaseError> {
+    // The TTL monitor only runs once a minute, so expiry is checked here as well.
+    let filter = doc! { "token": token, "expires_at": { "$gt": bson::DateTime::now() } };
+    Ok(self.sessions_collection.find_one(filter).await?.map(Session::from))
   }
 
-  fn document_to_challenge(&self, doc: Document) -> ChallengeSchema {
-    let challenge = ChallengeSchema {
-      challenge_id: doc.get_str("challenge_id").ok().map(|s| s.to_owned()).unwrap(),
-      user_id: doc.get_str("user_id").ok().map(|s| s.to_owned()).unwrap(),
-      created_at: doc.get_i64("created_at").ok().unwrap(),
-      expires_at: doc.get_i64("expires_at").ok().unwrap(),
-    };
+  async fn list_sessions(&self, username: &str) -> Result<Vec<Session>, DatabaseError> {
+    let filter = doc! { "username": username, "expires_at": { "$gt": bson::DateTime::now() } };
+    let find_options = FindOptions::builder().sort(doc! { "created_at": 1 }).build();
+    let sessions = self.sessions_collection.find(filter, find_options).await?;
+    Ok(sessions.into_iter().map(Session::from).collect())
+  }
 
-    challenge
+  async fn revoke_session(&self, username: &str, id: &str) -> Result<bool, DatabaseError> {
+    match self.sessions_collection.collection().delete_one(doc! { "session_id": id, "username": username }, None).await {
+      Ok(result) => Ok(result.deleted_count > 0),
+      Err(e) => Err(Report::new(DatabaseError::UpdateError)
+        .attach_printable(format!("Failed to revoke session: {}", e))),
+    }
   }
-}
\ No newline at end of file
+}
@@ -0,0 +1,116 @@
+use utoipa::{
+  openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+  Modify, OpenApi,
+};
+use crate::crypto::KeyType;
+use crate::session::Session;
+use crate::routes::{
+  error::{ErrorCode, ErrorResponse},
+  response::{
+    HealthResponse,
+    InclusionProof,
+    MessageSingleResponse,
+    NonMembershipProof,
+    NoteHistoryResponse,
+    NoteResponse,
+    NullifierResponseData,
+    NullifierRoot,
+    NullifierVerification,
+    SpendResponse,
+    UserSingleResponse
+  },
+  schema::{
+    AdminAction, AuditEntry, AuthData, Betrayal, BetrayalNoteRequest, CreateUserSchema, DoubleSpendFlag, FlagStatus, KeyRecord, MessageRequestSchema, MessageSchema, ModerationRequest, NoteHistoryRequest, NoteHistorySaved, NoteNullifierSchema, NoteRequest, NoteSchema, NullifierHistoryRequest, NullifierRequest, NullifierState, NullifierStatus, RevokeSessionRequest, RotateKeyRequest, SaveNoteHistoryRequestSchema, SpendOutcome, User, UserIdentifier, UserRequest, UsernameRequest
+  },
+  admin, betrayals, health, messages, notes, nullifier, users,
+};
+
+/// OpenAPI 3 document of every route in `app`, served at `/openapi.json`.
+///
+/// Several `GET` routes take a JSON body. Most OpenAPI tooling shows the body
+/// but some HTTP clients refuse to send one with `GET`.
+#[derive(OpenApi)]
+#[openapi(
+  info(title = "IOU service", description = "IOU note storage with double-spending prevention"),
+  paths(
+    health::healthz,
+    health::readyz,
+    health::metrics,
+    users::get_user,
+    users::create_user,
+    users::create_and_send_challenge,
+    users::verify_challenge,
+    users::validate_session,
+    users::logout,
+    users::list_sessions,
+    users::revoke_session,
+    users::rotate_key,
+    users::key_history,
+    nullifier::verify_nullifier,
+    nullifier::nullifier_history,
+    nullifier::nullifier_root,
+    nullifier::nullifier_proof,
+    nullifier::nullifier_absence,
+    nullifier::store_nullifier,
+    nullifier::spend,
+    betrayals::user_betrayals,
+    betrayals::note_betrayals,
+    notes::save_note,
+    notes::get_notes,
+    messages::send_message,
+    messages::read_user_messages,
+    notes::create_and_transfer_note_history,
+    notes::get_user_note_history,
+    admin::list_flagged_users,
+    admin::flag_evidence,
+    admin::clear_flag,
+    admin::confirm_flag,
+    admin::suspend_user,
+    admin::restore_user,
+    admin::audit_log,
+  ),
+  components(schemas(
+    ErrorCode, ErrorResponse,
+    HealthResponse, InclusionProof, MessageSingleResponse, NonMembershipProof, NoteHistoryResponse, NoteResponse, NullifierResponseData, NullifierRoot, NullifierVerification, SpendResponse, UserSingleResponse,
+    Session,
+    AdminAction, AuditEntry, AuthData, Betrayal, BetrayalNoteRequest, CreateUserSchema, DoubleSpendFlag, FlagStatus, KeyRecord, MessageRequestSchema, MessageSchema, ModerationRequest, NoteHistoryRequest, NoteHistorySaved, NoteNullifierSchema, NoteRequest, NoteSchema, NullifierHistoryRequest, NullifierRequest, NullifierState, NullifierStatus, RevokeSessionRequest, RotateKeyRequest, SaveNoteHistoryRequestSchema, SpendOutcome, User, UserIdentifier, UserRequest, UsernameRequest,
+    KeyType,
+  )),
+  modifiers(&SessionAuth),
+  tags(
+    (name = "health", description = "Liveness and readiness probes"),
+    (name = "users", description = "User accounts"),
+    (name = "auth", description = "Challenge based login"),
+    (name = "notes", description = "Notes and note transfers"),
+    (name = "messages", description = "Messages between users"),
+    (name = "nullifiers", description = "Double-spend detection"),
+    (name = "admin", description = "Review of double-spend flags and user moderation, for users in `auth.admins`"),
+  )
+)]
+pub struct ApiDoc;
+
+/// Declares the `session` bearer scheme and the `signature` scheme of signed
+/// requests, used by routes that take an `AuthUser`.
+struct SessionAuth;
+
+impl Modify for SessionAuth {
+  fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+    let components = openapi.components.get_or_insert_with(Default::default);
+    components.add_security_scheme(
+      "session",
+      SecurityScheme::Http(
+        HttpBuilder::new()
+          .scheme(HttpAuthScheme::Bearer)
+          .description(Some("Session id returned by /get_session"))
+          .build(),
+      ),
+    );
+    components.add_security_scheme(
+      "signature",
+      SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+        "X-IOU-Signature",
+        "Signature over \"<nonce>\\n<path>\\n<canonical JSON body>\", sent with X-IOU-User and X-IOU-Nonce",
+      ))),
+    );
+  }
+}
@@ -0,0 +1,200 @@
+use std::{
+  collections::{BTreeMap, HashMap},
+  fmt::Write,
+  net::SocketAddr,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+use axum::{
+  extract::{ConnectInfo, Request},
+  http::header::RETRY_AFTER,
+  middleware::Next,
+  response::{IntoResponse, Response},
+};
+use crate::config::{Quota, RateLimitConfig};
+use crate::routes::{error::{ApiError, ErrorCode}, extract::session_id, signed::SignedBy};
+use crate::store::Store;
+
+// Past this many buckets the full ones are dropped, a full bucket is the same as none.
+const MAX_BUCKETS: usize = 10_000;
+
+// Route name of the `default` quota, shared by every route without its own.
+const DEFAULT_ROUTE: &str = "*";
+
+/// What a bucket is keyed by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ClientKind {
+  Ip,
+  User,
+}
+
+impl ClientKind {
+  pub fn as_str(self) -> &'static str {
+    match self {
+      ClientKind::Ip => "ip",
+      ClientKind::User => "user",
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct BucketKey {
+  route: String,
+  kind: ClientKind,
+  client: String,
+}
+
+#[derive(Debug)]
+struct Bucket {
+  tokens: f64,
+  updated: Instant,
+  quota: Quota,
+}
+
+impl Bucket {
+  fn refill(&mut self, now: Instant) {
+    let refilled = now.duration_since(self.updated).as_secs_f64() * self.quota.per_second();
+    self.tokens = (self.tokens + refilled).min(self.quota.burst as f64);
+    self.updated = now;
+  }
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+  buckets: HashMap<BucketKey, Bucket>,
+  // by route and client kind
+  rejections: BTreeMap<(String, ClientKind), u64>,
+}
+
+/// Token buckets per route for every client IP and authenticated user.
+///
+/// Buckets live in process, so each replica enforces the quotas on its own.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+  config: Arc<RateLimitConfig>,
+  state: Arc<Mutex<LimiterState>>,
+}
+
+impl RateLimiter {
+  pub fn new(config: RateLimitConfig) -> Self {
+    Self {
+      config: Arc::new(config),
+      state: Arc::default(),
+    }
+  }
+
+  // The route `path` is charged to and its quota, `None` when it is unlimited.
+  fn quota(&self, path: &str) -> Option<(&str, Quota)> {
+    if !self.config.enabled {
+      return None;
+    }
+    match self.config.routes.get_key_value(path) {
+      Some((route, quota)) => Some((route.as_str(), *quota)),
+      None => self.config.default.map(|quota| (DEFAULT_ROUTE, quota)),
+    }
+  }
+
+  /// Takes a token from the bucket of `client` for `path`. When the bucket is empty
+  /// the rejection is counted and the time until the next token is returned.
+  pub fn check(&self, path: &str, kind: ClientKind, client: &str) -> Result<(), Duration> {
+    let Some((route, quota)) = self.quota(path) else {
+      return Ok(());
+    };
+    let now = Instant::now();
+    let mut state = self.state.lock().unwrap();
+
+    if state.buckets.len() >= MAX_BUCKETS {
+      state.buckets.retain(|_, bucket| {
+        bucket.refill(now);
+        bucket.tokens < bucket.quota.burst as f64
+      });
+    }
+
+    let key = BucketKey { route: route.to_owned(), kind, client: client.to_owned() };
+    let bucket = state.buckets.entry(key)
+      .or_insert(Bucket { tokens: quota.burst as f64, updated: now, quota });
+    bucket.quota = quota;
+    bucket.refill(now);
+    if bucket.tokens >= 1.0 {
+      bucket.tokens -= 1.0;
+      return Ok(());
+    }
+
+    let wait = Duration::from_secs_f64((1.0 - bucket.tokens) / quota.per_second());
+    *state.rejections.entry((route.to_owned(), kind)).or_default() += 1;
+    Err(wait)
+  }
+
+  /// Rejected requests by route and client kind.
+  pub fn rejections(&self) -> Vec<(String, ClientKind, u64)> {
+    self.state.lock().unwrap().rejections.iter()
+      .map(|((route, kind), count)| (route.clone(), *kind, *count))
+      .collect()
+  }
+
+  /// The rejection counters in the Prometheus text format.
+  pub fn render_metrics(&self) -> String {
+    let mut out = String::new();
+    out.push_str("# HELP iou_rate_limit_rejections_total Requests rejected with 429 by the rate limiter.\n");
+    out.push_str("# TYPE iou_rate_limit_rejections_total counter\n");
+    for (route, kind, count) in self.rejections() {
+      let _ = writeln!(out, "iou_rate_limit_rejections_total{{route=\"{}\",client=\"{}\"}} {}", route, kind.as_str(), count);
+    }
+    out
+  }
+
+  // The address the request came from, or the last `X-Forwarded-For` entry when trusted.
+  // That one is appended by our proxy, the entries before it are whatever the client sent.
+  fn client_ip(&self, request: &Request) -> String {
+    if self.config.trust_forwarded_for {
+      let forwarded = request.headers().get_all("x-forwarded-for").iter().last()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .map(str::trim)
+        .filter(|ip| !ip.is_empty());
+      if let Some(ip) = forwarded {
+        return ip.to_owned();
+      }
+    }
+
+    match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+      Some(ConnectInfo(addr)) => addr.ip().to_string(),
+      None => "unknown".to_owned(),
+    }
+  }
+}
+
+fn too_many_requests(wait: Duration) -> Response {
+  let seconds = wait.as_secs_f64().ceil().max(1.0) as u64;
+  let error = ApiError::new(ErrorCode::RateLimited, format!("Too many requests, retry in {} seconds", seconds));
+  ([(RETRY_AFTER, seconds.to_string())], error).into_response()
+}
+
+/// Charges every request to its client IP, before any other work is done for it.
+pub async fn limit_by_ip(limiter: RateLimiter, request: Request, next: Next) -> Response {
+  let ip = limiter.client_ip(&request);
+  match limiter.check(request.uri().path(), ClientKind::Ip, &ip) {
+    Ok(()) => next.run(request).await,
+    Err(wait) => too_many_requests(wait),
+  }
+}
+
+/// Charges requests with a valid session or signature to their user as well. Runs after
+/// `verify_signed_request`, so a claimed username alone never uses up someone's quota.
+pub async fn limit_by_user(limiter: RateLimiter, request: Request, next: Next) -> Response {
+  let username = match request.extensions().get::<SignedBy>() {
+    Some(SignedBy(username)) => Some(username.clone()),
+    None => match (session_id(request.headers()), request.extensions().get::<Store>()) {
+      // Lookup failures are left to the handler to report.
+      (Some(token), Some(db)) => db.get_session(token).await.ok().flatten().map(|session| session.username),
+      _ => None,
+    },
+  };
+
+  if let Some(username) = username {
+    if let Err(wait) = limiter.check(request.uri().path(), ClientKind::User, &username) {
+      return too_many_requests(wait);
+    }
+  }
+  next.run(request).await
+}
@@ -0,0 +1,126 @@
+use bson::{doc, Bson, Document};
+use futures::stream::TryStreamExt;
+use mongodb::{error::{ErrorKind, WriteFailure}, options::{FindOneAndUpdateOptions, FindOptions}, ClientSession, Collection};
+use serde::{de::DeserializeOwned, Serialize};
+use error_stack::{Report, Result};
+use crate::routes::error::DatabaseError;
+
+/// Typed access to a MongoDB collection.
+///
+/// Reads go through raw documents that are decoded one at a time, so a
+/// malformed document surfaces as `DatabaseError::ConversionError` naming its
+/// `_id` instead of panicking or failing the whole cursor.
+#[derive(Debug, Clone)]
+pub struct Repository<T: Send + Sync> {
+  collection: Collection<T>,
+}
+
+/// Decodes `doc` into `T`, reporting the `_id` of the document on failure.
+pub fn decode<T: DeserializeOwned>(doc: Document) -> Result<T, DatabaseError> {
+  let id = doc.get("_id").map(Bson::to_string).unwrap_or_else(|| "<missing _id>".to_owned());
+
+  bson::from_document(doc).map_err(|e| Report::new(DatabaseError::ConversionError)
+    .attach_printable(format!("Failed to decode document {}: {}", id, e)))
+}
+
+// Server error code for a unique index violation.
+const DUPLICATE_KEY: i32 = 11000;
+
+/// Whether `e` was caused by a unique index rejecting a write.
+pub fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+  match e.kind.as_ref() {
+    ErrorKind::Write(WriteFailure::WriteError(write_error)) => write_error.code == DUPLICATE_KEY,
+    ErrorKind::Command(command_error) => command_error.code == DUPLICATE_KEY,
+    _ => false,
+  }
+}
+
+impl<T> Repository<T>
+where
+  T: Serialize + DeserializeOwned + Unpin + Send + Sync,
+{
+  pub fn new(collection: Collection<T>) -> Self {
+    Self { collection }
+  }
+
+  /// The underlying typed handle, for writes that need options or a session.
+  pub fn collection(&self) -> &Collection<T> {
+    &self.collection
+  }
+
+  fn documents(&self) -> Collection<Document> {
+    self.collection.clone_with_type()
+  }
+
+  /// Inserts `item` and returns it as stored, including the generated `_id`.
+  pub async fn insert(&self, item: &T) -> Result<T, DatabaseError> {
+    let inserted_id = match self.collection.insert_one(item, None).await {
+      Ok(result) => result.inserted_id,
+      Err(e) => {
+        let context = if is_duplicate_key(&e) { DatabaseError::DuplicateKeyError } else { DatabaseError::InsertError };
+        return Err(Report::new(context)
+          .attach_printable(format!("Failed to insert into '{}': {}", self.collection.name(), e)));
+      },
+    };
+
+    match self.find_one(doc! { "_id": inserted_id.clone() }).await? {
+      Some(item) => Ok(item),
+      None => Err(Report::new(DatabaseError::NotFoundError)
+        .attach_printable(format!("Document {} not found after insertion", inserted_id))),
+    }
+  }
+
+  pub async fn find_one(&self, filter: Document) -> Result<Option<T>, DatabaseError> {
+    match self.documents().find_one(filter, None).await {
+      Ok(Some(doc)) => decode(doc).map(Some),
+      Ok(None) => Ok(None),
+      Err(e) => Err(Report::new(DatabaseError::FetchError)
+        .attach_printable(format!("Failed to fetch from '{}': {}", self.collection.name(), e))),
+    }
+  }
+
+  pub async fn find_one_with_session(&self, filter: Document, session: &mut ClientSession) -> Result<Option<T>, DatabaseError> {
+    match self.documents().find_one_with_session(filter, None, session).await {
+      Ok(Some(doc)) => decode(doc).map(Some),
+      Ok(None) => Ok(None),
+      Err(e) => Err(Report::new(DatabaseError::FetchError)
+        .attach_printable(format!("Failed to fetch from '{}': {}", self.collection.name(), e))),
+    }
+  }
+
+  /// Updates the first document matching `filter`, returning it as `options` asks,
+  /// before the update by default. `None` when nothing matched.
+  pub async fn find_one_and_update(
+    &self,
+    filter: Document,
+    update: Document,
+    options: impl Into<Option<FindOneAndUpdateOptions>>,
+  ) -> Result<Option<T>, DatabaseError> {
+    match self.documents().find_one_and_update(filter, update, options).await {
+      Ok(Some(doc)) => decode(doc).map(Some),
+      Ok(None) => Ok(None),
+      Err(e) => Err(Report::new(DatabaseError::UpdateError)
+        .attach_printable(format!("Failed to update '{}': {}", self.collection.name(), e))),
+    }
+  }
+
+  pub async fn find(&self, filter: Document, options: impl Into<Option<FindOptions>>) -> Result<Vec<T>, DatabaseError> {
+    let mut cursor = match self.documents().find(filter, options).await {
+      Ok(cur) => cur,
+      Err(e) => return Err(Report::new(DatabaseError::FetchError)
+        .attach_printable(format!("Failed to fetch from '{}': {}", self.collection.name(), e))),
+    };
+
+    let mut items = Vec::new();
+    loop {
+      match cursor.try_next().await {
+        Ok(Some(doc)) => items.push(decode(doc)?),
+        Ok(None) => break,
+        Err(e) => return Err(Report::new(DatabaseError::FetchError)
+          .attach_printable(format!("Failed to fetch next document from '{}': {}", self.collection.name(), e))),
+      }
+    }
+
+    Ok(items)
+  }
+}
@@ -0,0 +1,188 @@
+use axum::{extract::Extension, Json};
+use crate::store::{audit_entry, Store};
+use super::{
+  schema::{AdminAction, AuditEntry, DoubleSpendFlag, ModerationRequest, User, UsernameRequest},
+  error::{ApiError, ErrorCode},
+  extract::ApiJson,
+  policy::AdminUser,
+};
+
+#[utoipa::path(
+  get,
+  path = "/admin/flagged_users",
+  tag = "admin",
+  security(("session" = []), ("signature" = [])),
+  responses(
+    (status = 200, description = "Users flagged for double spending, by username", body = [User]),
+    (status = 401, description = "`UNAUTHORIZED`, missing or invalid session", body = ErrorResponse),
+    (status = 403, description = "`FORBIDDEN`, the user is not an admin", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
+#[axum::debug_handler]
+pub async fn list_flagged_users(Extension(db): Extension<Store>, _admin: AdminUser) -> Result<Json<Vec<User>>, ApiError> {
+  Ok(Json(db.list_flagged_users().await?))
+}
+
+/// The evidence behind a user's flag, one entry per detected double spend.
+#[utoipa::path(
+  get,
+  path = "/admin/flag_evidence",
+  tag = "admin",
+  request_body = UsernameRequest,
+  security(("session" = []), ("signature" = [])),
+  responses(
+    (status = 200, description = "Every flag raised on the user, oldest first", body = [DoubleSpendFlag]),
+    (status = 400, description = "Malformed request body", body = ErrorResponse),
+    (status = 401, description = "`UNAUTHORIZED`, missing or invalid session", body = ErrorResponse),
+    (status = 403, description = "`FORBIDDEN`, the user is not an admin", body = ErrorResponse),
+    (status = 404, description = "`USER_NOT_FOUND`", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
+#[axum::debug_handler]
+pub async fn flag_evidence(
+  Extension(db): Extension<Store>,
+  _admin: AdminUser,
+  ApiJson(payload): ApiJson<UsernameRequest>,
+) -> Result<Json<Vec<DoubleSpendFlag>>, ApiError> {
+  db.get_user_with_username(&payload.username).await
+    .map_err(|err| ApiError::from(err).on_not_found(ErrorCode::UserNotFound))?;
+  Ok(Json(db.get_flags(&payload.username).await?))
+}
+
+/// Lifts the user's flag after review. Their flags are kept as `cleared`.
+#[utoipa::path(
+  post,
+  path = "/admin/clear_flag",
+  tag = "admin",
+  request_body = ModerationRequest,
+  security(("session" = []), ("signature" = [])),
+  responses(
+    (status = 200, description = "The user after the change", body = User),
+    (status = 400, description = "Malformed request body or an empty `reason`", body = ErrorResponse),
+    (status = 401, description = "`UNAUTHORIZED`, missing or invalid session", body = ErrorResponse),
+    (status = 403, description = "`FORBIDDEN`, the user is not an admin", body = ErrorResponse),
+    (status = 404, description = "`USER_NOT_FOUND`", body = ErrorResponse),
+    (status = 409, description = "`NOT_FLAGGED`, the user has no flag to review", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
+#[axum::debug_handler]
+pub async fn clear_flag(
+  Extension(db): Extension<Store>,
+  admin: AdminUser,
+  ApiJson(payload): ApiJson<ModerationRequest>,
+) -> Result<Json<User>, ApiError> {
+  moderate(&db, &admin, AdminAction::ClearFlag, &payload).await
+}
+
+/// Upholds the user's flag after review. The user stays flagged.
+#[utoipa::path(
+  post,
+  path = "/admin/confirm_flag",
+  tag = "admin",
+  request_body = ModerationRequest,
+  security(("session" = []), ("signature" = [])),
+  responses(
+    (status = 200, description = "The user after the change", body = User),
+    (status = 400, description = "Malformed request body or an empty `reason`", body = ErrorResponse),
+    (status = 401, description = "`UNAUTHORIZED`, missing or invalid session", body = ErrorResponse),
+    (status = 403, description = "`FORBIDDEN`, the user is not an admin", body = ErrorResponse),
+    (status = 404, description = "`USER_NOT_FOUND`", body = ErrorResponse),
+    (status = 409, description = "`NOT_FLAGGED`, the user has no flag to review", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
+#[axum::debug_handler]
+pub async fn confirm_flag(
+  Extension(db): Extension<Store>,
+  admin: AdminUser,
+  ApiJson(payload): ApiJson<ModerationRequest>,
+) -> Result<Json<User>, ApiError> {
+  moderate(&db, &admin, AdminAction::ConfirmFlag, &payload).await
+}
+
+/// Locks the user out of every authenticated route.
+#[utoipa::path(
+  post,
+  path = "/admin/suspend_user",
+  tag = "admin",
+  request_body = ModerationRequest,
+  security(("session" = []), ("signature" = [])),
+  responses(
+    (status = 200, description = "The user after the change", body = User),
+    (status = 400, description = "Malformed request body or an empty `reason`", body = ErrorResponse),
+    (status = 401, description = "`UNAUTHORIZED`, missing or invalid session", body = ErrorResponse),
+    (status = 403, description = "`FORBIDDEN`, the user is not an admin", body = ErrorResponse),
+    (status = 404, description = "`USER_NOT_FOUND`", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
+#[axum::debug_handler]
+pub async fn suspend_user(
+  Extension(db): Extension<Store>,
+  admin: AdminUser,
+  ApiJson(payload): ApiJson<ModerationRequest>,
+) -> Result<Json<User>, ApiError> {
+  moderate(&db, &admin, AdminAction::Suspend, &payload).await
+}
+
+/// Lifts a suspension.
+#[utoipa::path(
+  post,
+  path = "/admin/restore_user",
+  tag = "admin",
+  request_body = ModerationRequest,
+  security(("session" = []), ("signature" = [])),
+  responses(
+    (status = 200, description = "The user after the change", body = User),
+    (status = 400, description = "Malformed request body or an empty `reason`", body = ErrorResponse),
+    (status = 401, description = "`UNAUTHORIZED`, missing or invalid session", body = ErrorResponse),
+    (status = 403, description = "`FORBIDDEN`, the user is not an admin", body = ErrorResponse),
+    (status = 404, description = "`USER_NOT_FOUND`", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
+#[axum::debug_handler]
+pub async fn restore_user(
+  Extension(db): Extension<Store>,
+  admin: AdminUser,
+  ApiJson(payload): ApiJson<ModerationRequest>,
+) -> Result<Json<User>, ApiError> {
+  moderate(&db, &admin, AdminAction::Restore, &payload).await
+}
+
+#[utoipa::path(
+  get,
+  path = "/admin/audit_log",
+  tag = "admin",
+  security(("session" = []), ("signature" = [])),
+  responses(
+    (status = 200, description = "Every admin action, oldest first", body = [AuditEntry]),
+    (status = 401, description = "`UNAUTHORIZED`, missing or invalid session", body = ErrorResponse),
+    (status = 403, description = "`FORBIDDEN`, the user is not an admin", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
+#[axum::debug_handler]
+pub async fn audit_log(Extension(db): Extension<Store>, _admin: AdminUser) -> Result<Json<Vec<AuditEntry>>, ApiError> {
+  Ok(Json(db.get_audit_log().await?))
+}
+
+// Applies `action` and writes it to the audit trail, then returns the updated user.
+async fn moderate(db: &Store, AdminUser(admin): &AdminUser, action: AdminAction, payload: &ModerationRequest) -> Result<Json<User>, ApiError> {
+  if payload.reason.trim().is_empty() {
+    return Err(ApiError::new(ErrorCode::InvalidRequest, "reason must not be empty"));
+  }
+
+  let entry = audit_entry(&admin.username, action, &payload.username, payload.reason.trim());
+  let applied = db.moderate(&entry).await
+    .map_err(|err| ApiError::from(err).on_not_found(ErrorCode::UserNotFound))?;
+  if !applied {
+    return Err(ApiError::new(ErrorCode::NotFlagged, format!("User '{}' is not flagged", payload.username)));
+  }
+
+  let user = db.get_user_with_username(&payload.username).await?.user;
+  Ok(Json(user))
+}
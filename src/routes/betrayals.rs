@@ -0,0 +1,50 @@
+use axum::{extract::Extension, Json};
+use crate::store::Store;
+use super::{
+  schema::{Betrayal, BetrayalNoteRequest, UsernameRequest},
+  error::{ApiError, ErrorCode},
+  extract::ApiJson,
+};
+
+/// Every double spend of a user, so wallets can show why a counterparty was flagged.
+#[utoipa::path(
+  get,
+  path = "/betrayals_for_user",
+  tag = "nullifiers",
+  request_body = UsernameRequest,
+  responses(
+    (status = 200, description = "Betrayals of the user, oldest first", body = [Betrayal]),
+    (status = 400, description = "Malformed request body", body = ErrorResponse),
+    (status = 404, description = "`USER_NOT_FOUND`", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
+#[axum::debug_handler]
+pub async fn user_betrayals(
+  Extension(db): Extension<Store>,
+  ApiJson(payload): ApiJson<UsernameRequest>,
+) -> Result<Json<Vec<Betrayal>>, ApiError> {
+  db.get_user_with_username(&payload.username).await
+    .map_err(|err| ApiError::from(err).on_not_found(ErrorCode::UserNotFound))?;
+  Ok(Json(db.get_user_betrayals(&payload.username).await?))
+}
+
+/// Every double spend involving a note, in either of the two spends.
+#[utoipa::path(
+  get,
+  path = "/betrayals_for_note",
+  tag = "nullifiers",
+  request_body = BetrayalNoteRequest,
+  responses(
+    (status = 200, description = "Betrayals involving the note, oldest first, empty when there are none", body = [Betrayal]),
+    (status = 400, description = "Malformed request body", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
+#[axum::debug_handler]
+pub async fn note_betrayals(
+  Extension(db): Extension<Store>,
+  ApiJson(payload): ApiJson<BetrayalNoteRequest>,
+) -> Result<Json<Vec<Betrayal>>, ApiError> {
+  Ok(Json(db.get_note_betrayals(&payload.note).await?))
+}
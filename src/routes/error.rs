@@ -1,51 +1,12 @@
-use thiserror::Error;
-use mongodb::bson;
-use error_stack::Result;
-use std::error::Error;
-
-#[derive(Debug)]
-pub struct ConvertToDocError;
-impl std::fmt::Display for ConvertToDocError {
-    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        fmt.write_str("Convert to document failed")
-    }
-}
-
-impl Error for ConvertToDocError {}
-
-#[derive(Debug)]
-pub struct ConvertFromDocError;
-
-impl std::fmt::Display for ConvertFromDocError {
-    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        fmt.write_str("Convert from document failed")
-    }
-}
-
-impl Error for ConvertFromDocError {}
-
-
-#[derive(Debug)]
-pub struct InsertDocumentError;
-
-impl std::fmt::Display for InsertDocumentError {
-    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        fmt.write_str("Inserting Document failed")
-    }
-}
-
-impl std::error::Error for InsertDocumentError {}
-
-#[derive(Debug)]
-pub struct CreateUserError;
-
-impl std::fmt::Display for CreateUserError {
-    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        fmt.write_str("Create User failed, username not unique.")
-    }
-}
-
-impl std::error::Error for CreateUserError {}
+use axum::{
+    extract::rejection::JsonRejection,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use error_stack::Report;
+use serde::Serialize;
+use utoipa::ToSchema;
 
 #[derive(Debug)]
 pub enum DatabaseError {
@@ -56,6 +17,14 @@ pub enum DatabaseError {
     IndexCreationError,
     AuthenticationError,
     NotFoundError,
+    MigrationError,
+    DuplicateKeyError,
+    ChallengeReplayError,
+    ChallengeUserMismatchError,
+    NonceReplayError,
+    ConcurrentUpdateError,
+    NullifierOwnerMismatchError,
+    LeafContentionError,
 }
 
 impl std::fmt::Display for DatabaseError {
@@ -68,45 +37,140 @@ impl std::fmt::Display for DatabaseError {
             DatabaseError::IndexCreationError => write!(f, "Failed to create index"),
             DatabaseError::AuthenticationError => write!(f, "Authentication failed"),
             DatabaseError::NotFoundError => write!(f, "Data not found"),
+            DatabaseError::MigrationError => write!(f, "Failed to run migrations"),
+            DatabaseError::DuplicateKeyError => write!(f, "Data already exists"),
+            DatabaseError::ChallengeReplayError => write!(f, "Challenge is unknown, expired or already used"),
+            DatabaseError::ChallengeUserMismatchError => write!(f, "Challenge was issued to another user"),
+            DatabaseError::NonceReplayError => write!(f, "Nonce must be greater than the last accepted nonce"),
+            DatabaseError::ConcurrentUpdateError => write!(f, "Data was changed by another request"),
+            DatabaseError::NullifierOwnerMismatchError => write!(f, "Nullifier was spent by another user"),
+            DatabaseError::LeafContentionError => write!(f, "Too many concurrent spends, retry"),
         }
     }
 }
 
 impl std::error::Error for DatabaseError {}
 
-#[derive(Error, Debug)]
-pub enum MyError {
-    #[error("MongoDB error: {0}")]
-    MongoError(#[from] mongodb::error::Error),
+/// Machine-readable error codes. They are part of the API, so existing codes
+/// must never be renamed or reused for a different condition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    InvalidRequest,
+    PayloadTooLarge,
+    Unauthorized,
+    Forbidden,
+    AccountSuspended,
+    NotFound,
+    UserNotFound,
+    Conflict,
+    UsernameTaken,
+    NullifierConflict,
+    NoteInvalid,
+    NullifierMismatch,
+    ChallengeInvalid,
+    ChallengeUserMismatch,
+    NonceInvalid,
+    KeyReused,
+    NotFlagged,
+    RateLimited,
+    Busy,
+    InternalError,
+}
 
-    #[error("MongoDB query error: {0}")]
-    MongoQueryError(String),
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidRequest | ErrorCode::NoteInvalid => StatusCode::BAD_REQUEST,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::Unauthorized | ErrorCode::ChallengeInvalid | ErrorCode::ChallengeUserMismatch | ErrorCode::NonceInvalid => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden | ErrorCode::AccountSuspended => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound | ErrorCode::UserNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::Conflict | ErrorCode::UsernameTaken | ErrorCode::NullifierConflict | ErrorCode::KeyReused | ErrorCode::NotFlagged => StatusCode::CONFLICT,
+            ErrorCode::NullifierMismatch => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::Busy => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
 
-    #[error("BSON serialization error: {0}")]
-    BsonSerializationError(#[from] bson::ser::Error),
+/// Body of every error response.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    pub error: String,
+}
 
-    #[error("BSON deserialization error: {0}")]
-    BsonDeserializationError(#[from] bson::de::Error),
+/// The error type returned by every handler. The HTTP status is derived from
+/// the code, so the two can never disagree.
+#[derive(Debug)]
+pub struct ApiError {
+    code: ErrorCode,
+    message: String,
+}
 
-    #[error("Data validation error: {0}")]
-    ValidationError(String),
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
 
-    #[error("Invalid ID: {0}")]
-    InvalidIdError(String),
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
 
-    #[error("Not found: {0}")]
-    NotFoundError(String),
+    /// Replaces the generic `NOT_FOUND` code with a more specific one.
+    pub fn on_not_found(self, code: ErrorCode) -> Self {
+        match self.code {
+            ErrorCode::NotFound => Self { code, ..self },
+            _ => self,
+        }
+    }
 
-    #[error("Unauthorized: {0}")]
-    UnauthorizedError(String),
+    /// Replaces the generic `CONFLICT` code with a more specific one.
+    pub fn on_conflict(self, code: ErrorCode) -> Self {
+        match self.code {
+            ErrorCode::Conflict => Self { code, ..self },
+            _ => self,
+        }
+    }
+}
 
-    #[error("Internal server error: {0}")]
-    InternalServerError(String),
+impl From<Report<DatabaseError>> for ApiError {
+    fn from(report: Report<DatabaseError>) -> Self {
+        let code = match report.current_context() {
+            DatabaseError::NotFoundError => ErrorCode::NotFound,
+            DatabaseError::DuplicateKeyError | DatabaseError::ConcurrentUpdateError => ErrorCode::Conflict,
+            DatabaseError::AuthenticationError => ErrorCode::Unauthorized,
+            DatabaseError::ChallengeReplayError => ErrorCode::ChallengeInvalid,
+            DatabaseError::ChallengeUserMismatchError => ErrorCode::ChallengeUserMismatch,
+            DatabaseError::NonceReplayError => ErrorCode::NonceInvalid,
+            DatabaseError::NullifierOwnerMismatchError => ErrorCode::Forbidden,
+            DatabaseError::LeafContentionError => ErrorCode::Busy,
+            _ => ErrorCode::InternalError,
+        };
+
+        // The report can name collections and queries, keep those out of responses.
+        if code == ErrorCode::InternalError {
+            log::error!("Request failed: {:?}", report);
+        }
+        Self::new(code, report.current_context().to_string())
+    }
 }
 
-pub type MyResult<T> = Result<T, MyError>;
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        let code = match rejection.status() {
+            StatusCode::PAYLOAD_TOO_LARGE => ErrorCode::PayloadTooLarge,
+            _ => ErrorCode::InvalidRequest,
+        };
+        Self::new(code, rejection.body_text())
+    }
+}
 
-#[derive(serde::Serialize)]
-pub struct ErrorResponse {
-   pub error: String,
-}
\ No newline at end of file
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorResponse { code: self.code, error: self.message };
+        (self.code.status(), Json(body)).into_response()
+    }
+}
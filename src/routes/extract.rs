@@ -0,0 +1,69 @@
+use axum::{
+  async_trait,
+  extract::{FromRequest, FromRequestParts},
+  http::{header::AUTHORIZATION, request::Parts, HeaderMap},
+  Extension,
+};
+use crate::{session::Session, store::Store};
+use super::{error::{ApiError, ErrorCode}, schema::User, signed::SignedBy};
+
+/// `Json` body extractor that reports malformed bodies as an `ApiError`.
+#[derive(Debug, FromRequest)]
+#[from_request(via(axum::Json), rejection(ApiError))]
+pub struct ApiJson<T>(pub T);
+
+/// The user behind the session in `Authorization: Bearer <session id>`, or
+/// behind the signature of a signed request (see `signed`).
+///
+/// Handlers taking an `AuthUser` reject requests without a valid session or
+/// signature with `401 UNAUTHORIZED`, and suspended users with `403 ACCOUNT_SUSPENDED`.
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+  pub username: String,
+  pub user: User,
+  /// `None` for signed requests.
+  pub session: Option<Session>,
+}
+
+pub(crate) fn session_id(headers: &HeaderMap) -> Option<&str> {
+  headers.get(AUTHORIZATION)?
+    .to_str().ok()?
+    .strip_prefix("Bearer ")
+    .map(str::trim)
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AuthUser {
+  type Rejection = ApiError;
+
+  async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+    let Extension(db) = Extension::<Store>::from_request_parts(parts, state).await
+      .map_err(|_| ApiError::new(ErrorCode::InternalError, "Storage is not configured"))?;
+
+    // The signature was already checked by `verify_signed_request`.
+    if let Some(SignedBy(username)) = parts.extensions.get::<SignedBy>().cloned() {
+      let user = load_user(&db, &username).await?;
+      return Ok(AuthUser { username, user, session: None });
+    }
+
+    let session_id = session_id(&parts.headers)
+      .ok_or_else(|| ApiError::new(ErrorCode::Unauthorized, "Missing session, send Authorization: Bearer <session id>"))?;
+    let session = db.get_session(session_id).await?
+      .ok_or_else(|| ApiError::new(ErrorCode::Unauthorized, "Invalid or expired session"))?;
+
+    let user = load_user(&db, &session.username).await?;
+    Ok(AuthUser { username: session.username.clone(), user, session: Some(session) })
+  }
+}
+
+// A session can outlive its user, treat that as an invalid session.
+async fn load_user(db: &Store, username: &str) -> Result<User, ApiError> {
+  match db.get_user_with_username(username).await {
+    Ok(response) if response.user.suspended == Some(true) => Err(ApiError::new(ErrorCode::AccountSuspended, "Account is suspended")),
+    Ok(response) => Ok(response.user),
+    Err(err) => match ApiError::from(err) {
+      err if err.code() == ErrorCode::NotFound => Err(ApiError::new(ErrorCode::Unauthorized, "Invalid or expired session")),
+      err => Err(err),
+    },
+  }
+}
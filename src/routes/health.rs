@@ -0,0 +1,66 @@
+use axum::{extract::Extension, http::{header::CONTENT_TYPE, StatusCode}, Json, response::IntoResponse};
+use crate::{rate_limit::RateLimiter, store::Store};
+use super::response::HealthResponse;
+
+/// Liveness probe, answers as long as the process is serving requests.
+#[utoipa::path(
+  get,
+  path = "/healthz",
+  tag = "health",
+  responses((status = 200, description = "The process is up", body = HealthResponse))
+)]
+#[axum::debug_handler]
+pub async fn healthz() -> impl IntoResponse {
+  (StatusCode::OK, Json(HealthResponse { status: "ok", reason: None }))
+}
+
+/// Readiness probe, answers 200 once the database is reachable and every
+/// migration has been applied, 503 otherwise.
+#[utoipa::path(
+  get,
+  path = "/readyz",
+  tag = "health",
+  responses(
+    (status = 200, description = "Ready to serve requests", body = HealthResponse),
+    (status = 503, description = "Database unreachable or migrations pending", body = HealthResponse),
+  )
+)]
+#[axum::debug_handler]
+pub async fn readyz(Extension(db): Extension<Store>) -> impl IntoResponse {
+  let unavailable = |reason: String| (StatusCode::SERVICE_UNAVAILABLE, Json(HealthResponse {
+    status: "unavailable",
+    reason: Some(reason),
+  }));
+
+  if let Err(err) = db.ping().await {
+    return unavailable(format!("Database unreachable: {}", err));
+  }
+
+  match db.migration_status().await {
+    Ok(migrations) => {
+      let pending: Vec<String> = migrations.iter()
+        .filter(|migration| !migration.applied)
+        .map(|migration| migration.version.to_string())
+        .collect();
+      if !pending.is_empty() {
+        return unavailable(format!("Migrations not applied: {}", pending.join(", ")));
+      }
+    },
+    Err(err) => return unavailable(format!("Failed to check migrations: {}", err)),
+  }
+
+  (StatusCode::OK, Json(HealthResponse { status: "ok", reason: None }))
+}
+
+/// Service metrics in the Prometheus text format, currently the requests
+/// rejected by the rate limiter by route and client kind.
+#[utoipa::path(
+  get,
+  path = "/metrics",
+  tag = "health",
+  responses((status = 200, description = "Prometheus metrics", body = String, content_type = "text/plain"))
+)]
+#[axum::debug_handler]
+pub async fn metrics(Extension(limiter): Extension<RateLimiter>) -> impl IntoResponse {
+  ([(CONTENT_TYPE, "text/plain; version=0.0.4")], limiter.render_metrics())
+}
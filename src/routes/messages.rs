@@ -1,47 +1,58 @@
 use axum::{
   Extension,
   Json,
-  http::StatusCode,
-  response::IntoResponse
 };
-use crate::mongo::IOUServiceDB;
-use crate::routes::schema::{MessageRequestSchema, UsernameRequest};
-use super::error::ErrorResponse;
+use crate::store::Store;
+use crate::routes::schema::{MessageRequestSchema, MessageSchema, UsernameRequest};
+use super::{error::ApiError, policy::Authorized, response::MessageSingleResponse};
 
+#[utoipa::path(
+  get,
+  path = "/read_messages",
+  tag = "messages",
+  request_body = UsernameRequest,
+  security(("session" = []), ("signature" = [])),
+  responses(
+    (status = 200, description = "Unread messages, oldest first. They are marked read.", body = [MessageSchema]),
+    (status = 400, description = "Malformed request body", body = ErrorResponse),
+    (status = 401, description = "`UNAUTHORIZED`, missing or invalid session", body = ErrorResponse),
+    (status = 403, description = "`FORBIDDEN`, `username` is not the authenticated user", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
 #[axum::debug_handler]
 pub async fn read_user_messages(
-  Extension(db): Extension<IOUServiceDB>,
-  Json(payload): Json<UsernameRequest>
-) -> impl IntoResponse {
-  match db.get_unread_messages(&payload.username).await {
-    Ok(messages) => {
-      println!("{:#?}", messages);
-      (StatusCode::OK, Json(messages)).into_response()
-    },
-    Err(err) => {
-      let error_message = format!("Failed to get user messages: {}", err);
-      (StatusCode::INTERNAL_SERVER_ERROR, Json(ErrorResponse { error: error_message })).into_response()
-    }
-  }
+  Extension(db): Extension<Store>,
+  Authorized { body: payload, .. }: Authorized<UsernameRequest>
+) -> Result<Json<Vec<MessageSchema>>, ApiError> {
+  let messages = db.get_unread_messages(&payload.username).await?;
+  log::info!("Read {} unread messages of {}", messages.len(), payload.username);
+  Ok(Json(messages))
 }
 
+#[utoipa::path(
+  post,
+  path = "/send_message",
+  tag = "messages",
+  request_body = MessageRequestSchema,
+  security(("session" = []), ("signature" = [])),
+  responses(
+    (status = 200, description = "The stored message", body = MessageSingleResponse),
+    (status = 400, description = "Malformed request body", body = ErrorResponse),
+    (status = 401, description = "`UNAUTHORIZED`, missing or invalid session", body = ErrorResponse),
+    (status = 403, description = "`FORBIDDEN`, `sender` is not the authenticated user", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
 #[axum::debug_handler]
-pub async fn send_message(Extension(db): Extension<IOUServiceDB>, Json(payload): Json<MessageRequestSchema>) -> impl IntoResponse {
+pub async fn send_message(Extension(db): Extension<Store>, Authorized { body: payload, .. }: Authorized<MessageRequestSchema>) -> Result<Json<MessageSingleResponse>, ApiError> {
   let message = MessageRequestSchema {
     recipient: payload.recipient,
     sender: payload.sender,
     message: payload.message,
     attachment_id: payload.attachment_id
   };
-  println!("{:#?}", message);
-  match db.send_message(&message).await {
-    Ok(message_response) => {
-      println!("{:#?}", message_response);
-      (StatusCode::OK, Json(message_response)).into_response()
-    },
-    Err(err) => {
-      let error_message = format!("Failed to get send message: {}", err);
-      (StatusCode::INTERNAL_SERVER_ERROR, Json(ErrorResponse { error: error_message })).into_response()
-    }
-  }
-}
\ No newline at end of file
+  let message_response = db.send_message(&message).await?;
+  log::info!("Stored message from {} to {}", message.sender, message.recipient);
+  Ok(Json(message_response))
+}
@@ -1,7 +1,13 @@
 pub mod notes;
 pub mod messages;
 pub mod nullifier;
+pub mod betrayals;
 pub mod users;
 pub mod schema;
 pub mod response;
-pub mod error;
\ No newline at end of file
+pub mod error;
+pub mod health;
+pub mod extract;
+pub mod signed;
+pub mod policy;
+pub mod admin;
@@ -1,30 +1,66 @@
-use axum::{extract::Extension, http::StatusCode, Json};
-use crate::mongo::IOUServiceDB;
+use axum::{extract::Extension, Json};
+use crate::crypto::note_commitment;
+use crate::store::Store;
 use crate::routes::schema::NoteSchema;
 use super::{response::{MessageSingleResponse, NoteResponse}, schema::{
   NoteHistoryRequest, NoteHistorySaved, NoteRequest, SaveNoteRequestSchema, UsernameRequest
 }};
-use crate::routes::error::DatabaseError;
-use mongodb::bson::doc;
+use super::{error::{ApiError, ErrorCode}, extract::{ApiJson, AuthUser}, policy::{Authorized, Principal}};
 
+#[utoipa::path(
+  get,
+  path = "/get_notes",
+  tag = "notes",
+  request_body = NoteRequest,
+  responses(
+    (status = 200, description = "Notes owned by the public key, `blind` is empty unless the session or signature is the owner's", body = [NoteSchema]),
+    (status = 400, description = "Malformed request body", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
 #[axum::debug_handler]
 pub async fn get_notes(
-    Extension(db): Extension<IOUServiceDB>,
-    Json(payload): Json<NoteRequest>
-) -> Result<Json<Vec<NoteSchema>>, StatusCode> {
-    let notes = db.get_user_notes(&payload.owner_pub_key).await
-      .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Extension(db): Extension<Store>,
+    auth: Option<AuthUser>,
+    ApiJson(payload): ApiJson<NoteRequest>
+) -> Result<Json<Vec<NoteSchema>>, ApiError> {
+    let notes = db.get_user_notes(&payload.owner_pub_key).await?;
 
-    let filtered_notes = match payload.step {
+    let filtered_notes: Vec<NoteSchema> = match payload.step {
       Some(step) => notes.into_iter().filter(|note| note.step == step).collect(),
       None => notes,
     };
 
+    // A blind opens the note's commitment and nullifier, only the owner gets it.
+    let is_owner = auth.is_some_and(|auth| auth.authorize("owner_pub_key", Principal::Pubkey(&payload.owner_pub_key)).is_ok());
+    let filtered_notes = match is_owner {
+      true => filtered_notes,
+      false => filtered_notes.into_iter().map(|note| NoteSchema { blind: String::new(), ..note }).collect(),
+    };
+
     Ok(Json(filtered_notes))
 }
 
+#[utoipa::path(
+  post,
+  path = "/store_note",
+  tag = "notes",
+  request_body = NoteSchema,
+  security(("session" = []), ("signature" = [])),
+  responses(
+    (status = 200, description = "The stored note", body = NoteResponse),
+    (status = 400, description = "Malformed request body, or `NOTE_INVALID` when a field is not a field element", body = ErrorResponse),
+    (status = 401, description = "`UNAUTHORIZED`, missing or invalid session", body = ErrorResponse),
+    (status = 403, description = "`FORBIDDEN`, `owner` is not the authenticated user's pubkey", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
 #[axum::debug_handler]
-pub async fn save_note(Extension(db): Extension<IOUServiceDB>, Json(payload): Json<NoteSchema>) -> Result<Json<NoteResponse>, StatusCode> {
+pub async fn save_note(Extension(db): Extension<Store>, Authorized { body: payload, .. }: Authorized<NoteSchema>) -> Result<Json<NoteResponse>, ApiError> {
+  // A note that can't be committed to could never be spent.
+  if note_commitment(&payload).is_err() {
+    return Err(ApiError::new(ErrorCode::NoteInvalid, "note fields must be field elements, 0x prefixed hex or decimal, and owner a hex public key"));
+  }
   let new_note = SaveNoteRequestSchema {
     owner: payload.owner,
     asset_hash: payload.asset_hash,
@@ -34,53 +70,65 @@ pub async fn save_note(Extension(db): Extension<IOUServiceDB>, Json(payload): Js
     out_index: payload.out_index,
     blind: payload.blind
   };
-  println!("{:#?}", new_note);
-  match db.store_note(&new_note).await {
-    Ok(note_response) => {
-      println!("{:#?}", note_response);
-      Ok(Json(note_response))
-    }
-    Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
-  }
+  let note_response = db.store_note(&new_note).await?;
+  // Without `blind`, it is what keeps the note private.
+  log::info!("Stored note of {} at step {}, value {}", new_note.owner, new_note.step, new_note.value);
+  Ok(Json(note_response))
 }
 
+#[utoipa::path(
+  post,
+  path = "/create_and_transfer_note_history",
+  tag = "notes",
+  request_body = NoteHistoryRequest,
+  security(("session" = []), ("signature" = [])),
+  responses(
+    (status = 200, description = "The message announcing the transfer to the recipient", body = MessageSingleResponse),
+    (status = 400, description = "Malformed request body", body = ErrorResponse),
+    (status = 401, description = "`UNAUTHORIZED`, missing or invalid session", body = ErrorResponse),
+    (status = 403, description = "`FORBIDDEN`, `owner_username` is not the authenticated user", body = ErrorResponse),
+    (status = 404, description = "`USER_NOT_FOUND`, there is no user `recipient_username`, nothing was stored", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
 pub async fn create_and_transfer_note_history(
-  Extension(db): Extension<IOUServiceDB>,
-  Json(payload): Json<NoteHistoryRequest>
-) -> Result<Json<MessageSingleResponse>, StatusCode> {
+  Extension(db): Extension<Store>,
+  Authorized { body: payload, .. }: Authorized<NoteHistoryRequest>
+) -> Result<Json<MessageSingleResponse>, ApiError> {
   // Call the database function
   let res: MessageSingleResponse = db.create_and_transfer_note_history(
-      payload.owner_username,  // This converts Option<String> to Option<&str>
+      payload.owner_username,
       &payload.recipient_username,
       payload.note_history,
       payload.message,
   )
   .await
-  .map_err(|e| {
-      eprintln!("Failed to transfer note: {:?}", e);
-      StatusCode::INTERNAL_SERVER_ERROR
-  })?;
+  .map_err(|err| ApiError::from(err).on_not_found(ErrorCode::UserNotFound))?;
 
   Ok(Json(res))
 }
 
+#[utoipa::path(
+  get,
+  path = "/get_note_history_for_user",
+  tag = "notes",
+  request_body = UsernameRequest,
+  security(("session" = []), ("signature" = [])),
+  responses(
+    (status = 200, description = "Note histories received by the user. They are removed from the inbox.", body = [NoteHistorySaved]),
+    (status = 400, description = "Malformed request body", body = ErrorResponse),
+    (status = 401, description = "`UNAUTHORIZED`, missing or invalid session", body = ErrorResponse),
+    (status = 403, description = "`FORBIDDEN`, `username` is not the authenticated user", body = ErrorResponse),
+    (status = 404, description = "`USER_NOT_FOUND`", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
 pub async fn get_user_note_history(
-  Extension(db): Extension<IOUServiceDB>,
-  Json(payload): Json<UsernameRequest>
-) -> Result<Json<Vec<NoteHistorySaved>>, StatusCode> {
+  Extension(db): Extension<Store>,
+  Authorized { body: payload, .. }: Authorized<UsernameRequest>
+) -> Result<Json<Vec<NoteHistorySaved>>, ApiError> {
   match db.get_note_history_for_user(payload.username).await {
-    Ok(notes) => {
-        let response: Vec<NoteHistorySaved> = notes.into_iter()
-            .map(|note| NoteHistorySaved::from(note))
-            .collect();
-        Ok(Json(response))
-    },
-    Err(e) => {
-        eprintln!("Failed to get note history: {:?}", e);
-        match e.current_context() {
-            DatabaseError::NotFoundError => Err(StatusCode::NOT_FOUND),
-            _ => Err(StatusCode::INTERNAL_SERVER_ERROR),
-        }
-    }
+    Ok(notes) => Ok(Json(notes)),
+    Err(e) => Err(ApiError::from(e).on_not_found(ErrorCode::UserNotFound)),
+  }
 }
-}
\ No newline at end of file
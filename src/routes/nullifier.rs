@@ -1,36 +1,236 @@
-use axum::{extract::Extension, http::StatusCode, Json, response::IntoResponse};
-use crate::mongo::IOUServiceDB;
-use super::{response::NullifierResponse, schema::{NoteNullifierSchema,  NullifierRequest}};
-use mongodb::bson::doc;
-use super::error::ErrorResponse;
+use axum::{extract::Extension, http::StatusCode, Json};
+use crate::crypto::{field_to_hex, note_nullifier, parse_field};
+use crate::merkle::NullifierTree;
+use crate::store::Store;
+use super::{response::{InclusionProof, NonMembershipProof, NullifierResponseData, NullifierRoot, NullifierVerification, SpendResponse}, schema::{NoteNullifierSchema, NoteSchema, NullifierHistoryRequest, NullifierRequest, NullifierStatus}};
+use super::{error::{ApiError, ErrorCode}, extract::{ApiJson, AuthUser}, policy::{Authorized, Principal}};
 
+/// Whether a nullifier was spent, and in which state. Read only, to flag a double
+/// spend submit it to `/spend`.
+#[utoipa::path(
+  get,
+  path = "/verify_nullifier",
+  tag = "nullifiers",
+  request_body = NullifierRequest,
+  responses(
+    (status = 200, description = "`spent_in_this_state`, with the stored record", body = NullifierVerification),
+    (status = 400, description = "Malformed request body", body = ErrorResponse),
+    (status = 404, description = "`unknown`, the nullifier was never spent", body = NullifierVerification),
+    (status = 409, description = "`spent_in_another_state`, with the stored record", body = NullifierVerification),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
 #[axum::debug_handler]
 pub async fn verify_nullifier(
-  Extension(db): Extension<IOUServiceDB>,
-  Json(payload): Json<NullifierRequest>
-) -> Result<Json<NullifierResponse>, String> {
-  let nullifier_response = db.get_nullifier(&payload.nullifier, &payload.state).await;
-  Ok(Json(nullifier_response))
+  Extension(db): Extension<Store>,
+  ApiJson(payload): ApiJson<NullifierRequest>
+) -> Result<(StatusCode, Json<NullifierVerification>), ApiError> {
+  let verification = db.verify_nullifier(&canonical(&payload.nullifier), &payload.state).await?;
+  let status = match verification.status {
+    NullifierStatus::Unknown => StatusCode::NOT_FOUND,
+    NullifierStatus::SpentInThisState => StatusCode::OK,
+    NullifierStatus::SpentInAnotherState => StatusCode::CONFLICT,
+  };
+  Ok((status, Json(verification)))
+}
+
+/// A stored nullifier with its state vector, ordered by step.
+#[utoipa::path(
+  get,
+  path = "/nullifier_history",
+  tag = "nullifiers",
+  request_body = NullifierHistoryRequest,
+  responses(
+    (status = 200, description = "The stored nullifier", body = NoteNullifierSchema),
+    (status = 400, description = "Malformed request body", body = ErrorResponse),
+    (status = 404, description = "`NOT_FOUND`, the nullifier was never spent", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
+#[axum::debug_handler]
+pub async fn nullifier_history(
+  Extension(db): Extension<Store>,
+  ApiJson(payload): ApiJson<NullifierHistoryRequest>
+) -> Result<Json<NoteNullifierSchema>, ApiError> {
+  match db.find_nullifier(&canonical(&payload.nullifier)).await? {
+    Some(stored) => Ok(Json(stored)),
+    None => Err(ApiError::new(ErrorCode::NotFound, format!("Nullifier '{}' not found", payload.nullifier))),
+  }
+}
+
+/// Root of the Merkle tree over every stored nullifier, in the order they were stored.
+#[utoipa::path(
+  get,
+  path = "/nullifier_root",
+  tag = "nullifiers",
+  responses(
+    (status = 200, description = "The current root and number of leaves", body = NullifierRoot),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
+#[axum::debug_handler]
+pub async fn nullifier_root(
+  Extension(db): Extension<Store>,
+  Extension(tree): Extension<NullifierTree>,
+) -> Result<Json<NullifierRoot>, ApiError> {
+  tree.sync(&db).await?;
+  Ok(Json(tree.root()))
+}
+
+/// Inclusion proof of a stored nullifier against the current root.
+#[utoipa::path(
+  get,
+  path = "/nullifier_proof",
+  tag = "nullifiers",
+  request_body = NullifierHistoryRequest,
+  responses(
+    (status = 200, description = "Merkle path of the nullifier", body = InclusionProof),
+    (status = 400, description = "Malformed request body", body = ErrorResponse),
+    (status = 404, description = "`NOT_FOUND`, the nullifier was never spent", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
+#[axum::debug_handler]
+pub async fn nullifier_proof(
+  Extension(db): Extension<Store>,
+  Extension(tree): Extension<NullifierTree>,
+  ApiJson(payload): ApiJson<NullifierHistoryRequest>
+) -> Result<Json<InclusionProof>, ApiError> {
+  tree.sync(&db).await?;
+  match tree.prove(&canonical(&payload.nullifier))? {
+    Some(proof) => Ok(Json(proof)),
+    None => Err(ApiError::new(ErrorCode::NotFound, format!("Nullifier '{}' not found", payload.nullifier))),
+  }
+}
+
+/// Signed proof that a nullifier is not stored, against the current sparse root.
+#[utoipa::path(
+  get,
+  path = "/nullifier_absence",
+  tag = "nullifiers",
+  request_body = NullifierHistoryRequest,
+  responses(
+    (status = 200, description = "Sparse Merkle path to the nullifier's empty leaf, signed by the server", body = NonMembershipProof),
+    (status = 400, description = "Malformed request body", body = ErrorResponse),
+    (status = 409, description = "`NULLIFIER_CONFLICT`, the nullifier was spent, see `/nullifier_proof`", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
+#[axum::debug_handler]
+pub async fn nullifier_absence(
+  Extension(db): Extension<Store>,
+  Extension(tree): Extension<NullifierTree>,
+  ApiJson(payload): ApiJson<NullifierHistoryRequest>
+) -> Result<Json<NonMembershipProof>, ApiError> {
+  tree.sync(&db).await?;
+  match tree.prove_absence(&canonical(&payload.nullifier))? {
+    Some(proof) => Ok(Json(proof)),
+    None => Err(ApiError::new(ErrorCode::NullifierConflict, format!("Nullifier '{}' was spent", payload.nullifier))),
+  }
 }
 
+#[utoipa::path(
+  post,
+  path = "/store_nullifier",
+  tag = "nullifiers",
+  request_body = NoteNullifierSchema,
+  security(("session" = []), ("signature" = [])),
+  responses(
+    (status = 200, description = "The stored nullifier", body = NullifierResponseData),
+    (status = 400, description = "Malformed request body, or `NOTE_INVALID` when `note` is not a note with field element fields", body = ErrorResponse),
+    (status = 401, description = "`UNAUTHORIZED`, missing or invalid session", body = ErrorResponse),
+    (status = 403, description = "`FORBIDDEN`, `owner` is not the authenticated user, or `note.owner` not their pubkey", body = ErrorResponse),
+    (status = 409, description = "`NULLIFIER_CONFLICT`", body = ErrorResponse),
+    (status = 422, description = "`NULLIFIER_MISMATCH`, `nullifier` is not the nullifier of `note`", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+    (status = 503, description = "`BUSY`, too many concurrent spends, nothing was stored", body = ErrorResponse),
+  )
+)]
 #[axum::debug_handler]
-pub async fn store_nullifier(Extension(db): Extension<IOUServiceDB>, Json(payload): Json<NoteNullifierSchema>) -> impl IntoResponse {
+pub async fn store_nullifier(
+  Extension(db): Extension<Store>,
+  Extension(tree): Extension<NullifierTree>,
+  Authorized { auth, body: payload }: Authorized<NoteNullifierSchema>
+) -> Result<Json<NullifierResponseData>, ApiError> {
+  let payload = checked(&auth, payload)?;
   let new_nullifier = NoteNullifierSchema {
     nullifier: payload.nullifier,
     note: payload.note,
     step: payload.step,
     owner: payload.owner,
-    state: payload.state, 
+    state: payload.state,
+    // filled in by the store
+    states: Vec::new(),
   };
-  println!("{:#?}", new_nullifier);
   match db.store_nullifier(&new_nullifier).await {
     Ok(nullifier_res) => {
-      println!("{:#?}", nullifier_res);
-      (StatusCode::OK, Json(nullifier_res)).into_response()
+      log::info!("Stored nullifier {} of {} at step {}", new_nullifier.nullifier, new_nullifier.owner, new_nullifier.step);
+      catch_up(&tree, &db).await;
+      Ok(Json(nullifier_res))
     },
-    Err(err) => {
-      let nullifier_res_err = format!("Failed to get send message: {}", err);
-      (StatusCode::INTERNAL_SERVER_ERROR, Json(ErrorResponse { error: nullifier_res_err })).into_response()
-    }
+    Err(err) => Err(ApiError::from(err).on_conflict(ErrorCode::NullifierConflict)),
+  }
+}
+
+/// Checks and stores a nullifier in one step. Use this instead of `/verify_nullifier`
+/// followed by `/store_nullifier`, which lets two concurrent spends both pass the check.
+#[utoipa::path(
+  post,
+  path = "/spend",
+  tag = "nullifiers",
+  request_body = NoteNullifierSchema,
+  security(("session" = []), ("signature" = [])),
+  responses(
+    (status = 200, description = "`accepted` when stored, `duplicate_same_state` when already stored with this state, `conflicting_state` when already stored with another state, which flags the owner", body = SpendResponse),
+    (status = 400, description = "Malformed request body, or `NOTE_INVALID` when `note` is not a note with field element fields", body = ErrorResponse),
+    (status = 401, description = "`UNAUTHORIZED`, missing or invalid session", body = ErrorResponse),
+    (status = 403, description = "`FORBIDDEN`, `owner` is not the authenticated user or `note.owner` not their pubkey, or another user spent the nullifier first", body = ErrorResponse),
+    (status = 422, description = "`NULLIFIER_MISMATCH`, `nullifier` is not the nullifier of `note`", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+    (status = 503, description = "`BUSY`, too many concurrent spends, nothing was stored", body = ErrorResponse),
+  )
+)]
+#[axum::debug_handler]
+pub async fn spend(
+  Extension(db): Extension<Store>,
+  Extension(tree): Extension<NullifierTree>,
+  Authorized { auth, body: payload }: Authorized<NoteNullifierSchema>
+) -> Result<Json<SpendResponse>, ApiError> {
+  let outcome = db.spend_nullifier(&checked(&auth, payload)?).await?;
+  catch_up(&tree, &db).await;
+  Ok(Json(outcome))
+}
+
+// Adds the leaves of a spend that was just stored to the tree. The spend is committed by
+// then, so a failure only leaves the tree behind, and the next read catches it up.
+async fn catch_up(tree: &NullifierTree, db: &Store) {
+  if let Err(err) = tree.sync(db).await {
+    log::warn!("Nullifier tree not updated after a spend, catching up on the next read: {:?}", err);
   }
-}
\ No newline at end of file
+}
+
+// Recomputes the nullifier from the note a spend carries and rejects the spend unless
+// they match, and unless the note is owned by the authenticated user's key. The nullifier
+// is stored in its canonical spelling, so the same one given in decimal or hex can't be
+// spent twice.
+fn checked(auth: &AuthUser, mut spend: NoteNullifierSchema) -> Result<NoteNullifierSchema, ApiError> {
+  let note: NoteSchema = serde_json::from_str(&spend.note)
+    .map_err(|e| ApiError::new(ErrorCode::NoteInvalid, format!("note must be a note serialized as JSON: {}", e)))?;
+  auth.authorize("note.owner", Principal::Pubkey(&note.owner))?;
+  let nullifier = note_nullifier(&note)
+    .map_err(|_| ApiError::new(ErrorCode::NoteInvalid, "note fields must be field elements, 0x prefixed hex or decimal, and owner a hex public key"))?;
+
+  match parse_field(&spend.nullifier, "nullifier") {
+    Ok(submitted) if submitted == nullifier => {
+      spend.nullifier = field_to_hex(nullifier);
+      Ok(spend)
+    },
+    _ => Err(ApiError::new(ErrorCode::NullifierMismatch, "nullifier is not Poseidon(commitment, blind) of note")),
+  }
+}
+
+// Lookups accept any spelling of a nullifier. Nullifiers stored before they were
+// checked need not be field elements and are looked up as given.
+fn canonical(nullifier: &str) -> String {
+  parse_field(nullifier, "nullifier").map(field_to_hex).unwrap_or_else(|_| nullifier.to_owned())
+}
@@ -0,0 +1,125 @@
+use axum::{
+  async_trait,
+  extract::{FromRequest, FromRequestParts, Request},
+  http::request::Parts,
+  Extension,
+};
+use serde::de::DeserializeOwned;
+use crate::config::AuthConfig;
+use super::{
+  error::{ApiError, ErrorCode},
+  extract::{ApiJson, AuthUser},
+  schema::{MessageRequestSchema, NoteHistoryRequest, NoteNullifierSchema, NoteSchema, UsernameRequest},
+};
+
+/// Who a request body claims to act for.
+#[derive(Debug, Clone, Copy)]
+pub enum Principal<'a> {
+  Username(&'a str),
+  Pubkey(&'a str),
+}
+
+/// Request bodies that name the user they act for. Every rule tying a body
+/// field to the authenticated user lives in this file.
+pub trait ActsFor {
+  /// The name of the field naming the acting user, and its value.
+  fn acting_for(&self) -> (&'static str, Principal<'_>);
+}
+
+impl ActsFor for MessageRequestSchema {
+  fn acting_for(&self) -> (&'static str, Principal<'_>) {
+    ("sender", Principal::Username(&self.sender))
+  }
+}
+
+impl ActsFor for NoteHistoryRequest {
+  fn acting_for(&self) -> (&'static str, Principal<'_>) {
+    ("owner_username", Principal::Username(&self.owner_username))
+  }
+}
+
+// Notes are owned by a public key rather than a username.
+impl ActsFor for NoteSchema {
+  fn acting_for(&self) -> (&'static str, Principal<'_>) {
+    ("owner", Principal::Pubkey(&self.owner))
+  }
+}
+
+impl ActsFor for NoteNullifierSchema {
+  fn acting_for(&self) -> (&'static str, Principal<'_>) {
+    ("owner", Principal::Username(&self.owner))
+  }
+}
+
+impl ActsFor for UsernameRequest {
+  fn acting_for(&self) -> (&'static str, Principal<'_>) {
+    ("username", Principal::Username(&self.username))
+  }
+}
+
+impl AuthUser {
+  /// Rejects `principal` with `403 FORBIDDEN` unless it is the authenticated user.
+  pub fn authorize(&self, field: &str, principal: Principal<'_>) -> Result<(), ApiError> {
+    let allowed = match principal {
+      Principal::Username(username) => username == self.username,
+      Principal::Pubkey(pubkey) => self.user.pubkey.as_deref() == Some(pubkey),
+    };
+
+    match allowed {
+      true => Ok(()),
+      false => Err(ApiError::new(ErrorCode::Forbidden, format!("{} must be the authenticated user", field))),
+    }
+  }
+}
+
+/// A JSON body of type `T` acting for the authenticated user.
+///
+/// Rejects requests without a session or signature with `401 UNAUTHORIZED`
+/// and bodies acting for someone else with `403 FORBIDDEN`.
+#[derive(Debug)]
+pub struct Authorized<T> {
+  pub auth: AuthUser,
+  pub body: T,
+}
+
+#[async_trait]
+impl<S, T> FromRequest<S> for Authorized<T>
+where
+  S: Send + Sync,
+  T: DeserializeOwned + ActsFor,
+{
+  type Rejection = ApiError;
+
+  async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+    let (mut parts, body) = request.into_parts();
+    let auth = AuthUser::from_request_parts(&mut parts, state).await?;
+    let ApiJson(body) = ApiJson::<T>::from_request(Request::from_parts(parts, body), state).await?;
+
+    let (field, principal) = body.acting_for();
+    auth.authorize(field, principal)?;
+    Ok(Self { auth, body })
+  }
+}
+
+/// An authenticated user listed in `auth.admins`.
+///
+/// Rejects requests without a session or signature with `401 UNAUTHORIZED`
+/// and everyone else with `403 FORBIDDEN`.
+#[derive(Debug, Clone)]
+pub struct AdminUser(pub AuthUser);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AdminUser {
+  type Rejection = ApiError;
+
+  async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+    let auth = AuthUser::from_request_parts(parts, state).await?;
+    let Extension(config) = Extension::<AuthConfig>::from_request_parts(parts, state).await
+      .map_err(|_| ApiError::new(ErrorCode::InternalError, "Admins are not configured"))?;
+
+    match config.admins.contains(&auth.username) {
+      true => Ok(AdminUser(auth)),
+      false => Err(ApiError::new(ErrorCode::Forbidden, "Admin role required")),
+    }
+  }
+}
@@ -1,38 +1,89 @@
 use crate::routes::schema::User;
-use serde::Serialize;
-use crate::routes::schema::{MessageSchema, NoteNullifierSchema, NoteHistorySaved, NoteSchema};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::routes::schema::{MessageSchema, NoteNullifierSchema, NoteHistorySaved, NoteSchema, NullifierStatus, SpendOutcome};
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Clone, ToSchema)]
 pub struct UserSingleResponse {
     pub status: &'static str,
     pub user: User,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct MessageSingleResponse {
     pub status: &'static str,
     pub message: MessageSchema,
 }
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct NullifierResponseData {
     pub status: &'static str,
     pub nullifier: NoteNullifierSchema,
 }
-#[derive(Debug, Serialize)]
-pub enum NullifierResponse {
-  Ok(NoteNullifierSchema),
-  NotFound,
-  Error,
+/// Result of `/spend`. `nullifier` is the stored record, which differs from the
+/// submitted one on a `conflicting_state`.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SpendResponse {
+    pub outcome: SpendOutcome,
+    pub nullifier: NoteNullifierSchema,
+}
+/// Result of `/verify_nullifier`. `nullifier` is the stored record, `None` when `unknown`.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct NullifierVerification {
+    pub status: NullifierStatus,
+    pub nullifier: Option<NoteNullifierSchema>,
+}
+
+/// Roots of the append-only and the sparse nullifier trees over the first `size`
+/// stored nullifiers. `public_key` is the hex ed25519 key that signs non-membership proofs.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct NullifierRoot {
+    pub root: String,
+    pub sparse_root: String,
+    pub size: u64,
+    pub public_key: String,
+}
+
+/// Merkle path of `nullifier` to `root`. `leaf_sibling` is the neighbouring leaf digest,
+/// `path` the siblings of the nodes above it from the root down. Field elements are `0x`
+/// prefixed hex.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct InclusionProof {
+    pub nullifier: String,
+    pub leaf_index: u64,
+    pub root: String,
+    pub size: u64,
+    pub leaf_sibling: String,
+    pub path: Vec<String>,
 }
 
-#[derive(Debug, Serialize)]
+/// Path to the empty leaf of `nullifier` in the sparse tree with `root`, from the root
+/// down, `null` where the sibling is an empty subtree. `signature` is the server's
+/// ed25519 signature over the nullifier, root and size, see the README.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct NonMembershipProof {
+    pub nullifier: String,
+    pub root: String,
+    pub size: u64,
+    pub path: Vec<Option<String>>,
+    pub public_key: String,
+    pub signature: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
 pub struct NoteResponse {
     pub status: &'static str,
     pub note: NoteSchema,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct NoteHistoryResponse {
     pub status: &'static str,
     pub note_history: NoteHistorySaved,
 }
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct HealthResponse {
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
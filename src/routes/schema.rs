@@ -1,23 +1,37 @@
-use mongodb::bson::{doc, Bson};
+use mongodb::bson::{doc, oid::ObjectId, Bson};
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::crypto::KeyType;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct User {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
-    pub id: Option<String>,
+    #[schema(value_type = Option<Object>)]
+    pub id: Option<ObjectId>,
     pub has_double_spent: Option<bool>,
     pub nonce: Option<String>,
     pub username: Option<String>,
     pub pubkey: Option<String>,
-    pub messages: Option<Vec<String>>,
-    pub notes: Option<Vec<bson::oid::ObjectId>>,
+    /// Scheme of `pubkey`, absent for users created before key types existed (ed25519).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_type: Option<KeyType>,
+    /// Set by an admin, suspended users are refused on every authenticated route.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suspended: Option<bool>,
+    #[schema(value_type = Option<Vec<Object>>)]
+    pub messages: Option<Vec<ObjectId>>,
+    #[schema(value_type = Option<Vec<Object>>)]
+    pub notes: Option<Vec<ObjectId>>,
     pub address: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct CreateUserSchema {
     pub username: String,
     pub pubkey: String,
+    /// Scheme of `pubkey`, ed25519 when omitted.
+    #[serde(default)]
+    pub key_type: KeyType,
     pub nonce: String,
     pub address: String,
     pub messages: Vec<String>,
@@ -25,24 +39,24 @@ pub struct CreateUserSchema {
     pub has_double_spent: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct UserRequest {
   pub identifier: UserIdentifier,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub enum UserIdentifier {
     Username(String),
     Address(String),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct UsernameRequest {
   pub username: String,
 }
 
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 pub struct NoteSchema {
     pub(crate) asset_hash: String,
     pub(crate) owner: String,
@@ -51,10 +65,12 @@ pub struct NoteSchema {
     pub(crate) parent_note: String,
     pub(crate) out_index: String,
     pub(crate) blind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
     pub(crate) _id: Option<Bson>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 pub struct SaveNoteRequestSchema {
     pub(crate) asset_hash: String,
     pub(crate) owner: String,
@@ -65,68 +81,117 @@ pub struct SaveNoteRequestSchema {
     pub(crate) blind: String,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 pub struct SaveNoteHistoryRequestSchema {
     pub data: Vec<u8>,
     pub address: String,
     pub sender: String,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 pub struct NoteHistorySaved {
     pub sender: String,
+    #[serde(with = "serde_bytes")]
     pub data: Vec<u8>,
     pub address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
     pub _id: Option<Bson>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct NoteHistorySchema {
     note: NoteSchema,
     history: Vec<NoteSchema>,
     spent: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 pub struct MessageSchema {
     pub recipient: String,
     pub sender: String,
     pub message: String,
     pub timestamp: i64,
+    #[schema(value_type = Option<Object>)]
     pub attachment_id: Option<Bson>,
     pub read: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
     pub _id: Option<Bson>,
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct MessageRequestSchema {
     pub recipient: String,
     pub sender: String,
     pub message: String,
+    #[schema(value_type = Option<Object>)]
     pub attachment_id: Option<Bson>,
 }
 
-// We must add a future state vector
-#[derive(Debug, Deserialize, Serialize)]
+/// A spent nullifier. In requests `step` and `state` are those of the spend, in
+/// responses those of the spend that first stored the nullifier.
+#[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
 pub struct NoteNullifierSchema {
     pub nullifier: String,
     pub note: String, // Note structure serialized as JSON
     pub step: i32,
     pub owner: String, // Address serialized as JSON
     pub state: String,
+    /// Every state the nullifier was spent in, ordered by step. Kept by the server,
+    /// ignored in requests.
+    #[serde(default)]
+    pub states: Vec<NullifierState>,
 }
-#[derive(Debug, Deserialize, Serialize)]
+
+/// One entry of a nullifier's state vector.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, ToSchema)]
+pub struct NullifierState {
+    pub step: i32,
+    pub state: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct NullifierHistoryRequest {
+    pub nullifier: String,
+}
+/// How `/spend` treated a nullifier, judged against its whole state vector.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SpendOutcome {
+    /// The step and state were new and are now in the state vector.
+    Accepted,
+    /// The state vector already has this state at this step, e.g. a retried request.
+    DuplicateSameState,
+    /// The state vector has another state at this step, or this state at another
+    /// step. Nothing was written.
+    ConflictingState,
+}
+
+/// What `/verify_nullifier` knows about a nullifier and a state.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum NullifierStatus {
+    /// The nullifier was never spent.
+    Unknown,
+    /// The given state is in the nullifier's state vector.
+    SpentInThisState,
+    /// The nullifier was spent, but never in the given state.
+    SpentInAnotherState,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
 pub struct NullifierRequest {
     pub nullifier: String,
     pub state: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
 pub struct NoteRequest {
     pub owner_pub_key: String,
     pub step: Option<u32>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
 pub struct NoteHistoryRequest {
     pub owner_username: String,
     pub recipient_username: String,
@@ -134,7 +199,7 @@ pub struct NoteHistoryRequest {
     pub message: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct ChallengeSchema {
     pub challenge_id: String,
     pub user_id: String,
@@ -142,9 +207,144 @@ pub struct ChallengeSchema {
     pub expires_at: i64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct AuthData {
     pub username: String,
     pub signature_hex: String,
     pub challenge_id: String,
 }
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RevokeSessionRequest {
+    pub session_id: String,
+}
+
+/// A key of a user and the interval it was valid in. `valid_from` is `None` for
+/// the key the user was created with, `valid_until` is `None` for the current key.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct KeyRecord {
+    pub username: String,
+    pub pubkey: String,
+    pub key_type: KeyType,
+    pub valid_from: Option<i64>,
+    pub valid_until: Option<i64>,
+}
+
+/// Replaces the user's key with `new_pubkey`. `signature_hex` is made with the
+/// current key over `rotation_message`.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RotateKeyRequest {
+    pub username: String,
+    pub new_pubkey: String,
+    #[serde(default)]
+    pub new_key_type: KeyType,
+    pub signature_hex: String,
+}
+
+/// Review state of a double-spend flag.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum FlagStatus {
+    Open,
+    Confirmed,
+    Cleared,
+}
+
+impl FlagStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FlagStatus::Open => "open",
+            FlagStatus::Confirmed => "confirmed",
+            FlagStatus::Cleared => "cleared",
+        }
+    }
+
+    pub fn parse(tag: &str) -> Option<Self> {
+        match tag {
+            "open" => Some(FlagStatus::Open),
+            "confirmed" => Some(FlagStatus::Confirmed),
+            "cleared" => Some(FlagStatus::Cleared),
+            _ => None,
+        }
+    }
+}
+
+/// Evidence recorded each time a user is flagged for double spending: the
+/// nullifier already stored and the state it was presented with again.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct DoubleSpendFlag {
+    pub id: String,
+    pub username: String,
+    pub nullifier: NoteNullifierSchema,
+    pub presented_state: String,
+    pub flagged_at: i64,
+    pub status: FlagStatus,
+}
+
+/// Durable evidence of a double spend, kept regardless of how admins review the
+/// flag: the nullifier as first spent and the spend that conflicted with it.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct Betrayal {
+    pub id: String,
+    /// Owner of the first spend, the user that was flagged.
+    pub username: String,
+    pub spent: NoteNullifierSchema,
+    pub conflicting: NoteNullifierSchema,
+    /// Unix timestamp of the conflicting spend.
+    pub detected_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct BetrayalNoteRequest {
+    /// A note as stored with its nullifier, matched against both spends.
+    pub note: String,
+}
+
+/// What an admin did to a user.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminAction {
+    ClearFlag,
+    ConfirmFlag,
+    Suspend,
+    Restore,
+}
+
+impl AdminAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AdminAction::ClearFlag => "clear_flag",
+            AdminAction::ConfirmFlag => "confirm_flag",
+            AdminAction::Suspend => "suspend",
+            AdminAction::Restore => "restore",
+        }
+    }
+
+    pub fn parse(tag: &str) -> Option<Self> {
+        match tag {
+            "clear_flag" => Some(AdminAction::ClearFlag),
+            "confirm_flag" => Some(AdminAction::ConfirmFlag),
+            "suspend" => Some(AdminAction::Suspend),
+            "restore" => Some(AdminAction::Restore),
+            _ => None,
+        }
+    }
+}
+
+/// An entry of the admin audit trail. Entries are only ever appended.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct AuditEntry {
+    pub id: String,
+    pub admin: String,
+    pub action: AdminAction,
+    pub username: String,
+    pub reason: String,
+    pub at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ModerationRequest {
+    pub username: String,
+    /// Why, kept in the audit trail.
+    pub reason: String,
+}
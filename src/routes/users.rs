@@ -1,104 +1,268 @@
-use axum::{extract::Extension, http::StatusCode, Json, response::IntoResponse};
-use crate::mongo::IOUServiceDB;
+use axum::{extract::Extension, http::StatusCode, Json};
+use crate::crypto::validate_public_key;
+use crate::store::Store;
 use super::{
-  schema::{AuthData, CreateUserSchema, UserRequest, UserIdentifier},
-  error::{DatabaseError, ErrorResponse}
+  schema::{AuthData, CreateUserSchema, KeyRecord, RevokeSessionRequest, RotateKeyRequest, User, UserRequest, UserIdentifier, UsernameRequest},
+  response::UserSingleResponse,
+  error::{ApiError, DatabaseError, ErrorCode},
+  extract::{ApiJson, AuthUser},
 };
-use mongodb::bson::doc;
-use uuid::Uuid;
+use crate::session::Session;
 
+#[utoipa::path(
+  post,
+  path = "/get_user",
+  tag = "users",
+  request_body = UserRequest,
+  responses(
+    (status = 200, description = "The user", body = User),
+    (status = 400, description = "Malformed request body", body = ErrorResponse),
+    (status = 404, description = "`USER_NOT_FOUND`", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
 #[axum::debug_handler]
 pub async fn get_user(
-    Extension(db): Extension<IOUServiceDB>,
-    Json(payload): Json<UserRequest>
-) -> impl IntoResponse {
+    Extension(db): Extension<Store>,
+    ApiJson(payload): ApiJson<UserRequest>
+) -> Result<Json<User>, ApiError> {
     let result = match payload.identifier {
         UserIdentifier::Username(username) => db.get_user_with_username(&username).await,
         UserIdentifier::Address(address) => db.get_user_with_address(&address).await,
     };
 
     match result {
-        Ok(user_response) => {
-            (StatusCode::OK, Json(user_response.user)).into_response()
-        },
-        Err(err) => {
-            let (status, error_message) = match err.current_context() {
-                DatabaseError::NotFoundError => (
-                    StatusCode::NOT_FOUND,
-                    format!("User not found: {}", err)
-                ),
-                DatabaseError::FetchError => (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Failed to fetch user: {}", err)
-                ),
-                DatabaseError::ConversionError => (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Failed to process user data: {}", err)
-                ),
-                _ => (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("An unexpected error occurred: {}", err)
-                ),
-            };
-            (status, Json(ErrorResponse { error: error_message })).into_response()
-        }
+        Ok(user_response) => Ok(Json(user_response.user)),
+        Err(err) => Err(ApiError::from(err).on_not_found(ErrorCode::UserNotFound)),
     }
 }
 
+#[utoipa::path(
+  post,
+  path = "/create_user",
+  tag = "users",
+  request_body = CreateUserSchema,
+  responses(
+    (status = 201, description = "The created user", body = UserSingleResponse),
+    (status = 400, description = "Malformed request body or a pubkey that is not a valid `key_type` key", body = ErrorResponse),
+    (status = 409, description = "`USERNAME_TAKEN`", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
 #[axum::debug_handler]
 pub async fn create_user(
-    Extension(db): Extension<IOUServiceDB>,
-    Json(payload): Json<CreateUserSchema>
-) -> impl IntoResponse {
+    Extension(db): Extension<Store>,
+    ApiJson(payload): ApiJson<CreateUserSchema>
+) -> Result<(StatusCode, Json<UserSingleResponse>), ApiError> {
+  if validate_public_key(payload.key_type, &payload.pubkey).is_err() {
+    return Err(ApiError::new(
+      ErrorCode::InvalidRequest,
+      format!("pubkey is not a valid {} key", payload.key_type.as_str()),
+    ));
+  }
+
   match db.create_user(&payload).await {
     Ok(user_response) => {
-      println!("{:#?}", user_response);
-      (StatusCode::CREATED, Json(user_response)).into_response()
+      log::info!("Created user {}", payload.username);
+      Ok((StatusCode::CREATED, Json(user_response)))
     },
-    Err(err) => {
-      let error_message = format!("Failed to create user: {}", err);
-      (StatusCode::INTERNAL_SERVER_ERROR, Json(ErrorResponse { error: error_message })).into_response()
-    }
+    Err(err) => Err(ApiError::from(err).on_conflict(ErrorCode::UsernameTaken)),
   }
 }
 
+#[utoipa::path(
+  post,
+  path = "/auth",
+  tag = "auth",
+  request_body(content = String, description = "Username to issue the challenge for"),
+  responses(
+    (status = 200, description = "Hex encoded challenge to sign, single use and bound to the user", body = String),
+    (status = 400, description = "Malformed request body", body = ErrorResponse),
+    (status = 404, description = "`USER_NOT_FOUND`", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
 #[axum::debug_handler]
 pub async fn create_and_send_challenge(
-  Extension(state): Extension<IOUServiceDB>,
-  Json(username): Json<String>,
-) -> Result<Json<String>, (StatusCode, Json<String>)> {
-  match state.get_challenge(None, &username).await {
-    Ok(challenge) => Ok(Json(hex::encode(challenge))),
-    Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, Json("Failed to create challenge".to_string()))),
-  }
+  Extension(state): Extension<Store>,
+  ApiJson(username): ApiJson<String>,
+) -> Result<Json<String>, ApiError> {
+  // Only issue challenges that someone can answer.
+  state.get_user_with_username(&username).await
+    .map_err(|err| ApiError::from(err).on_not_found(ErrorCode::UserNotFound))?;
+
+  let challenge = state.create_challenge(&username).await?;
+  Ok(Json(hex::encode(challenge.challenge_id)))
 }
 
+/// Exchanges a signed challenge for a session id. Send the session id as
+/// `Authorization: Bearer <session id>` on protected routes.
+#[utoipa::path(
+  post,
+  path = "/get_session",
+  tag = "auth",
+  request_body = AuthData,
+  responses(
+    (status = 200, description = "Session id, valid for `auth.session_ttl` seconds", body = String),
+    (status = 400, description = "Malformed request body", body = ErrorResponse),
+    (status = 401, description = "`UNAUTHORIZED` for an unknown user or invalid signature, `CHALLENGE_INVALID` for an unknown, expired or used challenge or one issued before a key rotation, `CHALLENGE_USER_MISMATCH` for a challenge issued to another user", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
+#[axum::debug_handler]
 pub async fn verify_challenge(
-  Extension(state): Extension<IOUServiceDB>,
-  Json(auth_request): Json<AuthData>,
-) -> impl IntoResponse {
-  match state.authenticate_user(&auth_request.username, &auth_request.signature_hex, &auth_request.challenge_id).await {
-    Ok(true) => {
-      // Create a session token
-      let session_id = Uuid::new_v4().to_string();
-      state.insert_session(session_id.clone(), auth_request.username.clone());
-
-      Ok((StatusCode::OK, Json(session_id)))
+  Extension(state): Extension<Store>,
+  ApiJson(auth_request): ApiJson<AuthData>,
+) -> Result<Json<String>, ApiError> {
+  match state.authenticate_user(&auth_request.username, &auth_request.signature_hex, &auth_request.challenge_id).await? {
+    true => {
+      let session = state.create_session(&auth_request.username).await?;
+      Ok(Json(session.token))
     },
-    Ok(false) => Ok((StatusCode::UNAUTHORIZED, Json("Invalid signature".to_string()))),
-    Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "Authentication failed".to_string())),
+    false => Err(ApiError::new(ErrorCode::Unauthorized, "Invalid signature")),
   }
 }
 
-pub async fn validate_session(
-  Extension(state): Extension<IOUServiceDB>,
-  Json(session_id): Json<String>,
-) -> Result<String, (StatusCode, String)> {
-  let sessions = state.sessions.read().unwrap();
+/// Returns the username the session belongs to.
+#[utoipa::path(
+  get,
+  path = "/validate_session",
+  tag = "auth",
+  security(("session" = []), ("signature" = [])),
+  responses(
+    (status = 200, description = "Username of the session", body = String),
+    (status = 401, description = "`UNAUTHORIZED`, missing or invalid session", body = ErrorResponse),
+  )
+)]
+#[axum::debug_handler]
+pub async fn validate_session(auth: AuthUser) -> Json<String> {
+  Json(auth.username)
+}
 
-  if sessions.get(&session_id).is_some() {
-    Ok("authenticated".to_owned())
-  } else {
-      Err((StatusCode::UNAUTHORIZED, "Unauthorized".to_owned()))
+/// Ends the session the request was made with.
+#[utoipa::path(
+  post,
+  path = "/logout",
+  tag = "auth",
+  security(("session" = [])),
+  responses(
+    (status = 204, description = "The session is revoked"),
+    (status = 400, description = "`INVALID_REQUEST`, the request was signed instead of using a session", body = ErrorResponse),
+    (status = 401, description = "`UNAUTHORIZED`, missing or invalid session", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
+#[axum::debug_handler]
+pub async fn logout(Extension(state): Extension<Store>, auth: AuthUser) -> Result<StatusCode, ApiError> {
+  let Some(session) = &auth.session else {
+    return Err(ApiError::new(ErrorCode::InvalidRequest, "Signed requests have no session to end"));
+  };
+  state.revoke_session(&auth.username, &session.id).await?;
+  Ok(StatusCode::NO_CONTENT)
+}
+
+/// Lists the active sessions of the logged in user.
+#[utoipa::path(
+  get,
+  path = "/list_sessions",
+  tag = "auth",
+  security(("session" = []), ("signature" = [])),
+  responses(
+    (status = 200, description = "Active sessions, oldest first", body = [Session]),
+    (status = 401, description = "`UNAUTHORIZED`, missing or invalid session", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
+#[axum::debug_handler]
+pub async fn list_sessions(Extension(state): Extension<Store>, auth: AuthUser) -> Result<Json<Vec<Session>>, ApiError> {
+  Ok(Json(state.list_sessions(&auth.username).await?))
+}
+
+/// Revokes one of the logged in user's sessions, e.g. on a lost device.
+#[utoipa::path(
+  post,
+  path = "/revoke_session",
+  tag = "auth",
+  request_body = RevokeSessionRequest,
+  security(("session" = []), ("signature" = [])),
+  responses(
+    (status = 204, description = "The session is revoked"),
+    (status = 400, description = "Malformed request body", body = ErrorResponse),
+    (status = 401, description = "`UNAUTHORIZED`, missing or invalid session", body = ErrorResponse),
+    (status = 404, description = "`NOT_FOUND`, no such session for this user", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
+#[axum::debug_handler]
+pub async fn revoke_session(
+  Extension(state): Extension<Store>,
+  auth: AuthUser,
+  ApiJson(payload): ApiJson<RevokeSessionRequest>,
+) -> Result<StatusCode, ApiError> {
+  match state.revoke_session(&auth.username, &payload.session_id).await? {
+    true => Ok(StatusCode::NO_CONTENT),
+    false => Err(ApiError::new(ErrorCode::NotFound, "Session not found")),
   }
-}
\ No newline at end of file
+}
+
+/// Replaces the user's key. The current key signs the handover, see `rotation_message`.
+#[utoipa::path(
+  post,
+  path = "/rotate_key",
+  tag = "auth",
+  request_body = RotateKeyRequest,
+  responses(
+    (status = 200, description = "Every key of the user, oldest first, ending with the new one", body = [KeyRecord]),
+    (status = 400, description = "Malformed request body or a `new_pubkey` that is not a valid `new_key_type` key", body = ErrorResponse),
+    (status = 401, description = "`UNAUTHORIZED`, the handover is not signed by the current key", body = ErrorResponse),
+    (status = 404, description = "`USER_NOT_FOUND`", body = ErrorResponse),
+    (status = 409, description = "`KEY_REUSED` for a key the user had before, `CONFLICT` when the key was rotated concurrently", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
+#[axum::debug_handler]
+pub async fn rotate_key(
+  Extension(state): Extension<Store>,
+  ApiJson(payload): ApiJson<RotateKeyRequest>,
+) -> Result<Json<Vec<KeyRecord>>, ApiError> {
+  if validate_public_key(payload.new_key_type, &payload.new_pubkey).is_err() {
+    return Err(ApiError::new(
+      ErrorCode::InvalidRequest,
+      format!("new_pubkey is not a valid {} key", payload.new_key_type.as_str()),
+    ));
+  }
+
+  let rotated = state.rotate_key(&payload).await.map_err(|err| {
+    let reused = matches!(err.current_context(), DatabaseError::DuplicateKeyError);
+    let err = ApiError::from(err).on_not_found(ErrorCode::UserNotFound);
+    if reused { err.on_conflict(ErrorCode::KeyReused) } else { err }
+  })?;
+  if !rotated {
+    return Err(ApiError::new(ErrorCode::Unauthorized, "Invalid handover signature"));
+  }
+  Ok(Json(state.list_keys(&payload.username).await?))
+}
+
+/// Lists every key a user had and when it was valid.
+#[utoipa::path(
+  get,
+  path = "/key_history",
+  tag = "auth",
+  request_body = UsernameRequest,
+  responses(
+    (status = 200, description = "Every key of the user, oldest first, ending with the current one", body = [KeyRecord]),
+    (status = 400, description = "Malformed request body", body = ErrorResponse),
+    (status = 404, description = "`USER_NOT_FOUND`", body = ErrorResponse),
+    (status = 500, description = "Storage failure", body = ErrorResponse),
+  )
+)]
+#[axum::debug_handler]
+pub async fn key_history(
+  Extension(state): Extension<Store>,
+  ApiJson(payload): ApiJson<UsernameRequest>,
+) -> Result<Json<Vec<KeyRecord>>, ApiError> {
+  match state.list_keys(&payload.username).await {
+    Ok(keys) => Ok(Json(keys)),
+    Err(err) => Err(ApiError::from(err).on_not_found(ErrorCode::UserNotFound)),
+  }
+}
@@ -0,0 +1,51 @@
+use async_trait::async_trait;
+use bson::oid::ObjectId;
+use chrono::Utc;
+use error_stack::Result;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+use crate::routes::error::DatabaseError;
+
+/// A login session. The `token` is the bearer secret and is never serialized,
+/// `id` is what users see when they list or revoke their sessions.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Session {
+  pub id: String,
+  #[serde(skip)]
+  pub token: String,
+  pub username: String,
+  pub created_at: i64,
+  pub expires_at: i64,
+}
+
+impl Session {
+  /// A fresh session for `username` that expires `ttl` seconds from now.
+  pub fn new(username: &str, ttl: i64) -> Self {
+    let now = Utc::now().timestamp();
+    Self {
+      id: ObjectId::new().to_hex(),
+      token: Uuid::new_v4().to_string(),
+      username: username.to_owned(),
+      created_at: now,
+      expires_at: now + ttl,
+    }
+  }
+
+  pub fn is_expired(&self, now: i64) -> bool {
+    self.expires_at <= now
+  }
+}
+
+/// Storage for login sessions. Expired sessions are never returned, even
+/// before a backend has physically removed them.
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+  async fn create_session(&self, username: &str) -> Result<Session, DatabaseError>;
+  /// Resolves a bearer token to its session.
+  async fn get_session(&self, token: &str) -> Result<Option<Session>, DatabaseError>;
+  /// Active sessions of `username`, oldest first.
+  async fn list_sessions(&self, username: &str) -> Result<Vec<Session>, DatabaseError>;
+  /// Revokes the session `id` if it belongs to `username`. Returns whether one was revoked.
+  async fn revoke_session(&self, username: &str, id: &str) -> Result<bool, DatabaseError>;
+}
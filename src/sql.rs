@@ -0,0 +1,1253 @@
+use bson::{oid::ObjectId, Bson};
+use sqlx::{any::{AnyPoolOptions, AnyRow}, migrate::{Migrate, Migrator}, AnyConnection, AnyPool, Row};
+use crate::routes::{
+  error::DatabaseError,
+  response::{
+    MessageSingleResponse,
+    NoteHistoryResponse,
+    NoteResponse,
+    NullifierResponseData,
+    UserSingleResponse
+  },
+  schema::{
+    AdminAction, AuditEntry, Betrayal, ChallengeSchema, CreateUserSchema, DoubleSpendFlag, FlagStatus, KeyRecord, MessageRequestSchema, MessageSchema, NoteHistorySaved, NoteNullifierSchema, NoteSchema, NullifierState, SaveNoteHistoryRequestSchema, SaveNoteRequestSchema, SpendOutcome, User
+  }
+};
+use crate::session::{Session, SessionStore};
+use crate::config::AuthConfig;
+use crate::crypto::KeyType;
+use crate::store::{challenge_error, first_spend, leaf_backoff, leaf_contention_error, new_betrayal, new_challenge, new_flag, spend_outcome, IOUStore, MigrationStatus, MAX_LEAF_ATTEMPTS};
+use chrono::Utc;
+use async_trait::async_trait;
+use error_stack::{Report, Result};
+
+static SQLITE_MIGRATIONS: Migrator = sqlx::migrate!("./migrations/sqlite");
+static POSTGRES_MIGRATIONS: Migrator = sqlx::migrate!("./migrations/postgres");
+
+/// Relational storage backend for SQLite (`sqlite:`) and PostgreSQL (`postgres:`) urls.
+///
+/// Ids are generated as ObjectId hex strings so responses look the same as
+/// the ones served from MongoDB.
+#[derive(Debug, Clone)]
+pub struct SqlDB {
+  pool: AnyPool,
+  migrator: &'static Migrator,
+  auth: AuthConfig,
+}
+
+impl SqlDB {
+  /// Connects to `url`. Migrations are applied separately through `IOUStore::migrate`.
+  /// `auth` sets how long challenges and sessions stay valid.
+  pub async fn init(url: &str, auth: AuthConfig) -> Result<Self, DatabaseError> {
+    sqlx::any::install_default_drivers();
+
+    let migrator = if url.starts_with("sqlite:") {
+      &SQLITE_MIGRATIONS
+    } else if url.starts_with("postgres:") || url.starts_with("postgresql:") {
+      &POSTGRES_MIGRATIONS
+    } else {
+      return Err(Report::new(DatabaseError::MigrationError)
+        .attach_printable(format!("Unsupported database url '{}'", url)));
+    };
+
+    // Every connection to `sqlite::memory:` opens a fresh database, so keep a single one.
+    let max_connections = if url.contains(":memory:") { 1 } else { 5 };
+    let pool = match AnyPoolOptions::new().max_connections(max_connections).connect(url).await {
+      Ok(pool) => pool,
+      Err(e) => return Err(Report::new(DatabaseError::FetchError)
+        .attach_printable(format!("Failed to connect to '{}': {}", url, e))),
+    };
+
+    Ok(Self { pool, migrator, auth })
+  }
+
+  async fn acquire(&self) -> Result<sqlx::pool::PoolConnection<sqlx::Any>, DatabaseError> {
+    self.pool.acquire().await.map_err(|e| Report::new(DatabaseError::FetchError)
+      .attach_printable(format!("Failed to acquire connection: {}", e)))
+  }
+
+  async fn begin(&self) -> Result<sqlx::Transaction<'static, sqlx::Any>, DatabaseError> {
+    self.pool.begin().await.map_err(|e| Report::new(DatabaseError::UpdateError)
+      .attach_printable(format!("Failed to start transaction: {}", e)))
+  }
+
+  // Betrayals matching `filter`, which binds `value` as `$1`, oldest first.
+  async fn fetch_betrayals(&self, filter: &str, value: &str) -> Result<Vec<Betrayal>, DatabaseError> {
+    let query = format!(
+      "SELECT id, username, nullifier, note, step, state, states, conflicting_owner, conflicting_note, conflicting_step, conflicting_state, detected_at FROM betrayals WHERE {} ORDER BY detected_at, id",
+      filter,
+    );
+    let rows = match sqlx::query(&query).bind(value).fetch_all(&self.pool).await {
+      Ok(rows) => rows,
+      Err(e) => return Err(Report::new(DatabaseError::FetchError)
+        .attach_printable(format!("Failed to fetch betrayals: {}", e))),
+    };
+    rows.iter().map(row_to_betrayal).collect()
+  }
+
+  // Stores the first spend of `body` with `query`, its state and the next leaf of the
+  // nullifier Merkle tree in one transaction. Returns false when `query` inserted nothing.
+  // The leaf is `MAX(leaf_index) + 1` rather than from a sequence, which would hand out
+  // leaves in the order spends start instead of the order they commit. Concurrent spends
+  // can pick the same leaf, the losers roll back and retry with the next one.
+  async fn insert_nullifier(&self, body: &NoteNullifierSchema, query: &str) -> Result<bool, DatabaseError> {
+    for attempt in 1..=MAX_LEAF_ATTEMPTS {
+      let mut tx = self.begin().await?;
+      let inserted = sqlx::query(query)
+        .bind(new_id())
+        .bind(&body.nullifier)
+        .bind(&body.note)
+        .bind(body.step as i64)
+        .bind(&body.owner)
+        .bind(&body.state)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| insert_error(e, "nullifier"))?;
+      if inserted.rows_affected() == 0 {
+        return Ok(false);
+      }
+      insert_state(&mut tx, &body.nullifier, body.step, &body.state).await?;
+
+      let leaf = sqlx::query("INSERT INTO nullifier_leaves (leaf_index, nullifier) SELECT COALESCE(MAX(leaf_index) + 1, 0), $1 FROM nullifier_leaves")
+        .bind(&body.nullifier)
+        .execute(&mut *tx)
+        .await;
+      if let Err(e) = leaf {
+        match insert_error(e, "nullifier leaf") {
+          err if matches!(err.current_context(), DatabaseError::DuplicateKeyError) => {
+            drop(tx);
+            leaf_backoff(attempt).await;
+            continue;
+          },
+          err => return Err(err),
+        }
+      }
+
+      tx.commit().await.map_err(|e| Report::new(DatabaseError::InsertError)
+        .attach_printable(format!("Failed to commit nullifier '{}': {}", body.nullifier, e)))?;
+      return Ok(true);
+    }
+    Err(leaf_contention_error(&body.nullifier))
+  }
+}
+
+fn get_current_timestamp() -> i64 {
+  Utc::now().timestamp()
+}
+
+fn new_id() -> String {
+  ObjectId::new().to_hex()
+}
+
+fn id_to_bson(id: &str) -> Option<Bson> {
+  ObjectId::parse_str(id).ok().map(Bson::ObjectId)
+}
+
+// Unique constraint violations become `DuplicateKeyError` so handlers can report a conflict.
+fn insert_error(e: sqlx::Error, what: &str) -> Report<DatabaseError> {
+  let is_duplicate = matches!(&e, sqlx::Error::Database(db_err) if db_err.is_unique_violation());
+  let context = if is_duplicate { DatabaseError::DuplicateKeyError } else { DatabaseError::InsertError };
+  Report::new(context).attach_printable(format!("Failed to insert {}: {}", what, e))
+}
+
+fn conversion_error(e: sqlx::Error) -> Report<DatabaseError> {
+  Report::new(DatabaseError::ConversionError)
+    .attach_printable(format!("Failed to read row: {}", e))
+}
+
+fn parse_key_type(username: &str, tag: &str) -> Result<KeyType, DatabaseError> {
+  KeyType::parse(tag).ok_or_else(|| Report::new(DatabaseError::ConversionError)
+    .attach_printable(format!("User '{}' has unknown key type '{}'", username, tag)))
+}
+
+// Row conversions
+fn row_to_key_record(row: &AnyRow) -> Result<KeyRecord, DatabaseError> {
+  let username: String = row.try_get("username").map_err(conversion_error)?;
+  let key_type: String = row.try_get("key_type").map_err(conversion_error)?;
+  Ok(KeyRecord {
+    key_type: parse_key_type(&username, &key_type)?,
+    pubkey: row.try_get("pubkey").map_err(conversion_error)?,
+    valid_from: row.try_get("valid_from").map_err(conversion_error)?,
+    valid_until: Some(row.try_get("valid_until").map_err(conversion_error)?),
+    username,
+  })
+}
+
+fn row_to_flag(row: &AnyRow) -> Result<DoubleSpendFlag, DatabaseError> {
+  let id: String = row.try_get("id").map_err(conversion_error)?;
+  let username: String = row.try_get("username").map_err(conversion_error)?;
+  let step: i64 = row.try_get("step").map_err(conversion_error)?;
+  let state: String = row.try_get("state").map_err(conversion_error)?;
+  let status: String = row.try_get("status").map_err(conversion_error)?;
+  let status = FlagStatus::parse(&status).ok_or_else(|| Report::new(DatabaseError::ConversionError)
+    .attach_printable(format!("Flag '{}' has unknown status '{}'", id, status)))?;
+
+  Ok(DoubleSpendFlag {
+    nullifier: NoteNullifierSchema {
+      nullifier: row.try_get("nullifier").map_err(conversion_error)?,
+      note: row.try_get("note").map_err(conversion_error)?,
+      step: step as i32,
+      owner: username.clone(),
+      states: states_from_row(row, step as i32, &state)?,
+      state,
+    },
+    presented_state: row.try_get("presented_state").map_err(conversion_error)?,
+    flagged_at: row.try_get("flagged_at").map_err(conversion_error)?,
+    status,
+    username,
+    id,
+  })
+}
+
+fn row_to_betrayal(row: &AnyRow) -> Result<Betrayal, DatabaseError> {
+  let username: String = row.try_get("username").map_err(conversion_error)?;
+  let nullifier: String = row.try_get("nullifier").map_err(conversion_error)?;
+  let step: i64 = row.try_get("step").map_err(conversion_error)?;
+  let state: String = row.try_get("state").map_err(conversion_error)?;
+  let conflicting_step: i64 = row.try_get("conflicting_step").map_err(conversion_error)?;
+  let conflicting_state: String = row.try_get("conflicting_state").map_err(conversion_error)?;
+
+  Ok(Betrayal {
+    id: row.try_get("id").map_err(conversion_error)?,
+    spent: NoteNullifierSchema {
+      nullifier: nullifier.clone(),
+      note: row.try_get("note").map_err(conversion_error)?,
+      step: step as i32,
+      owner: username.clone(),
+      states: states_from_row(row, step as i32, &state)?,
+      state,
+    },
+    // A conflicting spend is never stored, its vector is the spend itself.
+    conflicting: NoteNullifierSchema {
+      nullifier,
+      note: row.try_get("conflicting_note").map_err(conversion_error)?,
+      step: conflicting_step as i32,
+      owner: row.try_get("conflicting_owner").map_err(conversion_error)?,
+      states: vec![NullifierState { step: conflicting_step as i32, state: conflicting_state.clone() }],
+      state: conflicting_state,
+    },
+    detected_at: row.try_get("detected_at").map_err(conversion_error)?,
+    username,
+  })
+}
+
+fn row_to_audit_entry(row: &AnyRow) -> Result<AuditEntry, DatabaseError> {
+  let id: String = row.try_get("id").map_err(conversion_error)?;
+  let action: String = row.try_get("action").map_err(conversion_error)?;
+  let action = AdminAction::parse(&action).ok_or_else(|| Report::new(DatabaseError::ConversionError)
+    .attach_printable(format!("Audit entry '{}' has unknown action '{}'", id, action)))?;
+
+  Ok(AuditEntry {
+    admin: row.try_get("admin").map_err(conversion_error)?,
+    username: row.try_get("username").map_err(conversion_error)?,
+    reason: row.try_get("reason").map_err(conversion_error)?,
+    at: row.try_get("at").map_err(conversion_error)?,
+    action,
+    id,
+  })
+}
+
+fn row_to_session(row: &AnyRow) -> Result<Session, DatabaseError> {
+  Ok(Session {
+    id: row.try_get("id").map_err(conversion_error)?,
+    token: row.try_get("token").map_err(conversion_error)?,
+    username: row.try_get("username").map_err(conversion_error)?,
+    created_at: row.try_get("created_at").map_err(conversion_error)?,
+    expires_at: row.try_get("expires_at").map_err(conversion_error)?,
+  })
+}
+
+fn row_to_message(row: &AnyRow) -> Result<MessageSchema, DatabaseError> {
+  let id: String = row.try_get("id").map_err(conversion_error)?;
+  let attachment_id: Option<String> = row.try_get("attachment_id").map_err(conversion_error)?;
+  let attachment_id = match attachment_id {
+    Some(json) => match serde_json::from_str::<Bson>(&json) {
+      Ok(bson) => Some(bson),
+      Err(e) => return Err(Report::new(DatabaseError::ConversionError)
+        .attach_printable(format!("Failed to decode attachment of message '{}': {}", id, e))),
+    },
+    None => None,
+  };
+
+  Ok(MessageSchema {
+    sender: row.try_get("sender").map_err(conversion_error)?,
+    recipient: row.try_get("recipient").map_err(conversion_error)?,
+    message: row.try_get("message").map_err(conversion_error)?,
+    timestamp: row.try_get("timestamp").map_err(conversion_error)?,
+    attachment_id,
+    read: row.try_get("read").map_err(conversion_error)?,
+    _id: id_to_bson(&id),
+  })
+}
+
+// The state vector is read separately, see `find_nullifier`.
+fn row_to_nullifier(row: &AnyRow) -> Result<NoteNullifierSchema, DatabaseError> {
+  let step: i64 = row.try_get("step").map_err(conversion_error)?;
+
+  Ok(NoteNullifierSchema {
+    nullifier: row.try_get("nullifier").map_err(conversion_error)?,
+    note: row.try_get("note").map_err(conversion_error)?,
+    step: step as i32,
+    owner: row.try_get("owner").map_err(conversion_error)?,
+    state: row.try_get("state").map_err(conversion_error)?,
+    states: Vec::new(),
+  })
+}
+
+fn row_to_nullifier_state(row: &AnyRow) -> Result<NullifierState, DatabaseError> {
+  let step: i64 = row.try_get("step").map_err(conversion_error)?;
+  Ok(NullifierState {
+    step: step as i32,
+    state: row.try_get("state").map_err(conversion_error)?,
+  })
+}
+
+// Flags and betrayals keep the state vector of the spent nullifier as JSON, rows
+// written before the vector hold `[]` and only the first spend.
+fn states_to_json(states: &[NullifierState]) -> Result<String, DatabaseError> {
+  serde_json::to_string(states).map_err(|e| Report::new(DatabaseError::ConversionError)
+    .attach_printable(format!("Failed to encode state vector: {}", e)))
+}
+
+fn states_from_row(row: &AnyRow, step: i32, state: &str) -> Result<Vec<NullifierState>, DatabaseError> {
+  let states: String = row.try_get("states").map_err(conversion_error)?;
+  let states: Vec<NullifierState> = serde_json::from_str(&states).map_err(|e| Report::new(DatabaseError::ConversionError)
+    .attach_printable(format!("Failed to decode state vector: {}", e)))?;
+  if states.is_empty() {
+    return Ok(vec![NullifierState { step, state: state.to_owned() }]);
+  }
+  Ok(states)
+}
+
+async fn insert_state(tx: &mut sqlx::Transaction<'static, sqlx::Any>, nullifier: &str, step: i32, state: &str) -> Result<(), DatabaseError> {
+  sqlx::query("INSERT INTO nullifier_states (nullifier, step, state) VALUES ($1, $2, $3)")
+    .bind(nullifier)
+    .bind(step as i64)
+    .bind(state)
+    .execute(&mut **tx)
+    .await
+    .map_err(|e| insert_error(e, "nullifier state"))?;
+  Ok(())
+}
+
+fn row_to_note(row: &AnyRow) -> Result<NoteSchema, DatabaseError> {
+  let id: String = row.try_get("id").map_err(conversion_error)?;
+  let value: i64 = row.try_get("value").map_err(conversion_error)?;
+  let step: i64 = row.try_get("step").map_err(conversion_error)?;
+
+  Ok(NoteSchema {
+    asset_hash: row.try_get("asset_hash").map_err(conversion_error)?,
+    owner: row.try_get("owner").map_err(conversion_error)?,
+    value: value as u64,
+    step: step as u32,
+    parent_note: row.try_get("parent_note").map_err(conversion_error)?,
+    out_index: row.try_get("out_index").map_err(conversion_error)?,
+    blind: row.try_get("blind").map_err(conversion_error)?,
+    _id: id_to_bson(&id),
+  })
+}
+
+fn row_to_note_history(row: &AnyRow) -> Result<NoteHistorySaved, DatabaseError> {
+  let id: String = row.try_get("id").map_err(conversion_error)?;
+
+  Ok(NoteHistorySaved {
+    sender: row.try_get("sender").map_err(conversion_error)?,
+    data: row.try_get("data").map_err(conversion_error)?,
+    address: row.try_get("address").map_err(conversion_error)?,
+    _id: id_to_bson(&id),
+  })
+}
+
+// Queries shared between pooled connections and transactions
+async fn find_user(conn: &mut AnyConnection, column: &str, value: &str) -> Result<User, DatabaseError> {
+  let query = format!(
+    "SELECT id, username, pubkey, key_type, nonce, address, has_double_spent, suspended FROM users WHERE {} = $1",
+    column
+  );
+  let row = match sqlx::query(&query).bind(value).fetch_optional(&mut *conn).await {
+    Ok(Some(row)) => row,
+    Ok(None) => return Err(Report::new(DatabaseError::NotFoundError)
+      .attach_printable(format!("User '{}' not found", value))),
+    Err(e) => return Err(Report::new(DatabaseError::FetchError)
+      .attach_printable(format!("Failed to fetch user '{}': {}", value, e))),
+  };
+
+  let id: String = row.try_get("id").map_err(conversion_error)?;
+  let username: String = row.try_get("username").map_err(conversion_error)?;
+  let key_type = match row.try_get::<Option<String>, _>("key_type").map_err(conversion_error)? {
+    Some(tag) => Some(parse_key_type(&username, &tag)?),
+    None => None,
+  };
+
+  let notes = match sqlx::query("SELECT note_id FROM user_notes WHERE user_id = $1 ORDER BY seq")
+    .bind(&id)
+    .fetch_all(&mut *conn)
+    .await
+  {
+    Ok(rows) => rows.iter()
+      .filter_map(|row| row.try_get::<String, _>("note_id").ok())
+      .filter_map(|note_id| ObjectId::parse_str(note_id).ok())
+      .collect(),
+    Err(e) => return Err(Report::new(DatabaseError::FetchError)
+      .attach_printable(format!("Failed to fetch notes of user '{}': {}", username, e))),
+  };
+
+  let messages = match sqlx::query("SELECT id FROM messages WHERE recipient = $1 ORDER BY timestamp")
+    .bind(&username)
+    .fetch_all(&mut *conn)
+    .await
+  {
+    Ok(rows) => rows.iter()
+      .filter_map(|row| row.try_get::<String, _>("id").ok())
+      .filter_map(|message_id| ObjectId::parse_str(message_id).ok())
+      .collect(),
+    Err(e) => return Err(Report::new(DatabaseError::FetchError)
+      .attach_printable(format!("Failed to fetch messages of user '{}': {}", username, e))),
+  };
+
+  Ok(User {
+    has_double_spent: Some(row.try_get("has_double_spent").map_err(conversion_error)?),
+    nonce: Some(row.try_get("nonce").map_err(conversion_error)?),
+    pubkey: Some(row.try_get("pubkey").map_err(conversion_error)?),
+    key_type,
+    suspended: Some(row.try_get("suspended").map_err(conversion_error)?),
+    address: Some(row.try_get("address").map_err(conversion_error)?),
+    username: Some(username),
+    messages: Some(messages),
+    notes: Some(notes),
+    id: ObjectId::parse_str(&id).ok(),
+  })
+}
+
+async fn insert_message(conn: &mut AnyConnection, body: &MessageRequestSchema) -> Result<MessageSchema, DatabaseError> {
+  let id = new_id();
+  let attachment_id = match &body.attachment_id {
+    Some(bson) => match serde_json::to_string(bson) {
+      Ok(json) => Some(json),
+      Err(e) => return Err(Report::new(DatabaseError::ConversionError)
+        .attach_printable(format!("Failed to encode attachment: {}", e))),
+    },
+    None => None,
+  };
+  let message = MessageSchema {
+    sender: body.sender.clone(),
+    recipient: body.recipient.clone(),
+    message: body.message.clone(),
+    timestamp: get_current_timestamp(),
+    attachment_id: body.attachment_id.clone(),
+    read: false,
+    _id: id_to_bson(&id),
+  };
+
+  match sqlx::query(
+    "INSERT INTO messages (id, sender, recipient, message, timestamp, attachment_id, read) VALUES ($1, $2, $3, $4, $5, $6, $7)"
+  )
+    .bind(&id)
+    .bind(&message.sender)
+    .bind(&message.recipient)
+    .bind(&message.message)
+    .bind(message.timestamp)
+    .bind(attachment_id)
+    .bind(false)
+    .execute(&mut *conn)
+    .await
+  {
+    Ok(_) => Ok(message),
+    Err(e) => Err(Report::new(DatabaseError::InsertError)
+      .attach_printable(format!("Failed to insert message: {}", e))),
+  }
+}
+
+async fn insert_note_history(conn: &mut AnyConnection, body: SaveNoteHistoryRequestSchema) -> Result<NoteHistorySaved, DatabaseError> {
+  let id = new_id();
+
+  if let Err(e) = sqlx::query("INSERT INTO note_history (id, sender, address, data) VALUES ($1, $2, $3, $4)")
+    .bind(&id)
+    .bind(&body.sender)
+    .bind(&body.address)
+    .bind(&body.data)
+    .execute(&mut *conn)
+    .await
+  {
+    return Err(Report::new(DatabaseError::InsertError)
+      .attach_printable(format!("Failed to insert note history: {}", e)));
+  }
+
+  if let Err(e) = sqlx::query("INSERT INTO user_notes (user_id, note_id) SELECT id, $1 FROM users WHERE address = $2")
+    .bind(&id)
+    .bind(&body.address)
+    .execute(&mut *conn)
+    .await
+  {
+    return Err(Report::new(DatabaseError::UpdateError)
+      .attach_printable(format!("Failed to update user's notes: {}", e)));
+  }
+
+  Ok(NoteHistorySaved {
+    sender: body.sender,
+    data: body.data,
+    address: body.address,
+    _id: id_to_bson(&id),
+  })
+}
+
+#[async_trait]
+impl IOUStore for SqlDB {
+  async fn ping(&self) -> Result<(), DatabaseError> {
+    match sqlx::query("SELECT 1").execute(&self.pool).await {
+      Ok(_) => Ok(()),
+      Err(e) => Err(Report::new(DatabaseError::FetchError)
+        .attach_printable(format!("Failed to ping database: {}", e))),
+    }
+  }
+
+  // Migrations
+  async fn migration_status(&self) -> Result<Vec<MigrationStatus>, DatabaseError> {
+    let mut conn = self.acquire().await?;
+    let applied = match conn.ensure_migrations_table().await {
+      Ok(_) => match conn.list_applied_migrations().await {
+        Ok(applied) => applied,
+        Err(e) => return Err(Report::new(DatabaseError::MigrationError)
+          .attach_printable(format!("Failed to list applied migrations: {}", e))),
+      },
+      Err(e) => return Err(Report::new(DatabaseError::MigrationError)
+        .attach_printable(format!("Failed to create migrations table: {}", e))),
+    };
+
+    Ok(self.migrator.iter()
+      .map(|migration| MigrationStatus {
+        version: migration.version,
+        description: migration.description.to_string(),
+        applied: applied.iter().any(|done| done.version == migration.version),
+      })
+      .collect())
+  }
+
+  async fn migrate(&self) -> Result<Vec<MigrationStatus>, DatabaseError> {
+    if let Err(e) = self.migrator.run(&self.pool).await {
+      return Err(Report::new(DatabaseError::MigrationError)
+        .attach_printable(format!("Failed to apply migrations: {}", e)));
+    }
+
+    self.migration_status().await
+  }
+
+  // User
+  async fn get_user_with_username(&self, username: &str) -> Result<UserSingleResponse, DatabaseError> {
+    let mut conn = self.acquire().await?;
+    let user = find_user(&mut conn, "username", username).await?;
+
+    Ok(UserSingleResponse {
+      status: "success",
+      user
+    })
+  }
+
+  async fn get_user_with_address(&self, address: &str) -> Result<UserSingleResponse, DatabaseError> {
+    let mut conn = self.acquire().await?;
+    let user = find_user(&mut conn, "address", address).await?;
+
+    Ok(UserSingleResponse {
+      status: "success",
+      user
+    })
+  }
+
+  async fn create_user(&self, body: &CreateUserSchema) -> Result<UserSingleResponse, DatabaseError> {
+    let mut conn = self.acquire().await?;
+
+    if let Err(e) = sqlx::query(
+      "INSERT INTO users (id, username, pubkey, key_type, nonce, address, has_double_spent) VALUES ($1, $2, $3, $4, $5, $6, $7)"
+    )
+      .bind(new_id())
+      .bind(&body.username)
+      .bind(&body.pubkey)
+      .bind(body.key_type.as_str())
+      .bind(&body.nonce)
+      .bind(&body.address)
+      .bind(body.has_double_spent)
+      .execute(&mut *conn)
+      .await
+    {
+      return Err(insert_error(e, "user"));
+    }
+
+    let user = find_user(&mut conn, "username", &body.username).await?;
+
+    Ok(UserSingleResponse {
+      status: "success",
+      user,
+    })
+  }
+
+  // Messages
+  async fn send_message(&self, body: &MessageRequestSchema) -> Result<MessageSingleResponse, DatabaseError> {
+    let mut conn = self.acquire().await?;
+    let message = insert_message(&mut conn, body).await?;
+
+    Ok(MessageSingleResponse {
+      status: "success",
+      message
+    })
+  }
+
+  async fn get_unread_messages(&self, username: &str) -> Result<Vec<MessageSchema>, DatabaseError> {
+    let mut tx = self.begin().await?;
+
+    let rows = match sqlx::query(
+      "SELECT id, sender, recipient, message, timestamp, attachment_id, read FROM messages WHERE recipient = $1 AND read = $2 ORDER BY timestamp"
+    )
+      .bind(username)
+      .bind(false)
+      .fetch_all(&mut *tx)
+      .await
+    {
+      Ok(rows) => rows,
+      Err(e) => return Err(Report::new(DatabaseError::FetchError)
+        .attach_printable(format!("Failed to fetch unread messages: {}", e))),
+    };
+
+    let mut messages = Vec::new();
+    for row in rows.iter() {
+      let id: String = row.try_get("id").map_err(conversion_error)?;
+      if let Err(e) = sqlx::query("UPDATE messages SET read = $1 WHERE id = $2")
+        .bind(true)
+        .bind(&id)
+        .execute(&mut *tx)
+        .await
+      {
+        return Err(Report::new(DatabaseError::UpdateError)
+          .attach_printable(format!("Failed to update message read status: {}", e)));
+      }
+      messages.push(row_to_message(row)?);
+    }
+
+    tx.commit().await.map_err(|e| Report::new(DatabaseError::UpdateError)
+      .attach_printable(format!("Failed to update message read status: {}", e)))?;
+
+    Ok(messages)
+  }
+
+  // Nullifiers
+  async fn store_nullifier(&self, body: &NoteNullifierSchema) -> Result<NullifierResponseData, DatabaseError> {
+    self.insert_nullifier(body, "INSERT INTO nullifiers (id, nullifier, note, step, owner, state) VALUES ($1, $2, $3, $4, $5, $6)").await?;
+
+    Ok(NullifierResponseData {
+      status: "success",
+      nullifier: first_spend(body)
+    })
+  }
+
+  async fn record_nullifier_state(&self, body: &NoteNullifierSchema) -> Result<Option<NoteNullifierSchema>, DatabaseError> {
+    let query = "INSERT INTO nullifiers (id, nullifier, note, step, owner, state) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (nullifier) DO NOTHING";
+    if self.insert_nullifier(body, query).await? {
+      return Ok(None);
+    }
+
+    // Nullifiers are never deleted, the conflicting row is still there.
+    let Some(stored) = self.find_nullifier(&body.nullifier).await? else {
+      return Err(Report::new(DatabaseError::NotFoundError)
+        .attach_printable(format!("Nullifier '{}' conflicted but is not stored", body.nullifier)));
+    };
+    if stored.owner != body.owner || spend_outcome(&stored.states, body.step, &body.state) != SpendOutcome::Accepted {
+      return Ok(Some(stored));
+    }
+
+    // The unique (nullifier, step) and (nullifier, state) indexes reject the entry when
+    // a concurrent spend got there first, the vector then holds its step or state.
+    let appended = sqlx::query("INSERT INTO nullifier_states (nullifier, step, state) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING")
+      .bind(&body.nullifier)
+      .bind(body.step as i64)
+      .bind(&body.state)
+      .execute(&self.pool)
+      .await
+      .map_err(|e| insert_error(e, "nullifier state"))?;
+    if appended.rows_affected() == 1 {
+      return Ok(Some(stored));
+    }
+    self.find_nullifier(&body.nullifier).await
+  }
+
+  async fn find_nullifier(&self, nullifier: &str) -> Result<Option<NoteNullifierSchema>, DatabaseError> {
+    let row = sqlx::query("SELECT nullifier, note, step, owner, state FROM nullifiers WHERE nullifier = $1")
+      .bind(nullifier)
+      .fetch_optional(&self.pool)
+      .await
+      .map_err(|e| Report::new(DatabaseError::FetchError)
+        .attach_printable(format!("Failed to fetch nullifier '{}': {}", nullifier, e)))?;
+    let Some(row) = row else {
+      return Ok(None);
+    };
+
+    let rows = sqlx::query("SELECT step, state FROM nullifier_states WHERE nullifier = $1 ORDER BY step")
+      .bind(nullifier)
+      .fetch_all(&self.pool)
+      .await
+      .map_err(|e| Report::new(DatabaseError::FetchError)
+        .attach_printable(format!("Failed to fetch states of nullifier '{}': {}", nullifier, e)))?;
+
+    let mut stored = row_to_nullifier(&row)?;
+    stored.states = rows.iter().map(row_to_nullifier_state).collect::<Result<_, _>>()?;
+    Ok(Some(stored))
+  }
+
+  async fn nullifier_leaves(&self, from: u64) -> Result<Vec<String>, DatabaseError> {
+    let rows = sqlx::query("SELECT nullifier FROM nullifier_leaves WHERE leaf_index >= $1 ORDER BY leaf_index")
+      .bind(from as i64)
+      .fetch_all(&self.pool)
+      .await
+      .map_err(|e| Report::new(DatabaseError::FetchError)
+        .attach_printable(format!("Failed to fetch nullifier leaves: {}", e)))?;
+    rows.iter().map(|row| row.try_get("nullifier").map_err(conversion_error)).collect()
+  }
+
+  async fn flag_double_spend(&self, stored: &NoteNullifierSchema, conflicting: &NoteNullifierSchema) -> Result<(), DatabaseError> {
+    let mut tx = self.begin().await?;
+
+    let flagged = match sqlx::query("UPDATE users SET has_double_spent = $1 WHERE username = $2")
+      .bind(true)
+      .bind(&stored.owner)
+      .execute(&mut *tx)
+      .await
+    {
+      Ok(result) => result,
+      Err(e) => return Err(Report::new(DatabaseError::UpdateError)
+        .attach_printable(format!("Failed to flag user '{}': {}", stored.owner, e))),
+    };
+    if flagged.rows_affected() == 0 {
+      return Err(Report::new(DatabaseError::NotFoundError)
+        .attach_printable(format!("Owner '{}' of nullifier '{}' not found", stored.owner, stored.nullifier)));
+    }
+
+    let flag = new_flag(stored, &conflicting.state);
+    if let Err(e) = sqlx::query(
+      "INSERT INTO double_spend_flags (id, username, nullifier, note, step, state, states, presented_state, flagged_at, status) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)"
+    )
+      .bind(&flag.id)
+      .bind(&flag.username)
+      .bind(&flag.nullifier.nullifier)
+      .bind(&flag.nullifier.note)
+      .bind(flag.nullifier.step as i64)
+      .bind(&flag.nullifier.state)
+      .bind(states_to_json(&flag.nullifier.states)?)
+      .bind(&flag.presented_state)
+      .bind(flag.flagged_at)
+      .bind(flag.status.as_str())
+      .execute(&mut *tx)
+      .await
+    {
+      return Err(insert_error(e, "double-spend flag"));
+    }
+
+    let betrayal = new_betrayal(stored, conflicting);
+    if let Err(e) = sqlx::query(
+      "INSERT INTO betrayals (id, username, nullifier, note, step, state, states, conflicting_owner, conflicting_note, conflicting_step, conflicting_state, detected_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)"
+    )
+      .bind(&betrayal.id)
+      .bind(&betrayal.username)
+      .bind(&betrayal.spent.nullifier)
+      .bind(&betrayal.spent.note)
+      .bind(betrayal.spent.step as i64)
+      .bind(&betrayal.spent.state)
+      .bind(states_to_json(&betrayal.spent.states)?)
+      .bind(&betrayal.conflicting.owner)
+      .bind(&betrayal.conflicting.note)
+      .bind(betrayal.conflicting.step as i64)
+      .bind(&betrayal.conflicting.state)
+      .bind(betrayal.detected_at)
+      .execute(&mut *tx)
+      .await
+    {
+      return Err(insert_error(e, "betrayal"));
+    }
+
+    tx.commit().await.map_err(|e| Report::new(DatabaseError::UpdateError)
+      .attach_printable(format!("Failed to commit flag of user '{}': {}", stored.owner, e)))
+  }
+
+  async fn get_user_betrayals(&self, username: &str) -> Result<Vec<Betrayal>, DatabaseError> {
+    self.fetch_betrayals("username = $1", username).await
+  }
+
+  async fn get_note_betrayals(&self, note: &str) -> Result<Vec<Betrayal>, DatabaseError> {
+    self.fetch_betrayals("note = $1 OR conflicting_note = $1", note).await
+  }
+
+  // Notes
+  async fn store_note(&self, body: &SaveNoteRequestSchema) -> Result<NoteResponse, DatabaseError> {
+    let mut tx = self.begin().await?;
+    let id = new_id();
+
+    if let Err(e) = sqlx::query(
+      "INSERT INTO notes (id, asset_hash, owner, value, step, parent_note, out_index, blind) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)"
+    )
+      .bind(&id)
+      .bind(&body.asset_hash)
+      .bind(&body.owner)
+      .bind(body.value as i64)
+      .bind(body.step as i64)
+      .bind(&body.parent_note)
+      .bind(&body.out_index)
+      .bind(&body.blind)
+      .execute(&mut *tx)
+      .await
+    {
+      return Err(Report::new(DatabaseError::InsertError)
+        .attach_printable(format!("Failed to insert note: {}", e)));
+    }
+
+    if let Err(e) = sqlx::query("INSERT INTO user_notes (user_id, note_id) SELECT id, $1 FROM users WHERE pubkey = $2")
+      .bind(&id)
+      .bind(&body.owner)
+      .execute(&mut *tx)
+      .await
+    {
+      return Err(Report::new(DatabaseError::UpdateError)
+        .attach_printable(format!("Failed to update user's notes: {}", e)));
+    }
+
+    tx.commit().await.map_err(|e| Report::new(DatabaseError::InsertError)
+      .attach_printable(format!("Failed to commit note: {}", e)))?;
+
+    let note = NoteSchema {
+      asset_hash: body.asset_hash.clone(),
+      owner: body.owner.clone(),
+      value: body.value,
+      step: body.step,
+      parent_note: body.parent_note.clone(),
+      out_index: body.out_index.clone(),
+      blind: body.blind.clone(),
+      _id: id_to_bson(&id),
+    };
+
+    Ok(NoteResponse { status: "success", note })
+  }
+
+  async fn get_user_notes(&self, user_pub_key: &str) -> Result<Vec<NoteSchema>, DatabaseError> {
+    let rows = match sqlx::query(
+      "SELECT id, asset_hash, owner, value, step, parent_note, out_index, blind FROM notes WHERE owner = $1"
+    )
+      .bind(user_pub_key)
+      .fetch_all(&self.pool)
+      .await
+    {
+      Ok(rows) => rows,
+      Err(e) => return Err(Report::new(DatabaseError::FetchError)
+        .attach_printable(format!("Failed to fetch user notes: {}", e))),
+    };
+
+    rows.iter().map(row_to_note).collect()
+  }
+
+  // Notes History
+  async fn store_note_history(&self, body: SaveNoteHistoryRequestSchema) -> Result<NoteHistoryResponse, DatabaseError> {
+    let mut tx = self.begin().await?;
+    let note_history = insert_note_history(&mut tx, body).await?;
+    tx.commit().await.map_err(|e| Report::new(DatabaseError::InsertError)
+      .attach_printable(format!("Failed to commit note history: {}", e)))?;
+
+    Ok(NoteHistoryResponse {
+      status: "success",
+      note_history
+    })
+  }
+
+  async fn get_note_history_for_user(&self, username: String) -> Result<Vec<NoteHistorySaved>, DatabaseError> {
+    let mut tx = self.begin().await?;
+    let user = find_user(&mut tx, "username", &username).await?;
+    let user_id = user.id.map(|id| id.to_hex()).unwrap_or_default();
+
+    let rows = match sqlx::query(
+      "SELECT h.id, h.sender, h.address, h.data FROM user_notes n JOIN note_history h ON h.id = n.note_id WHERE n.user_id = $1 ORDER BY n.seq"
+    )
+      .bind(&user_id)
+      .fetch_all(&mut *tx)
+      .await
+    {
+      Ok(rows) => rows,
+      Err(e) => return Err(Report::new(DatabaseError::FetchError)
+        .attach_printable(format!("Failed to fetch note history: {}", e))),
+    };
+    let notes = rows.iter().map(row_to_note_history).collect::<Result<Vec<_>, _>>()?;
+
+    // Remove the notes from the user once they have been handed out
+    if let Err(e) = sqlx::query("DELETE FROM user_notes WHERE user_id = $1")
+      .bind(&user_id)
+      .execute(&mut *tx)
+      .await
+    {
+      return Err(Report::new(DatabaseError::UpdateError)
+        .attach_printable(format!("Failed to remove notes from user: {}", e)));
+    }
+
+    tx.commit().await.map_err(|e| Report::new(DatabaseError::UpdateError)
+      .attach_printable(format!("Failed to remove notes from user: {}", e)))?;
+
+    Ok(notes)
+  }
+
+  async fn create_and_transfer_note_history(
+    &self,
+    owner_username: String,
+    recipient_username: &str,
+    body: SaveNoteHistoryRequestSchema,
+    message: String,
+  ) -> Result<MessageSingleResponse, DatabaseError> {
+    let mut tx = self.begin().await?;
+
+    let stored_note = insert_note_history(&mut tx, SaveNoteHistoryRequestSchema {
+      data: body.data,
+      address: body.address,
+      sender: owner_username.clone(),
+    }).await?;
+    let note_id = match &stored_note._id {
+      Some(Bson::ObjectId(id)) => id.to_hex(),
+      _ => return Err(Report::new(DatabaseError::InsertError)
+        .attach_printable("Stored note history has no id")),
+    };
+
+    if let Err(e) = sqlx::query("DELETE FROM user_notes WHERE note_id = $1 AND user_id IN (SELECT id FROM users WHERE username = $2)")
+      .bind(&note_id)
+      .bind(&owner_username)
+      .execute(&mut *tx)
+      .await
+    {
+      return Err(Report::new(DatabaseError::UpdateError)
+        .attach_printable(format!("Failed to remove note from current owner: {}", e)));
+    }
+
+    let received = match sqlx::query("INSERT INTO user_notes (user_id, note_id) SELECT id, $1 FROM users WHERE username = $2")
+      .bind(&note_id)
+      .bind(recipient_username)
+      .execute(&mut *tx)
+      .await
+    {
+      Ok(result) => result,
+      Err(e) => return Err(Report::new(DatabaseError::UpdateError)
+        .attach_printable(format!("Failed to update user's notes: {}", e))),
+    };
+    // Dropping `tx` rolls back the stored note history.
+    if received.rows_affected() == 0 {
+      return Err(Report::new(DatabaseError::NotFoundError)
+        .attach_printable(format!("Recipient '{}' not found", recipient_username)));
+    }
+
+    let message = insert_message(&mut tx, &MessageRequestSchema {
+      recipient: recipient_username.to_owned(),
+      sender: owner_username,
+      message,
+      attachment_id: stored_note._id.clone(),
+    }).await?;
+
+    tx.commit().await.map_err(|e| Report::new(DatabaseError::UpdateError)
+      .attach_printable(format!("Failed to commit note transfer: {}", e)))?;
+
+    Ok(MessageSingleResponse {
+      status: "success",
+      message
+    })
+  }
+
+  // Challenges
+  async fn create_challenge(&self, username: &str) -> Result<ChallengeSchema, DatabaseError> {
+    let challenge = new_challenge(username, self.auth.challenge_ttl);
+
+    if let Err(e) = sqlx::query("DELETE FROM challenges WHERE expires_at <= $1")
+      .bind(challenge.created_at)
+      .execute(&self.pool)
+      .await
+    {
+      log::warn!("Error removing expired challenges: {:?}", e);
+    }
+
+    match sqlx::query("INSERT INTO challenges (challenge_id, user_id, created_at, expires_at) VALUES ($1, $2, $3, $4)")
+      .bind(&challenge.challenge_id)
+      .bind(&challenge.user_id)
+      .bind(challenge.created_at)
+      .bind(challenge.expires_at)
+      .execute(&self.pool)
+      .await
+    {
+      Ok(_) => Ok(challenge),
+      Err(e) => Err(insert_error(e, "challenge")),
+    }
+  }
+
+  async fn consume_challenge(&self, challenge_id: &str, username: &str) -> Result<ChallengeSchema, DatabaseError> {
+    let now = get_current_timestamp();
+
+    // Deleting is what claims the challenge, of two concurrent logins only one gets the row.
+    let row = match sqlx::query(
+      "DELETE FROM challenges WHERE challenge_id = $1 AND user_id = $2 AND expires_at > $3 RETURNING challenge_id, user_id, created_at, expires_at"
+    )
+      .bind(challenge_id)
+      .bind(username)
+      .bind(now)
+      .fetch_optional(&self.pool)
+      .await
+    {
+      Ok(row) => row,
+      Err(e) => return Err(Report::new(DatabaseError::UpdateError)
+        .attach_printable(format!("Failed to consume challenge: {}", e))),
+    };
+
+    if let Some(row) = row {
+      return Ok(ChallengeSchema {
+        challenge_id: row.try_get("challenge_id").map_err(conversion_error)?,
+        user_id: row.try_get("user_id").map_err(conversion_error)?,
+        created_at: row.try_get("created_at").map_err(conversion_error)?,
+        expires_at: row.try_get("expires_at").map_err(conversion_error)?,
+      });
+    }
+
+    let issued_to_other = match sqlx::query("SELECT user_id FROM challenges WHERE challenge_id = $1 AND user_id <> $2 AND expires_at > $3")
+      .bind(challenge_id)
+      .bind(username)
+      .bind(now)
+      .fetch_optional(&self.pool)
+      .await
+    {
+      Ok(row) => row.is_some(),
+      Err(e) => return Err(Report::new(DatabaseError::FetchError)
+        .attach_printable(format!("Failed to fetch challenge: {}", e))),
+    };
+    Err(challenge_error(challenge_id, issued_to_other))
+  }
+
+  // Nonces
+  async fn compare_and_swap_nonce(&self, username: &str, current: &str, new: &str) -> Result<bool, DatabaseError> {
+    match sqlx::query("UPDATE users SET nonce = $1 WHERE username = $2 AND nonce = $3")
+      .bind(new)
+      .bind(username)
+      .bind(current)
+      .execute(&self.pool)
+      .await
+    {
+      Ok(result) => Ok(result.rows_affected() == 1),
+      Err(e) => Err(Report::new(DatabaseError::UpdateError)
+        .attach_printable(format!("Failed to update nonce of user '{}': {}", username, e))),
+    }
+  }
+
+  // Keys
+  async fn get_key_history(&self, username: &str) -> Result<Vec<KeyRecord>, DatabaseError> {
+    let rows = match sqlx::query(
+      "SELECT username, pubkey, key_type, valid_from, valid_until FROM key_history WHERE username = $1 ORDER BY valid_until"
+    )
+      .bind(username)
+      .fetch_all(&self.pool)
+      .await
+    {
+      Ok(rows) => rows,
+      Err(e) => return Err(Report::new(DatabaseError::FetchError)
+        .attach_printable(format!("Failed to fetch key history of user '{}': {}", username, e))),
+    };
+
+    rows.iter().map(row_to_key_record).collect()
+  }
+
+  async fn replace_key(&self, retired: &KeyRecord, new_key_type: KeyType, new_pubkey: &str) -> Result<bool, DatabaseError> {
+    let mut tx = self.begin().await?;
+
+    let result = match sqlx::query("UPDATE users SET pubkey = $1, key_type = $2 WHERE username = $3 AND pubkey = $4")
+      .bind(new_pubkey)
+      .bind(new_key_type.as_str())
+      .bind(&retired.username)
+      .bind(&retired.pubkey)
+      .execute(&mut *tx)
+      .await
+    {
+      Ok(result) => result,
+      Err(e) => return Err(Report::new(DatabaseError::UpdateError)
+        .attach_printable(format!("Failed to update key of user '{}': {}", retired.username, e))),
+    };
+    if result.rows_affected() != 1 {
+      return Ok(false);
+    }
+
+    if let Err(e) = sqlx::query(
+      "INSERT INTO key_history (username, pubkey, key_type, valid_from, valid_until) VALUES ($1, $2, $3, $4, $5)"
+    )
+      .bind(&retired.username)
+      .bind(&retired.pubkey)
+      .bind(retired.key_type.as_str())
+      .bind(retired.valid_from)
+      .bind(retired.valid_until)
+      .execute(&mut *tx)
+      .await
+    {
+      return Err(insert_error(e, "key history"));
+    }
+
+    tx.commit().await.map_err(|e| Report::new(DatabaseError::UpdateError)
+      .attach_printable(format!("Failed to commit key rotation: {}", e)))?;
+    Ok(true)
+  }
+
+  // Moderation
+  async fn list_flagged_users(&self) -> Result<Vec<User>, DatabaseError> {
+    let mut conn = self.acquire().await?;
+    let rows = match sqlx::query("SELECT username FROM users WHERE has_double_spent = $1 ORDER BY username")
+      .bind(true)
+      .fetch_all(&mut *conn)
+      .await
+    {
+      Ok(rows) => rows,
+      Err(e) => return Err(Report::new(DatabaseError::FetchError)
+        .attach_printable(format!("Failed to fetch flagged users: {}", e))),
+    };
+
+    let mut users = Vec::new();
+    for row in rows.iter() {
+      let username: String = row.try_get("username").map_err(conversion_error)?;
+      users.push(find_user(&mut conn, "username", &username).await?);
+    }
+    Ok(users)
+  }
+
+  async fn get_flags(&self, username: &str) -> Result<Vec<DoubleSpendFlag>, DatabaseError> {
+    let rows = match sqlx::query(
+      "SELECT id, username, nullifier, note, step, state, states, presented_state, flagged_at, status FROM double_spend_flags WHERE username = $1 ORDER BY flagged_at"
+    )
+      .bind(username)
+      .fetch_all(&self.pool)
+      .await
+    {
+      Ok(rows) => rows,
+      Err(e) => return Err(Report::new(DatabaseError::FetchError)
+        .attach_printable(format!("Failed to fetch flags of user '{}': {}", username, e))),
+    };
+
+    rows.iter().map(row_to_flag).collect()
+  }
+
+  async fn moderate(&self, entry: &AuditEntry) -> Result<bool, DatabaseError> {
+    let mut tx = self.begin().await?;
+    let user = find_user(&mut tx, "username", &entry.username).await?;
+
+    let flagged = user.has_double_spent == Some(true);
+    let (user_update, flag_update) = match entry.action {
+      AdminAction::ClearFlag | AdminAction::ConfirmFlag if !flagged => return Ok(false),
+      AdminAction::ClearFlag => (
+        Some("UPDATE users SET has_double_spent = FALSE WHERE username = $1"),
+        Some("UPDATE double_spend_flags SET status = 'cleared' WHERE username = $1 AND status IN ('open', 'confirmed')"),
+      ),
+      AdminAction::ConfirmFlag => (
+        None,
+        Some("UPDATE double_spend_flags SET status = 'confirmed' WHERE username = $1 AND status = 'open'"),
+      ),
+      AdminAction::Suspend => (Some("UPDATE users SET suspended = TRUE WHERE username = $1"), None),
+      AdminAction::Restore => (Some("UPDATE users SET suspended = FALSE WHERE username = $1"), None),
+    };
+
+    for query in [user_update, flag_update].into_iter().flatten() {
+      if let Err(e) = sqlx::query(query).bind(&entry.username).execute(&mut *tx).await {
+        return Err(Report::new(DatabaseError::UpdateError)
+          .attach_printable(format!("Failed to {} user '{}': {}", entry.action.as_str(), entry.username, e)));
+      }
+    }
+
+    if let Err(e) = sqlx::query(
+      "INSERT INTO admin_audit_log (id, admin, action, username, reason, at) VALUES ($1, $2, $3, $4, $5, $6)"
+    )
+      .bind(&entry.id)
+      .bind(&entry.admin)
+      .bind(entry.action.as_str())
+      .bind(&entry.username)
+      .bind(&entry.reason)
+      .bind(entry.at)
+      .execute(&mut *tx)
+      .await
+    {
+      return Err(insert_error(e, "audit entry"));
+    }
+
+    tx.commit().await.map_err(|e| Report::new(DatabaseError::UpdateError)
+      .attach_printable(format!("Failed to commit moderation of user '{}': {}", entry.username, e)))?;
+    Ok(true)
+  }
+
+  async fn get_audit_log(&self) -> Result<Vec<AuditEntry>, DatabaseError> {
+    let rows = match sqlx::query("SELECT id, admin, action, username, reason, at FROM admin_audit_log ORDER BY at, seq")
+      .fetch_all(&self.pool)
+      .await
+    {
+      Ok(rows) => rows,
+      Err(e) => return Err(Report::new(DatabaseError::FetchError)
+        .attach_printable(format!("Failed to fetch audit log: {}", e))),
+    };
+
+    rows.iter().map(row_to_audit_entry).collect()
+  }
+}
+
+#[async_trait]
+impl SessionStore for SqlDB {
+  async fn create_session(&self, username: &str) -> Result<Session, DatabaseError> {
+    let session = Session::new(username, self.auth.session_ttl);
+
+    // Expired rows are only filtered on read, clear them out as new ones come in.
+    if let Err(e) = sqlx::query("DELETE FROM sessions WHERE expires_at <= $1")
+      .bind(session.created_at)
+      .execute(&self.pool)
+      .await
+    {
+      log::warn!("Error removing expired sessions: {:?}", e);
+    }
+
+    match sqlx::query("INSERT INTO sessions (id, token, username, created_at, expires_at) VALUES ($1, $2, $3, $4, $5)")
+      .bind(&session.id)
+      .bind(&session.token)
+      .bind(&session.username)
+      .bind(session.created_at)
+      .bind(session.expires_at)
+      .execute(&self.pool)
+      .await
+    {
+      Ok(_) => Ok(session),
+      Err(e) => Err(insert_error(e, "session")),
+    }
+  }
+
+  async fn get_session(&self, token: &str) -> Result<Option<Session>, DatabaseError> {
+    match sqlx::query("SELECT id, token, username, created_at, expires_at FROM sessions WHERE token = $1 AND expires_at > $2")
+      .bind(token)
+      .bind(get_current_timestamp())
+      .fetch_optional(&self.pool)
+      .await
+    {
+      Ok(Some(row)) => row_to_session(&row).map(Some),
+      Ok(None) => Ok(None),
+      Err(e) => Err(Report::new(DatabaseError::FetchError)
+        .attach_printable(format!("Failed to fetch session: {}", e))),
+    }
+  }
+
+  async fn list_sessions(&self, username: &str) -> Result<Vec<Session>, DatabaseError> {
+    let rows = match sqlx::query("SELECT id, token, username, created_at, expires_at FROM sessions WHERE username = $1 AND expires_at > $2 ORDER BY created_at")
+      .bind(username)
+      .bind(get_current_timestamp())
+      .fetch_all(&self.pool)
+      .await
+    {
+      Ok(rows) => rows,
+      Err(e) => return Err(Report::new(DatabaseError::FetchError)
+        .attach_printable(format!("Failed to fetch sessions of '{}': {}", username, e))),
+    };
+
+    rows.iter().map(row_to_session).collect()
+  }
+
+  async fn revoke_session(&self, username: &str, id: &str) -> Result<bool, DatabaseError> {
+    match sqlx::query("DELETE FROM sessions WHERE id = $1 AND username = $2")
+      .bind(id)
+      .bind(username)
+      .execute(&self.pool)
+      .await
+    {
+      Ok(result) => Ok(result.rows_affected() > 0),
+      Err(e) => Err(Report::new(DatabaseError::UpdateError)
+        .attach_printable(format!("Failed to revoke session: {}", e))),
+    }
+  }
+}
@@ -0,0 +1,410 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use bson::oid::ObjectId;
+use error_stack::{Report, Result};
+use chrono::Utc;
+use rand::{Rng, distributions::Alphanumeric};
+use serde::Serialize;
+use crate::crypto::{verify_signature, KeyType};
+use crate::session::SessionStore;
+use crate::routes::{
+  error::DatabaseError,
+  response::{
+    MessageSingleResponse,
+    NoteHistoryResponse,
+    NoteResponse,
+    NullifierResponseData,
+    NullifierVerification,
+    SpendResponse,
+    UserSingleResponse
+  },
+  schema::{
+    AdminAction, AuditEntry, Betrayal, ChallengeSchema, CreateUserSchema, DoubleSpendFlag, FlagStatus, KeyRecord, MessageRequestSchema, MessageSchema, NoteHistorySaved, NoteNullifierSchema, NoteSchema, NullifierState, NullifierStatus, RotateKeyRequest, SaveNoteHistoryRequestSchema, SaveNoteRequestSchema, SpendOutcome, User
+  }
+};
+
+/// A schema migration known to a backend and whether it has been applied.
+#[derive(Debug, Clone, Serialize)]
+pub struct MigrationStatus {
+  pub version: i64,
+  pub description: String,
+  pub applied: bool,
+}
+
+/// Storage backend behind every route handler.
+///
+/// `IOUServiceDB` implements it on top of MongoDB, `SqlDB` on SQLite or
+/// PostgreSQL and `MemoryDB` keeps everything in process for local runs.
+/// Every backend also stores the login sessions.
+#[async_trait]
+pub trait IOUStore: SessionStore {
+  /// Round trip to the database, used by the readiness probe.
+  async fn ping(&self) -> Result<(), DatabaseError>;
+
+  // Migrations
+  async fn migration_status(&self) -> Result<Vec<MigrationStatus>, DatabaseError>;
+  /// Applies pending migrations. Runs once at startup, before the server accepts requests.
+  async fn migrate(&self) -> Result<Vec<MigrationStatus>, DatabaseError>;
+
+  // Users
+  async fn get_user_with_username(&self, username: &str) -> Result<UserSingleResponse, DatabaseError>;
+  async fn get_user_with_address(&self, address: &str) -> Result<UserSingleResponse, DatabaseError>;
+  async fn create_user(&self, body: &CreateUserSchema) -> Result<UserSingleResponse, DatabaseError>;
+
+  // Messages
+  async fn send_message(&self, body: &MessageRequestSchema) -> Result<MessageSingleResponse, DatabaseError>;
+  async fn get_unread_messages(&self, username: &str) -> Result<Vec<MessageSchema>, DatabaseError>;
+
+  // Nullifiers
+  async fn store_nullifier(&self, body: &NoteNullifierSchema) -> Result<NullifierResponseData, DatabaseError>;
+  async fn find_nullifier(&self, nullifier: &str) -> Result<Option<NoteNullifierSchema>, DatabaseError>;
+  /// Adds `body.state` at `body.step` to the state vector of `body.nullifier`, storing the
+  /// nullifier on its first spend. Writes only when the stored nullifier is `body.owner`'s
+  /// and `spend_outcome` of the vector is `Accepted`, and returns the record as it was
+  /// before, `None` when it was new. Must be
+  /// atomic, backends rely on unique indexes rather than a read before the write.
+  async fn record_nullifier_state(&self, body: &NoteNullifierSchema) -> Result<Option<NoteNullifierSchema>, DatabaseError>;
+  /// Nullifiers in the order they were first stored, from the `from`th on. The position
+  /// of a nullifier is its leaf in the nullifier Merkle tree and never changes, backends
+  /// assign it in the write that stores the nullifier.
+  async fn nullifier_leaves(&self, from: u64) -> Result<Vec<String>, DatabaseError>;
+  /// Flags the owner of `stored` for spending it again as `conflicting` and records the
+  /// flag and the betrayal, in one step.
+  async fn flag_double_spend(&self, stored: &NoteNullifierSchema, conflicting: &NoteNullifierSchema) -> Result<(), DatabaseError>;
+  /// Betrayals of `username`, oldest first.
+  async fn get_user_betrayals(&self, username: &str) -> Result<Vec<Betrayal>, DatabaseError>;
+  /// Betrayals where either spend carried `note`, oldest first.
+  async fn get_note_betrayals(&self, note: &str) -> Result<Vec<Betrayal>, DatabaseError>;
+
+  // Notes
+  async fn store_note(&self, body: &SaveNoteRequestSchema) -> Result<NoteResponse, DatabaseError>;
+  async fn get_user_notes(&self, user_pub_key: &str) -> Result<Vec<NoteSchema>, DatabaseError>;
+
+  // Notes History
+  async fn store_note_history(&self, body: SaveNoteHistoryRequestSchema) -> Result<NoteHistoryResponse, DatabaseError>;
+  async fn get_note_history_for_user(&self, username: String) -> Result<Vec<NoteHistorySaved>, DatabaseError>;
+  async fn create_and_transfer_note_history(
+    &self,
+    owner_username: String,
+    recipient_username: &str,
+    body: SaveNoteHistoryRequestSchema,
+    message: String,
+  ) -> Result<MessageSingleResponse, DatabaseError>;
+
+  // Challenges
+  /// Issues a challenge for `username` that expires after `auth.challenge_ttl` seconds.
+  async fn create_challenge(&self, username: &str) -> Result<ChallengeSchema, DatabaseError>;
+  /// Atomically deletes and returns the challenge if it was issued to `username` and
+  /// has not expired. Fails with `ChallengeUserMismatchError` when it was issued to
+  /// another user and `ChallengeReplayError` when it is unknown, expired or used.
+  async fn consume_challenge(&self, challenge_id: &str, username: &str) -> Result<ChallengeSchema, DatabaseError>;
+
+  // Nonces
+  /// Sets the user's nonce to `new` only if it is still `current`. Returns false when
+  /// another request changed it first.
+  async fn compare_and_swap_nonce(&self, username: &str, current: &str, new: &str) -> Result<bool, DatabaseError>;
+
+  // Keys
+  /// Retired keys of `username`, oldest first.
+  async fn get_key_history(&self, username: &str) -> Result<Vec<KeyRecord>, DatabaseError>;
+  /// Adds `retired` to the history and makes `new_pubkey` the user's key, only if the
+  /// user's key is still `retired.pubkey`. Returns false when it changed first.
+  async fn replace_key(&self, retired: &KeyRecord, new_key_type: KeyType, new_pubkey: &str) -> Result<bool, DatabaseError>;
+
+  // Moderation
+  /// Users flagged for double spending, confirmed or still open, by username.
+  async fn list_flagged_users(&self) -> Result<Vec<User>, DatabaseError>;
+  /// Every double-spend flag raised on `username`, oldest first.
+  async fn get_flags(&self, username: &str) -> Result<Vec<DoubleSpendFlag>, DatabaseError>;
+  /// Applies `entry.action` to `entry.username` and appends `entry` to the audit trail
+  /// in one step. Returns false without writing when clearing or confirming a user
+  /// that is not flagged.
+  async fn moderate(&self, entry: &AuditEntry) -> Result<bool, DatabaseError>;
+  /// The audit trail, oldest first.
+  async fn get_audit_log(&self) -> Result<Vec<AuditEntry>, DatabaseError>;
+
+  /// Whether `nullifier` was spent, and if so in `state` or only in others. Never writes.
+  async fn verify_nullifier(&self, nullifier: &str, state: &str) -> Result<NullifierVerification, DatabaseError> {
+    let stored = self.find_nullifier(nullifier).await?;
+    let status = match &stored {
+      None => NullifierStatus::Unknown,
+      Some(stored) if stored.states.iter().any(|entry| entry.state == state) => NullifierStatus::SpentInThisState,
+      Some(_) => NullifierStatus::SpentInAnotherState,
+    };
+    Ok(NullifierVerification { status, nullifier: stored })
+  }
+
+  /// Spends `body.nullifier` at `body.step` in `body.state` with a single write, so two
+  /// concurrent spends can never both be accepted. A conflicting spend by the owner flags
+  /// them, a spend of another user's nullifier fails with `NullifierOwnerMismatchError`.
+  async fn spend_nullifier(&self, body: &NoteNullifierSchema) -> Result<SpendResponse, DatabaseError> {
+    let Some(mut stored) = self.record_nullifier_state(body).await? else {
+      return Ok(SpendResponse { outcome: SpendOutcome::Accepted, nullifier: first_spend(body) });
+    };
+
+    // Checked before the outcome, otherwise anyone who learns a note could get its
+    // owner flagged by spending it in another state.
+    if stored.owner != body.owner {
+      return Err(Report::new(DatabaseError::NullifierOwnerMismatchError)
+        .attach_printable(format!("User '{}' spent nullifier '{}' of '{}'", body.owner, body.nullifier, stored.owner)));
+    }
+
+    let outcome = spend_outcome(&stored.states, body.step, &body.state);
+    match outcome {
+      SpendOutcome::Accepted => push_state(&mut stored.states, body.step, &body.state),
+      SpendOutcome::DuplicateSameState => {},
+      SpendOutcome::ConflictingState => {
+        self.flag_double_spend(&stored, &first_spend(body)).await?;
+        log::warn!("User '{}' attempted to double spend nullifier '{}', their account is flagged", stored.owner, body.nullifier);
+      },
+    }
+    Ok(SpendResponse { outcome, nullifier: stored })
+  }
+
+  /// Every key of `username`, oldest first, ending with the current one.
+  async fn list_keys(&self, username: &str) -> Result<Vec<KeyRecord>, DatabaseError> {
+    let user = self.get_user_with_username(username).await?.user;
+    let mut keys = self.get_key_history(username).await?;
+    keys.push(current_key(username, &user, keys.last())?);
+    Ok(keys)
+  }
+
+  /// The key of `username` that was valid at the unix timestamp `at`.
+  async fn key_valid_at(&self, username: &str, at: i64) -> Result<KeyRecord, DatabaseError> {
+    // Validity intervals follow each other, the first key still valid at `at` is the one.
+    let keys = self.list_keys(username).await?;
+    match keys.into_iter().find(|key| key.valid_until.map_or(true, |until| at < until)) {
+      Some(key) => Ok(key),
+      None => Err(Report::new(DatabaseError::NotFoundError)
+        .attach_printable(format!("User '{}' has no key valid at {}", username, at))),
+    }
+  }
+
+  /// Replaces the user's key after checking the handover signature of the current key
+  /// over `rotation_message`. Returns false for a wrong signature. A key the user had
+  /// before fails with `DuplicateKeyError`, a concurrent rotation with `ConcurrentUpdateError`.
+  async fn rotate_key(&self, request: &RotateKeyRequest) -> Result<bool, DatabaseError> {
+    let keys = self.list_keys(&request.username).await?;
+    let current = match keys.last() {
+      Some(key) => key.clone(),
+      None => return Err(Report::new(DatabaseError::NotFoundError)
+        .attach_printable(format!("User '{}' has no key", request.username))),
+    };
+
+    // Never going back to an old key also means a handover can't be replayed.
+    if keys.iter().any(|key| key.pubkey == request.new_pubkey) {
+      return Err(Report::new(DatabaseError::DuplicateKeyError)
+        .attach_printable(format!("User '{}' already used this key", request.username)));
+    }
+
+    let message = rotation_message(&request.username, &current.pubkey, request.new_key_type, &request.new_pubkey);
+    if !verify_signature(current.key_type, &current.pubkey, &request.signature_hex, &message)? {
+      return Ok(false);
+    }
+
+    let retired = KeyRecord { valid_until: Some(Utc::now().timestamp()), ..current };
+    if !self.replace_key(&retired, request.new_key_type, &request.new_pubkey).await? {
+      return Err(Report::new(DatabaseError::ConcurrentUpdateError)
+        .attach_printable(format!("Key of user '{}' was rotated concurrently", request.username)));
+    }
+    Ok(true)
+  }
+
+  /// Consumes the challenge, then checks `signature_hex` over it against the user's key
+  /// at the time the challenge was issued. A challenge issued before a rotation fails with
+  /// `ChallengeReplayError`, the retired key can no longer log in.
+  /// The challenge is spent even when the signature is wrong.
+  async fn authenticate_user(&self, username: &str, signature_hex: &str, challenge_id: &str) -> Result<bool, DatabaseError> {
+    let challenge = self.consume_challenge(challenge_id, username).await?;
+
+    let key = match self.key_valid_at(username, challenge.created_at).await {
+      Ok(key) => key,
+      Err(e) => return Err(e.change_context(DatabaseError::AuthenticationError)
+        .attach_printable("User not found")),
+    };
+    if key.valid_until.is_some_and(|until| until <= Utc::now().timestamp()) {
+      return Err(Report::new(DatabaseError::ChallengeReplayError)
+        .attach_printable(format!("Key of user '{}' was rotated after the challenge was issued", username)));
+    }
+
+    verify_signature(key.key_type, &key.pubkey, signature_hex, challenge.challenge_id.as_bytes())
+  }
+
+  /// Checks a signed request: `nonce` must be above the user's stored nonce and
+  /// `signature_hex` must be the user's signature over `message`. On success the
+  /// stored nonce becomes `nonce`, so the same signature is never accepted twice.
+  /// Stale or concurrently used nonces fail with `NonceReplayError`.
+  async fn authenticate_request(&self, username: &str, nonce: u64, signature_hex: &str, message: &[u8]) -> Result<bool, DatabaseError> {
+    let user = match self.get_user_with_username(username).await {
+      Ok(response) => response.user,
+      Err(e) => return Err(e.change_context(DatabaseError::AuthenticationError)
+        .attach_printable("User not found")),
+    };
+
+    let current = user.nonce.unwrap_or_default();
+    let current_value = if current.is_empty() {
+      0
+    } else {
+      match current.parse::<u64>() {
+        Ok(value) => value,
+        Err(e) => return Err(Report::new(DatabaseError::ConversionError)
+          .attach_printable(format!("Nonce of user '{}' is not a number: {}", username, e))),
+      }
+    };
+    if nonce <= current_value {
+      return Err(Report::new(DatabaseError::NonceReplayError)
+        .attach_printable(format!("Nonce {} is not above the current nonce {}", nonce, current_value)));
+    }
+
+    let public_key = match user.pubkey {
+      Some(key) => key,
+      None => return Err(Report::new(DatabaseError::ConversionError)
+        .attach_printable(format!("User '{}' has no pubkey", username))),
+    };
+    if !verify_signature(user.key_type.unwrap_or_default(), &public_key, signature_hex, message)? {
+      return Ok(false);
+    }
+
+    // Two requests signed with the same or racing nonces both pass the check above,
+    // only the one that swaps the stored nonce is accepted.
+    if !self.compare_and_swap_nonce(username, &current, &nonce.to_string()).await? {
+      return Err(Report::new(DatabaseError::NonceReplayError)
+        .attach_printable(format!("Nonce of user '{}' changed concurrently", username)));
+    }
+    Ok(true)
+  }
+}
+
+/// Shared handle to the configured backend, passed to handlers as an `Extension`.
+pub type Store = Arc<dyn IOUStore>;
+
+/// The bytes the current key signs to hand over to `new_pubkey`.
+pub fn rotation_message(username: &str, current_pubkey: &str, new_key_type: KeyType, new_pubkey: &str) -> Vec<u8> {
+  format!("rotate_key\n{}\n{}\n{}\n{}", username, current_pubkey, new_key_type.as_str(), new_pubkey).into_bytes()
+}
+
+// The current key of `user`, valid since the last rotation.
+fn current_key(username: &str, user: &User, previous: Option<&KeyRecord>) -> Result<KeyRecord, DatabaseError> {
+  let pubkey = match &user.pubkey {
+    Some(key) => key.clone(),
+    None => return Err(Report::new(DatabaseError::ConversionError)
+      .attach_printable(format!("User '{}' has no pubkey", username))),
+  };
+
+  Ok(KeyRecord {
+    username: username.to_owned(),
+    pubkey,
+    key_type: user.key_type.unwrap_or_default(),
+    valid_from: previous.and_then(|key| key.valid_until),
+    valid_until: None,
+  })
+}
+
+/// A new random challenge for `username` that expires `ttl` seconds from now.
+pub(crate) fn new_challenge(username: &str, ttl: i64) -> ChallengeSchema {
+  let now = Utc::now().timestamp();
+  let challenge_id: String = rand::thread_rng()
+    .sample_iter(&Alphanumeric)
+    .take(32)
+    .map(char::from)
+    .collect();
+
+  ChallengeSchema {
+    challenge_id,
+    user_id: username.to_owned(),
+    created_at: now,
+    expires_at: now + ttl,
+  }
+}
+
+/// `body` as the first spend of its nullifier, with a state vector of its own step and state.
+pub(crate) fn first_spend(body: &NoteNullifierSchema) -> NoteNullifierSchema {
+  NoteNullifierSchema {
+    states: vec![NullifierState { step: body.step, state: body.state.clone() }],
+    ..body.clone()
+  }
+}
+
+/// How a spend at `step` in `state` relates to the state vector `states`: a step holds
+/// one state and a state belongs to one step.
+pub fn spend_outcome(states: &[NullifierState], step: i32, state: &str) -> SpendOutcome {
+  if states.iter().any(|entry| entry.step == step && entry.state == state) {
+    SpendOutcome::DuplicateSameState
+  } else if states.iter().any(|entry| entry.step == step || entry.state == state) {
+    SpendOutcome::ConflictingState
+  } else {
+    SpendOutcome::Accepted
+  }
+}
+
+/// Adds `state` at `step`, keeping `states` ordered by step.
+pub(crate) fn push_state(states: &mut Vec<NullifierState>, step: i32, state: &str) {
+  states.push(NullifierState { step, state: state.to_owned() });
+  states.sort_by_key(|entry| entry.step);
+}
+
+/// Evidence that `stored` was spent again as `conflicting`, detected now.
+pub(crate) fn new_betrayal(stored: &NoteNullifierSchema, conflicting: &NoteNullifierSchema) -> Betrayal {
+  Betrayal {
+    id: ObjectId::new().to_hex(),
+    username: stored.owner.clone(),
+    spent: stored.clone(),
+    conflicting: conflicting.clone(),
+    detected_at: Utc::now().timestamp(),
+  }
+}
+
+/// An open flag on the owner of `stored`, which was presented again with `presented_state`.
+pub(crate) fn new_flag(stored: &NoteNullifierSchema, presented_state: &str) -> DoubleSpendFlag {
+  DoubleSpendFlag {
+    id: ObjectId::new().to_hex(),
+    username: stored.owner.clone(),
+    nullifier: stored.clone(),
+    presented_state: presented_state.to_owned(),
+    flagged_at: Utc::now().timestamp(),
+    status: FlagStatus::Open,
+  }
+}
+
+/// A new audit trail entry for `admin` applying `action` to `username`.
+pub fn audit_entry(admin: &str, action: AdminAction, username: &str, reason: &str) -> AuditEntry {
+  AuditEntry {
+    id: ObjectId::new().to_hex(),
+    admin: admin.to_owned(),
+    action,
+    username: username.to_owned(),
+    reason: reason.to_owned(),
+    at: Utc::now().timestamp(),
+  }
+}
+
+/// Attempts a backend makes to give a first spend the next leaf of the nullifier tree.
+/// Leaves are handed out in commit order, so concurrent first spends contend for the
+/// same one and all but one of them retry.
+pub(crate) const MAX_LEAF_ATTEMPTS: u32 = 12;
+
+/// Waits before retry `attempt` of a spend that lost its leaf, with jitter so the
+/// losers of one round don't collide again.
+pub(crate) async fn leaf_backoff(attempt: u32) {
+  let ceiling = 2u64 << attempt.min(7);
+  let delay = rand::thread_rng().gen_range(1..=ceiling);
+  tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+}
+
+/// The error for a spend that lost its leaf on every attempt. Nothing was stored and
+/// the spend can be retried.
+pub(crate) fn leaf_contention_error(nullifier: &str) -> Report<DatabaseError> {
+  Report::new(DatabaseError::LeafContentionError)
+    .attach_printable(format!("Nullifier '{}' lost its tree leaf {} times", nullifier, MAX_LEAF_ATTEMPTS))
+}
+
+/// The error for a challenge that could not be consumed by its requester.
+pub(crate) fn challenge_error(challenge_id: &str, issued_to_other: bool) -> Report<DatabaseError> {
+  if issued_to_other {
+    Report::new(DatabaseError::ChallengeUserMismatchError)
+      .attach_printable(format!("Challenge '{}' was issued to another user", challenge_id))
+  } else {
+    Report::new(DatabaseError::ChallengeReplayError)
+      .attach_printable(format!("Challenge '{}' is unknown, expired or already used", challenge_id))
+  }
+}
@@ -0,0 +1,139 @@
+mod common;
+
+use axum::http::StatusCode;
+use common::{pubkey, TestApp};
+use serde_json::{json, Value};
+use service_http::{
+  config::Config,
+  crypto::{field_to_hex, note_nullifier},
+  routes::schema::NoteNullifierSchema,
+};
+
+const BOB: u8 = 2;
+
+// A note of bob's serialized as JSON, and its nullifier.
+fn bob_note() -> (String, String) {
+  let note = json!({
+    "asset_hash": "0x1", "owner": pubkey(BOB), "value": 1, "step": 1,
+    "parent_note": "", "out_index": "0", "blind": "0x2",
+  });
+  let nullifier = note_nullifier(&serde_json::from_value(note.clone()).unwrap()).unwrap();
+  (note.to_string(), field_to_hex(nullifier))
+}
+
+struct Fixture {
+  app: TestApp,
+  admin: String,
+  bob: String,
+}
+
+async fn fixture() -> Fixture {
+  let mut config = Config::default();
+  config.auth.admins = vec!["admin".to_owned()];
+  let app = TestApp::new(&config);
+  let admin = app.user("admin", 1).await;
+  let bob = app.user("bob", BOB).await;
+
+  let (note, nullifier) = bob_note();
+  app.store.store_nullifier(&NoteNullifierSchema {
+    nullifier,
+    note,
+    step: 1,
+    owner: "bob".to_owned(),
+    state: "state-1".to_owned(),
+    states: Vec::new(),
+  }).await.unwrap();
+
+  Fixture {
+    admin: app.login(&admin).await,
+    bob: app.login(&bob).await,
+    app,
+  }
+}
+
+fn review(reason: &str) -> Value {
+  json!({ "username": "bob", "reason": reason })
+}
+
+#[tokio::test]
+async fn flags_are_reviewed_with_evidence() {
+  let fixture = fixture().await;
+  let (app, admin) = (&fixture.app, fixture.admin.as_str());
+
+  let (status, _) = app.send("/admin/confirm_flag", Some(admin), review("nothing to confirm")).await;
+  assert_eq!(status, StatusCode::CONFLICT);
+
+  let (note, nullifier) = bob_note();
+  let spend_again = json!({ "nullifier": nullifier, "note": note, "step": 1, "owner": "bob", "state": "state-2" });
+  app.send("/spend", Some(&fixture.bob), spend_again).await;
+
+  let (_, flagged) = app.send("/admin/flagged_users", Some(admin), Value::Null).await;
+  assert_eq!(flagged[0]["username"], "bob");
+
+  let (status, evidence) = app.send("/admin/flag_evidence", Some(admin), json!({ "username": "bob" })).await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(evidence[0]["nullifier"]["nullifier"], bob_note().1);
+  assert_eq!(evidence[0]["presented_state"], "state-2");
+  assert_eq!(evidence[0]["status"], "open");
+
+  let (status, user) = app.send("/admin/confirm_flag", Some(admin), review("same state spent twice")).await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(user["has_double_spent"], true);
+  let (_, evidence) = app.send("/admin/flag_evidence", Some(admin), json!({ "username": "bob" })).await;
+  assert_eq!(evidence[0]["status"], "confirmed");
+
+  let (status, user) = app.send("/admin/clear_flag", Some(admin), review("client retry, not a double spend")).await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(user["has_double_spent"], false);
+  let (_, evidence) = app.send("/admin/flag_evidence", Some(admin), json!({ "username": "bob" })).await;
+  assert_eq!(evidence[0]["status"], "cleared");
+  let (_, flagged) = app.send("/admin/flagged_users", Some(admin), Value::Null).await;
+  assert_eq!(flagged, json!([]));
+
+  let (status, body) = app.send("/admin/clear_flag", Some(admin), review("again")).await;
+  assert_eq!(status, StatusCode::CONFLICT);
+  assert_eq!(body["code"], "NOT_FLAGGED");
+}
+
+#[tokio::test]
+async fn suspended_users_are_locked_out() {
+  let fixture = fixture().await;
+  let (app, admin, bob) = (&fixture.app, fixture.admin.as_str(), fixture.bob.as_str());
+
+  let (status, _) = app.send("/admin/suspend_user", Some(admin), review(" ")).await;
+  assert_eq!(status, StatusCode::BAD_REQUEST);
+
+  let (status, user) = app.send("/admin/suspend_user", Some(admin), review("under investigation")).await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(user["suspended"], true);
+
+  let (status, body) = app.send("/validate_session", Some(bob), Value::Null).await;
+  assert_eq!(status, StatusCode::FORBIDDEN);
+  assert_eq!(body["code"], "ACCOUNT_SUSPENDED");
+
+  app.send("/admin/restore_user", Some(admin), review("cleared by support")).await;
+  let (status, _) = app.send("/validate_session", Some(bob), Value::Null).await;
+  assert_eq!(status, StatusCode::OK);
+
+  let (status, body) = app.send("/admin/suspend_user", Some(admin), json!({ "username": "nobody", "reason": "spam" })).await;
+  assert_eq!(status, StatusCode::NOT_FOUND);
+  assert_eq!(body["code"], "USER_NOT_FOUND");
+}
+
+#[tokio::test]
+async fn admin_actions_are_audited() {
+  let fixture = fixture().await;
+  let (app, admin) = (&fixture.app, fixture.admin.as_str());
+
+  app.send("/admin/suspend_user", Some(admin), review("under investigation")).await;
+  app.send("/admin/restore_user", Some(admin), review("cleared by support")).await;
+  // Rejected actions leave no trace.
+  app.send("/admin/clear_flag", Some(admin), review("not flagged")).await;
+
+  let (status, log) = app.send("/admin/audit_log", Some(admin), Value::Null).await;
+  assert_eq!(status, StatusCode::OK);
+  let entries: Vec<(&str, &str, &str)> = log.as_array().unwrap().iter()
+    .map(|entry| (entry["admin"].as_str().unwrap(), entry["action"].as_str().unwrap(), entry["reason"].as_str().unwrap()))
+    .collect();
+  assert_eq!(entries, [("admin", "suspend", "under investigation"), ("admin", "restore", "cleared by support")]);
+}
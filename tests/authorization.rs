@@ -0,0 +1,210 @@
+mod common;
+
+use axum::http::StatusCode;
+use common::{signed, TestApp, User};
+use serde_json::{json, Value};
+use service_http::{
+  api_routes,
+  config::Config,
+  crypto::{field_to_hex, note_nullifier},
+};
+
+// Anyone may call these.
+const PUBLIC: &[&str] = &[
+  "/healthz", "/readyz", "/metrics", "/get_user", "/create_user", "/verify_nullifier", "/nullifier_history", "/nullifier_root", "/nullifier_proof", "/nullifier_absence", "/auth", "/get_session",
+  "/get_notes", "/rotate_key", "/key_history", "/betrayals_for_user", "/betrayals_for_note",
+];
+
+// These need a session or signature and only ever act on the caller's own data.
+const AUTHENTICATED: &[&str] = &["/validate_session", "/logout", "/list_sessions", "/revoke_session"];
+
+// Only for users in `auth.admins`.
+const ADMIN: &[&str] = &[
+  "/admin/flagged_users", "/admin/flag_evidence", "/admin/clear_flag", "/admin/confirm_flag",
+  "/admin/suspend_user", "/admin/restore_user", "/admin/audit_log",
+];
+
+type BodyFor = fn(&User) -> Value;
+
+// Routes whose body names the user they act for, with a body acting for `actor`.
+fn owner_bound() -> Vec<(&'static str, BodyFor)> {
+  vec![
+    ("/send_message", |actor| json!({ "recipient": "carol", "sender": actor.username, "message": "hi", "attachment_id": null })),
+    ("/read_messages", |actor| json!({ "username": actor.username })),
+    ("/store_note", |actor| json!({
+      "asset_hash": "0x1", "owner": actor.pubkey, "value": 1, "step": 0,
+      "parent_note": "", "out_index": "0", "blind": "0x2",
+    })),
+    ("/store_nullifier", |actor| spend_of(actor, "0x3", format!("state-{}", actor.username))),
+    ("/spend", |actor| spend_of(actor, "0x4", format!("spent-state-{}", actor.username))),
+    ("/create_and_transfer_note_history", |actor| json!({
+      "owner_username": actor.username, "recipient_username": "carol", "message": "for you",
+      "note_history": { "data": [1, 2, 3], "address": "carol-address", "sender": actor.username },
+    })),
+    ("/get_note_history_for_user", |actor| json!({ "username": actor.username })),
+  ]
+}
+
+// A spend of a note of `actor`, with the nullifier the server recomputes from it.
+fn spend_of(actor: &User, blind: &str, state: String) -> Value {
+  let note = json!({
+    "asset_hash": "0x1", "owner": actor.pubkey, "value": 1, "step": 0,
+    "parent_note": "", "out_index": "0", "blind": blind,
+  });
+  let nullifier = note_nullifier(&serde_json::from_value(note.clone()).unwrap()).unwrap();
+  json!({
+    "nullifier": field_to_hex(nullifier), "note": note.to_string(), "step": 0,
+    "owner": actor.username, "state": state,
+  })
+}
+
+struct Fixture {
+  app: TestApp,
+  alice: User,
+  bob: User,
+  admin: User,
+}
+
+async fn fixture() -> Fixture {
+  let mut config = Config::default();
+  config.auth.admins = vec!["admin".to_owned()];
+  let app = TestApp::new(&config);
+  let alice = app.user("alice", 1).await;
+  let bob = app.user("bob", 2).await;
+  app.user("carol", 3).await;
+  let admin = app.user("admin", 4).await;
+  Fixture { app, alice, bob, admin }
+}
+
+#[test]
+fn every_route_has_a_policy() {
+  let bound: Vec<&str> = owner_bound().into_iter().map(|(path, _)| path).collect();
+  for route in api_routes() {
+    let policies = [
+      PUBLIC.contains(&route.path),
+      AUTHENTICATED.contains(&route.path),
+      bound.contains(&route.path),
+      ADMIN.contains(&route.path),
+    ];
+    assert_eq!(policies.iter().filter(|&&p| p).count(), 1, "{} needs exactly one authorization policy", route.path);
+  }
+}
+
+#[tokio::test]
+async fn public_routes_need_no_credentials() {
+  let fixture = fixture().await;
+  for path in PUBLIC {
+    let (status, _) = fixture.app.send(path, None, json!({})).await;
+    assert!(status != StatusCode::UNAUTHORIZED && status != StatusCode::FORBIDDEN, "{} answered {}", path, status);
+  }
+}
+
+#[tokio::test]
+async fn authenticated_routes_reject_anonymous_callers() {
+  let fixture = fixture().await;
+  for path in AUTHENTICATED {
+    let (status, body) = fixture.app.send(path, None, json!({ "session_id": "x" })).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", path);
+    assert_eq!(body["code"], "UNAUTHORIZED", "{}", path);
+  }
+}
+
+#[tokio::test]
+async fn owner_bound_routes_reject_anonymous_callers() {
+  let fixture = fixture().await;
+  for (path, body) in owner_bound() {
+    let (status, _) = fixture.app.send(path, None, body(&fixture.alice)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", path);
+  }
+}
+
+#[tokio::test]
+async fn owner_bound_routes_reject_impersonation() {
+  let fixture = fixture().await;
+  let bob = fixture.app.login(&fixture.bob).await;
+  for (path, body) in owner_bound() {
+    let (status, response) = fixture.app.send(path, Some(&bob), body(&fixture.alice)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{}", path);
+    assert_eq!(response["code"], "FORBIDDEN", "{}", path);
+  }
+}
+
+#[tokio::test]
+async fn owner_bound_routes_accept_the_owner() {
+  let fixture = fixture().await;
+  let alice = fixture.app.login(&fixture.alice).await;
+  for (path, body) in owner_bound() {
+    let (status, response) = fixture.app.send(path, Some(&alice), body(&fixture.alice)).await;
+    assert!(status.is_success(), "{} answered {}: {}", path, status, response);
+  }
+}
+
+#[tokio::test]
+async fn admin_routes_reject_everyone_else() {
+  let fixture = fixture().await;
+  let alice = fixture.app.login(&fixture.alice).await;
+  for path in ADMIN {
+    let body = json!({ "username": "bob", "reason": "review" });
+    let (status, _) = fixture.app.send(path, None, body.clone()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", path);
+
+    let (status, response) = fixture.app.send(path, Some(&alice), body).await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{}", path);
+    assert_eq!(response["code"], "FORBIDDEN", "{}", path);
+  }
+}
+
+#[tokio::test]
+async fn admin_routes_accept_admins() {
+  let fixture = fixture().await;
+  let admin = fixture.app.login(&fixture.admin).await;
+  for path in ["/admin/flagged_users", "/admin/flag_evidence", "/admin/suspend_user", "/admin/restore_user", "/admin/audit_log"] {
+    let (status, response) = fixture.app.send(path, Some(&admin), json!({ "username": "bob", "reason": "review" })).await;
+    assert!(status.is_success(), "{} answered {}: {}", path, status, response);
+  }
+}
+
+#[tokio::test]
+async fn signed_requests_are_held_to_the_same_policy() {
+  let fixture = fixture().await;
+  let as_alice = json!({ "recipient": "carol", "sender": "alice", "message": "hi", "attachment_id": null });
+
+  let (status, _, _) = fixture.app.call(signed("/send_message", &fixture.bob, 1, &as_alice)).await;
+  assert_eq!(status, StatusCode::FORBIDDEN);
+
+  let (status, _, _) = fixture.app.call(signed("/send_message", &fixture.alice, 1, &as_alice)).await;
+  assert!(status.is_success());
+}
+
+#[tokio::test]
+async fn spends_carry_a_note_of_the_authenticated_key() {
+  let fixture = fixture().await;
+  let bob = fixture.app.login(&fixture.bob).await;
+  for path in ["/store_nullifier", "/spend"] {
+    // Bob spends alice's note in his own name.
+    let mut body = spend_of(&fixture.alice, "0x5", format!("state-{}", path));
+    body["owner"] = json!("bob");
+    let (status, response) = fixture.app.send(path, Some(&bob), body).await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{}", path);
+    assert_eq!(response["code"], "FORBIDDEN", "{}", path);
+  }
+}
+
+#[tokio::test]
+async fn note_blinds_are_only_shown_to_their_owner() {
+  let fixture = fixture().await;
+  let alice = fixture.app.login(&fixture.alice).await;
+  let bob = fixture.app.login(&fixture.bob).await;
+  let note = json!({
+    "asset_hash": "0x1", "owner": fixture.alice.pubkey, "value": 1, "step": 0,
+    "parent_note": "", "out_index": "0", "blind": "0x2",
+  });
+  assert!(fixture.app.send("/store_note", Some(&alice), note).await.0.is_success());
+
+  let request = json!({ "owner_pub_key": fixture.alice.pubkey, "step": null });
+  for (token, blind) in [(None, ""), (Some(bob.as_str()), ""), (Some(alice.as_str()), "0x2")] {
+    let (status, notes) = fixture.app.send("/get_notes", token, request.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(notes[0]["blind"], blind, "{:?}", token);
+  }
+}
@@ -0,0 +1,91 @@
+use ark_bn254::Fr;
+use serde_json::json;
+use service_http::{
+  crypto::{field_to_hex, note_commitment, note_nullifier, parse_field, poseidon, poseidon_config},
+  routes::schema::NoteSchema,
+};
+
+// BN254 scalar field modulus.
+const MODULUS: &str = "21888242871839275222246405745257275088548364400416034343698204186575808495617";
+const MODULUS_HEX: &str = "0x30644e72e131a029b85045b68181585d2833e84879b9709143e1f593f0000001";
+
+// RFC 8032, section 7.1, TEST 1 public key.
+const OWNER: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+
+// circomlib's first round constant and MDS entry for Poseidon(2), from `poseidon_constants`.
+const POSEIDON_2_ARK: &str = "0x0ee9a592ba9a9518d05986d656f40c2114c4993c11bb29938d21d47304cd8e6e";
+const POSEIDON_2_MDS: &str = "0x109b7f411ba0e4c9b2b70caf5c36a7b194be7c11ad24378bfedb68592ba8118b";
+
+// circomlib's test vectors.
+const POSEIDON_1_2: &str = "0x115cc0f5e7d690413df64c6b9662e9cf2a3617f2743245519e19607a4417189a";
+const POSEIDON_1_TO_4: &str = "0x299c867db6c1fdd79dcefa40e4510b9837e60ebb1ce0663dbaa525df65250465";
+const POSEIDON_1_TO_6: &str = "20400040500897583745843009878988256314335038853985262692600694741116813247201";
+
+// Vectors shared with the client circuits, recompute them there after any change to
+// the field layout.
+const MINT_COMMITMENT: &str = "0x0a70a64598841bfaeba6e28968a3a6feaaf3c42aa99069edbf5de0ba65878c12";
+const MINT_NULLIFIER: &str = "0x0f777c5aede223415bd6a7f43baf012edfa322694f3a33100968140b3e9a7ef9";
+const CHANGE_COMMITMENT: &str = "0x1d2a19387176b5f89819f716a28289ef1fefbd7a302f49abd18d4d91eeedd09d";
+const CHANGE_NULLIFIER: &str = "0x132ba13a2122847fb41344b7c1acd3b30866b0b9b90dcb52442ba4224e7f3595";
+
+fn note(value: u64, step: u32, parent_note: &str, out_index: &str, blind: &str) -> NoteSchema {
+  serde_json::from_value(json!({
+    "asset_hash": "1", "owner": OWNER, "value": value, "step": step,
+    "parent_note": parent_note, "out_index": out_index, "blind": blind,
+  })).unwrap()
+}
+
+fn inputs(count: u64) -> Vec<Fr> {
+  (1..=count).map(Fr::from).collect()
+}
+
+#[test]
+fn poseidon_matches_circomlib() {
+  let config = poseidon_config(2);
+  assert_eq!(field_to_hex(config.ark[0][0]), POSEIDON_2_ARK);
+  assert_eq!(field_to_hex(config.mds[0][0]), POSEIDON_2_MDS);
+
+  assert_eq!(field_to_hex(poseidon(&inputs(2)).unwrap()), POSEIDON_1_2);
+  assert_eq!(field_to_hex(poseidon(&inputs(4)).unwrap()), POSEIDON_1_TO_4);
+  assert_eq!(poseidon(&inputs(6)).unwrap(), parse_field(POSEIDON_1_TO_6, "x").unwrap());
+
+  for count in [0, 17] {
+    assert!(poseidon(&inputs(count)).is_err(), "{} inputs", count);
+  }
+}
+
+#[test]
+fn note_vectors() {
+  let mint = note(100, 1, "", "0", "12345");
+  assert_eq!(field_to_hex(note_commitment(&mint).unwrap()), MINT_COMMITMENT);
+  assert_eq!(field_to_hex(note_nullifier(&mint).unwrap()), MINT_NULLIFIER);
+
+  let change = note(40, 2, MINT_COMMITMENT, "1", "0x2a");
+  assert_eq!(field_to_hex(note_commitment(&change).unwrap()), CHANGE_COMMITMENT);
+  assert_eq!(field_to_hex(note_nullifier(&change).unwrap()), CHANGE_NULLIFIER);
+}
+
+#[test]
+fn every_note_field_is_committed_to() {
+  let mint = note_commitment(&note(100, 1, "", "0", "12345")).unwrap();
+  for other in [note(101, 1, "", "0", "12345"), note(100, 2, "", "0", "12345"), note(100, 1, "1", "0", "12345"), note(100, 1, "", "1", "12345"), note(100, 1, "", "0", "12346")] {
+    assert_ne!(note_commitment(&other).unwrap(), mint);
+  }
+}
+
+#[test]
+fn field_elements_have_one_spelling() {
+  assert_eq!(parse_field("", "x").unwrap(), Fr::from(0u64));
+  assert_eq!(parse_field("42", "x").unwrap(), Fr::from(42u64));
+  assert_eq!(parse_field("0x2a", "x").unwrap(), Fr::from(42u64));
+  assert_eq!(parse_field("0x02a", "x").unwrap(), Fr::from(42u64));
+  assert_eq!(field_to_hex(Fr::from(42u64)), format!("0x{:0>64}", "2a"));
+
+  let largest = parse_field(&MODULUS.replace("617", "616"), "x").unwrap();
+  assert_eq!(largest, -Fr::from(1u64));
+  assert_eq!(parse_field(&MODULUS_HEX.replace("001", "000"), "x").unwrap(), largest);
+
+  for invalid in [MODULUS, MODULUS_HEX, "042", "-1", "1.5", "random", "0x", "0xzz", &format!("0x{}", "11".repeat(33))] {
+    assert!(parse_field(invalid, "x").is_err(), "{}", invalid);
+  }
+}
@@ -0,0 +1,111 @@
+// Scaffolding shared by the tests that go through the router: an app over a memory
+// store, users with ed25519 keys and a request helper. Every test binary compiles its
+// own copy and uses part of it.
+#![allow(dead_code)]
+
+use axum::{body::{to_bytes, Body}, http::{header, request, HeaderMap, Request, StatusCode}, Router};
+use ed25519_dalek::{Signer, SigningKey};
+use serde_json::Value;
+use service_http::{
+  api_routes, app,
+  config::Config,
+  memory::MemoryDB,
+  routes::{schema::CreateUserSchema, signed::{signing_message, NONCE_HEADER, SIGNATURE_HEADER, USER_HEADER}},
+  store::Store,
+};
+use std::sync::Arc;
+use tower::ServiceExt;
+
+#[derive(Clone)]
+pub struct TestApp {
+  pub app: Router,
+  pub store: Store,
+}
+
+/// A user created by `TestApp::user`, with the key behind `pubkey`.
+pub struct User {
+  pub username: &'static str,
+  pub pubkey: String,
+  pub key: SigningKey,
+}
+
+/// The ed25519 key of the user created with `seed`.
+pub fn key(seed: u8) -> SigningKey {
+  SigningKey::from_bytes(&[seed; 32])
+}
+
+/// The hex pubkey of the user created with `seed`, what their notes name as `owner`.
+pub fn pubkey(seed: u8) -> String {
+  hex::encode(key(seed).verifying_key().as_bytes())
+}
+
+/// A request to `path` with the method its route is registered with and a JSON body type.
+pub fn request(path: &str) -> request::Builder {
+  let method = api_routes().into_iter().find(|route| route.path == path).unwrap().method;
+  Request::builder().method(method).uri(path).header(header::CONTENT_TYPE, "application/json")
+}
+
+/// A request to `path` signed by `user` with `nonce` instead of carrying a session.
+pub fn signed(path: &str, user: &User, nonce: u64, body: &Value) -> Request<Body> {
+  let message = signing_message(path, nonce, body.to_string().as_bytes()).unwrap();
+  request(path)
+    .header(USER_HEADER, user.username)
+    .header(NONCE_HEADER, nonce.to_string())
+    .header(SIGNATURE_HEADER, hex::encode(user.key.sign(&message).to_bytes()))
+    .body(Body::from(body.to_string()))
+    .unwrap()
+}
+
+impl TestApp {
+  pub fn new(config: &Config) -> Self {
+    let store: Store = Arc::new(MemoryDB::new(config.auth.clone()));
+    Self { app: app(store.clone(), config), store }
+  }
+
+  /// Another app over the same store, like a second replica behind a load balancer.
+  pub fn replica(&self, config: &Config) -> Self {
+    Self { app: app(self.store.clone(), config), store: self.store.clone() }
+  }
+
+  pub async fn user(&self, username: &'static str, seed: u8) -> User {
+    self.store.create_user(&CreateUserSchema {
+      username: username.to_owned(),
+      pubkey: pubkey(seed),
+      key_type: Default::default(),
+      nonce: "0".to_owned(),
+      address: format!("{}-address", username),
+      messages: Vec::new(),
+      notes: Vec::new(),
+      has_double_spent: false,
+    }).await.unwrap();
+    User { username, pubkey: pubkey(seed), key: key(seed) }
+  }
+
+  /// A session token of `user`.
+  pub async fn login(&self, user: &User) -> String {
+    self.store.create_session(user.username).await.unwrap().token
+  }
+
+  /// Sends `body` to `path`, with `token` as the bearer session when given.
+  pub async fn send(&self, path: &str, token: Option<&str>, body: Value) -> (StatusCode, Value) {
+    let mut request = request(path);
+    if let Some(token) = token {
+      request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+    }
+    let (status, _, body) = self.call(request.body(Body::from(body.to_string())).unwrap()).await;
+    (status, body)
+  }
+
+  /// Sends `request` as is. A body that is not JSON comes back as a string, an empty one as `Null`.
+  pub async fn call(&self, request: Request<Body>) -> (StatusCode, HeaderMap, Value) {
+    let response = self.app.clone().oneshot(request).await.unwrap();
+    let (status, headers) = (response.status(), response.headers().clone());
+    let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body = match serde_json::from_slice(&bytes) {
+      Ok(json) => json,
+      Err(_) if bytes.is_empty() => Value::Null,
+      Err(_) => Value::String(String::from_utf8_lossy(&bytes).into_owned()),
+    };
+    (status, headers, body)
+  }
+}
@@ -0,0 +1,51 @@
+use service_http::config::{Config, ConfigArgs, ConfigError, StorageBackend};
+
+const SIGNING_KEY: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+
+fn config(backend: StorageBackend, uri: Option<&str>, signing_key: Option<&str>) -> Config {
+  let mut config = Config::default();
+  config.database.backend = backend;
+  config.database.uri = uri.map(str::to_owned);
+  config.nullifier_tree.signing_key = signing_key.map(str::to_owned);
+  config
+}
+
+#[test]
+fn persistent_backends_need_a_signing_key_to_serve() {
+  for (backend, uri) in [(StorageBackend::Mongo, "mongodb://localhost:27017"), (StorageBackend::Sql, "sqlite::memory:")] {
+    // `migrate` and `--print-config` only need a valid configuration.
+    assert!(config(backend, Some(uri), None).validate().is_ok(), "{:?}", backend);
+    let err = config(backend, Some(uri), None).validate_serving().unwrap_err();
+    assert!(matches!(err.current_context(), ConfigError::InvalidValue), "{:?}", backend);
+    assert!(config(backend, Some(uri), Some(SIGNING_KEY)).validate_serving().is_ok(), "{:?}", backend);
+  }
+
+  // Proofs of the memory backend never outlive the process, a key per run is enough.
+  assert!(config(StorageBackend::Memory, None, None).validate_serving().is_ok());
+}
+
+#[test]
+fn signing_keys_are_64_hex_digits() {
+  for invalid in ["", "zz", &SIGNING_KEY[2..]] {
+    let err = config(StorageBackend::Memory, None, Some(invalid)).validate().unwrap_err();
+    assert!(matches!(err.current_context(), ConfigError::InvalidValue), "{}", invalid);
+  }
+}
+
+// The only test that sets variables, the others never read the environment.
+#[test]
+fn backend_uri_variables_follow_the_storage_flag() {
+  std::env::remove_var("IOU_DATABASE_URI");
+  std::env::set_var("IOU_STORAGE", "mongo");
+  std::env::set_var("MONGODB_URI", "mongodb://localhost:27017");
+  std::env::set_var("DATABASE_URL", "sqlite::memory:");
+
+  let load = |storage, database_uri: Option<&str>| Config::load(&ConfigArgs {
+    storage,
+    database_uri: database_uri.map(str::to_owned),
+    ..Default::default()
+  }).unwrap().database.uri;
+  assert_eq!(load(None, None).as_deref(), Some("mongodb://localhost:27017"));
+  assert_eq!(load(Some(StorageBackend::Sql), None).as_deref(), Some("sqlite::memory:"));
+  assert_eq!(load(Some(StorageBackend::Sql), Some("sqlite://iou.db")).as_deref(), Some("sqlite://iou.db"));
+}
@@ -0,0 +1,128 @@
+mod common;
+
+use axum::http::StatusCode;
+use common::{key, pubkey, TestApp, User};
+use ed25519_dalek::Signer;
+use serde_json::{json, Value};
+use service_http::{config::Config, crypto::KeyType, store::rotation_message};
+use std::time::Duration;
+
+const ALICE: u8 = 1;
+
+async fn fixture() -> (TestApp, User) {
+  let app = TestApp::new(&Config::default());
+  let alice = app.user("alice", ALICE).await;
+  (app, alice)
+}
+
+// A rotation of alice's key from `current` to the key of `new`, signed by `signer`.
+fn rotation(current: u8, new: u8, signer: u8) -> Value {
+  let message = rotation_message("alice", &pubkey(current), KeyType::Ed25519, &pubkey(new));
+  json!({
+    "username": "alice", "new_pubkey": pubkey(new), "new_key_type": "ed25519",
+    "signature_hex": hex::encode(key(signer).sign(&message).to_bytes()),
+  })
+}
+
+// A login challenge issued to alice.
+async fn challenge(app: &TestApp) -> String {
+  let (status, challenge) = app.send("/auth", None, json!("alice")).await;
+  assert_eq!(status, StatusCode::OK);
+  String::from_utf8(hex::decode(challenge.as_str().unwrap()).unwrap()).unwrap()
+}
+
+// Answers `challenge` with the key of `signer`.
+async fn login(app: &TestApp, challenge: &str, signer: u8) -> (StatusCode, Value) {
+  let signature = hex::encode(key(signer).sign(challenge.as_bytes()).to_bytes());
+  app.send("/get_session", None, json!({ "username": "alice", "signature_hex": signature, "challenge_id": challenge })).await
+}
+
+#[tokio::test]
+async fn keys_are_handed_over_by_the_current_key() {
+  let (app, _) = fixture().await;
+
+  let (status, keys) = app.send("/rotate_key", None, rotation(ALICE, 5, ALICE)).await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!((&keys[0]["pubkey"], &keys[1]["pubkey"]), (&json!(pubkey(ALICE)), &json!(pubkey(5))));
+  assert!(keys[0]["valid_until"].is_i64());
+  assert_eq!(keys[1]["valid_until"], Value::Null);
+  assert_eq!(app.send("/key_history", None, json!({ "username": "alice" })).await.1, keys);
+
+  // Only the new key logs in, and only it hands over.
+  let (status, _) = login(&app, &challenge(&app).await, 5).await;
+  assert_eq!(status, StatusCode::OK);
+  let (status, body) = login(&app, &challenge(&app).await, ALICE).await;
+  assert_eq!((status, &body["code"]), (StatusCode::UNAUTHORIZED, &json!("UNAUTHORIZED")));
+  assert_eq!(app.send("/rotate_key", None, rotation(5, 6, 5)).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn handovers_not_signed_by_the_current_key_are_rejected() {
+  let (app, _) = fixture().await;
+
+  let (status, body) = app.send("/rotate_key", None, rotation(ALICE, 5, 5)).await;
+  assert_eq!((status, &body["code"]), (StatusCode::UNAUTHORIZED, &json!("UNAUTHORIZED")));
+  let (_, keys) = app.send("/key_history", None, json!({ "username": "alice" })).await;
+  assert_eq!(keys.as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn old_keys_are_never_reused() {
+  let (app, _) = fixture().await;
+  app.send("/rotate_key", None, rotation(ALICE, 5, ALICE)).await;
+
+  let (status, body) = app.send("/rotate_key", None, rotation(5, ALICE, 5)).await;
+  assert_eq!((status, &body["code"]), (StatusCode::CONFLICT, &json!("KEY_REUSED")));
+  // A replayed handover is the same request.
+  let (status, body) = app.send("/rotate_key", None, rotation(ALICE, 5, ALICE)).await;
+  assert_eq!((status, &body["code"]), (StatusCode::CONFLICT, &json!("KEY_REUSED")));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_rotations_hand_over_once() {
+  let (app, _) = fixture().await;
+
+  let rotations = (10..18).map(|new| {
+    let app = app.clone();
+    tokio::spawn(async move { app.send("/rotate_key", None, rotation(ALICE, new, ALICE)).await })
+  }).collect::<Vec<_>>();
+  let mut outcomes = Vec::new();
+  for rotation in rotations {
+    outcomes.push(rotation.await.unwrap());
+  }
+
+  // The losers either lost the swap or read the winner's key and no longer match it.
+  assert_eq!(outcomes.iter().filter(|(status, _)| *status == StatusCode::OK).count(), 1, "{:?}", outcomes);
+  for (status, body) in outcomes.iter().filter(|(status, _)| *status != StatusCode::OK) {
+    assert!(matches!((*status, body["code"].as_str()), (StatusCode::CONFLICT, Some("CONFLICT")) | (StatusCode::UNAUTHORIZED, Some("UNAUTHORIZED"))), "{} {}", status, body);
+  }
+  let (_, keys) = app.send("/key_history", None, json!({ "username": "alice" })).await;
+  assert_eq!(keys.as_array().unwrap().len(), 2);
+
+  // The swap only replaces the key it retires.
+  let retired = app.store.key_valid_at("alice", 0).await.unwrap();
+  assert!(!app.store.replace_key(&retired, KeyType::Ed25519, &pubkey(20)).await.unwrap());
+}
+
+#[tokio::test]
+async fn challenges_are_answered_with_the_key_they_were_issued_for() {
+  let (app, _) = fixture().await;
+
+  // Validity is kept in seconds, the rotation has to fall in a later one.
+  let before = challenge(&app).await;
+  tokio::time::sleep(Duration::from_millis(1100)).await;
+  app.send("/rotate_key", None, rotation(ALICE, 5, ALICE)).await;
+  let after = challenge(&app).await;
+
+  // The retired key can't log in with a challenge it was issued before the rotation.
+  let (status, body) = login(&app, &before, ALICE).await;
+  assert_eq!((status, &body["code"]), (StatusCode::UNAUTHORIZED, &json!("CHALLENGE_INVALID")));
+  let (status, _) = login(&app, &after, ALICE).await;
+  assert_eq!(status, StatusCode::UNAUTHORIZED);
+  let (status, _) = login(&app, &challenge(&app).await, 5).await;
+  assert_eq!(status, StatusCode::OK);
+
+  let now = chrono::Utc::now().timestamp();
+  assert_eq!(app.store.key_valid_at("alice", now - 60).await.unwrap().pubkey, pubkey(ALICE));
+  assert_eq!(app.store.key_valid_at("alice", now + 60).await.unwrap().pubkey, pubkey(5));
+}
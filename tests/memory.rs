@@ -0,0 +1,101 @@
+use mongodb::bson::oid::ObjectId;
+use serde_json::json;
+use service_http::{
+  config::AuthConfig,
+  crypto::KeyType,
+  memory::MemoryDB,
+  routes::{error::DatabaseError, schema::{CreateUserSchema, NoteNullifierSchema, NullifierStatus, SaveNoteHistoryRequestSchema}},
+  store::IOUStore,
+};
+
+fn alice() -> CreateUserSchema {
+  CreateUserSchema {
+    username: "alice".to_owned(),
+    pubkey: "alice-pubkey".to_owned(),
+    key_type: KeyType::default(),
+    nonce: "0".to_owned(),
+    address: "alice-address".to_owned(),
+    messages: Vec::new(),
+    notes: Vec::new(),
+    has_double_spent: false,
+  }
+}
+
+#[tokio::test]
+async fn users_round_trip() {
+  let db = MemoryDB::new(AuthConfig::default());
+  db.create_user(&alice()).await.unwrap();
+
+  let user = db.get_user_with_username("alice").await.unwrap().user;
+  assert_eq!(user.pubkey.as_deref(), Some("alice-pubkey"));
+  let user = db.get_user_with_address("alice-address").await.unwrap().user;
+  assert_eq!(user.username.as_deref(), Some("alice"));
+
+  assert!(db.create_user(&alice()).await.is_err());
+  assert!(db.get_user_with_username("bob").await.is_err());
+}
+
+#[tokio::test]
+async fn users_keep_the_notes_and_messages_they_are_created_with() {
+  let db = MemoryDB::new(AuthConfig::default());
+  let (note, message) = (ObjectId::new(), ObjectId::new());
+  db.create_user(&CreateUserSchema { notes: vec![note.to_hex()], messages: vec![message.to_hex()], ..alice() }).await.unwrap();
+
+  let user = db.get_user_with_username("alice").await.unwrap().user;
+  assert_eq!((user.notes, user.messages), (Some(vec![note]), Some(vec![message])));
+}
+
+#[tokio::test]
+async fn notes_round_trip() {
+  let db = MemoryDB::new(AuthConfig::default());
+  db.create_user(&alice()).await.unwrap();
+  let note = serde_json::from_value(json!({
+    "asset_hash": "1", "owner": "alice-pubkey", "value": 10, "step": 1,
+    "parent_note": "", "out_index": "0", "blind": "7",
+  })).unwrap();
+  db.store_note(&note).await.unwrap();
+
+  let notes = db.get_user_notes("alice-pubkey").await.unwrap();
+  assert_eq!(notes.len(), 1);
+  assert_eq!(serde_json::to_value(&notes[0]).unwrap()["value"], 10);
+  assert!(db.get_user_notes("bob-pubkey").await.unwrap().is_empty());
+
+  let user = db.get_user_with_username("alice").await.unwrap().user;
+  assert_eq!(user.notes.map(|notes| notes.len()), Some(1));
+}
+
+#[tokio::test]
+async fn transfers_to_unknown_recipients_store_nothing() {
+  let db = MemoryDB::new(AuthConfig::default());
+  db.create_user(&alice()).await.unwrap();
+
+  let note_history = SaveNoteHistoryRequestSchema { data: vec![1, 2, 3], address: "bob-address".to_owned(), sender: "alice".to_owned() };
+  let err = db.create_and_transfer_note_history("alice".to_owned(), "bob", note_history, "for you".to_owned()).await.unwrap_err();
+  assert!(matches!(err.current_context(), DatabaseError::NotFoundError), "{:?}", err);
+  assert!(db.get_unread_messages("bob").await.unwrap().is_empty());
+  assert!(db.get_note_history_for_user("alice".to_owned()).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn nullifiers_round_trip() {
+  let db = MemoryDB::new(AuthConfig::default());
+  let spent = NoteNullifierSchema {
+    nullifier: "nullifier-1".to_owned(),
+    note: "{}".to_owned(),
+    step: 1,
+    owner: "alice".to_owned(),
+    state: "state-1".to_owned(),
+    states: Vec::new(),
+  };
+  db.store_nullifier(&spent).await.unwrap();
+
+  let verification = db.verify_nullifier("nullifier-1", "state-1").await.unwrap();
+  assert_eq!(verification.status, NullifierStatus::SpentInThisState);
+  let stored = verification.nullifier.unwrap();
+  assert_eq!((stored.owner, stored.step), (spent.owner, spent.step));
+
+  let verification = db.verify_nullifier("nullifier-1", "state-2").await.unwrap();
+  assert_eq!(verification.status, NullifierStatus::SpentInAnotherState);
+  let verification = db.verify_nullifier("nullifier-2", "state-1").await.unwrap();
+  assert_eq!(verification.status, NullifierStatus::Unknown);
+}
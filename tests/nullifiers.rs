@@ -0,0 +1,390 @@
+mod common;
+
+use axum::http::StatusCode;
+use ark_ff::PrimeField;
+use common::{pubkey, TestApp};
+use serde_json::{json, Value};
+use service_http::{
+  config::Config,
+  crypto::{field_to_hex, note_nullifier, parse_field},
+  merkle::{verify_inclusion, verify_non_membership},
+  routes::{response::{InclusionProof, NonMembershipProof}, schema::{AdminAction, NoteNullifierSchema, NullifierState, SpendOutcome}},
+  store::audit_entry,
+};
+
+const ALICE: u8 = 1;
+
+struct Fixture {
+  app: TestApp,
+  alice: String,
+}
+
+async fn fixture() -> Fixture {
+  let app = TestApp::new(&Config::default());
+  let alice = app.user("alice", ALICE).await;
+  Fixture { alice: app.login(&alice).await, app }
+}
+
+fn spend_of(nullifier: &str, state: &str) -> NoteNullifierSchema {
+  NoteNullifierSchema {
+    nullifier: nullifier.to_owned(),
+    note: "{}".to_owned(),
+    step: 1,
+    owner: "alice".to_owned(),
+    state: state.to_owned(),
+    states: Vec::new(),
+  }
+}
+
+// `spent` as stored, with its own step and state as the whole vector.
+fn stored(spent: &NoteNullifierSchema) -> Value {
+  let states = vec![NullifierState { step: spent.step, state: spent.state.clone() }];
+  serde_json::to_value(NoteNullifierSchema { states, ..spent.clone() }).unwrap()
+}
+
+// A note of alice's serialized as JSON, and its nullifier. Notes differ by `blind`.
+fn note(blind: &str) -> (String, String) {
+  let note = json!({
+    "asset_hash": "0x1", "owner": pubkey(ALICE), "value": 10, "step": 1,
+    "parent_note": "", "out_index": "0", "blind": blind,
+  });
+  let nullifier = note_nullifier(&serde_json::from_value(note.clone()).unwrap()).unwrap();
+  (note.to_string(), field_to_hex(nullifier))
+}
+
+// The spend of the note with `blind` that `/spend` gets.
+fn spend_of_note(blind: &str, state: &str) -> NoteNullifierSchema {
+  let (note, nullifier) = note(blind);
+  NoteNullifierSchema { note, ..spend_of(&nullifier, state) }
+}
+
+async fn spend(fixture: &Fixture, blind: &str, state: &str) -> (StatusCode, Value) {
+  spend_at(fixture, blind, 1, state).await
+}
+
+async fn spend_at(fixture: &Fixture, blind: &str, step: i32, state: &str) -> (StatusCode, Value) {
+  post(fixture, serde_json::to_value(NoteNullifierSchema { step, ..spend_of_note(blind, state) }).unwrap()).await
+}
+
+async fn post(fixture: &Fixture, body: Value) -> (StatusCode, Value) {
+  post_as(fixture, &fixture.alice, body).await
+}
+
+async fn post_as(fixture: &Fixture, session: &str, body: Value) -> (StatusCode, Value) {
+  fixture.app.send("/spend", Some(session), body).await
+}
+
+async fn verify(fixture: &Fixture, blind: &str, state: &str) -> (StatusCode, Value) {
+  get(fixture, "/verify_nullifier", json!({ "nullifier": note(blind).1, "state": state })).await
+}
+
+async fn get(fixture: &Fixture, path: &str, body: Value) -> (StatusCode, Value) {
+  fixture.app.send(path, None, body).await
+}
+
+#[tokio::test]
+async fn spend_reports_what_happened() {
+  let fixture = fixture().await;
+
+  let (status, body) = spend(&fixture, "0x1", "state-1").await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(body["outcome"], "accepted");
+
+  // A retry is harmless.
+  let (status, body) = spend(&fixture, "0x1", "state-1").await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(body["outcome"], "duplicate_same_state");
+
+  let (status, body) = spend(&fixture, "0x1", "state-2").await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(body, json!({
+    "outcome": "conflicting_state",
+    "nullifier": stored(&spend_of_note("0x1", "state-1")),
+  }));
+}
+
+#[tokio::test]
+async fn each_step_adds_to_the_state_vector() {
+  let fixture = fixture().await;
+
+  assert_eq!(spend_at(&fixture, "0x1", 1, "state-1").await.1["outcome"], "accepted");
+  assert_eq!(spend_at(&fixture, "0x1", 3, "state-3").await.1["outcome"], "accepted");
+  assert_eq!(spend_at(&fixture, "0x1", 2, "state-2").await.1["outcome"], "accepted");
+  assert_eq!(spend_at(&fixture, "0x1", 3, "state-3").await.1["outcome"], "duplicate_same_state");
+
+  // A step holds one state and a state belongs to one step.
+  assert_eq!(spend_at(&fixture, "0x1", 2, "state-4").await.1["outcome"], "conflicting_state");
+  assert_eq!(spend_at(&fixture, "0x1", 4, "state-1").await.1["outcome"], "conflicting_state");
+  assert_eq!(fixture.app.store.get_flags("alice").await.unwrap().len(), 2);
+
+  let (status, body) = get(&fixture, "/nullifier_history", json!({ "nullifier": note("0x1").1 })).await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!((&body["step"], &body["state"]), (&json!(1), &json!("state-1")));
+  assert_eq!(body["states"], json!([
+    { "step": 1, "state": "state-1" },
+    { "step": 2, "state": "state-2" },
+    { "step": 3, "state": "state-3" },
+  ]));
+
+  assert_eq!(verify(&fixture, "0x1", "state-2").await.0, StatusCode::OK);
+  assert_eq!(verify(&fixture, "0x1", "state-4").await.0, StatusCode::CONFLICT);
+
+  let (status, body) = get(&fixture, "/nullifier_history", json!({ "nullifier": note("0x2").1 })).await;
+  assert_eq!(status, StatusCode::NOT_FOUND);
+  assert_eq!(body["code"], "NOT_FOUND");
+}
+
+#[tokio::test]
+async fn spends_must_carry_their_note() {
+  let fixture = fixture().await;
+  let valid = serde_json::to_value(spend_of_note("0x1", "state-1")).unwrap();
+  let with = |field: &str, value: Value| {
+    let mut body = valid.clone();
+    body[field] = value;
+    body
+  };
+
+  let (status, body) = post(&fixture, with("nullifier", json!(note("0x2").1))).await;
+  assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+  assert_eq!(body["code"], "NULLIFIER_MISMATCH");
+
+  let random_blind = note("0x1").0.replace("\"0x1\"", "\"random\"");
+  for note in [json!("{}"), json!("not json"), json!(random_blind)] {
+    let (status, body) = post(&fixture, with("note", note.clone())).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", note);
+    assert_eq!(body["code"], "NOTE_INVALID", "{}", note);
+  }
+  assert_eq!(verify(&fixture, "0x1", "state-1").await.0, StatusCode::NOT_FOUND);
+
+  // The same nullifier in decimal is the same nullifier.
+  let decimal = parse_field(&note("0x1").1, "nullifier").unwrap().into_bigint().to_string();
+  assert_eq!(post(&fixture, valid.clone()).await.1["outcome"], "accepted");
+  let (_, body) = post(&fixture, with("nullifier", json!(decimal))).await;
+  assert_eq!(body["outcome"], "duplicate_same_state");
+  assert_eq!(body["nullifier"]["nullifier"], note("0x1").1);
+}
+
+#[tokio::test]
+async fn only_conflicting_spends_flag_the_owner() {
+  let fixture = fixture().await;
+  let flagged = |fixture: &Fixture| {
+    let store = fixture.app.store.clone();
+    async move { store.get_user_with_username("alice").await.unwrap().user.has_double_spent }
+  };
+
+  spend(&fixture, "0x1", "state-1").await;
+  spend(&fixture, "0x1", "state-1").await;
+  assert_eq!(flagged(&fixture).await, Some(false));
+
+  spend(&fixture, "0x1", "state-2").await;
+  assert_eq!(flagged(&fixture).await, Some(true));
+  let flags = fixture.app.store.get_flags("alice").await.unwrap();
+  assert_eq!(flags.len(), 1);
+  assert_eq!(flags[0].presented_state, "state-2");
+}
+
+#[tokio::test]
+async fn spends_of_another_users_nullifier_are_forbidden() {
+  let fixture = fixture().await;
+  let bob = fixture.app.user("bob", 2).await;
+  let bob = fixture.app.login(&bob).await;
+  spend(&fixture, "0x1", "state-1").await;
+
+  // Bob learned alice's note and spends it as his own in another state.
+  let spend = NoteNullifierSchema { owner: "bob".to_owned(), ..spend_of_note("0x1", "state-2") };
+  let (status, body) = post_as(&fixture, &bob, serde_json::to_value(spend).unwrap()).await;
+  assert_eq!(status, StatusCode::FORBIDDEN);
+  assert_eq!(body["code"], "FORBIDDEN");
+
+  for username in ["alice", "bob"] {
+    let user = fixture.app.store.get_user_with_username(username).await.unwrap().user;
+    assert_eq!(user.has_double_spent, Some(false), "{}", username);
+    assert!(fixture.app.store.get_flags(username).await.unwrap().is_empty(), "{}", username);
+    assert!(fixture.app.store.get_user_betrayals(username).await.unwrap().is_empty(), "{}", username);
+  }
+  let stored_spend = fixture.app.store.find_nullifier(&note("0x1").1).await.unwrap().unwrap();
+  assert_eq!(serde_json::to_value(stored_spend).unwrap(), stored(&spend_of_note("0x1", "state-1")));
+}
+
+#[tokio::test]
+async fn verify_answers_without_side_effects() {
+  let fixture = fixture().await;
+
+  let (status, body) = verify(&fixture, "0x1", "state-1").await;
+  assert_eq!(status, StatusCode::NOT_FOUND);
+  assert_eq!(body, json!({ "status": "unknown", "nullifier": null }));
+
+  spend(&fixture, "0x1", "state-1").await;
+
+  let (status, body) = verify(&fixture, "0x1", "state-1").await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(body["status"], "spent_in_this_state");
+  assert_eq!(body["nullifier"]["owner"], "alice");
+
+  let (status, body) = verify(&fixture, "0x1", "state-2").await;
+  assert_eq!(status, StatusCode::CONFLICT);
+  assert_eq!(body["status"], "spent_in_another_state");
+  assert_eq!(body["nullifier"]["state"], "state-1");
+
+  // Reads never flag anyone.
+  let user = fixture.app.store.get_user_with_username("alice").await.unwrap().user;
+  assert_eq!(user.has_double_spent, Some(false));
+  assert!(fixture.app.store.get_flags("alice").await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn betrayals_are_kept_as_evidence() {
+  let fixture = fixture().await;
+  let first = NoteNullifierSchema { note: "note-a".to_owned(), ..spend_of("nullifier-1", "state-1") };
+  let second = NoteNullifierSchema { note: "note-b".to_owned(), ..spend_of("nullifier-1", "state-2") };
+  fixture.app.store.spend_nullifier(&first).await.unwrap();
+  fixture.app.store.spend_nullifier(&second).await.unwrap();
+
+  // Clearing the flag does not erase the evidence.
+  fixture.app.store.moderate(&audit_entry("admin", AdminAction::ClearFlag, "alice", "reviewed")).await.unwrap();
+
+  let (status, betrayals) = get(&fixture, "/betrayals_for_user", json!({ "username": "alice" })).await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(betrayals.as_array().unwrap().len(), 1);
+  assert_eq!(betrayals[0]["username"], "alice");
+  assert_eq!(betrayals[0]["spent"], stored(&first));
+  assert_eq!(betrayals[0]["conflicting"], stored(&second));
+  assert!(betrayals[0]["detected_at"].as_i64().unwrap() > 0);
+
+  for note in ["note-a", "note-b"] {
+    let (_, by_note) = get(&fixture, "/betrayals_for_note", json!({ "note": note })).await;
+    assert_eq!(by_note, betrayals, "{}", note);
+  }
+  let (_, unrelated) = get(&fixture, "/betrayals_for_note", json!({ "note": "note-c" })).await;
+  assert_eq!(unrelated, json!([]));
+
+  let (status, body) = get(&fixture, "/betrayals_for_user", json!({ "username": "nobody" })).await;
+  assert_eq!(status, StatusCode::NOT_FOUND);
+  assert_eq!(body["code"], "USER_NOT_FOUND");
+}
+
+#[tokio::test]
+async fn concurrent_spends_accept_exactly_one() {
+  let fixture = fixture().await;
+
+  let spends = (0..16).map(|i| {
+    let store = fixture.app.store.clone();
+    tokio::spawn(async move { store.spend_nullifier(&spend_of("contested", &format!("state-{}", i))).await.unwrap() })
+  });
+  let mut outcomes = Vec::new();
+  for spend in spends {
+    outcomes.push(spend.await.unwrap().outcome);
+  }
+
+  assert_eq!(outcomes.iter().filter(|&&outcome| outcome == SpendOutcome::Accepted).count(), 1);
+  assert_eq!(outcomes.iter().filter(|&&outcome| outcome == SpendOutcome::ConflictingState).count(), 15);
+}
+
+#[tokio::test]
+async fn stored_nullifiers_have_inclusion_proofs() {
+  let fixture = fixture().await;
+  let (status, empty) = get(&fixture, "/nullifier_root", json!({})).await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(empty["size"], 0);
+
+  let mut roots = vec![empty["root"].clone()];
+  for blind in ["0x1", "0x2", "0x3"] {
+    assert_eq!(spend(&fixture, blind, "state-1").await.0, StatusCode::OK);
+    roots.push(get(&fixture, "/nullifier_root", json!({})).await.1["root"].clone());
+  }
+  // A retried spend adds no leaf.
+  spend(&fixture, "0x2", "state-1").await;
+  let (_, root) = get(&fixture, "/nullifier_root", json!({})).await;
+  assert_eq!((&root["root"], &root["size"]), (&roots[3], &json!(3)));
+  roots.dedup();
+  assert_eq!(roots.len(), 4);
+
+  for (index, blind) in ["0x1", "0x2", "0x3"].into_iter().enumerate() {
+    let (status, body) = get(&fixture, "/nullifier_proof", json!({ "nullifier": note(blind).1 })).await;
+    assert_eq!(status, StatusCode::OK);
+    let proof: InclusionProof = serde_json::from_value(body).unwrap();
+    assert_eq!((proof.leaf_index, proof.root.as_str()), (index as u64, roots[3].as_str().unwrap()));
+    assert!(verify_inclusion(&proof));
+
+    let forged = InclusionProof { nullifier: note("0x4").1, ..proof.clone() };
+    assert!(!verify_inclusion(&forged));
+    let moved = InclusionProof { leaf_index: (index as u64 + 1) % 3, ..proof };
+    assert!(!verify_inclusion(&moved));
+  }
+
+  let (status, body) = get(&fixture, "/nullifier_proof", json!({ "nullifier": note("0x4").1 })).await;
+  assert_eq!(status, StatusCode::NOT_FOUND);
+  assert_eq!(body["code"], "NOT_FOUND");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_reads_catch_the_tree_up_once() {
+  let fixture = fixture().await;
+  for blind in ["0x1", "0x2", "0x3", "0x4", "0x5"] {
+    fixture.app.store.store_nullifier(&spend_of_note(blind, "state-1")).await.unwrap();
+  }
+
+  let reads = (0..8).map(|_| {
+    let app = fixture.app.clone();
+    tokio::spawn(async move { app.send("/nullifier_root", None, json!({})).await.1 })
+  }).collect::<Vec<_>>();
+  let mut roots = Vec::new();
+  for read in reads {
+    roots.push(read.await.unwrap());
+  }
+
+  // Every read waited for the one sync, none of them appended the leaves again.
+  assert!(roots.iter().all(|root| root["size"] == 5 && root["root"] == roots[0]["root"]), "{:?}", roots);
+  let (_, proof) = get(&fixture, "/nullifier_proof", json!({ "nullifier": note("0x5").1 })).await;
+  assert_eq!(proof["leaf_index"], 4);
+}
+
+#[tokio::test]
+async fn replicas_catch_up_on_spends_stored_by_others() {
+  let fixture = fixture().await;
+  let replica = Fixture { app: fixture.app.replica(&Config::default()), alice: fixture.alice.clone() };
+  spend(&fixture, "0x1", "state-1").await;
+  spend(&replica, "0x2", "state-1").await;
+
+  // Each replica added its own spend when it was stored, and the other's before reading.
+  let (_, root) = get(&fixture, "/nullifier_root", json!({})).await;
+  let (_, replica_root) = get(&replica, "/nullifier_root", json!({})).await;
+  assert_eq!(root["size"], 2);
+  assert_eq!((&root["root"], &root["sparse_root"]), (&replica_root["root"], &replica_root["sparse_root"]));
+  let (_, proof) = get(&replica, "/nullifier_proof", json!({ "nullifier": note("0x1").1 })).await;
+  assert_eq!(proof["leaf_index"], 0);
+}
+
+#[tokio::test]
+async fn unspent_nullifiers_have_signed_non_membership_proofs() {
+  let (fixture, other) = (fixture().await, fixture().await);
+  spend(&fixture, "0x1", "state-1").await;
+  let (_, published) = get(&fixture, "/nullifier_root", json!({})).await;
+  let public_key = published["public_key"].as_str().unwrap();
+
+  let (status, body) = get(&fixture, "/nullifier_absence", json!({ "nullifier": note("0x2").1 })).await;
+  assert_eq!(status, StatusCode::OK);
+  let proof: NonMembershipProof = serde_json::from_value(body).unwrap();
+  assert_eq!((&json!(proof.root), &json!(proof.size)), (&published["sparse_root"], &json!(1)));
+  assert!(verify_non_membership(&proof, public_key));
+
+  // The proof is bound to its nullifier, root and key.
+  assert!(!verify_non_membership(&NonMembershipProof { nullifier: note("0x3").1, ..proof.clone() }, public_key));
+  assert!(!verify_non_membership(&NonMembershipProof { root: published["root"].as_str().unwrap().to_owned(), ..proof.clone() }, public_key));
+  assert!(!verify_non_membership(&proof, &"11".repeat(32)));
+
+  // Once spent there is no proof, and the old one no longer matches the root.
+  spend(&fixture, "0x2", "state-1").await;
+  let (status, body) = get(&fixture, "/nullifier_absence", json!({ "nullifier": note("0x2").1 })).await;
+  assert_eq!(status, StatusCode::CONFLICT);
+  assert_eq!(body["code"], "NULLIFIER_CONFLICT");
+  let (_, root) = get(&fixture, "/nullifier_root", json!({})).await;
+  assert_ne!(json!(proof.root), root["sparse_root"]);
+
+  // The sparse root depends on the set of nullifiers, not on the order they were spent in.
+  spend(&other, "0x2", "state-1").await;
+  spend(&other, "0x1", "state-1").await;
+  let (_, other_root) = get(&other, "/nullifier_root", json!({})).await;
+  assert_eq!(other_root["sparse_root"], root["sparse_root"]);
+  assert_ne!(other_root["root"], root["root"]);
+}
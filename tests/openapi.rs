@@ -0,0 +1,44 @@
+use http::Method;
+use service_http::{api_routes, openapi::ApiDoc};
+use utoipa::{openapi::PathItemType, OpenApi};
+
+fn path_item_type(method: &Method) -> PathItemType {
+  match *method {
+    Method::GET => PathItemType::Get,
+    Method::POST => PathItemType::Post,
+    Method::PUT => PathItemType::Put,
+    Method::DELETE => PathItemType::Delete,
+    Method::PATCH => PathItemType::Patch,
+    ref other => panic!("no OpenAPI operation for {}", other),
+  }
+}
+
+#[test]
+fn every_route_is_documented() {
+  let spec = ApiDoc::openapi();
+
+  let missing: Vec<String> = api_routes().iter()
+    .filter(|route| {
+      spec.paths.paths.get(route.path)
+        .map_or(true, |item| !item.operations.contains_key(&path_item_type(&route.method)))
+    })
+    .map(|route| format!("{} {}", route.method, route.path))
+    .collect();
+
+  assert!(missing.is_empty(), "routes missing from the OpenAPI document: {:?}", missing);
+}
+
+#[test]
+fn every_documented_operation_is_routed() {
+  let spec = ApiDoc::openapi();
+  let routes = api_routes();
+
+  for (path, item) in &spec.paths.paths {
+    for operation in item.operations.keys() {
+      assert!(
+        routes.iter().any(|route| route.path == path && path_item_type(&route.method) == *operation),
+        "{} {} is documented but not routed", serde_json::to_string(operation).unwrap(), path
+      );
+    }
+  }
+}
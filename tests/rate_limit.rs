@@ -0,0 +1,109 @@
+mod common;
+
+use axum::{body::Body, http::{header, StatusCode}};
+use common::{request, TestApp};
+use service_http::config::{Config, Quota};
+
+struct Fixture {
+  app: TestApp,
+  alice: String,
+}
+
+// Two requests per route and client, behind a proxy so tests can pick client IPs.
+async fn fixture(enabled: bool) -> Fixture {
+  let mut config = Config::default();
+  config.rate_limit.enabled = enabled;
+  config.rate_limit.trust_forwarded_for = true;
+  config.rate_limit.default = None;
+  config.rate_limit.routes = [("/healthz", Quota::new(2, 60)), ("/validate_session", Quota::new(2, 60))]
+    .into_iter().map(|(route, quota)| (route.to_owned(), quota)).collect();
+
+  let app = TestApp::new(&config);
+  let alice = app.user("alice", 1).await;
+  Fixture { alice: app.login(&alice).await, app }
+}
+
+// Calls `path` as the client at `ip`, returns the status, Retry-After and body.
+async fn send(app: &TestApp, path: &str, ip: &str, token: Option<&str>) -> (StatusCode, Option<String>, String) {
+  let mut request = request(path).header("x-forwarded-for", ip);
+  if let Some(token) = token {
+    request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+  }
+
+  let (status, headers, body) = app.call(request.body(Body::empty()).unwrap()).await;
+  let retry_after = headers.get(header::RETRY_AFTER).map(|value| value.to_str().unwrap().to_owned());
+  (status, retry_after, body.as_str().map_or_else(|| body.to_string(), str::to_owned))
+}
+
+#[tokio::test]
+async fn clients_are_limited_per_ip_and_route() {
+  let fixture = fixture(true).await;
+  let app = &fixture.app;
+
+  for _ in 0..2 {
+    assert_eq!(send(app, "/healthz", "10.0.0.1", None).await.0, StatusCode::OK);
+  }
+  let (status, retry_after, body) = send(app, "/healthz", "10.0.0.1", None).await;
+  assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+  assert_eq!(retry_after.as_deref(), Some("1"));
+  assert!(body.contains("RATE_LIMITED"), "{}", body);
+
+  // Other clients and unlisted routes are unaffected.
+  assert_eq!(send(app, "/healthz", "10.0.0.2", None).await.0, StatusCode::OK);
+  assert_eq!(send(app, "/readyz", "10.0.0.1", None).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn spoofed_forwarded_entries_share_the_proxied_quota() {
+  let fixture = fixture(true).await;
+  let app = &fixture.app;
+
+  // The client made up the leading entries, the proxy appended the address it saw.
+  for spoofed in ["1.1.1.1", "2.2.2.2"] {
+    let forwarded = format!("{}, 10.0.0.1", spoofed);
+    assert_eq!(send(app, "/healthz", &forwarded, None).await.0, StatusCode::OK);
+  }
+  assert_eq!(send(app, "/healthz", "3.3.3.3, 10.0.0.1", None).await.0, StatusCode::TOO_MANY_REQUESTS);
+  assert_eq!(send(app, "/healthz", "10.0.0.1, 10.0.0.2", None).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn users_are_limited_across_ips() {
+  let fixture = fixture(true).await;
+  let (app, alice) = (&fixture.app, fixture.alice.as_str());
+
+  assert_eq!(send(app, "/validate_session", "10.0.0.1", Some(alice)).await.0, StatusCode::OK);
+  assert_eq!(send(app, "/validate_session", "10.0.0.2", Some(alice)).await.0, StatusCode::OK);
+  let (status, retry_after, _) = send(app, "/validate_session", "10.0.0.3", Some(alice)).await;
+  assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+  assert!(retry_after.is_some());
+
+  // An invalid session is not charged to anyone's user bucket.
+  assert_eq!(send(app, "/validate_session", "10.0.0.4", Some("bogus")).await.0, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn rejections_are_exported_as_metrics() {
+  let fixture = fixture(true).await;
+  let (app, alice) = (&fixture.app, fixture.alice.as_str());
+
+  for _ in 0..4 {
+    send(app, "/healthz", "10.0.0.1", None).await;
+  }
+  for ip in ["10.0.0.1", "10.0.0.2", "10.0.0.3"] {
+    send(app, "/validate_session", ip, Some(alice)).await;
+  }
+
+  let (status, _, metrics) = send(app, "/metrics", "10.0.0.1", None).await;
+  assert_eq!(status, StatusCode::OK);
+  assert!(metrics.contains("iou_rate_limit_rejections_total{route=\"/healthz\",client=\"ip\"} 2"), "{}", metrics);
+  assert!(metrics.contains("iou_rate_limit_rejections_total{route=\"/validate_session\",client=\"user\"} 1"), "{}", metrics);
+}
+
+#[tokio::test]
+async fn disabled_limits_let_everything_through() {
+  let fixture = fixture(false).await;
+  for _ in 0..5 {
+    assert_eq!(send(&fixture.app, "/healthz", "10.0.0.1", None).await.0, StatusCode::OK);
+  }
+}
@@ -0,0 +1,67 @@
+use ark_serialize::CanonicalSerialize;
+use arkeddsa::{ed_on_bn254_twist::EdwardsConfig, SigningKey};
+use blake2::Blake2b512;
+use rand::{rngs::StdRng, SeedableRng};
+use service_http::crypto::{message_to_field_elements, poseidon_config, validate_public_key, verify_signature, KeyType, SIGNATURE_POSEIDON_ARITY};
+
+// RFC 8032, section 7.1, TEST 1: empty message.
+const ED25519_PUBKEY: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+const ED25519_SIGNATURE: &str = "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b";
+
+fn to_hex(value: &impl CanonicalSerialize) -> String {
+  let mut bytes = Vec::new();
+  value.serialize_compressed(&mut bytes).unwrap();
+  hex::encode(bytes)
+}
+
+fn babyjubjub_key(seed: u64) -> SigningKey<EdwardsConfig> {
+  SigningKey::<EdwardsConfig>::generate::<Blake2b512>(&mut StdRng::seed_from_u64(seed)).unwrap()
+}
+
+fn babyjubjub_sign(key: &SigningKey<EdwardsConfig>, message: &[u8]) -> String {
+  to_hex(&key.sign::<Blake2b512, _>(poseidon_config(SIGNATURE_POSEIDON_ARITY), &message_to_field_elements(message)))
+}
+
+#[test]
+fn ed25519_rfc8032_vector_verifies() {
+  assert!(verify_signature(KeyType::Ed25519, ED25519_PUBKEY, ED25519_SIGNATURE, b"").unwrap());
+  assert!(validate_public_key(KeyType::Ed25519, ED25519_PUBKEY).is_ok());
+}
+
+#[test]
+fn ed25519_rejects_tampered_signature_and_message() {
+  let mut tampered = hex::decode(ED25519_SIGNATURE).unwrap();
+  tampered[0] ^= 1;
+  assert!(!verify_signature(KeyType::Ed25519, ED25519_PUBKEY, &hex::encode(tampered), b"").unwrap());
+  assert!(!verify_signature(KeyType::Ed25519, ED25519_PUBKEY, ED25519_SIGNATURE, b"x").unwrap());
+}
+
+#[test]
+fn babyjubjub_signature_round_trips() {
+  let key = babyjubjub_key(1);
+  let pubkey = to_hex(key.public_key());
+  let message = b"challenge that is longer than a single thirty one byte field element";
+
+  assert!(validate_public_key(KeyType::BabyJubJub, &pubkey).is_ok());
+  assert!(verify_signature(KeyType::BabyJubJub, &pubkey, &babyjubjub_sign(&key, message), message).unwrap());
+}
+
+#[test]
+fn babyjubjub_rejects_wrong_message_and_key() {
+  let key = babyjubjub_key(1);
+  let other = babyjubjub_key(2);
+  let signature = babyjubjub_sign(&key, b"challenge");
+
+  assert!(!verify_signature(KeyType::BabyJubJub, &to_hex(key.public_key()), &signature, b"challengf").unwrap());
+  assert!(!verify_signature(KeyType::BabyJubJub, &to_hex(other.public_key()), &signature, b"challenge").unwrap());
+}
+
+#[test]
+fn key_types_are_not_interchangeable() {
+  let key = babyjubjub_key(1);
+  let pubkey = to_hex(key.public_key());
+  let signature = babyjubjub_sign(&key, b"");
+
+  assert!(verify_signature(KeyType::Ed25519, &pubkey, &signature, b"").map_or(true, |valid| !valid));
+  assert!(verify_signature(KeyType::BabyJubJub, ED25519_PUBKEY, ED25519_SIGNATURE, b"").map_or(true, |valid| !valid));
+}
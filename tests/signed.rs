@@ -0,0 +1,81 @@
+mod common;
+
+use axum::{body::Body, http::{HeaderValue, StatusCode}};
+use common::{signed, TestApp, User};
+use ed25519_dalek::Signer;
+use serde_json::{json, Value};
+use service_http::{config::Config, routes::signed::{signing_message, SIGNATURE_HEADER}};
+
+async fn fixture() -> (TestApp, User) {
+  let app = TestApp::new(&Config::default());
+  let alice = app.user("alice", 1).await;
+  app.user("bob", 2).await;
+  (app, alice)
+}
+
+fn message(text: &str) -> Value {
+  json!({ "recipient": "bob", "sender": "alice", "message": text, "attachment_id": null })
+}
+
+async fn send(app: &TestApp, alice: &User, nonce: u64, body: &Value) -> (StatusCode, Value) {
+  let (status, _, body) = app.call(signed("/send_message", alice, nonce, body)).await;
+  (status, body)
+}
+
+fn rejected(response: (StatusCode, Value), code: &str) {
+  assert_eq!((response.0, response.1["code"].as_str()), (StatusCode::UNAUTHORIZED, Some(code)), "{}", response.1);
+}
+
+#[tokio::test]
+async fn nonces_are_accepted_once_and_only_upwards() {
+  let (app, alice) = fixture().await;
+
+  assert_eq!(send(&app, &alice, 5, &message("first")).await.0, StatusCode::OK);
+  // A replay, even of a new body, and a nonce below the last one.
+  rejected(send(&app, &alice, 5, &message("first")).await, "NONCE_INVALID");
+  rejected(send(&app, &alice, 5, &message("second")).await, "NONCE_INVALID");
+  rejected(send(&app, &alice, 4, &message("second")).await, "NONCE_INVALID");
+  // Nonces may skip values.
+  assert_eq!(send(&app, &alice, 9, &message("second")).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn signatures_cover_the_body_and_path() {
+  let (app, alice) = fixture().await;
+
+  let mut tampered_body = signed("/send_message", &alice, 1, &message("hi"));
+  *tampered_body.body_mut() = Body::from(message("send me your notes").to_string());
+  let (status, _, body) = app.call(tampered_body).await;
+  rejected((status, body), "UNAUTHORIZED");
+
+  let mut tampered_path = signed("/send_message", &alice, 1, &message("hi"));
+  let other_path = signing_message("/store_note", 1, message("hi").to_string().as_bytes()).unwrap();
+  let signature = HeaderValue::from_str(&hex::encode(alice.key.sign(&other_path).to_bytes())).unwrap();
+  tampered_path.headers_mut().insert(SIGNATURE_HEADER, signature);
+  let (status, _, body) = app.call(tampered_path).await;
+  rejected((status, body), "UNAUTHORIZED");
+
+  // Rejected signatures don't use up the nonce.
+  assert_eq!(send(&app, &alice, 1, &message("hi")).await.0, StatusCode::OK);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn racing_requests_with_one_nonce_are_accepted_once() {
+  let (app, alice) = fixture().await;
+
+  let requests = (0..8).map(|i| {
+    let (app, request) = (app.clone(), signed("/send_message", &alice, 1, &message(&format!("copy {}", i))));
+    tokio::spawn(async move { app.call(request).await })
+  }).collect::<Vec<_>>();
+  let mut outcomes = Vec::new();
+  for request in requests {
+    let (status, _, body) = request.await.unwrap();
+    outcomes.push((status, body));
+  }
+
+  assert_eq!(outcomes.iter().filter(|(status, _)| *status == StatusCode::OK).count(), 1, "{:?}", outcomes);
+  for outcome in outcomes.into_iter().filter(|(status, _)| *status != StatusCode::OK) {
+    rejected(outcome, "NONCE_INVALID");
+  }
+  assert_eq!(app.store.get_unread_messages("bob").await.unwrap().len(), 1);
+}
@@ -0,0 +1,253 @@
+use service_http::{
+  config::AuthConfig,
+  routes::{error::DatabaseError, schema::{NoteNullifierSchema, NullifierState, SaveNoteHistoryRequestSchema}},
+  sql::SqlDB,
+  store::IOUStore,
+};
+use sqlx::{migrate::{Migrate, Migrator}, AnyConnection, Connection};
+
+static MIGRATIONS: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+// Version of the migration that makes nullifiers unique by rebuilding the table.
+const UNIQUE_NULLIFIERS: i64 = 20250101000000;
+
+async fn migrated() -> SqlDB {
+  let db = SqlDB::init("sqlite::memory:", AuthConfig::default()).await.unwrap();
+  db.migrate().await.unwrap();
+  db
+}
+
+// A named in-memory database, shared by every connection to `url` while one is open.
+// Lets a test reach tables the store has no method for.
+async fn shared(name: &str) -> (String, AnyConnection) {
+  sqlx::any::install_default_drivers();
+  let url = format!("sqlite:file:{}?mode=memory&cache=shared", name);
+  let conn = AnyConnection::connect(&url).await.unwrap();
+  (url, conn)
+}
+
+fn spend_of(nullifier: &str, step: i32, state: &str) -> NoteNullifierSchema {
+  NoteNullifierSchema {
+    nullifier: nullifier.to_owned(),
+    note: "{}".to_owned(),
+    step,
+    owner: "alice".to_owned(),
+    state: state.to_owned(),
+    states: Vec::new(),
+  }
+}
+
+fn states(entries: &[(i32, &str)]) -> Vec<NullifierState> {
+  entries.iter().map(|(step, state)| NullifierState { step: *step, state: (*state).to_owned() }).collect()
+}
+
+#[tokio::test]
+async fn migrations_are_applied_once() {
+  let db = SqlDB::init("sqlite::memory:", AuthConfig::default()).await.unwrap();
+  let pending = db.migration_status().await.unwrap();
+  assert_eq!(pending.len(), MIGRATIONS.iter().count());
+  assert!(pending.iter().all(|migration| !migration.applied));
+
+  let applied = db.migrate().await.unwrap();
+  assert!(applied.iter().all(|migration| migration.applied));
+  // Running again finds nothing to do and leaves the schema as it is.
+  let again = db.migrate().await.unwrap();
+  assert_eq!(again.iter().map(|migration| migration.version).collect::<Vec<_>>(),
+    applied.iter().map(|migration| migration.version).collect::<Vec<_>>());
+  assert!(again.iter().all(|migration| migration.applied));
+}
+
+#[tokio::test]
+async fn nullifier_leaves_follow_insertion_order() {
+  let db = migrated().await;
+  for nullifier in ["n-1", "n-2", "n-3"] {
+    db.store_nullifier(&spend_of(nullifier, 1, "s-1")).await.unwrap();
+  }
+
+  assert_eq!(db.nullifier_leaves(0).await.unwrap(), ["n-1", "n-2", "n-3"]);
+  assert_eq!(db.nullifier_leaves(2).await.unwrap(), ["n-3"]);
+}
+
+#[tokio::test]
+async fn taken_leaves_are_retried_then_reported() {
+  let (url, mut conn) = shared("taken_leaves").await;
+  let db = SqlDB::init(&url, AuthConfig::default()).await.unwrap();
+  db.migrate().await.unwrap();
+
+  // Stands in for a concurrent spend that claims the same leaf first, on every attempt.
+  sqlx::query(
+    "CREATE TRIGGER take_leaf BEFORE INSERT ON nullifier_leaves WHEN NEW.nullifier = 'contended'
+     BEGIN INSERT INTO nullifier_leaves (leaf_index, nullifier) VALUES (NEW.leaf_index, 'rival'); END"
+  ).execute(&mut conn).await.unwrap();
+
+  // Losing the leaf is retryable, never reported as the nullifier being taken.
+  let err = db.store_nullifier(&spend_of("contended", 1, "s-1")).await.unwrap_err();
+  assert!(matches!(err.current_context(), DatabaseError::LeafContentionError));
+  // Every attempt was rolled back with its leaf.
+  assert!(db.find_nullifier("contended").await.unwrap().is_none());
+  assert!(db.nullifier_leaves(0).await.unwrap().is_empty());
+
+  sqlx::query("DROP TRIGGER take_leaf").execute(&mut conn).await.unwrap();
+  db.store_nullifier(&spend_of("contended", 1, "s-1")).await.unwrap();
+  assert_eq!(db.nullifier_leaves(0).await.unwrap(), ["contended"]);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_first_spends_all_get_a_leaf() {
+  // A file, so the pool's connections write concurrently.
+  let path = std::env::temp_dir().join(format!("iou-concurrent-spends-{}.db", std::process::id()));
+  let db = SqlDB::init(&format!("sqlite:{}?mode=rwc", path.display()), AuthConfig::default()).await.unwrap();
+  db.migrate().await.unwrap();
+
+  let spends = (0..12).map(|index| {
+    let db = db.clone();
+    tokio::spawn(async move { db.store_nullifier(&spend_of(&format!("n-{}", index), 1, &format!("s-{}", index))).await })
+  }).collect::<Vec<_>>();
+  for spend in spends {
+    spend.await.unwrap().unwrap();
+  }
+
+  let mut leaves = db.nullifier_leaves(0).await.unwrap();
+  leaves.sort();
+  let mut expected = (0..12).map(|index| format!("n-{}", index)).collect::<Vec<_>>();
+  expected.sort();
+  assert_eq!(leaves, expected);
+  // Leaves stay dense, the last spend took leaf 11.
+  assert_eq!(db.nullifier_leaves(11).await.unwrap().len(), 1);
+  std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn recorded_states_extend_the_vector_on_conflict() {
+  let db = migrated().await;
+  assert!(db.record_nullifier_state(&spend_of("n-1", 1, "s-1")).await.unwrap().is_none());
+
+  // The nullifier is already stored, the next step is appended to its vector.
+  let stored = db.record_nullifier_state(&spend_of("n-1", 2, "s-2")).await.unwrap().unwrap();
+  assert_eq!(stored.states, states(&[(1, "s-1")]));
+  assert_eq!(db.find_nullifier("n-1").await.unwrap().unwrap().states, states(&[(1, "s-1"), (2, "s-2")]));
+
+  // A replayed spend and spends reusing a step or a state are left out.
+  for (step, state) in [(2, "s-2"), (2, "s-3"), (3, "s-1")] {
+    let stored = db.record_nullifier_state(&spend_of("n-1", step, state)).await.unwrap().unwrap();
+    assert_eq!(stored.states, states(&[(1, "s-1"), (2, "s-2")]));
+  }
+  // So does a new step spent by anyone but the owner.
+  let other = NoteNullifierSchema { owner: "bob".to_owned(), ..spend_of("n-1", 3, "s-3") };
+  assert_eq!(db.record_nullifier_state(&other).await.unwrap().unwrap().owner, "alice");
+  assert_eq!(db.find_nullifier("n-1").await.unwrap().unwrap().states, states(&[(1, "s-1"), (2, "s-2")]));
+
+  // Only the first spend took a leaf.
+  assert_eq!(db.nullifier_leaves(0).await.unwrap(), ["n-1"]);
+  let first = db.find_nullifier("n-1").await.unwrap().unwrap();
+  assert_eq!((first.step, first.state.as_str()), (1, "s-1"));
+}
+
+#[tokio::test]
+async fn flags_of_unknown_owners_are_not_found() {
+  let db = migrated().await;
+  db.store_nullifier(&spend_of("n-1", 1, "s-1")).await.unwrap();
+  let stored = db.find_nullifier("n-1").await.unwrap().unwrap();
+
+  // Nobody named alice was created, no flag or betrayal is left without a user.
+  let err = db.flag_double_spend(&stored, &spend_of("n-1", 1, "s-2")).await.unwrap_err();
+  assert!(matches!(err.current_context(), DatabaseError::NotFoundError), "{:?}", err);
+  assert!(db.get_flags("alice").await.unwrap().is_empty());
+  assert!(db.get_user_betrayals("alice").await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn transfers_to_unknown_recipients_store_nothing() {
+  let (url, mut conn) = shared("unknown_recipient").await;
+  let db = SqlDB::init(&url, AuthConfig::default()).await.unwrap();
+  db.migrate().await.unwrap();
+
+  let note_history = SaveNoteHistoryRequestSchema { data: vec![1, 2, 3], address: "bob-address".to_owned(), sender: "alice".to_owned() };
+  let err = db.create_and_transfer_note_history("alice".to_owned(), "bob", note_history, "for you".to_owned()).await.unwrap_err();
+  assert!(matches!(err.current_context(), DatabaseError::NotFoundError), "{:?}", err);
+
+  for table in ["note_history", "messages"] {
+    let (count,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(*) FROM {}", table)).fetch_one(&mut conn).await.unwrap();
+    assert_eq!(count, 0, "{}", table);
+  }
+}
+
+#[tokio::test]
+async fn challenges_are_consumed_once_by_their_user() {
+  let db = migrated().await;
+  let challenge = db.create_challenge("alice").await.unwrap();
+
+  let err = db.consume_challenge(&challenge.challenge_id, "bob").await.unwrap_err();
+  assert!(matches!(err.current_context(), DatabaseError::ChallengeUserMismatchError));
+
+  let consumed = db.consume_challenge(&challenge.challenge_id, "alice").await.unwrap();
+  assert_eq!(consumed.challenge_id, challenge.challenge_id);
+  assert_eq!((consumed.user_id.as_str(), consumed.expires_at), ("alice", challenge.expires_at));
+
+  let err = db.consume_challenge(&challenge.challenge_id, "alice").await.unwrap_err();
+  assert!(matches!(err.current_context(), DatabaseError::ChallengeReplayError));
+}
+
+#[tokio::test]
+async fn expired_challenges_are_not_consumed() {
+  let db = SqlDB::init("sqlite::memory:", AuthConfig { challenge_ttl: 0, ..AuthConfig::default() }).await.unwrap();
+  db.migrate().await.unwrap();
+  let challenge = db.create_challenge("alice").await.unwrap();
+
+  let err = db.consume_challenge(&challenge.challenge_id, "alice").await.unwrap_err();
+  assert!(matches!(err.current_context(), DatabaseError::ChallengeReplayError));
+}
+
+// Applies every migration before `version` the way `Migrator::run` does.
+async fn migrate_before(conn: &mut AnyConnection, version: i64) {
+  conn.ensure_migrations_table().await.unwrap();
+  for migration in MIGRATIONS.iter().filter(|migration| migration.version < version) {
+    conn.apply(migration).await.unwrap();
+  }
+}
+
+async fn insert_legacy_nullifier(conn: &mut AnyConnection, id: &str, nullifier: &str, step: i64, state: &str) {
+  sqlx::query("INSERT INTO nullifiers (id, nullifier, note, step, owner, state) VALUES ($1, $2, '{}', $3, 'alice', $4)")
+    .bind(id)
+    .bind(nullifier)
+    .bind(step)
+    .bind(state)
+    .execute(conn)
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn unique_nullifier_rebuild_keeps_stored_nullifiers() {
+  let (url, mut conn) = shared("unique_nullifier_rebuild").await;
+  migrate_before(&mut conn, UNIQUE_NULLIFIERS).await;
+  // Ids are ObjectIds, leaves are backfilled in their order.
+  insert_legacy_nullifier(&mut conn, "000000000000000000000002", "n-2", 4, "s-2").await;
+  insert_legacy_nullifier(&mut conn, "000000000000000000000001", "n-1", 3, "s-1").await;
+
+  let db = SqlDB::init(&url, AuthConfig::default()).await.unwrap();
+  let applied = db.migrate().await.unwrap();
+  assert!(applied.iter().all(|migration| migration.applied));
+
+  let stored = db.find_nullifier("n-1").await.unwrap().unwrap();
+  assert_eq!((stored.step, stored.state.as_str(), stored.owner.as_str()), (3, "s-1", "alice"));
+  assert_eq!(stored.states, states(&[(3, "s-1")]));
+  assert_eq!(db.nullifier_leaves(0).await.unwrap(), ["n-1", "n-2"]);
+
+  // The rebuilt table is unique by nullifier rather than by state.
+  let err = db.store_nullifier(&spend_of("n-1", 5, "s-5")).await.unwrap_err();
+  assert!(matches!(err.current_context(), DatabaseError::DuplicateKeyError));
+  db.store_nullifier(&spend_of("n-3", 1, "s-2-elsewhere")).await.unwrap();
+}
+
+#[tokio::test]
+async fn unique_nullifier_rebuild_refuses_a_nullifier_in_several_states() {
+  let (url, mut conn) = shared("unique_nullifier_conflict").await;
+  migrate_before(&mut conn, UNIQUE_NULLIFIERS).await;
+  insert_legacy_nullifier(&mut conn, "000000000000000000000001", "n-1", 1, "s-1").await;
+  insert_legacy_nullifier(&mut conn, "000000000000000000000002", "n-1", 1, "s-2").await;
+
+  let db = SqlDB::init(&url, AuthConfig::default()).await.unwrap();
+  let err = db.migrate().await.unwrap_err();
+  assert!(matches!(err.current_context(), DatabaseError::MigrationError));
+}